@@ -0,0 +1,98 @@
+// rupt compare <image> <reference> [--heatmap <path>]
+// 2枚の画像の違いを数値で表示し, 画素ごとの誤差を色で表した画像を書き出す
+use crate::renderer::*;
+use std::io::{Error, ErrorKind};
+
+const USAGE: &str = "usage: rupt compare <image> <reference> [--heatmap <path.ppm|path.pfm>]";
+
+pub fn compare(args: &[String]) -> std::io::Result<()> {
+    let usage = || Error::new(ErrorKind::InvalidInput, USAGE);
+    let (image_path, reference_path, heatmap_path) = match args {
+        [image, reference] => (image, reference, derived_path(image, "error", "ppm")),
+        [image, reference, flag, heatmap] if flag == "--heatmap" => {
+            (image, reference, heatmap.clone())
+        }
+        _ => return Err(usage()),
+    };
+
+    let (width, height, image) = read_image(image_path)?;
+    let (reference_width, reference_height, reference) = read_image(reference_path)?;
+    if (width, height) != (reference_width, reference_height) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "image sizes differ: {}x{} and {}x{}",
+                width, height, reference_width, reference_height
+            ),
+        ));
+    }
+
+    let metrics = ImageMetrics::compare(width, height, &image, &reference);
+    println!("MSE:    {:.6e}", metrics.mse);
+    println!("RMSE:   {:.6e}", metrics.rmse);
+    println!("relMSE: {:.6e}", metrics.relative_mse);
+    println!("PSNR:   {:.3} dB", metrics.psnr);
+    println!("SSIM:   {:.6}", metrics.ssim);
+    println!("FLIP:   {:.6}", metrics.flip);
+
+    let heatmap = error_heatmap(&flip_error_map(width, height, &image, &reference));
+    write_heatmap(&heatmap_path, width, height, heatmap)?;
+    println!("heatmap: {}", heatmap_path);
+
+    Ok(())
+}
+
+fn write_heatmap(
+    file_path: &str,
+    width: i32,
+    height: i32,
+    mut heatmap: Picture,
+) -> std::io::Result<()> {
+    if file_path.to_lowercase().ends_with(".pfm") {
+        return write_pfm(
+            file_path,
+            width,
+            height,
+            &AovLayer::color("", heatmap.pixels()),
+        );
+    }
+
+    heatmap.encode(&TransferFunction::Srgb);
+    write_encoded_ppm(
+        file_path,
+        width,
+        height,
+        &heatmap,
+        &TransferFunction::Srgb,
+        false,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrapper::color::Color;
+
+    #[test]
+    fn compare_writes_a_heatmap_next_to_the_image() {
+        let dir = std::env::temp_dir().join(format!("rupt-compare-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        let mut pixels = vec![Color::new(0.5, 0.5, 0.5); 12];
+        write_pfm(&path("reference.pfm"), 4, 3, &AovLayer::color("", &pixels)).unwrap();
+        pixels[0] = Color::new(1.0, 0.0, 0.0);
+        write_pfm(&path("image.pfm"), 4, 3, &AovLayer::color("", &pixels)).unwrap();
+
+        compare(&[path("image.pfm"), path("reference.pfm")]).unwrap();
+        let (width, height, heatmap) = read_image(&path("image.error.ppm")).unwrap();
+        assert_eq!((width, height), (4, 3));
+        assert!(heatmap.pixels()[0].channel(0) > heatmap.pixels()[11].channel(0));
+
+        write_pfm(&path("small.pfm"), 2, 3, &AovLayer::color("", &pixels[..6])).unwrap();
+        assert!(compare(&[path("small.pfm"), path("reference.pfm")]).is_err());
+        assert!(compare(&[path("image.pfm")]).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,18 +1,26 @@
+mod compare;
+#[cfg(test)]
+mod reference_images;
 mod renderer;
 mod wrapper;
 
 use renderer::*;
 use wrapper::{
+    aabb::Aabb,
     color::Color,
     vec::{V3, V3U},
 };
 
 fn cornell_box() -> renderer::Scene {
+    renderer::Scene::new(cornell_box_objects())
+}
+
+fn cornell_box_objects() -> Vec<renderer::Object> {
     let width = 100.0;
     let height = 82.0;
     let depth = 250.0;
 
-    renderer::Scene::new(vec![
+    vec![
         // left
         renderer::Object {
             figure: renderer::Figure::Rhombus(renderer::Rhombus {
@@ -117,7 +125,7 @@ fn cornell_box() -> renderer::Scene {
             emission: Color::new(50.0, 50.0, 50.0),
             ..Default::default()
         },
-    ])
+    ]
 }
 
 fn mis_example() -> renderer::Scene {
@@ -221,10 +229,271 @@ fn mis_example() -> renderer::Scene {
     ])
 }
 
+fn sky_example() -> renderer::Scene {
+    let mut scene = renderer::Scene::new(vec![
+        // ground
+        renderer::Object {
+            figure: renderer::Figure::Rhombus(renderer::Rhombus {
+                origin: V3::new(-1000.0, 0.0, -1000.0),
+                a: V3::new(2000.0, 0.0, 0.0),
+                b: V3::new(0.0, 0.0, 2000.0),
+            }),
+            color: Color::new(0.5, 0.45, 0.4),
+            ..Default::default()
+        },
+        renderer::Object {
+            figure: renderer::Figure::Sphere(renderer::Sphere {
+                radius: 20.0,
+                center: V3::new(30.0, 20.0, 60.0),
+            }),
+            color: Color::new(0.75, 0.25, 0.25),
+            ..Default::default()
+        },
+        renderer::Object {
+            figure: renderer::Figure::parallelepiped(
+                V3::new(60.0, 0.0, 40.0),
+                V3::new(25.0, 0.0, 10.0),
+                V3::new(0.0, 40.0, 0.0),
+                V3::new(-10.0, 0.0, 25.0),
+            ),
+            color: Color::new(0.75, 0.75, 0.75),
+            ..Default::default()
+        },
+    ]);
+    scene.set_sky(Some(renderer::Sky::new(renderer::SkyParameter {
+        sun_dir: V3U::from_v3(V3::new(-0.5, 0.6, 0.4)),
+        ..Default::default()
+    })));
+
+    scene
+}
+
+fn fog_example() -> renderer::Scene {
+    let mut objects = cornell_box_objects();
+    // 煙の球
+    objects.push(renderer::Object {
+        figure: renderer::Figure::Sphere(renderer::Sphere {
+            radius: 14.0,
+            center: V3::new(50.0, 45.0, 60.0),
+        }),
+        reflection: Reflection::Transparent,
+        medium: Some(renderer::Medium {
+            sigma_a: Color::new(0.002, 0.01, 0.02),
+            sigma_s: Color::new(0.08, 0.08, 0.08),
+            g: 0.5,
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+
+    let mut scene = renderer::Scene::new(objects);
+    scene.set_medium(Some(renderer::Medium {
+        sigma_a: Color::new(0.0005, 0.0005, 0.0005),
+        sigma_s: Color::new(0.003, 0.003, 0.003),
+        g: 0.3,
+        ..Default::default()
+    }));
+
+    scene
+}
+
+// ガラス球をロウのような表面下散乱の球に置き換える
+fn subsurface_example() -> renderer::Scene {
+    let mut objects = cornell_box_objects();
+    for object in objects
+        .iter_mut()
+        .filter(|o| o.reflection == Reflection::Refraction)
+    {
+        object.color = Color::new(1.0, 1.0, 1.0);
+        object.reflection = Reflection::Subsurface(renderer::SubsurfaceParameter::new(
+            Color::new(0.9, 0.75, 0.55),
+            Color::new(3.0, 1.5, 0.8),
+            0.0,
+        ));
+    }
+
+    renderer::Scene::new(objects)
+}
+
+// ガラス球を分散の強いガラスに置き換える, INTEGRATOR=spectralで虹色の集光模様が出る
+fn dispersion_example() -> renderer::Scene {
+    let mut objects = cornell_box_objects();
+    for object in objects
+        .iter_mut()
+        .filter(|o| o.reflection == Reflection::Refraction)
+    {
+        object.reflection =
+            Reflection::Dielectric(renderer::RefractiveIndex::Cauchy { a: 1.55, b: 0.03 });
+    }
+
+    renderer::Scene::new(objects)
+}
+
+// 炎の上がる煙, 密度と放射の強さは手続き的に作る(VOLUMEにファイルを指定するとそれを読む)
+fn smoke_example() -> renderer::Scene {
+    let (min, max) = (V3::new(40.0, 0.5, 60.0), V3::new(60.0, 45.0, 85.0));
+    let grid = match option_env!("VOLUME") {
+        Some(path) => renderer::VolumeGrid::load(path, Aabb::new(min, max)).unwrap(),
+        None => smoke_grid(Aabb::new(min, max)),
+    };
+
+    let mut objects = cornell_box_objects();
+    objects.push(renderer::Object {
+        figure: renderer::Figure::parallelepiped(
+            min,
+            V3::new(max.x() - min.x(), 0.0, 0.0),
+            V3::new(0.0, max.y() - min.y(), 0.0),
+            V3::new(0.0, 0.0, max.z() - min.z()),
+        ),
+        reflection: Reflection::Transparent,
+        medium: Some(renderer::Medium {
+            sigma_a: Color::new(0.1, 0.1, 0.1),
+            sigma_s: Color::new(0.6, 0.6, 0.6),
+            g: 0.2,
+            emission: Color::new(40.0, 12.0, 2.0),
+            grid: Some(std::sync::Arc::new(grid)),
+        }),
+        ..Default::default()
+    });
+
+    renderer::Scene::new(objects)
+}
+
+fn smoke_grid(bounds: Aabb) -> renderer::VolumeGrid {
+    let n = 32;
+    let mut density = Vec::with_capacity(n * n * n);
+    let mut temperature = Vec::with_capacity(n * n * n);
+    for k in 0..n {
+        for j in 0..n {
+            for i in 0..n {
+                let (x, y, z) = (
+                    (i as f64 + 0.5) / n as f64 - 0.5,
+                    (j as f64 + 0.5) / n as f64,
+                    (k as f64 + 0.5) / n as f64 - 0.5,
+                );
+                // 上に行くほど広がる柱にゆらぎを加える
+                let r = (x * x + z * z).sqrt();
+                let wobble = 0.04 * (13.0 * y + 7.0 * x).sin() * (11.0 * z - 5.0 * y).cos();
+                let radius = 0.12 + 0.25 * y + wobble;
+                let d = ((radius - r) / 0.08).clamp(0.0, 1.0) * (1.0 - y).max(0.0);
+                density.push(d);
+                temperature.push(((0.35 - y) / 0.35).max(0.0).powi(2));
+            }
+        }
+    }
+
+    renderer::VolumeGrid::new(bounds, (n, n, n), density, Some(temperature))
+}
+
+// 形状を持たない光源(点光源, スポットライト, 平行光源)で照らした, 光沢のある球と屈折率の違うガラス球
+fn lights_example() -> renderer::Scene {
+    let sphere = |center: V3, radius: f64, color: Color, reflection: Reflection| renderer::Object {
+        figure: renderer::Figure::Sphere(renderer::Sphere { center, radius }),
+        color,
+        reflection,
+        ..Default::default()
+    };
+    let objects = vec![
+        // floor
+        renderer::Object {
+            figure: renderer::Figure::Rhombus(renderer::Rhombus {
+                origin: V3::new(-1000.0, 0.0, -1000.0),
+                a: V3::new(2000.0, 0.0, 0.0),
+                b: V3::new(0.0, 0.0, 2000.0),
+            }),
+            color: Color::new(0.6, 0.6, 0.6),
+            ..Default::default()
+        },
+        sphere(
+            V3::new(25.0, 16.0, 60.0),
+            16.0,
+            Color::new(0.9, 0.75, 0.5),
+            Reflection::Glossy(0.2),
+        ),
+        sphere(
+            V3::new(62.0, 12.0, 80.0),
+            12.0,
+            Color::new(1.0, 1.0, 1.0),
+            Reflection::Dielectric(renderer::RefractiveIndex::bk7()),
+        ),
+        // 水
+        sphere(
+            V3::new(85.0, 9.0, 105.0),
+            9.0,
+            Color::new(1.0, 1.0, 1.0),
+            Reflection::Dielectric(renderer::RefractiveIndex::Constant(1.33)),
+        ),
+    ];
+    let lights = vec![
+        renderer::Light::Point(renderer::PointLight {
+            position: V3::new(20.0, 70.0, 110.0),
+            intensity: Color::new(2000.0, 1800.0, 1500.0),
+        }),
+        renderer::Light::Spot(renderer::SpotLight {
+            position: V3::new(80.0, 80.0, 60.0),
+            dir: V3U::from_v3(V3::new(-0.2, -1.0, 0.3)),
+            intensity: Color::new(3000.0, 4000.0, 6000.0),
+            cone_angle: 0.4,
+            penumbra_angle: 0.1,
+        }),
+        renderer::Light::Directional(renderer::DirectionalLight {
+            dir: V3U::from_v3(V3::new(0.4, -1.0, -0.6)),
+            irradiance: Color::new(0.3, 0.3, 0.35),
+            angular_diameter: 0.02,
+        }),
+    ];
+
+    renderer::Scene::with_lights(objects, lights)
+}
+
+fn example_scene(name: &str) -> Result<renderer::Scene, String> {
+    match name {
+        "cornell_box" => Ok(cornell_box()),
+        "mis" => Ok(mis_example()),
+        "sky" => Ok(sky_example()),
+        "fog" => Ok(fog_example()),
+        "smoke" => Ok(smoke_example()),
+        "subsurface" => Ok(subsurface_example()),
+        "dispersion" => Ok(dispersion_example()),
+        "lights" => Ok(lights_example()),
+        _ => Err(format!("unknown scene: {}", name)),
+    }
+}
+
+fn world_setting(renderer: &Renderer) -> WorldSetting {
+    WorldSetting {
+        camera: Camera {
+            position: V3::new(50.0, 52.0, 220.0),
+            dir: V3U::from_v3(V3::new(0.0, -0.04, -1.0)),
+            up: V3U::unit_y(),
+        },
+        screen: Screen {
+            width: 30.0 * renderer.width as f64 / renderer.height as f64,
+            height: 30.0,
+            dist: 40.0,
+        },
+    }
+}
+
 fn main() {
-    let scene = cornell_box();
-    //let scene = mis_example();
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).map(String::as_str) == Some("compare") {
+        if let Err(e) = compare::compare(&args[2..]) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let resume = args.iter().skip(1).any(|a| a == "--resume");
+
+    let mut scene = example_scene(option_env!("SCENE").unwrap_or("cornell_box")).unwrap();
+    if let Some(selection) = option_env!("LIGHT_SELECTION") {
+        scene.set_light_selection(selection.parse::<LightSelection>().unwrap());
+    }
     let option = RendererOption {
+        integrator: option_env!("INTEGRATOR")
+            .map(|r| r.parse::<IntegratorKind>().unwrap())
+            .unwrap_or(IntegratorKind::PathTracing),
         enable_mis: option_env!("ENABLE_MIS")
             .map(|r| r.parse::<bool>().unwrap())
             .unwrap_or(true),
@@ -232,27 +501,371 @@ fn main() {
         enable_mis_debug_mode: option_env!("ENABLE_MIS_DEBUG_MODE")
             .map(|r| r.parse::<bool>().unwrap())
             .unwrap_or(false),
+        clamp: option_env!("CLAMP")
+            .map(|r| r.parse::<RadianceClamp>().unwrap())
+            .unwrap_or_default(),
     };
 
     let renderer = Renderer {
         width: 640,
         height: 480,
         spp: 16,
-        gamma: 2.2,
+        working_space: option_env!("WORKING_SPACE")
+            .map(|r| r.parse::<ColorSpace>().unwrap())
+            .unwrap_or_default(),
+        tone_mapping: option_env!("TONE_MAPPING")
+            .map(|r| r.parse::<ToneMapping>().unwrap())
+            .unwrap_or_default(),
+        exposure: option_env!("EXPOSURE")
+            .map(|r| r.parse::<Exposure>().unwrap())
+            .unwrap_or_default(),
+        transfer_function: option_env!("TRANSFER_FUNCTION")
+            .map(|r| r.parse::<TransferFunction>().unwrap())
+            .unwrap_or_default(),
+        dither: true,
+        accumulation: option_env!("ACCUMULATION")
+            .map(|r| r.parse::<Accumulation>().unwrap())
+            .unwrap_or_default(),
+        denoise: option_env!("DENOISE")
+            .map(|r| r.parse::<DenoiseOutput>().unwrap())
+            .unwrap_or_default(),
+        aov: option_env!("AOV")
+            .map(|r| r.parse::<AovOutput>().unwrap())
+            .unwrap_or_default(),
+        passes: RenderPass::parse_list(option_env!("PASSES").unwrap_or("default")).unwrap(),
+        diagnostics: option_env!("DIAGNOSTICS")
+            .map(|r| r.parse::<bool>().unwrap())
+            .unwrap_or(false),
+        seed: option_env!("SEED").map(|r| r.parse::<u64>().unwrap()),
+        // CHECKPOINTは書き出す間隔(画素あたりのサンプル数), --resumeでout.checkpointから続ける
+        checkpoint: (option_env!("CHECKPOINT").is_some() || resume).then(|| Checkpoint {
+            path: "out.checkpoint".to_string(),
+            interval: option_env!("CHECKPOINT")
+                .map(|r| r.parse::<i32>().unwrap())
+                .unwrap_or(16),
+            resume,
+        }),
         option,
     };
-    let world = WorldSetting {
-        camera: Camera {
-            position: V3::new(50.0, 52.0, 220.0),
-            dir: V3U::from_v3(V3::new(0.0, -0.04, -1.0)),
-            up: V3U::unit_y(),
-        },
-        screen: Screen {
-            width: 30.0 * renderer.width as f64 / renderer.height as f64,
-            height: 30.0,
-            dist: 40.0,
-        },
-    };
+    let world = world_setting(&renderer);
+
+    if let Err(e) = renderer.write_ppm("out.ppm", &world, &scene) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_renderer(kind: IntegratorKind, width: i32, height: i32, spp: i32) -> Renderer {
+        Renderer {
+            width,
+            height,
+            spp,
+            working_space: ColorSpace::LinearSrgb,
+            tone_mapping: ToneMapping::default(),
+            exposure: Exposure::default(),
+            transfer_function: TransferFunction::Srgb,
+            dither: false,
+            accumulation: Accumulation::Mean,
+            denoise: DenoiseOutput::Off,
+            aov: AovOutput::Off,
+            passes: Vec::new(),
+            diagnostics: false,
+            seed: None,
+            checkpoint: None,
+            option: RendererOption {
+                integrator: kind,
+                enable_mis: true,
+                mis_power_heuristic: 2,
+                enable_mis_debug_mode: false,
+                clamp: RadianceClamp::default(),
+            },
+        }
+    }
+
+    fn mean_luminance(kind: IntegratorKind, scene: &Scene) -> f64 {
+        let renderer = test_renderer(kind, 16, 12, 1024);
+        let world = world_setting(&renderer);
+        let pixels = renderer.render(&world, scene).unwrap().into_vec();
+
+        pixels.iter().map(|c| c.luminance()).sum::<f64>() / pixels.len() as f64
+    }
+
+    #[test]
+    fn bdpt_agrees_with_path_tracing_on_cornell_box() {
+        let scene = cornell_box();
+        let pt = mean_luminance(IntegratorKind::PathTracing, &scene);
+        let bdpt = mean_luminance(IntegratorKind::BidirectionalPathTracing, &scene);
+
+        assert!(
+            (pt - bdpt).abs() < 0.15 * pt,
+            "path tracing: {}, bdpt: {}",
+            pt,
+            bdpt
+        );
+    }
+
+    #[test]
+    fn mlt_agrees_with_path_tracing_on_cornell_box() {
+        let scene = cornell_box();
+        let pt = mean_luminance(IntegratorKind::PathTracing, &scene);
+        let mlt = mean_luminance(IntegratorKind::MetropolisLightTransport, &scene);
+
+        assert!(
+            (pt - mlt).abs() < 0.15 * pt,
+            "path tracing: {}, mlt: {}",
+            pt,
+            mlt
+        );
+    }
+
+    #[test]
+    fn photon_mapping_agrees_with_path_tracing_on_cornell_box() {
+        let scene = cornell_box();
+        let pt = mean_luminance(IntegratorKind::PathTracing, &scene);
+
+        // フォトンの数と最終収集を減らしてmean_luminanceと同じサンプル数で描く
+        let renderer = Renderer {
+            seed: Some(1),
+            ..test_renderer(IntegratorKind::PhotonMapping, 16, 12, 1024)
+        };
+        let world = world_setting(&renderer);
+        let integrators: [(&str, &dyn Integrator); 2] = [
+            (
+                "photon mapping",
+                &PhotonMapper {
+                    global_photons: 20000,
+                    caustic_photons: 50000,
+                    nearest_photons: 32,
+                    max_radius: 8.0,
+                    final_gather_samples: 1,
+                    max_depth: 16,
+                },
+            ),
+            (
+                "sppm",
+                &StochasticProgressivePhotonMapper {
+                    photons_per_iteration: 2000,
+                    initial_radius: 4.0,
+                    alpha: 2.0 / 3.0,
+                    max_depth: 16,
+                },
+            ),
+        ];
+        for (name, integrator) in integrators {
+            let pixels = renderer.render_with(integrator, &world, &scene);
+            let pixels = pixels.unwrap().into_vec();
+            let pm = pixels.iter().map(|c| c.luminance()).sum::<f64>() / pixels.len() as f64;
+
+            assert!(
+                (pt - pm).abs() < 0.15 * pt,
+                "path tracing: {}, {}: {}",
+                pt,
+                name,
+                pm
+            );
+        }
+    }
+
+    #[test]
+    fn integrators_reject_settings_they_would_ignore() {
+        let unsupported = |renderer: &Renderer, scene: &Scene| {
+            let world = world_setting(renderer);
+            matches!(
+                renderer.render(&world, scene),
+                Err(RenderError::Unsupported(_))
+            )
+        };
+
+        let fog = fog_example();
+        assert!(unsupported(
+            &test_renderer(IntegratorKind::SpectralPathTracing, 4, 3, 1),
+            &fog
+        ));
+        assert!(!unsupported(
+            &test_renderer(IntegratorKind::PathTracing, 4, 3, 1),
+            &fog
+        ));
+
+        // 表面下散乱はPathTracerを使う手法でなければ屈折として扱われてしまう
+        let subsurface = subsurface_example();
+        for kind in [
+            IntegratorKind::BidirectionalPathTracing,
+            IntegratorKind::PhotonMapping,
+            IntegratorKind::StochasticProgressivePhotonMapping,
+            IntegratorKind::SpectralPathTracing,
+        ] {
+            assert!(unsupported(&test_renderer(kind, 4, 3, 1), &subsurface));
+        }
+        for kind in [
+            IntegratorKind::PathTracing,
+            IntegratorKind::MetropolisLightTransport,
+        ] {
+            assert!(!unsupported(&test_renderer(kind, 4, 3, 1), &subsurface));
+        }
+
+        let scene = cornell_box();
+        let mut renderer = test_renderer(IntegratorKind::SpectralPathTracing, 4, 3, 1);
+        renderer.option.clamp = "10".parse().unwrap();
+        assert!(unsupported(&renderer, &scene));
+        renderer.option.integrator = IntegratorKind::PathTracing;
+        assert!(!unsupported(&renderer, &scene));
+
+        let mut renderer = test_renderer(IntegratorKind::BidirectionalPathTracing, 4, 3, 1);
+        renderer.accumulation = "mom".parse().unwrap();
+        assert!(unsupported(&renderer, &scene));
+        renderer.option.integrator = IntegratorKind::SpectralPathTracing;
+        assert!(!unsupported(&renderer, &scene));
+
+        // チェックポイントは画素ごとに計算する手法でAOVなしの場合だけ書き出せる
+        let mut renderer = Renderer {
+            checkpoint: Some(Checkpoint {
+                path: "unused.checkpoint".to_string(),
+                interval: 1,
+                resume: false,
+            }),
+            ..test_renderer(IntegratorKind::BidirectionalPathTracing, 4, 3, 1)
+        };
+        assert!(unsupported(&renderer, &scene));
+        renderer.option.integrator = IntegratorKind::PathTracing;
+        assert!(matches!(
+            renderer.render_aovs(&world_setting(&renderer), &scene),
+            Err(RenderError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn passes_use_the_same_median_of_means_as_the_image() {
+        let renderer = Renderer {
+            accumulation: "mom:3".parse().unwrap(),
+            passes: RenderPass::defaults(),
+            ..test_renderer(IntegratorKind::PathTracing, 8, 6, 9)
+        };
+        let world = world_setting(&renderer);
+        let (picture, aovs) = renderer.render_aovs(&world, &cornell_box()).unwrap();
+
+        // directとindirectで経路を分け尽くす
+        let pass = |name: &str| &aovs.passes.iter().find(|(n, _)| n == name).unwrap().1;
+        for (i, c) in picture.pixels().iter().enumerate() {
+            let sum = pass("direct")[i] + pass("indirect")[i];
+            for k in 0..3 {
+                assert!(
+                    (sum.channel(k) - c.channel(k)).abs() < 1e-9 * (1.0 + c.channel(k)),
+                    "{:?} vs {:?}",
+                    sum,
+                    c
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn seeded_renders_are_reproducible() {
+        let scene = cornell_box();
+        for kind in [
+            IntegratorKind::BidirectionalPathTracing,
+            IntegratorKind::MetropolisLightTransport,
+        ] {
+            let renderer = Renderer {
+                seed: Some(7),
+                ..test_renderer(kind, 8, 6, 4)
+            };
+            let world = world_setting(&renderer);
+            let first = renderer.render(&world, &scene).unwrap().into_vec();
+            let second = renderer.render(&world, &scene).unwrap().into_vec();
+
+            // MLTは連鎖の寄与を足す順序が並列化の分け方で変わるので丸め誤差の分だけずれる
+            for (a, b) in first.iter().zip(&second) {
+                for k in 0..3 {
+                    let (a, b) = (a.channel(k), b.channel(k));
+                    assert!(
+                        (a - b).abs() <= 1e-9 * a.abs().max(1.0),
+                        "{:?}: {} vs {}",
+                        kind,
+                        a,
+                        b
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn resumed_render_matches_uninterrupted_render() {
+        let path = std::env::temp_dir()
+            .join(format!("rupt-resume-{}.checkpoint", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let scene = cornell_box();
+        let renderer = |spp: i32, resume: bool| Renderer {
+            accumulation: "mom:3".parse().unwrap(),
+            checkpoint: Some(Checkpoint {
+                path: path.clone(),
+                interval: 2,
+                resume,
+            }),
+            ..test_renderer(IntegratorKind::PathTracing, 8, 6, spp)
+        };
+        let world = world_setting(&renderer(0, false));
+
+        // 3サンプルで終えた描画に5サンプル足す, シードはチェックポイントに残ったものを使う
+        renderer(3, false).render(&world, &scene).unwrap();
+        let resumed = renderer(8, true).render(&world, &scene).unwrap();
+        let data = CheckpointData::decode(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(data.samples, 8);
+
+        let uninterrupted = Renderer {
+            accumulation: "mom:3".parse().unwrap(),
+            seed: Some(data.seed),
+            ..test_renderer(IntegratorKind::PathTracing, 8, 6, 8)
+        };
+        let expected = uninterrupted.render(&world, &scene).unwrap();
+        assert_eq!(resumed.pixels(), expected.pixels());
+
+        // 大きさや積分器が違うチェックポイントからは再開しない
+        let other = Renderer {
+            width: 4,
+            ..renderer(8, true)
+        };
+        assert!(matches!(
+            other.render(&world, &scene),
+            Err(RenderError::Checkpoint(_))
+        ));
+        let mut other = renderer(16, true);
+        other.option.integrator = IntegratorKind::SpectralPathTracing;
+        assert!(other.render(&world, &scene).is_err());
+
+        // シーンやカメラが違っても再開しない
+        let rejected = |world: &WorldSetting, scene: &Scene| {
+            matches!(
+                renderer(16, true).render(world, scene),
+                Err(RenderError::Checkpoint(_))
+            )
+        };
+        assert!(rejected(&world, &mis_example()));
+        let mut moved = world_setting(&renderer(0, false));
+        moved.camera.position = V3::new(50.0, 52.0, 230.0);
+        assert!(rejected(&moved, &scene));
+
+        std::fs::remove_file(&path).unwrap();
+    }
 
-    renderer.write_ppm("out.ppm", &world, &scene).unwrap();
+    #[test]
+    fn example_scenes_are_valid() {
+        for scene in [
+            cornell_box(),
+            mis_example(),
+            sky_example(),
+            fog_example(),
+            subsurface_example(),
+            dispersion_example(),
+            smoke_example(),
+            lights_example(),
+        ] {
+            assert_eq!(scene.validate(), Ok(()));
+        }
+    }
 }
@@ -0,0 +1,256 @@
+// 組み込みのシーンを小さく, 乱数のシードを固定して描画し, tests/reference/の参照画像と比べる
+// 参照画像はサンプル数を増やして収束させたもので, 許容値はシーンごとのsppでのモンテカルロの雑音から決める
+// 違いが許容値を超えたらtarget/reference-diff/に描画結果と差の画像を書き出す
+// 描画が意図して変わった場合は UPDATE_REFERENCES=1 cargo test reference で参照画像を作り直す
+use crate::*;
+
+const WIDTH: i32 = 32;
+const HEIGHT: i32 = 24;
+const REFERENCE_SPP: i32 = 4096;
+const SEED: u64 = 1;
+// 参照画像は別のシードで描き, 比べる描画と同じ乱数列を使わないようにする
+const REFERENCE_SEED: u64 = 0x5eed;
+
+fn reference_path(name: &str) -> String {
+    format!(
+        "{}/tests/reference/{}.pfm",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    )
+}
+
+fn diff_path(name: &str, suffix: &str) -> String {
+    format!(
+        "{}/target/reference-diff/{}.{}.pfm",
+        env!("CARGO_MANIFEST_DIR"),
+        name,
+        suffix
+    )
+}
+
+fn render(scene: &Scene, integrator: IntegratorKind, spp: i32, seed: u64) -> Picture {
+    let renderer = Renderer {
+        width: WIDTH,
+        height: HEIGHT,
+        spp,
+        working_space: ColorSpace::LinearSrgb,
+        tone_mapping: ToneMapping::default(),
+        exposure: Exposure::default(),
+        transfer_function: TransferFunction::Srgb,
+        dither: false,
+        accumulation: Accumulation::Mean,
+        denoise: DenoiseOutput::Off,
+        aov: AovOutput::Off,
+        passes: Vec::new(),
+        diagnostics: false,
+        seed: Some(seed),
+        checkpoint: None,
+        option: RendererOption {
+            integrator,
+            enable_mis: true,
+            mis_power_heuristic: 2,
+            enable_mis_debug_mode: false,
+            clamp: RadianceClamp::default(),
+        },
+    };
+    let world = world_setting(&renderer);
+
+    renderer.render(&world, scene).unwrap()
+}
+
+// 期待値が変わらない変更(乱数の使い方など)では雑音の範囲に収まり, 偏りが入ると許容値を超える
+fn check(name: &str, scene: Scene, integrator: IntegratorKind, spp: i32, tolerance: Tolerance) {
+    let write = |path: &str, layer: AovLayer| {
+        std::fs::create_dir_all(std::path::Path::new(path).parent().unwrap()).unwrap();
+        write_pfm(path, WIDTH, HEIGHT, &layer).unwrap();
+    };
+
+    if std::env::var_os("UPDATE_REFERENCES").is_some() {
+        let reference = render(&scene, integrator, REFERENCE_SPP, REFERENCE_SEED);
+        write(
+            &reference_path(name),
+            AovLayer::color("", reference.pixels()),
+        );
+        return;
+    }
+    let picture = render(&scene, integrator, spp, SEED);
+
+    let (width, height, reference) = read_image(&reference_path(name)).unwrap_or_else(|e| {
+        panic!(
+            "cannot read the reference image of {} ({}), run with UPDATE_REFERENCES=1",
+            name, e
+        )
+    });
+    assert_eq!((width, height), (WIDTH, HEIGHT));
+    let metrics = ImageMetrics::compare(WIDTH, HEIGHT, &picture, &reference);
+    let relative_mse = trimmed_relative_mse(&picture, &reference);
+    let mean_luminance = mean_luminance_error(&picture, &reference);
+    if relative_mse <= tolerance.relative_mse
+        && metrics.flip <= tolerance.flip
+        && mean_luminance <= tolerance.mean_luminance
+    {
+        return;
+    }
+
+    let difference = (picture.pixels().iter().zip(reference.pixels()))
+        .map(|(a, b)| {
+            let d = |k| (a.channel(k) - b.channel(k)).abs();
+            Color::new(d(0), d(1), d(2))
+        })
+        .collect::<Vec<_>>();
+    let flip = flip_error_map(WIDTH, HEIGHT, &picture, &reference);
+    write(
+        &diff_path(name, "render"),
+        AovLayer::color("", picture.pixels()),
+    );
+    write(&diff_path(name, "diff"), AovLayer::color("", &difference));
+    write(
+        &diff_path(name, "flip"),
+        AovLayer::scalar("flip", "Y", flip),
+    );
+    panic!(
+        "{} differs from the reference: relative_mse {} mean_luminance {} {:?} (tolerance {:?}), see {}",
+        name,
+        relative_mse,
+        mean_luminance,
+        metrics,
+        tolerance,
+        diff_path(name, "*")
+    );
+}
+
+// 誤差の大きい2%の画素を除いたrelMSE
+// まれな明るいサンプル(firefly)で値が何桁も振れるので, それを外して雑音の大きさだけを見る
+fn trimmed_relative_mse(image: &Picture, reference: &Picture) -> f64 {
+    let mut errors = (image.pixels().iter().zip(reference.pixels()))
+        .map(|(a, b)| {
+            (0..3)
+                .map(|k| {
+                    (a.channel(k) - b.channel(k)).powi(2)
+                        / (b.channel(k).powi(2) + RELATIVE_MSE_EPS)
+                })
+                .sum::<f64>()
+                / 3.0
+        })
+        .collect::<Vec<_>>();
+    errors.sort_by(f64::total_cmp);
+    let n = errors.len() - errors.len() * 2 / 100;
+    errors[..n].iter().sum::<f64>() / n.max(1) as f64
+}
+
+// 画像全体の明るさの偏りを見る, 平均輝度の相対誤差
+// 光源が直接見える画素やfireflyで平均が振れないように, 参照画像の輝度の上位1%の値で頭打ちにする
+fn mean_luminance_error(image: &Picture, reference: &Picture) -> f64 {
+    let mut luminances = (reference.pixels().iter())
+        .map(|c| c.luminance())
+        .collect::<Vec<_>>();
+    luminances.sort_by(f64::total_cmp);
+    let clip = luminances[luminances.len() * 99 / 100];
+    let mean = |p: &Picture| {
+        (p.pixels().iter())
+            .map(|c| c.luminance().min(clip))
+            .sum::<f64>()
+            / p.pixels().len() as f64
+    };
+
+    (mean(image) / mean(reference) - 1.0).abs()
+}
+
+// 各指標の上限
+// SEEDを1から12まで変えて同じsppで描画したときの最大値のおよそ1.5倍
+#[derive(Debug)]
+struct Tolerance {
+    relative_mse: f64,
+    flip: f64,
+    mean_luminance: f64,
+}
+
+#[test]
+fn reference_cornell_box() {
+    check(
+        "cornell_box",
+        cornell_box(),
+        IntegratorKind::PathTracing,
+        256,
+        Tolerance {
+            relative_mse: 0.15,
+            flip: 0.065,
+            mean_luminance: 0.08,
+        },
+    );
+}
+
+#[test]
+fn reference_mis_example() {
+    check(
+        "mis_example",
+        mis_example(),
+        IntegratorKind::PathTracing,
+        64,
+        Tolerance {
+            relative_mse: 0.0002,
+            flip: 0.021,
+            mean_luminance: 0.055,
+        },
+    );
+}
+
+#[test]
+fn reference_sky_example() {
+    check(
+        "sky_example",
+        sky_example(),
+        IntegratorKind::PathTracing,
+        64,
+        Tolerance {
+            relative_mse: 0.0065,
+            flip: 0.01,
+            mean_luminance: 0.0085,
+        },
+    );
+}
+
+#[test]
+fn reference_fog_example() {
+    check(
+        "fog_example",
+        fog_example(),
+        IntegratorKind::PathTracing,
+        256,
+        Tolerance {
+            relative_mse: 0.1,
+            flip: 0.065,
+            mean_luminance: 0.07,
+        },
+    );
+}
+
+#[test]
+fn reference_subsurface_example() {
+    check(
+        "subsurface_example",
+        subsurface_example(),
+        IntegratorKind::PathTracing,
+        256,
+        Tolerance {
+            relative_mse: 0.085,
+            flip: 0.055,
+            mean_luminance: 0.07,
+        },
+    );
+}
+
+#[test]
+fn reference_dispersion_example() {
+    check(
+        "dispersion_example",
+        dispersion_example(),
+        IntegratorKind::SpectralPathTracing,
+        256,
+        Tolerance {
+            relative_mse: 0.28,
+            flip: 0.15,
+            mean_luminance: 0.085,
+        },
+    );
+}
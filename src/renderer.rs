@@ -1,11 +1,38 @@
+mod aov;
+mod checkpoint;
+mod color_management;
+mod diagnostics;
 mod figure;
+mod firefly;
+mod integrator;
+mod light;
+mod light_path;
+mod light_sampler;
+mod medium;
 mod picture;
 mod reflection;
+#[allow(clippy::module_inception)]
 mod renderer;
+mod sampler;
 mod scene;
+mod sky;
+mod spectrum;
 
+pub use aov::*;
+pub use checkpoint::*;
+pub use color_management::*;
+pub use diagnostics::*;
 pub use figure::*;
+pub use firefly::*;
+pub use integrator::*;
+pub use light::*;
+pub use light_path::*;
+pub use light_sampler::*;
+pub use medium::*;
 pub use picture::*;
 pub use reflection::*;
 pub use renderer::*;
+pub use sampler::*;
 pub use scene::*;
+pub use sky::*;
+pub use spectrum::*;
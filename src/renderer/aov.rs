@@ -0,0 +1,580 @@
+use crate::renderer::{
+    uniform_random, Accumulator, ColorSpace, Object, Picture, Reflection, RenderPass, Scene, Sensor,
+};
+use crate::wrapper::{color::Color, ray::Ray, vec::V3};
+use rayon::prelude::*;
+use std::fs::File;
+use std::str::FromStr;
+
+mod exr;
+pub use exr::*;
+
+// 鏡面反射や屈折をたどる回数の上限
+const SPECULAR_DEPTH: i32 = 8;
+
+// 描画と同時に求める画素ごとの補助的な値(arbitrary output variables)
+// 幾何的な値と反射率は, 鏡面反射や屈折をたどって最初に拡散反射する面のもの(デノイズの手がかりにする)
+// IDはカメラからのレイが最初に当たった面のもの
+#[derive(Clone, Debug)]
+pub struct Aovs {
+    pub width: i32,
+    pub height: i32,
+    // 面までの経路の長さ, 当たらなかった画素は無限大
+    pub depth: Vec<f64>,
+    // レイの来た側を向いた法線
+    pub normal: Vec<V3>,
+    pub position: Vec<V3>,
+    // 光源や何にも当たらないところはそこまでの鏡面反射の色(直接見えれば1)
+    pub albedo: Vec<Color>,
+    // 画素の中心に最も近いサンプルで見えたもの
+    pub object_id: Vec<Option<usize>>,
+    pub material_id: Vec<Option<usize>>,
+    // LPEで振り分けた経路の寄与(パスの名前, 画素), 振り分けられない手法では空
+    pub passes: Vec<(String, Vec<Color>)>,
+    pub sample_count: Vec<u32>,
+}
+
+// 画素ごとに集計する途中の値
+struct AovPixel<'a> {
+    radience: Accumulator,
+    // パスごとに放射輝度と同じ組に分けて集め, 同じ組でまとめる
+    passes: Option<Vec<Accumulator>>,
+    hits: u32,
+    // 有限で, まとめるのに使ったサンプルの数
+    samples: u32,
+    depth: f64,
+    normal: V3,
+    position: V3,
+    albedo: Color,
+    // (画素の中心からの距離の2乗, 見えたオブジェクト)
+    center: Option<(f64, Option<&'a Object>)>,
+}
+
+impl<'a> AovPixel<'a> {
+    fn center_object(&self) -> Option<&'a Object> {
+        self.center.and_then(|(_, target)| target)
+    }
+}
+
+impl Aovs {
+    // 画素ごとにspp本のレイを飛ばし, 放射輝度とAOVを同時に求める
+    pub fn render_per_pixel(
+        sensor: &Sensor,
+        scene: &Scene,
+        passes: &[RenderPass],
+        radience: impl Fn(Ray) -> (Color, Option<Vec<Color>>) + Sync,
+    ) -> (Picture, Aovs) {
+        let mut pixels = Vec::with_capacity((sensor.width * sensor.height) as usize);
+
+        (0..sensor.width * sensor.height)
+            .into_par_iter()
+            .map(|i| {
+                let mut pixel = AovPixel {
+                    radience: Accumulator::new(&sensor.accumulation),
+                    passes: Some(
+                        passes
+                            .iter()
+                            .map(|_| Accumulator::new(&sensor.accumulation))
+                            .collect(),
+                    ),
+                    hits: 0,
+                    samples: 0,
+                    depth: 0.0,
+                    normal: V3::zero(),
+                    position: V3::zero(),
+                    albedo: Color::black(),
+                    center: None,
+                };
+
+                for sample in 0..sensor.spp {
+                    sensor.with_sample_sampler(i, sample, || {
+                        let r1 = uniform_random();
+                        let r2 = uniform_random();
+                        let ray = sensor.ray(i, r1, r2);
+
+                        if let Some((rad, values)) = sensor.sample(i, || radience(ray.clone())) {
+                            pixel.radience.add(rad);
+                            pixel.samples += 1;
+                            pixel.passes =
+                                pixel.passes.take().zip(values).map(|(mut sums, values)| {
+                                    for (s, v) in sums.iter_mut().zip(values) {
+                                        s.add(v);
+                                    }
+                                    sums
+                                });
+                        }
+
+                        let (primary, albedo, surface) = first_diffuse_hit(scene, ray);
+                        pixel.albedo += albedo;
+                        if let Some((length, normal, position)) = surface {
+                            pixel.hits += 1;
+                            pixel.depth += length;
+                            pixel.normal = pixel.normal + normal;
+                            pixel.position = pixel.position + position;
+                        }
+
+                        let d = (r1 - 0.5).powi(2) + (r2 - 0.5).powi(2);
+                        if pixel.center.is_none_or(|(nearest, _)| d < nearest) {
+                            pixel.center = Some((d, primary));
+                        }
+                    });
+                }
+
+                pixel
+            })
+            .collect_into_vec(&mut pixels);
+
+        let spp = sensor.spp.max(1) as f64;
+        let per_hit = |p: &AovPixel| 1.0 / p.hits.max(1) as f64;
+        let aovs = Aovs {
+            width: sensor.width,
+            height: sensor.height,
+            depth: pixels
+                .iter()
+                .map(|p| {
+                    if p.hits == 0 {
+                        f64::INFINITY
+                    } else {
+                        p.depth * per_hit(p)
+                    }
+                })
+                .collect(),
+            normal: pixels.iter().map(|p| p.normal.scale(per_hit(p))).collect(),
+            position: pixels
+                .iter()
+                .map(|p| p.position.scale(per_hit(p)))
+                .collect(),
+            albedo: pixels.iter().map(|p| p.albedo.scale(1.0 / spp)).collect(),
+            object_id: pixels
+                .iter()
+                .map(|p| p.center_object().and_then(|o| scene.object_id(o)))
+                .collect(),
+            material_id: pixels
+                .iter()
+                .map(|p| p.center_object().and_then(|o| scene.material_id(o)))
+                .collect(),
+            passes: match pixels
+                .iter()
+                .map(|p| p.passes.as_ref())
+                .collect::<Option<Vec<_>>>()
+            {
+                Some(values) => passes
+                    .iter()
+                    .enumerate()
+                    .map(|(k, pass)| {
+                        let pixels = (values.iter().zip(&pixels))
+                            .map(|(v, p)| v[k].mean_of(&p.radience.median_groups()))
+                            .collect();
+                        (pass.name.clone(), pixels)
+                    })
+                    .collect(),
+                None => Vec::new(),
+            },
+            sample_count: pixels.iter().map(|p| p.samples).collect(),
+        };
+
+        (
+            Picture::new(pixels.iter().map(|p| p.radience.result()).collect()),
+            aovs,
+        )
+    }
+
+    // 放射輝度を別に求める手法のための, AOVだけを求めるパス
+    pub fn render(sensor: &Sensor, scene: &Scene) -> Aovs {
+        Aovs::render_per_pixel(sensor, scene, &[], |_| (Color::black(), None)).1
+    }
+
+    // 出力する層, 当たらなかった画素のIDは-1
+    pub fn layers(&self) -> Vec<AovLayer> {
+        let id = |ids: &[Option<usize>]| ids.iter().map(|i| i.map_or(-1.0, |i| i as f64)).collect();
+
+        let mut layers = vec![
+            AovLayer::scalar("depth", "Z", self.depth.clone()),
+            AovLayer::vector("normal", &self.normal),
+            AovLayer::vector("position", &self.position),
+            AovLayer::color("albedo", &self.albedo),
+            AovLayer::scalar("object_id", "id", id(&self.object_id)),
+            AovLayer::scalar("material_id", "id", id(&self.material_id)),
+        ];
+        for (name, pixels) in &self.passes {
+            layers.push(AovLayer::color(name, pixels));
+        }
+        layers.push(AovLayer::scalar(
+            "sample_count",
+            "count",
+            self.sample_count.iter().map(|n| *n as f64).collect(),
+        ));
+
+        layers
+    }
+}
+
+// 最初に拡散反射する面(そこまでの経路の長さ, 法線, 位置)
+type Surface = (f64, V3, V3);
+
+// (最初に当たったオブジェクト, 最初に拡散反射する面の反射率, その面)
+// 光源や何にも当たらない場合は反射率をそこまでの鏡面反射の色とし, 当たらなければ面はNone
+fn first_diffuse_hit(scene: &Scene, ray: Ray) -> (Option<&Object>, Color, Option<Surface>) {
+    let mut ray = ray;
+    let mut primary = None;
+    let mut weight = Color::new(1.0, 1.0, 1.0);
+    let mut length = 0.0;
+
+    for depth in 0..SPECULAR_DEPTH {
+        let (hit, target) = match scene.intersect(&ray) {
+            Some(r) => r,
+            None => return (primary, weight, None),
+        };
+        if depth == 0 {
+            primary = Some(target);
+        }
+        length += hit.distance;
+        let surface = Some((length, hit.normal.as_v3(), hit.position));
+
+        if target.emission > Color::black() {
+            return (primary, weight, surface);
+        }
+        if target.reflection.is_nee_target()
+            || matches!(target.reflection, Reflection::Subsurface(_))
+        {
+            return (primary, weight.blend(target.color), surface);
+        }
+
+        weight = weight.blend(target.color);
+        ray = target.reflection.reflected(&ray, &hit).ray;
+    }
+
+    (primary, weight, None)
+}
+
+// 名前の付いた, 1つ以上のチャンネルからなる画像
+#[derive(Clone, Debug, PartialEq)]
+pub struct AovLayer {
+    // 空の場合は最終的な画像(EXRでの既定の層)
+    pub name: String,
+    pub channels: Vec<(String, Vec<f32>)>,
+}
+
+impl AovLayer {
+    pub fn scalar(name: &str, channel: &str, values: Vec<f64>) -> Self {
+        AovLayer {
+            name: name.to_string(),
+            channels: vec![(
+                channel.to_string(),
+                values.into_iter().map(|v| v as f32).collect(),
+            )],
+        }
+    }
+
+    pub fn color(name: &str, pixels: &[Color]) -> Self {
+        AovLayer::from_channels(name, &["R", "G", "B"], |i| {
+            pixels.iter().map(|c| c.channel(i) as f32).collect()
+        })
+    }
+
+    pub fn vector(name: &str, vectors: &[V3]) -> Self {
+        AovLayer::from_channels(name, &["X", "Y", "Z"], |i| {
+            vectors.iter().map(|v| v.axis(i) as f32).collect()
+        })
+    }
+
+    fn from_channels(name: &str, channels: &[&str], values: impl Fn(usize) -> Vec<f32>) -> Self {
+        AovLayer {
+            name: name.to_string(),
+            channels: channels
+                .iter()
+                .enumerate()
+                .map(|(i, c)| (c.to_string(), values(i)))
+                .collect(),
+        }
+    }
+
+    // EXRでのチャンネル名(layer.channel)
+    pub fn channel_name(&self, channel: &str) -> String {
+        if self.name.is_empty() {
+            channel.to_string()
+        } else {
+            format!("{}.{}", self.name, channel)
+        }
+    }
+}
+
+// AOVの出力方法
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum AovOutput {
+    #[default]
+    Off,
+    // 層ごとにPFMの画像を書き出す
+    Separate,
+    // 最終的な画像(線形)とすべての層を1つのEXRに書き出す
+    Exr,
+}
+
+impl FromStr for AovOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(AovOutput::Off),
+            "pfm" | "separate" => Ok(AovOutput::Separate),
+            "exr" => Ok(AovOutput::Exr),
+            _ => Err(format!("unknown aov output: {}", s)),
+        }
+    }
+}
+
+// Portable Float Map, 1チャンネルか3チャンネルの層のみ
+pub fn encode_pfm(width: i32, height: i32, layer: &AovLayer) -> Vec<u8> {
+    let channels = &layer.channels;
+    assert!(channels.len() == 1 || channels.len() == 3);
+
+    let mut bytes = format!(
+        "{}\n{} {}\n-1.0\n",
+        if channels.len() == 1 { "Pf" } else { "PF" },
+        width,
+        height
+    )
+    .into_bytes();
+    // 行は下から上の順
+    for y in (0..height).rev() {
+        for x in 0..width {
+            for (_, values) in channels {
+                bytes.extend_from_slice(&values[(y * width + x) as usize].to_le_bytes());
+            }
+        }
+    }
+
+    bytes
+}
+
+pub fn write_pfm(
+    file_path: &str,
+    width: i32,
+    height: i32,
+    layer: &AovLayer,
+) -> std::io::Result<()> {
+    use std::io::Write;
+
+    File::create(file_path)?.write_all(&encode_pfm(width, height, layer))
+}
+
+// PFMを読み, 画素を上の行から並べて返す(1チャンネルのものは灰色にする)
+pub fn decode_pfm(bytes: &[u8]) -> std::io::Result<(i32, i32, Vec<Color>)> {
+    use std::io::{Error, ErrorKind};
+    let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+
+    // ヘッダは空白で区切られた4つの値と, その後の1文字の空白
+    let mut fields = Vec::new();
+    let mut at = 0;
+    while fields.len() < 4 {
+        while at < bytes.len() && bytes[at].is_ascii_whitespace() {
+            at += 1;
+        }
+        let start = at;
+        while at < bytes.len() && !bytes[at].is_ascii_whitespace() {
+            at += 1;
+        }
+        if start == at {
+            return Err(invalid("truncated pfm header"));
+        }
+        fields.push(String::from_utf8_lossy(&bytes[start..at]).into_owned());
+    }
+    let body = &bytes[(at + 1).min(bytes.len())..];
+
+    let channels = match fields[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid("not a pfm file")),
+    };
+    let number = |s: &str| s.parse::<f64>().map_err(|_| invalid("invalid pfm header"));
+    let (width, height, scale) = (
+        number(&fields[1])?,
+        number(&fields[2])?,
+        number(&fields[3])?,
+    );
+    let (width, height) = (width as i32, height as i32);
+    if width <= 0 || height <= 0 || body.len() != (width * height) as usize * channels * 4 {
+        return Err(invalid("size does not match the header"));
+    }
+
+    // scaleが負ならリトルエンディアン
+    let values = body
+        .chunks_exact(4)
+        .map(|c| {
+            let b = [c[0], c[1], c[2], c[3]];
+            if scale < 0.0 {
+                f32::from_le_bytes(b) as f64
+            } else {
+                f32::from_be_bytes(b) as f64
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut pixels = Vec::with_capacity((width * height) as usize);
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let i = (y * width + x) as usize * channels;
+            pixels.push(if channels == 1 {
+                Color::new(values[i], values[i], values[i])
+            } else {
+                Color::new(values[i], values[i + 1], values[i + 2])
+            });
+        }
+    }
+
+    Ok((width, height, pixels))
+}
+
+pub fn write_exr(
+    file_path: &str,
+    width: i32,
+    height: i32,
+    color_space: ColorSpace,
+    layers: &[AovLayer],
+) -> std::io::Result<()> {
+    use std::io::Write;
+
+    File::create(file_path)?.write_all(&encode_exr(width, height, color_space, layers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::{Accumulation, Figure, IntegratorKind, Renderer, RendererOption, Sphere};
+    use crate::wrapper::vec::V3U;
+
+    #[test]
+    fn pfm_rows_are_bottom_up() {
+        let layer = AovLayer::scalar("depth", "Z", vec![1.0, 2.0, 3.0, 4.0]);
+        let bytes = encode_pfm(2, 2, &layer);
+        let header = b"Pf\n2 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+
+        let values = bytes[header.len()..]
+            .chunks(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![3.0, 4.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn pfm_round_trips() {
+        let pixels = (0..6)
+            .map(|i| Color::new(i as f64, 0.5, -0.25))
+            .collect::<Vec<_>>();
+        let bytes = encode_pfm(3, 2, &AovLayer::color("", &pixels));
+        assert_eq!(decode_pfm(&bytes).unwrap(), (3, 2, pixels));
+        assert!(decode_pfm(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn guides_follow_specular_reflection() {
+        // 正面の鏡で跳ね返ったレイがカメラの後ろの拡散面に当たる
+        let sphere = |z: f64, color, reflection| Object {
+            figure: Figure::Sphere(Sphere {
+                center: V3::new(0.0, 0.0, z),
+                radius: 1e6,
+            }),
+            color,
+            reflection,
+            ..Default::default()
+        };
+        let scene = Scene::new(vec![
+            sphere(1e6 + 10.0, Color::new(0.5, 0.5, 0.5), Reflection::Specular),
+            sphere(-1e6 - 20.0, Color::new(0.2, 0.4, 0.8), Reflection::Diffuse),
+        ]);
+        let ray = Ray {
+            origin: V3::zero(),
+            dir: V3U::from_v3(V3::new(0.0, 0.0, 1.0)),
+        };
+
+        let (primary, albedo, surface) = first_diffuse_hit(&scene, ray);
+        assert_eq!(primary.and_then(|o| scene.object_id(o)), Some(0));
+        for (i, expected) in [0.1, 0.2, 0.4].iter().enumerate() {
+            assert!((albedo.channel(i) - expected).abs() < 1e-9, "{:?}", albedo);
+        }
+        let (length, normal, position) = surface.unwrap();
+        assert!((length - 40.0).abs() < 1e-6, "{}", length);
+        assert!((normal - V3::new(0.0, 0.0, 1.0)).len() < 1e-6);
+        assert!((position.z() + 20.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn aovs_see_the_primary_hit() {
+        // 画面いっぱいに見える球と, 同じ材質の見えない球
+        let sphere = |center| Object {
+            figure: Figure::Sphere(Sphere {
+                center,
+                radius: 1000.0,
+            }),
+            color: Color::new(0.5, 0.25, 0.75),
+            ..Default::default()
+        };
+        let scene = Scene::new(vec![
+            sphere(V3::new(0.0, 0.0, -1e6)),
+            sphere(V3::new(0.0, 0.0, 1010.0)),
+        ]);
+        let renderer = Renderer {
+            width: 4,
+            height: 3,
+            spp: 4,
+            working_space: ColorSpace::LinearSrgb,
+            tone_mapping: Default::default(),
+            exposure: Default::default(),
+            transfer_function: Default::default(),
+            dither: false,
+            accumulation: Accumulation::Mean,
+            denoise: Default::default(),
+            aov: AovOutput::Off,
+            passes: RenderPass::defaults(),
+            diagnostics: false,
+            seed: None,
+            checkpoint: None,
+            option: RendererOption {
+                integrator: IntegratorKind::PathTracing,
+                enable_mis: true,
+                enable_mis_debug_mode: false,
+                mis_power_heuristic: 2,
+                clamp: Default::default(),
+            },
+        };
+        let world = crate::renderer::WorldSetting {
+            camera: crate::renderer::Camera {
+                position: V3::zero(),
+                dir: V3U::from_v3(V3::new(0.0, 0.0, 1.0)),
+                up: V3U::unit_y(),
+            },
+            screen: crate::renderer::Screen {
+                width: 0.1,
+                height: 0.075,
+                dist: 1.0,
+            },
+        };
+
+        let (picture, aovs) = renderer.render_aovs(&world, &scene).unwrap();
+        assert_eq!(picture.pixels().len(), 12);
+        for i in 0..12 {
+            // 画面の端ではレイが斜めになる分だけ遠い
+            assert!((aovs.depth[i] - 10.0).abs() < 0.05, "{}", aovs.depth[i]);
+            assert!((aovs.normal[i] - V3::new(0.0, 0.0, -1.0)).len() < 1e-3);
+            assert!((aovs.position[i].z() - 10.0).abs() < 0.01);
+            assert_eq!(aovs.albedo[i], Color::new(0.5, 0.25, 0.75));
+            assert_eq!(aovs.object_id[i], Some(1));
+            assert_eq!(aovs.material_id[i], Some(0));
+            assert_eq!(aovs.sample_count[i], 4);
+        }
+        assert_eq!(aovs.passes.len(), RenderPass::defaults().len());
+
+        let names = aovs
+            .layers()
+            .iter()
+            .flat_map(|l| {
+                l.channels
+                    .iter()
+                    .map(|(c, _)| l.channel_name(c))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert!(names.contains(&"depth.Z".to_string()));
+        assert!(names.contains(&"direct.R".to_string()));
+    }
+}
@@ -0,0 +1,317 @@
+use crate::renderer::{AovLayer, ColorSpace};
+use crate::wrapper::color::Color;
+use std::io::{Error, ErrorKind};
+
+// 圧縮なし, 1行ずつのscanlineで32bit floatのチャンネルを持つOpenEXR
+pub fn encode_exr(
+    width: i32,
+    height: i32,
+    color_space: ColorSpace,
+    layers: &[AovLayer],
+) -> Vec<u8> {
+    const FLOAT: i32 = 2;
+
+    // チャンネルは名前の順に並べる必要がある
+    let mut channels = layers
+        .iter()
+        .flat_map(|layer| {
+            layer
+                .channels
+                .iter()
+                .map(move |(name, values)| (layer.channel_name(name), values))
+        })
+        .collect::<Vec<_>>();
+    channels.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut chlist = Vec::new();
+    for (name, _) in &channels {
+        chlist.extend_from_slice(name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&FLOAT.to_le_bytes());
+        // pLinear, reserved
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        // x, y sampling
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+    }
+    chlist.push(0);
+
+    let window = [0, 0, width - 1, height - 1]
+        .iter()
+        .flat_map(|v: &i32| v.to_le_bytes())
+        .collect::<Vec<_>>();
+    let floats = |values: &[f32]| {
+        values
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>()
+    };
+
+    let mut bytes = vec![0x76, 0x2f, 0x31, 0x01];
+    // version 2, single-part scanline
+    bytes.extend_from_slice(&2u32.to_le_bytes());
+
+    let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(kind.as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        bytes.extend_from_slice(value);
+    };
+    attribute("channels", "chlist", &chlist);
+    attribute(
+        "chromaticities",
+        "chromaticities",
+        &floats(&chromaticities(color_space)),
+    );
+    attribute("compression", "compression", &[0]);
+    attribute("dataWindow", "box2i", &window);
+    attribute("displayWindow", "box2i", &window);
+    attribute("lineOrder", "lineOrder", &[0]);
+    attribute("pixelAspectRatio", "float", &floats(&[1.0]));
+    attribute("screenWindowCenter", "v2f", &floats(&[0.0, 0.0]));
+    attribute("screenWindowWidth", "float", &floats(&[1.0]));
+    bytes.push(0);
+
+    // 各行の位置の表のあとに, 行ごとにチャンネルの値を並べる
+    let line_size = channels.len() * width as usize * 4;
+    let table_end = bytes.len() + height as usize * 8;
+    for y in 0..height as usize {
+        let offset = (table_end + y * (8 + line_size)) as u64;
+        bytes.extend_from_slice(&offset.to_le_bytes());
+    }
+    for y in 0..height {
+        bytes.extend_from_slice(&y.to_le_bytes());
+        bytes.extend_from_slice(&(line_size as i32).to_le_bytes());
+        for (_, values) in &channels {
+            let start = (y * width) as usize;
+            bytes.extend_from_slice(&floats(&values[start..start + width as usize]));
+        }
+    }
+
+    bytes
+}
+
+// 圧縮なしのscanlineのOpenEXRからR, G, B(なければY)のチャンネルを読み, 画素を上の行から並べて返す
+pub fn decode_exr(bytes: &[u8]) -> std::io::Result<(i32, i32, Vec<Color>)> {
+    let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+    let unsupported = |message: &str| Error::new(ErrorKind::Unsupported, message.to_string());
+    let i32_at = |at: usize| -> std::io::Result<i32> {
+        bytes
+            .get(at..at + 4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(|| invalid("truncated exr file"))
+    };
+    // 0で終わる文字列と, その次の位置
+    let string_at = |at: usize| -> std::io::Result<(&[u8], usize)> {
+        let end = at
+            + (bytes.get(at..).unwrap_or(&[]))
+                .iter()
+                .position(|b| *b == 0)
+                .ok_or_else(|| invalid("truncated exr file"))?;
+        Ok((&bytes[at..end], end + 1))
+    };
+
+    if bytes.len() < 8 || bytes[..4] != [0x76, 0x2f, 0x31, 0x01] {
+        return Err(invalid("not an exr file"));
+    }
+    // tiled, multi-part, deep
+    if i32_at(4)? & 0x1a00 != 0 {
+        return Err(unsupported(
+            "only single-part scanline exr files are supported",
+        ));
+    }
+
+    // (名前, 型)
+    let mut channels = Vec::new();
+    let mut window = None;
+    let mut at = 8;
+    loop {
+        let (name, next) = string_at(at)?;
+        if name.is_empty() {
+            at = next;
+            break;
+        }
+        let (_, next) = string_at(next)?;
+        let size = i32_at(next)? as usize;
+        let value = bytes
+            .get(next + 4..next + 4 + size)
+            .ok_or_else(|| invalid("truncated exr file"))?;
+        match name {
+            b"channels" => {
+                let mut c = 0;
+                while c < value.len() && value[c] != 0 {
+                    let end = c + value[c..].iter().position(|b| *b == 0).unwrap_or(0);
+                    let kind = value
+                        .get(end + 1..end + 5)
+                        .ok_or_else(|| invalid("invalid channel list"))?;
+                    channels.push((
+                        String::from_utf8_lossy(&value[c..end]).into_owned(),
+                        i32::from_le_bytes([kind[0], kind[1], kind[2], kind[3]]),
+                    ));
+                    c = end + 1 + 16;
+                }
+            }
+            b"compression" if value != [0] => {
+                return Err(unsupported("only uncompressed exr files are supported"))
+            }
+            b"dataWindow" if value.len() == 16 => {
+                let v = |i: usize| {
+                    i32::from_le_bytes([value[i], value[i + 1], value[i + 2], value[i + 3]])
+                };
+                window = Some((v(0), v(4), v(8), v(12)));
+            }
+            _ => {}
+        }
+        at = next + 4 + size;
+    }
+
+    let (x0, y0, x1, y1) = window.ok_or_else(|| invalid("missing data window"))?;
+    let (width, height) = (x1 - x0 + 1, y1 - y0 + 1);
+    if width <= 0 || height <= 0 {
+        return Err(invalid("invalid data window"));
+    }
+    // チャンネルは名前の順に並んでいる
+    channels.sort();
+    let value_size = |kind: i32| if kind == 1 { 2 } else { 4 };
+    let line_size = channels.iter().map(|(_, k)| value_size(*k)).sum::<usize>() * width as usize;
+    let index = |name: &str| channels.iter().position(|(n, _)| n == name);
+    let rgb = match (index("R"), index("G"), index("B"), index("Y")) {
+        (Some(r), Some(g), Some(b), _) => [r, g, b],
+        (_, _, _, Some(y)) => [y, y, y],
+        _ => return Err(invalid("no R, G, B or Y channel")),
+    };
+
+    let mut pixels = vec![Color::black(); (width * height) as usize];
+    for line in 0..height as usize {
+        let offset = bytes
+            .get(at + line * 8..at + line * 8 + 8)
+            .map(|b| u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+            .ok_or_else(|| invalid("truncated offset table"))? as usize;
+        let y = (i32_at(offset)? - y0) as usize;
+        let data = bytes
+            .get(offset + 8..offset + 8 + line_size)
+            .filter(|_| y < height as usize)
+            .ok_or_else(|| invalid("invalid scanline"))?;
+
+        let mut values = vec![[0.0; 3]; width as usize];
+        let mut start = 0;
+        for (c, (_, kind)) in channels.iter().enumerate() {
+            let size = value_size(*kind);
+            for (x, value) in values.iter_mut().enumerate() {
+                let b = &data[start + x * size..start + (x + 1) * size];
+                let v = match kind {
+                    0 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    1 => half_to_f64(u16::from_le_bytes([b[0], b[1]])),
+                    _ => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                };
+                for k in 0..3 {
+                    if rgb[k] == c {
+                        value[k] = v;
+                    }
+                }
+            }
+            start += size * width as usize;
+        }
+        for (x, v) in values.iter().enumerate() {
+            pixels[y * width as usize + x] = Color::new(v[0], v[1], v[2]);
+        }
+    }
+
+    Ok((width, height, pixels))
+}
+
+// 16bitの浮動小数点数
+fn half_to_f64(h: u16) -> f64 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((h >> 10) & 0x1f) as i32;
+    let mantissa = (h & 0x3ff) as f64;
+    sign * match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f64.powi(exponent - 15),
+    }
+}
+
+// 原色と白色点のCIE xy座標
+fn chromaticities(color_space: ColorSpace) -> [f32; 8] {
+    match color_space {
+        ColorSpace::LinearSrgb => [0.64, 0.33, 0.30, 0.60, 0.15, 0.06, 0.3127, 0.3290],
+        ColorSpace::AcesCg => [0.713, 0.293, 0.165, 0.830, 0.128, 0.044, 0.32168, 0.33767],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wrapper::color::Color;
+    use std::convert::TryInto;
+
+    fn read_i32(bytes: &[u8], at: usize) -> i32 {
+        i32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    }
+
+    #[test]
+    fn exr_layout_is_readable() {
+        let (width, height) = (3, 2);
+        let beauty = (0..6)
+            .map(|i| Color::new(i as f64, 10.0 + i as f64, 20.0 + i as f64))
+            .collect::<Vec<_>>();
+        let layers = vec![
+            AovLayer::color("", &beauty),
+            AovLayer::scalar("depth", "Z", (0..6).map(|i| 100.0 + i as f64).collect()),
+        ];
+        let bytes = encode_exr(width, height, ColorSpace::LinearSrgb, &layers);
+        assert_eq!(&bytes[..8], &[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+
+        // チャンネルは名前の順
+        let chlist = bytes
+            .windows(b"chlist\0".len())
+            .position(|w| w == b"chlist\0")
+            .unwrap()
+            + b"chlist\0".len()
+            + 4;
+        let mut names = Vec::new();
+        let mut at = chlist;
+        while bytes[at] != 0 {
+            let end = at + bytes[at..].iter().position(|b| *b == 0).unwrap();
+            names.push(String::from_utf8(bytes[at..end].to_vec()).unwrap());
+            at = end + 1 + 16;
+        }
+        assert_eq!(names, vec!["B", "G", "R", "depth.Z"]);
+
+        // 2行目のチャンクの先頭の行番号と, depth.Zの値
+        let header_end = bytes
+            .windows(b"screenWindowWidth\0float\0".len())
+            .position(|w| w == b"screenWindowWidth\0float\0")
+            .unwrap()
+            + b"screenWindowWidth\0float\0".len()
+            + 8
+            + 1;
+        let offset = u64::from_le_bytes(bytes[header_end + 8..header_end + 16].try_into().unwrap());
+        let chunk = offset as usize;
+        assert_eq!(read_i32(&bytes, chunk), 1);
+        assert_eq!(read_i32(&bytes, chunk + 4), 4 * 3 * 4);
+        let depth = chunk + 8 + 3 * 3 * 4;
+        let value = f32::from_le_bytes(bytes[depth..depth + 4].try_into().unwrap());
+        assert_eq!(value, 103.0);
+        assert_eq!(chunk + 8 + 4 * 3 * 4, bytes.len());
+    }
+
+    #[test]
+    fn exr_round_trips() {
+        let pixels = (0..6)
+            .map(|i| Color::new(i as f64, 0.5, -0.25))
+            .collect::<Vec<_>>();
+        let layers = vec![
+            AovLayer::color("", &pixels),
+            AovLayer::scalar("depth", "Z", vec![1.0; 6]),
+        ];
+        let bytes = encode_exr(3, 2, ColorSpace::LinearSrgb, &layers);
+        assert_eq!(decode_exr(&bytes).unwrap(), (3, 2, pixels));
+        assert_eq!(half_to_f64(0x3c00), 1.0);
+        assert_eq!(half_to_f64(0xc000), -2.0);
+    }
+}
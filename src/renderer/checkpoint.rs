@@ -0,0 +1,263 @@
+use crate::renderer::{Accumulator, Renderer, Scene, WorldSetting};
+use crate::wrapper::color::Color;
+use std::fmt::{self, Write};
+use std::io::{Error, ErrorKind};
+use std::sync::Mutex;
+
+const MAGIC: &[u8; 8] = b"RUPTCKPT";
+const VERSION: u32 = 2;
+
+// 描画の途中経過を書き出す設定
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    pub path: String,
+    // 画素あたり何サンプルごとに書き出すか
+    pub interval: i32,
+    // pathの途中経過から続ける(終わった描画にサンプルを足すのにも使う)
+    pub resume: bool,
+}
+
+// 書き出す内容
+// 乱数はシードと画素とサンプルの番号から決まるので, シードと済んだサンプル数が乱数の状態になる
+#[derive(Debug, PartialEq)]
+pub struct CheckpointData {
+    pub width: i32,
+    pub height: i32,
+    pub seed: u64,
+    // 画素あたりの済んだサンプル数
+    pub samples: i32,
+    // シーンと結果を変える設定の指紋, 違うシーンや設定で再開しないように残す
+    pub fingerprint: u64,
+    // 画素ごとのAccumulatorの組ごとの(和, サンプル数)
+    pub pixels: Vec<Vec<(Color, usize)>>,
+}
+
+impl CheckpointData {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.extend(self.width.to_le_bytes());
+        bytes.extend(self.height.to_le_bytes());
+        bytes.extend(self.seed.to_le_bytes());
+        bytes.extend(self.samples.to_le_bytes());
+        bytes.extend(self.fingerprint.to_le_bytes());
+        bytes.extend((self.pixels.first().map_or(0, |p| p.len()) as u32).to_le_bytes());
+        for (sum, n) in self.pixels.iter().flatten() {
+            for k in 0..3 {
+                bytes.extend(sum.channel(k).to_le_bytes());
+            }
+            bytes.extend((*n as u64).to_le_bytes());
+        }
+
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> std::io::Result<Self> {
+        let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+        let mut at = 0;
+        let mut take = |n: usize| -> std::io::Result<&[u8]> {
+            let b = bytes
+                .get(at..at + n)
+                .ok_or_else(|| invalid("truncated checkpoint"))?;
+            at += n;
+            Ok(b)
+        };
+        let u32_le = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        let u64_le = |b: &[u8]| {
+            let mut a = [0; 8];
+            a.copy_from_slice(b);
+            u64::from_le_bytes(a)
+        };
+
+        if take(8)? != MAGIC {
+            return Err(invalid("not a checkpoint file"));
+        }
+        if u32_le(take(4)?) != VERSION {
+            return Err(invalid("unsupported checkpoint version"));
+        }
+        let width = u32_le(take(4)?) as i32;
+        let height = u32_le(take(4)?) as i32;
+        let seed = u64_le(take(8)?);
+        let samples = u32_le(take(4)?) as i32;
+        let fingerprint = u64_le(take(8)?);
+        let groups = u32_le(take(4)?) as usize;
+        if width <= 0 || height <= 0 || samples < 0 {
+            return Err(invalid("invalid checkpoint header"));
+        }
+
+        let f64_le = |b: &[u8]| f64::from_bits(u64_le(b));
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+        for _ in 0..width * height {
+            let mut pixel = Vec::with_capacity(groups);
+            for _ in 0..groups {
+                let b = take(32)?;
+                let sum = Color::new(f64_le(&b[..8]), f64_le(&b[8..16]), f64_le(&b[16..24]));
+                pixel.push((sum, u64_le(&b[24..]) as usize));
+            }
+            pixels.push(pixel);
+        }
+        if at != bytes.len() {
+            return Err(invalid("size does not match the header"));
+        }
+
+        Ok(CheckpointData {
+            width,
+            height,
+            seed,
+            samples,
+            fingerprint,
+            pixels,
+        })
+    }
+}
+
+// 描画中にチェックポイントを書き出し, 再開するときは読み込んだ途中経過を渡す
+pub struct Checkpointer {
+    path: String,
+    interval: i32,
+    fingerprint: u64,
+    // 読み込んだ(済んだサンプル数, 画素ごとのAccumulator), render_per_pixelが取り出す
+    resumed: Mutex<Option<(i32, Vec<Accumulator>)>>,
+}
+
+impl Checkpointer {
+    // 再開するならファイルを読んでシーンと設定が同じか確かめる, 戻り値のシードで描画する
+    pub fn open(
+        renderer: &Renderer,
+        checkpoint: &Checkpoint,
+        world: &WorldSetting,
+        scene: &Scene,
+    ) -> std::io::Result<(Self, u64)> {
+        let mut checkpointer = Checkpointer {
+            path: checkpoint.path.clone(),
+            interval: checkpoint.interval.max(1),
+            fingerprint: fingerprint(renderer, world, scene),
+            resumed: Mutex::new(None),
+        };
+        if !checkpoint.resume {
+            return Ok((checkpointer, renderer.seed.unwrap_or_else(rand::random)));
+        }
+
+        let data = CheckpointData::decode(&std::fs::read(&checkpoint.path)?)?;
+        let groups = Accumulator::new(&renderer.accumulation).groups().len();
+        let mismatch = if (data.width, data.height) != (renderer.width, renderer.height) {
+            Some(format!("size {}x{}", data.width, data.height))
+        } else if data.fingerprint != checkpointer.fingerprint {
+            Some("a different scene or settings".to_string())
+        } else if data.pixels.first().map(|p| p.len()) != Some(groups) {
+            Some("accumulation".to_string())
+        } else if renderer.seed.is_some_and(|seed| seed != data.seed) {
+            Some(format!("seed {}", data.seed))
+        } else if data.samples > renderer.spp {
+            Some(format!("{} samples per pixel", data.samples))
+        } else {
+            None
+        };
+        if let Some(mismatch) = mismatch {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "cannot resume from {}: it was rendered with {}",
+                    checkpoint.path, mismatch
+                ),
+            ));
+        }
+
+        let accumulators = data
+            .pixels
+            .into_iter()
+            .map(Accumulator::from_groups)
+            .collect();
+        *checkpointer.resumed.get_mut().unwrap() = Some((data.samples, accumulators));
+        Ok((checkpointer, data.seed))
+    }
+
+    pub fn interval(&self) -> i32 {
+        self.interval
+    }
+
+    pub fn take_resumed(&self) -> Option<(i32, Vec<Accumulator>)> {
+        self.resumed.lock().unwrap().take()
+    }
+
+    // 書き出しに失敗しても描画は続ける
+    pub fn save(&self, width: i32, height: i32, seed: u64, samples: i32, pixels: &[Accumulator]) {
+        let data = CheckpointData {
+            width,
+            height,
+            seed,
+            samples,
+            fingerprint: self.fingerprint,
+            pixels: pixels.iter().map(|p| p.groups().to_vec()).collect(),
+        };
+        // 書いている途中で止まっても前のチェックポイントが残るように, 別名で書いてから置き換える
+        let temporary = format!("{}.tmp", self.path);
+        let result = std::fs::write(&temporary, data.encode())
+            .and_then(|_| std::fs::rename(&temporary, &self.path));
+        if let Err(e) = result {
+            eprintln!("checkpoint: cannot write {}: {}", self.path, e);
+        }
+    }
+}
+
+// 途中経過の意味を変えるもの(シーン, カメラ, 積分器, サンプルのまとめ方, 経路の振り分け)の指紋
+// トーンマッピングなど書き出すときにだけ使う設定と, 別に確かめる大きさとシードは含めない
+fn fingerprint(renderer: &Renderer, world: &WorldSetting, scene: &Scene) -> u64 {
+    let mut hash = Fnv1a::default();
+    let _ = write!(
+        hash,
+        "{:?} {:?} {:?} {:?} {} {:?} ",
+        renderer.option,
+        renderer.working_space,
+        renderer.accumulation,
+        renderer.passes,
+        renderer.diagnostics,
+        world
+    );
+    let _ = scene.describe(&mut hash);
+    hash.0
+}
+
+// FNV-1a, 実行環境やRustのバージョンによらず同じ値になる
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+}
+
+impl Write for Fnv1a {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            self.0 = (self.0 ^ b as u64).wrapping_mul(0x100000001b3);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint_data_round_trips() {
+        let data = CheckpointData {
+            width: 2,
+            height: 1,
+            seed: 42,
+            samples: 3,
+            fingerprint: 0x0123456789abcdef,
+            pixels: vec![
+                vec![
+                    (Color::new(0.5, 1.0, f64::INFINITY), 2),
+                    (Color::black(), 1),
+                ],
+                vec![(Color::new(3.0, 2.0, 1.0), 1), (Color::black(), 2)],
+            ],
+        };
+        let bytes = data.encode();
+        assert_eq!(CheckpointData::decode(&bytes).unwrap(), data);
+        assert!(CheckpointData::decode(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
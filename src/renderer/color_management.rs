@@ -0,0 +1,216 @@
+use crate::renderer::uniform_random;
+use crate::wrapper::color::Color;
+use std::str::FromStr;
+
+// 線形なRGBの色空間
+// シーンの色や描画結果のRGBはRendererのworking_spaceの値として扱う
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum ColorSpace {
+    // sRGB/Rec.709の原色, D65
+    #[default]
+    LinearSrgb,
+    // ACES AP1の原色, D60
+    AcesCg,
+}
+
+// 線形の値を表示用の信号値に直す関数(OETF)
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum TransferFunction {
+    #[default]
+    Srgb,
+    Rec709,
+    Gamma(f64),
+    Linear,
+}
+
+impl ColorSpace {
+    pub fn convert(&self, c: Color, to: ColorSpace) -> Color {
+        // 白色点の違いはBradford変換で合わせたもの
+        const SRGB_TO_ACESCG: [[f64; 3]; 3] = [
+            [0.613097, 0.339523, 0.047379],
+            [0.070194, 0.916354, 0.013452],
+            [0.020616, 0.109570, 0.869815],
+        ];
+        const ACESCG_TO_SRGB: [[f64; 3]; 3] = [
+            [1.704859, -0.621715, -0.083299],
+            [-0.130078, 1.140734, -0.010560],
+            [-0.023964, -0.128975, 1.153013],
+        ];
+
+        match (self, to) {
+            (ColorSpace::LinearSrgb, ColorSpace::AcesCg) => mul(&SRGB_TO_ACESCG, c),
+            (ColorSpace::AcesCg, ColorSpace::LinearSrgb) => mul(&ACESCG_TO_SRGB, c),
+            _ => c,
+        }
+    }
+}
+
+fn mul(m: &[[f64; 3]; 3], c: Color) -> Color {
+    let v = [c.channel(0), c.channel(1), c.channel(2)];
+    let row = |r: &[f64; 3]| r[0] * v[0] + r[1] * v[1] + r[2] * v[2];
+    Color::new(row(&m[0]), row(&m[1]), row(&m[2]))
+}
+
+impl TransferFunction {
+    // 出力ファイルに記録する色空間の名前
+    pub fn name(&self) -> String {
+        match self {
+            TransferFunction::Srgb => "sRGB".to_string(),
+            TransferFunction::Rec709 => "Rec.709".to_string(),
+            TransferFunction::Gamma(gamma) => format!("sRGB primaries, gamma {}", gamma),
+            TransferFunction::Linear => "linear sRGB".to_string(),
+        }
+    }
+
+    // nameで記録された名前から戻す
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sRGB" => Some(TransferFunction::Srgb),
+            "Rec.709" => Some(TransferFunction::Rec709),
+            "linear sRGB" => Some(TransferFunction::Linear),
+            _ => name
+                .strip_prefix("sRGB primaries, gamma ")
+                .and_then(|g| g.parse().ok())
+                .map(TransferFunction::Gamma),
+        }
+    }
+
+    pub fn encode(&self, v: f64) -> f64 {
+        let v = v.max(0.0);
+        match self {
+            TransferFunction::Srgb => {
+                if v <= 0.0031308 {
+                    12.92 * v
+                } else {
+                    1.055 * v.powf(1.0 / 2.4) - 0.055
+                }
+            }
+            TransferFunction::Rec709 => {
+                if v < 0.018 {
+                    4.5 * v
+                } else {
+                    1.099 * v.powf(0.45) - 0.099
+                }
+            }
+            TransferFunction::Gamma(gamma) => v.powf(1.0 / gamma),
+            TransferFunction::Linear => v,
+        }
+    }
+
+    pub fn decode(&self, v: f64) -> f64 {
+        let v = v.max(0.0);
+        match self {
+            TransferFunction::Srgb => {
+                if v <= 0.04045 {
+                    v / 12.92
+                } else {
+                    ((v + 0.055) / 1.055).powf(2.4)
+                }
+            }
+            TransferFunction::Rec709 => {
+                if v < 0.081 {
+                    v / 4.5
+                } else {
+                    ((v + 0.099) / 1.099).powf(1.0 / 0.45)
+                }
+            }
+            TransferFunction::Gamma(gamma) => v.powf(*gamma),
+            TransferFunction::Linear => v,
+        }
+    }
+}
+
+impl FromStr for TransferFunction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "srgb" => Ok(TransferFunction::Srgb),
+            "rec709" => Ok(TransferFunction::Rec709),
+            "linear" => Ok(TransferFunction::Linear),
+            _ => s
+                .parse::<f64>()
+                .map(TransferFunction::Gamma)
+                .map_err(|_| format!("unknown transfer function: {}", s)),
+        }
+    }
+}
+
+impl FromStr for ColorSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "srgb" | "rec709" => Ok(ColorSpace::LinearSrgb),
+            "acescg" => Ok(ColorSpace::AcesCg),
+            _ => Err(format!("unknown color space: {}", s)),
+        }
+    }
+}
+
+// [0, 1]の信号値を8bitに丸める, noiseは量子化の幅を単位とした加えるノイズ
+pub fn quantize(v: f64, noise: f64) -> u8 {
+    (v * 255.0 + noise).round().clamp(0.0, 255.0) as u8
+}
+
+// 三角分布のディザ([-1, 1])
+pub fn dither_noise() -> f64 {
+    uniform_random() - uniform_random()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfer_functions_roundtrip() {
+        for tf in &[
+            TransferFunction::Srgb,
+            TransferFunction::Rec709,
+            TransferFunction::Gamma(2.2),
+            TransferFunction::Linear,
+        ] {
+            for i in 0..=100 {
+                let v = i as f64 / 100.0;
+                assert!((tf.decode(tf.encode(v)) - v).abs() < 1e-9, "{:?} {}", tf, v);
+            }
+            assert!((tf.encode(1.0) - 1.0).abs() < 1e-3);
+            assert_eq!(TransferFunction::from_name(&tf.name()), Some(*tf));
+        }
+
+        // 区分の境目で連続
+        let srgb = TransferFunction::Srgb;
+        assert!((srgb.encode(0.0031308) - srgb.encode(0.0031309)).abs() < 1e-5);
+        assert!((srgb.encode(0.18) - 0.4613).abs() < 1e-3);
+    }
+
+    #[test]
+    fn acescg_conversion_roundtrips_and_keeps_white() {
+        let c = Color::new(0.75, 0.25, 0.1);
+        let back = ColorSpace::AcesCg.convert(
+            ColorSpace::LinearSrgb.convert(c, ColorSpace::AcesCg),
+            ColorSpace::LinearSrgb,
+        );
+        let white = ColorSpace::LinearSrgb.convert(Color::new(1.0, 1.0, 1.0), ColorSpace::AcesCg);
+        for i in 0..3 {
+            assert!((back.channel(i) - c.channel(i)).abs() < 1e-4);
+            assert!((white.channel(i) - 1.0).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn quantize_rounds_and_dither_preserves_mean() {
+        assert_eq!(quantize(1.0, 0.0), 255);
+        assert_eq!(quantize(0.5 / 255.0 + 1e-9, 0.0), 1);
+        assert_eq!(quantize(-0.1, 0.0), 0);
+
+        // 8bitの間の値もディザの平均で表せる
+        let v = 100.25 / 255.0;
+        let n = 100000;
+        let mean = (0..n)
+            .map(|_| quantize(v, dither_noise()) as f64)
+            .sum::<f64>()
+            / n as f64;
+        assert!((mean - 100.25).abs() < 0.02, "{}", mean);
+    }
+}
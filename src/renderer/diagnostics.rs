@@ -0,0 +1,189 @@
+use crate::renderer::Picture;
+use crate::wrapper::color::Color;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+// 報告に載せるサンプルの数
+const LISTED_SAMPLES: usize = 8;
+
+thread_local! {
+    // 計算中のサンプルで最初に有限でない値が出た経路の深さとオブジェクトの番号
+    static NON_FINITE: Cell<Option<(usize, Option<usize>)>> = const { Cell::new(None) };
+}
+
+// 積分器が有限でない値に気づいたときに呼ぶ, 同じサンプルでは最初の報告だけを残す
+pub fn report_non_finite(depth: usize, object: Option<usize>) {
+    NON_FINITE.with(|cell| {
+        if cell.get().is_none() {
+            cell.set(Some((depth, object)));
+        }
+    });
+}
+
+// 報告された場所を取り出して消す
+pub fn take_non_finite() -> Option<(usize, Option<usize>)> {
+    NON_FINITE.with(|cell| cell.take())
+}
+
+// 放射輝度が有限でなかったサンプル
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NonFiniteSample {
+    pub pixel: i32,
+    // 積分器が報告しなかった場合はNone
+    pub depth: Option<usize>,
+    pub object: Option<usize>,
+    pub value: Color,
+}
+
+// 描画中に見つかった有限でないサンプルを集める
+#[derive(Default, Debug)]
+pub struct Diagnostics {
+    samples: Mutex<Vec<NonFiniteSample>>,
+}
+
+impl Diagnostics {
+    // サンプルを計算し, 有限でなければ記録してNoneを返す
+    pub fn check<T>(&self, pixel: i32, sample: impl FnOnce() -> (Color, T)) -> Option<(Color, T)> {
+        take_non_finite();
+        let (value, rest) = sample();
+        let reported = take_non_finite();
+        if value.is_finite() {
+            return Some((value, rest));
+        }
+
+        self.record(NonFiniteSample {
+            pixel,
+            depth: reported.map(|(depth, _)| depth),
+            object: reported.and_then(|(_, object)| object),
+            value,
+        });
+        None
+    }
+
+    // 画像全体を求める手法向けに, 有限でない画素を記録して黒にする
+    pub fn check_picture(&self, picture: Picture) -> Picture {
+        Picture::new(
+            picture
+                .into_vec()
+                .into_iter()
+                .enumerate()
+                .map(|(i, c)| {
+                    if c.is_finite() {
+                        return c;
+                    }
+                    self.record(NonFiniteSample {
+                        pixel: i as i32,
+                        depth: None,
+                        object: None,
+                        value: c,
+                    });
+                    Color::black()
+                })
+                .collect(),
+        )
+    }
+
+    pub fn record(&self, sample: NonFiniteSample) {
+        self.samples.lock().unwrap().push(sample);
+    }
+
+    pub fn samples(&self) -> Vec<NonFiniteSample> {
+        let mut samples = self.samples.lock().unwrap().clone();
+        samples.sort_by_key(|s| (s.pixel, s.depth, s.object));
+        samples
+    }
+
+    // 件数, 画素数, 深さとオブジェクトごとの件数, 最初のいくつかのサンプル
+    pub fn summary(&self, width: i32) -> String {
+        let samples = self.samples();
+        if samples.is_empty() {
+            return "diagnostics: no non-finite samples".into();
+        }
+
+        let mut pixels = samples.iter().map(|s| s.pixel).collect::<Vec<_>>();
+        pixels.dedup();
+        let mut by_depth = BTreeMap::new();
+        let mut by_object = BTreeMap::new();
+        for s in &samples {
+            *by_depth.entry(s.depth).or_insert(0) += 1;
+            *by_object.entry(s.object).or_insert(0) += 1;
+        }
+        let label = |v: Option<usize>| v.map_or("unknown".to_string(), |v| v.to_string());
+
+        let mut report = format!(
+            "diagnostics: {} non-finite samples in {} pixels were excluded",
+            samples.len(),
+            pixels.len()
+        );
+        for (name, counts) in [("depth", &by_depth), ("object", &by_object)] {
+            let counts = counts
+                .iter()
+                .map(|(k, n)| format!("{}: {}", label(*k), n))
+                .collect::<Vec<_>>();
+            write!(report, "\n  by {}: {}", name, counts.join(", ")).unwrap();
+        }
+        for s in samples.iter().take(LISTED_SAMPLES) {
+            write!(
+                report,
+                "\n  pixel ({}, {}) depth {} object {}: {:?}",
+                s.pixel % width,
+                s.pixel / width,
+                label(s.depth),
+                label(s.object),
+                s.value
+            )
+            .unwrap();
+        }
+        if samples.len() > LISTED_SAMPLES {
+            write!(
+                report,
+                "\n  ... and {} more",
+                samples.len() - LISTED_SAMPLES
+            )
+            .unwrap();
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_finite_samples_are_recorded_with_the_reported_vertex() {
+        let diagnostics = Diagnostics::default();
+        let finite = diagnostics.check(3, || {
+            report_non_finite(9, Some(9));
+            (Color::new(1.0, 1.0, 1.0), ())
+        });
+        assert!(finite.is_some());
+
+        let nan = diagnostics.check(5, || {
+            report_non_finite(2, Some(4));
+            report_non_finite(3, None);
+            (Color::new(f64::NAN, 0.0, 0.0), ())
+        });
+        assert!(nan.is_none());
+        let sample = diagnostics.samples()[0];
+        assert_eq!(
+            (sample.pixel, sample.depth, sample.object),
+            (5, Some(2), Some(4))
+        );
+        assert!(sample.value.channel(0).is_nan());
+
+        let picture = diagnostics.check_picture(Picture::new(vec![
+            Color::new(0.5, 0.5, 0.5),
+            Color::new(f64::INFINITY, 0.0, 0.0),
+        ]));
+        assert_eq!(picture.pixels()[1], Color::black());
+
+        let summary = diagnostics.summary(2);
+        assert!(summary.contains("2 non-finite samples in 2 pixels"));
+        assert!(summary.contains("by depth: unknown: 1, 2: 1"));
+        assert!(summary.contains("pixel (1, 2) depth 2 object 4"));
+    }
+}
@@ -1,5 +1,6 @@
-use crate::renderer::Reflection;
+use crate::renderer::{uniform_random, Medium, Reflection};
 use crate::wrapper::{
+    aabb::Aabb,
     color::Color,
     ray::Ray,
     vec::{V3, V3U},
@@ -41,6 +42,8 @@ pub struct Object {
     pub emission: Color,
     pub color: Color,
     pub reflection: Reflection,
+    // 内部を満たす媒質, 面を透過する(RefractionかTransparentの)閉じた形状にのみ意味がある
+    pub medium: Option<Medium>,
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -81,26 +84,19 @@ impl Default for Figure {
     }
 }
 
-impl Object {
+impl Figure {
     pub fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
         use Figure::*;
 
-        match &self.figure {
+        match self {
             Rhombus(r) => r.intersect(ray),
             Sphere(r) => r.intersect(ray),
             Figures(figs) => {
-                let mut min = std::f64::MAX;
+                let mut min = f64::MAX;
                 let mut result = None;
 
                 for fig in figs {
-                    if let Some(hit) = (Object {
-                        figure: fig.clone(),
-                        emission: self.emission,
-                        color: self.color,
-                        reflection: self.reflection.clone(),
-                    }
-                    .intersect(ray))
-                    {
+                    if let Some(hit) = fig.intersect(ray) {
                         if hit.distance < min {
                             min = hit.distance;
                             result = Some(hit);
@@ -113,32 +109,141 @@ impl Object {
         }
     }
 
+    pub fn bounds(&self) -> Aabb {
+        use Figure::*;
+
+        match self {
+            Rhombus(r) => r.bounds(),
+            Sphere(r) => r.bounds(),
+            Figures(figs) => figs
+                .iter()
+                .skip(1)
+                .fold(figs[0].bounds(), |b, fig| b.union(fig.bounds())),
+        }
+    }
+
+    pub fn area(&self) -> f64 {
+        use Figure::*;
+
+        match self {
+            Rhombus(r) => r.area(),
+            Sphere(r) => r.area(),
+            Figures(figs) => figs.iter().map(|fig| fig.area()).sum(),
+        }
+    }
+
     pub fn area_pdf(&self) -> f64 {
         use Figure::*;
 
-        match &self.figure {
+        match self {
             Rhombus(r) => r.area_pdf(),
             Sphere(r) => r.area_pdf(),
-            _ => unimplemented!(),
+            // 面積に比例して子を選ぶので全体で一様になる
+            Figures(_) => 1.0 / self.area(),
         }
     }
 
     pub fn sample(&self) -> SampleRecord {
         use Figure::*;
 
-        match &self.figure {
+        match self {
             Rhombus(r) => r.sample(),
             Sphere(r) => r.sample(),
             Figures(figs) => {
-                let i = rand::random::<usize>() % figs.len();
-                Object {
-                    figure: figs[i].clone(),
-                    emission: self.emission,
-                    color: self.color,
-                    reflection: self.reflection.clone(),
+                let total = self.area();
+                let mut xi = uniform_random() * total;
+
+                // 面積に比例した確率で子を選ぶ(誤差で最後まで行った場合は最後の子を使う)
+                let mut chosen = &figs[figs.len() - 1];
+                for fig in figs {
+                    let area = fig.area();
+                    if xi < area {
+                        chosen = fig;
+                        break;
+                    }
+                    xi -= area;
+                }
+
+                SampleRecord {
+                    pdf_value: 1.0 / total,
+                    ..chosen.sample()
                 }
-                .sample()
             }
         }
     }
+
+    // pointから見て寄与のある点を優先してサンプリングする(pdfは面積測度)
+    pub fn sample_from(&self, point: &V3) -> SampleRecord {
+        use Figure::*;
+
+        match self {
+            Sphere(r) => r.sample_from(point),
+            _ => self.sample(),
+        }
+    }
+
+    // sample_fromでxが選ばれる確率密度
+    pub fn area_pdf_from(&self, point: &V3, x: &V3) -> f64 {
+        use Figure::*;
+
+        match self {
+            Sphere(r) => r.area_pdf_from(point, x),
+            _ => self.area_pdf(),
+        }
+    }
+}
+
+impl Object {
+    pub fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
+        self.figure.intersect(ray)
+    }
+
+    pub fn area_pdf(&self) -> f64 {
+        self.figure.area_pdf()
+    }
+
+    pub fn sample(&self) -> SampleRecord {
+        self.figure.sample()
+    }
+
+    pub fn sample_from(&self, point: &V3) -> SampleRecord {
+        self.figure.sample_from(point)
+    }
+
+    pub fn area_pdf_from(&self, point: &V3, x: &V3) -> f64 {
+        self.figure.area_pdf_from(point, x)
+    }
+
+    // 内部の媒質, 表面下散乱の物体ではそのパラメータから決まる
+    pub fn interior_medium(&self) -> Option<&Medium> {
+        match &self.reflection {
+            Reflection::Subsurface(params) => Some(params.medium()),
+            _ => self.medium.as_ref(),
+        }
+    }
+
+    // 放射束(完全拡散光源として)
+    pub fn power(&self) -> f64 {
+        std::f64::consts::PI * self.figure.area() * self.emission.luminance()
+    }
+}
+
+#[test]
+fn intersect_parallelepiped_example() {
+    let cube = Figure::parallelepiped(
+        V3::new(0.0, 0.0, 0.0),
+        V3::new(10.0, 0.0, 0.0),
+        V3::new(0.0, 10.0, 0.0),
+        V3::new(0.0, 0.0, 10.0),
+    );
+
+    let hit = cube.intersect(&Ray {
+        origin: V3::new(5.0, 5.0, -5.0),
+        dir: V3U::unit_z(),
+    });
+    assert!(hit.is_some());
+    assert_eq!(hit.unwrap().distance, 5.0);
+
+    assert_eq!(cube.area_pdf(), 1.0 / 600.0);
+    assert_eq!(cube.sample().pdf_value, 1.0 / 600.0);
 }
@@ -1,5 +1,6 @@
-use crate::renderer::{HitRecord, SampleRecord};
+use crate::renderer::{uniform_random, HitRecord, SampleRecord};
 use crate::wrapper::{
+    aabb::Aabb,
     ray::Ray,
     vec::{V3, V3U},
 };
@@ -50,8 +51,8 @@ impl Rhombus {
     }
 
     pub fn sample(&self) -> SampleRecord {
-        let x = rand::random::<f64>();
-        let y = rand::random::<f64>();
+        let x = uniform_random();
+        let y = uniform_random();
 
         SampleRecord {
             point: self.origin + self.a.scale(x) + self.b.scale(y),
@@ -70,8 +71,16 @@ impl Rhombus {
         ]
     }
 
+    pub fn bounds(&self) -> Aabb {
+        Aabb::from_points(&self.polygon())
+    }
+
+    pub fn area(&self) -> f64 {
+        self.a.cross(self.b).len()
+    }
+
     pub fn area_pdf(&self) -> f64 {
-        1.0 / (self.a.cross(self.b).len())
+        1.0 / self.area()
     }
 }
 
@@ -1,5 +1,6 @@
-use crate::renderer::{HitRecord, SampleRecord};
+use crate::renderer::{uniform_random, HitRecord, SampleRecord};
 use crate::wrapper::{
+    aabb::Aabb,
     ray::Ray,
     vec::{V3, V3U},
 };
@@ -14,8 +15,8 @@ pub struct Sphere {
 
 impl Sphere {
     pub fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
-        let oc = self.center.clone() - ray.origin.clone();
-        let b = oc.dot(&ray.dir.clone().as_v3());
+        let oc = self.center - ray.origin;
+        let b = oc.dot(&ray.dir.as_v3());
         let det = b * b - oc.dot(&oc) + self.radius * self.radius;
 
         if det < 0.0 {
@@ -32,7 +33,7 @@ impl Sphere {
 
         let dist = if sol1 > EPS { sol1 } else { sol2 };
         let pos = ray.extend_at(dist);
-        let normal = V3U::from_v3(pos.clone() - self.center.clone());
+        let normal = V3U::from_v3(pos - self.center);
         let orienting_normal = normal.flip_if_close(&ray.dir);
 
         Some(HitRecord {
@@ -43,25 +44,94 @@ impl Sphere {
         })
     }
 
+    // 表面上の一様サンプリング
     pub fn sample(&self) -> SampleRecord {
-        loop {
-            let x = rand::random::<f64>();
-            let y = rand::random::<f64>();
-            let z = rand::random::<f64>();
-            let v = V3::new(x, y, z);
-
-            if v.len_square() <= 1.0 {
-                return SampleRecord {
-                    point: v.scale(self.radius) + self.center,
-                    normal: V3U::from_v3(v - self.center),
-                    pdf_value: self.area_pdf(),
-                };
-            }
+        let z = 1.0 - 2.0 * uniform_random();
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * std::f64::consts::PI * uniform_random();
+        let normal = V3U::from_v3_unsafe(V3::new(r * phi.cos(), r * phi.sin(), z));
+
+        SampleRecord {
+            point: self.center + normal.scale(self.radius),
+            normal,
+            pdf_value: self.area_pdf(),
+        }
+    }
+
+    // pointから見える球冠の立体角に沿ったサンプリング
+    // pdfはNEEの幾何項と単位を揃えるために面積測度に変換して返す
+    pub fn sample_from(&self, point: &V3) -> SampleRecord {
+        let oc = self.center - *point;
+        let dist2 = oc.len_square();
+        if dist2 <= self.radius * self.radius {
+            return self.sample();
+        }
+
+        let dist = dist2.sqrt();
+        let w = V3U::from_v3_unsafe(oc.scale(1.0 / dist));
+        let (u, v) = w.orthonormal_basis();
+
+        let cos_max = self.cos_theta_max(dist2);
+        let cos_theta = 1.0 - uniform_random() * (1.0 - cos_max);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * std::f64::consts::PI * uniform_random();
+        let dir = V3U::from_v3(
+            u.scale(sin_theta * phi.cos()) + v.scale(sin_theta * phi.sin()) + w.scale(cos_theta),
+        );
+
+        // 視線方向と球の手前側の交点(接線方向の誤差で判別式が負になった場合は接点とする)
+        let det = (self.radius * self.radius - dist2 * sin_theta * sin_theta).max(0.0);
+        let t = dist * cos_theta - det.sqrt();
+        let p = *point + dir.scale(t);
+        let normal = V3U::from_v3(p - self.center);
+
+        SampleRecord {
+            point: p,
+            normal,
+            pdf_value: self.solid_angle_pdf(point) * normal.dot(&dir).abs() / (t * t),
+        }
+    }
+
+    // sample_fromでpointからxが選ばれる確率密度(面積測度)
+    pub fn area_pdf_from(&self, point: &V3, x: &V3) -> f64 {
+        let oc = self.center - *point;
+        if oc.len_square() <= self.radius * self.radius {
+            return self.area_pdf();
+        }
+
+        let to_point = *point - *x;
+        let normal = V3U::from_v3(*x - self.center);
+        let cosine = normal.as_v3().dot(&to_point);
+        if cosine <= 0.0 {
+            // pointから見えない裏側
+            return 0.0;
         }
+
+        let dist2 = to_point.len_square();
+        self.solid_angle_pdf(point) * cosine / dist2.sqrt() / dist2
+    }
+
+    // sample_fromの立体角測度でのpdf(pointが球の外にある場合)
+    pub fn solid_angle_pdf(&self, point: &V3) -> f64 {
+        let cos_max = self.cos_theta_max((self.center - *point).len_square());
+        1.0 / (2.0 * std::f64::consts::PI * (1.0 - cos_max))
+    }
+
+    fn cos_theta_max(&self, dist2: f64) -> f64 {
+        (1.0 - self.radius * self.radius / dist2).max(0.0).sqrt()
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let r = V3::new(self.radius, self.radius, self.radius);
+        Aabb::new(self.center - r, self.center + r)
+    }
+
+    pub fn area(&self) -> f64 {
+        4.0 * std::f64::consts::PI * self.radius * self.radius
     }
 
     pub fn area_pdf(&self) -> f64 {
-        1.0 / (4.0 * std::f64::consts::PI * self.radius * self.radius)
+        1.0 / self.area()
     }
 }
 
@@ -72,7 +142,6 @@ fn intersect_sphere_example() {
     let sphere = Sphere {
         radius: 1.0,
         center: V3::new(0.0, 5.0, 0.0),
-        ..Default::default()
     };
 
     let hit = sphere.intersect(&Ray {
@@ -98,7 +167,6 @@ fn intersect_sphere_from_interior() {
     let sphere = Sphere {
         radius: 10.0,
         center: V3::new(0.0, 0.0, 0.0),
-        ..Default::default()
     };
 
     let hit = sphere.intersect(&Ray {
@@ -116,3 +184,134 @@ fn intersect_sphere_from_interior() {
         }
     );
 }
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for Sphere {
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+        Sphere {
+            center: quickcheck::Arbitrary::arbitrary(g),
+            radius: 0.1 + f64::abs(quickcheck::Arbitrary::arbitrary(g)) % 100.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck_macros::quickcheck;
+
+    const SAMPLES: usize = 200000;
+
+    fn on_surface(sphere: &Sphere, sample: &SampleRecord) -> bool {
+        let d = sample.point - sphere.center;
+        (d.len() - sphere.radius).abs() <= 1e-6 * sphere.radius.max(1.0)
+            && (d.scale(1.0 / sphere.radius).dot(&sample.normal.as_v3()) - 1.0).abs() <= 1e-6
+    }
+
+    #[quickcheck]
+    fn sample_on_sphere(sphere: Sphere) -> bool {
+        (0..100).all(|_| on_surface(&sphere, &sphere.sample()))
+    }
+
+    #[quickcheck]
+    fn sample_from_on_visible_sphere(sphere: Sphere, offset: V3U, dist: f64) -> bool {
+        let point = sphere.center + offset.scale(sphere.radius * (1.0 + dist.abs() % 10.0));
+
+        (0..100).all(|_| {
+            let sample = sphere.sample_from(&point);
+            on_surface(&sphere, &sample)
+                && sample.normal.as_v3().dot(&(point - sample.point)) >= -1e-6
+                && sample.pdf_value > 0.0
+        })
+    }
+
+    // 単位球面上の等面積の格子点
+    // (pdfが一部で大きくなったり0になったりするのでランダムなサンプルでは積分の結果が安定しない)
+    fn unit_sphere_grid() -> impl Iterator<Item = V3> {
+        let (nz, nphi) = (500, SAMPLES / 500);
+        (0..nz).flat_map(move |i| {
+            let z = 1.0 - 2.0 * (i as f64 + 0.5) / nz as f64;
+            let r = (1.0 - z * z).sqrt();
+            (0..nphi).map(move |j| {
+                let phi = 2.0 * std::f64::consts::PI * (j as f64 + 0.5) / nphi as f64;
+                V3::new(r * phi.cos(), r * phi.sin(), z)
+            })
+        })
+    }
+
+    // ∫ pdf dA の数値積分
+    fn integrate_area_pdf(sphere: &Sphere, point: &V3) -> f64 {
+        unit_sphere_grid()
+            .map(|v| {
+                let x = sphere.center + v.scale(sphere.radius);
+                sphere.area_pdf_from(point, &x) / sphere.area_pdf()
+            })
+            .sum::<f64>()
+            / SAMPLES as f64
+    }
+
+    #[test]
+    fn area_pdf_integrates_to_one() {
+        let sphere = Sphere {
+            center: V3::new(1.0, 2.0, 3.0),
+            radius: 2.5,
+        };
+
+        for point in &[
+            V3::new(1.0, 2.0, 3.0),
+            V3::new(1.0, 2.0, 6.0),
+            V3::new(-10.0, 2.0, 3.0),
+            V3::new(100.0, -50.0, 30.0),
+        ] {
+            let integral = integrate_area_pdf(&sphere, point);
+            assert!((integral - 1.0).abs() < 0.03, "{:?}: {}", point, integral);
+        }
+    }
+
+    #[test]
+    fn solid_angle_pdf_integrates_to_one() {
+        let sphere = Sphere {
+            center: V3::zero(),
+            radius: 1.0,
+        };
+        let point = V3::new(0.0, 0.0, 3.0);
+
+        // 全方向について, 球に当たるものの pdf を積分する
+        let integral = unit_sphere_grid()
+            .map(|v| {
+                let hit = sphere.intersect(&Ray {
+                    origin: point,
+                    dir: V3U::from_v3(v),
+                });
+                if hit.is_some() {
+                    sphere.solid_angle_pdf(&point) * 4.0 * std::f64::consts::PI
+                } else {
+                    0.0
+                }
+            })
+            .sum::<f64>()
+            / SAMPLES as f64;
+
+        assert!((integral - 1.0).abs() < 0.03, "{}", integral);
+    }
+
+    #[test]
+    fn sample_from_matches_area_pdf_from() {
+        let sphere = Sphere {
+            center: V3::new(0.0, 10.0, 0.0),
+            radius: 3.0,
+        };
+        let point = V3::new(2.0, 0.0, 1.0);
+
+        for _ in 0..1000 {
+            let sample = sphere.sample_from(&point);
+            let pdf = sphere.area_pdf_from(&point, &sample.point);
+            assert!(
+                (sample.pdf_value - pdf).abs() <= 1e-6 * pdf,
+                "{} {}",
+                sample.pdf_value,
+                pdf
+            );
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use crate::wrapper::color::Color;
+use std::str::FromStr;
+
+// 経路の寄与ごとの放射輝度の上限(直接光と間接光で別), 最大のチャンネルがこれを超えたら色を保って縮める
+// バイアスが入るので既定では無効
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RadianceClamp {
+    pub direct: f64,
+    pub indirect: f64,
+}
+
+impl Default for RadianceClamp {
+    fn default() -> Self {
+        RadianceClamp {
+            direct: f64::INFINITY,
+            indirect: f64::INFINITY,
+        }
+    }
+}
+
+impl RadianceClamp {
+    pub fn apply(&self, c: Color, direct: bool) -> Color {
+        let limit = if direct { self.direct } else { self.indirect };
+        let max = c.channel(0).max(c.channel(1)).max(c.channel(2));
+        if max > limit {
+            c.scale(limit / max)
+        } else {
+            c
+        }
+    }
+}
+
+impl FromStr for RadianceClamp {
+    type Err = String;
+
+    // "off", 両方に同じ値, または"direct:indirect"(上限なしは"inf")
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let limit = |v: &str| {
+            v.parse::<f64>()
+                .ok()
+                .filter(|v| *v > 0.0)
+                .ok_or_else(|| format!("invalid clamp: {}", s))
+        };
+
+        match s.split_once(':') {
+            _ if s == "off" => Ok(RadianceClamp::default()),
+            Some((direct, indirect)) => Ok(RadianceClamp {
+                direct: limit(direct)?,
+                indirect: limit(indirect)?,
+            }),
+            None => limit(s).map(|v| RadianceClamp {
+                direct: v,
+                indirect: v,
+            }),
+        }
+    }
+}
+
+// 画素の中のサンプルのまとめ方
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Accumulation {
+    // 平均(不偏)
+    #[default]
+    Mean,
+    // サンプルをgroups個の組に分けて平均を取り, 輝度が中央値の組の平均を使う
+    // まれな外れ値は1つの組にしか入らないので捨てられる
+    MedianOfMeans {
+        groups: usize,
+    },
+}
+
+impl FromStr for Accumulation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mean" => Ok(Accumulation::Mean),
+            "mom" | "median_of_means" => Ok(Accumulation::MedianOfMeans { groups: 5 }),
+            _ => match s.strip_prefix("mom:").map(|g| g.parse::<usize>()) {
+                Some(Ok(groups)) if groups > 0 => Ok(Accumulation::MedianOfMeans { groups }),
+                _ => Err(format!("unknown accumulation: {}", s)),
+            },
+        }
+    }
+}
+
+// 1つの画素のサンプルを集める
+pub struct Accumulator {
+    // 組ごとの(和, サンプル数)
+    groups: Vec<(Color, usize)>,
+    count: usize,
+}
+
+impl Accumulator {
+    pub fn new(accumulation: &Accumulation) -> Self {
+        let groups = match accumulation {
+            Accumulation::Mean => 1,
+            Accumulation::MedianOfMeans { groups } => *groups,
+        };
+
+        Accumulator {
+            groups: vec![(Color::black(), 0); groups.max(1)],
+            count: 0,
+        }
+    }
+
+    // チェックポイントから戻す
+    pub fn from_groups(groups: Vec<(Color, usize)>) -> Self {
+        Accumulator {
+            count: groups.iter().map(|(_, n)| n).sum(),
+            groups,
+        }
+    }
+
+    pub fn groups(&self) -> &[(Color, usize)] {
+        &self.groups
+    }
+
+    pub fn add(&mut self, c: Color) {
+        let n = self.groups.len();
+        let group = &mut self.groups[self.count % n];
+        group.0 += c;
+        group.1 += 1;
+        self.count += 1;
+    }
+
+    pub fn result(&self) -> Color {
+        self.mean_of(&self.median_groups())
+    }
+
+    // 平均の輝度が中央値になる組の番号(組の数が偶数なら真ん中の2つ)
+    pub fn median_groups(&self) -> Vec<usize> {
+        let mut groups = (0..self.groups.len())
+            .filter(|&k| self.groups[k].1 > 0)
+            .collect::<Vec<_>>();
+        let mean = |k: usize| self.groups[k].0.scale(1.0 / self.groups[k].1 as f64);
+        groups.sort_by(|&a, &b| mean(a).luminance().total_cmp(&mean(b).luminance()));
+
+        let k = groups.len();
+        match k {
+            0 => Vec::new(),
+            _ if k % 2 == 1 => vec![groups[k / 2]],
+            _ => vec![groups[k / 2 - 1], groups[k / 2]],
+        }
+    }
+
+    // 指定した組の平均の平均
+    // 同じ順にサンプルを足した別のAccumulatorを, 画像と同じ組でまとめるのに使う
+    pub fn mean_of(&self, groups: &[usize]) -> Color {
+        let mut sum = Color::black();
+        for &k in groups {
+            let (s, n) = self.groups[k];
+            if n > 0 {
+                sum += s.scale(1.0 / n as f64);
+            }
+        }
+        sum.scale(1.0 / groups.len().max(1) as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamp_keeps_hue_and_separates_direct() {
+        let clamp = "inf:1".parse::<RadianceClamp>().unwrap();
+        let c = Color::new(4.0, 2.0, 0.0);
+        assert_eq!(clamp.apply(c, true), c);
+        assert_eq!(clamp.apply(c, false), Color::new(1.0, 0.5, 0.0));
+        assert_eq!("off".parse::<RadianceClamp>(), Ok(RadianceClamp::default()));
+        assert!("0".parse::<RadianceClamp>().is_err());
+    }
+
+    #[test]
+    fn median_of_means_rejects_a_firefly() {
+        let mut mean = Accumulator::new(&Accumulation::Mean);
+        let mut robust = Accumulator::new(&"mom:5".parse().unwrap());
+        for i in 0..20 {
+            let c = if i == 7 {
+                Color::new(1000.0, 1000.0, 1000.0)
+            } else {
+                Color::new(0.5, 0.5, 0.5)
+            };
+            mean.add(c);
+            robust.add(c);
+        }
+
+        assert!(mean.result().luminance() > 10.0);
+        assert!((robust.result().luminance() - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn median_groups_select_the_same_samples_elsewhere() {
+        // 輝度の低い成分と高い成分に分けたもの
+        let accumulation = "mom:4".parse().unwrap();
+        let mut total = Accumulator::new(&accumulation);
+        let mut part = Accumulator::new(&accumulation);
+        for i in 0..8 {
+            let c = Color::new(i as f64, i as f64, i as f64);
+            total.add(c);
+            part.add(c.scale(0.25));
+        }
+
+        assert_eq!(total.median_groups(), vec![1, 2]);
+        assert_eq!(total.result(), Color::new(3.5, 3.5, 3.5));
+        assert_eq!(
+            part.mean_of(&total.median_groups()),
+            total.result().scale(0.25)
+        );
+    }
+}
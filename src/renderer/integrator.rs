@@ -0,0 +1,169 @@
+use crate::renderer::{Aovs, LightPaths, Picture, RenderPass, RendererOption, Scene, Sensor};
+use crate::wrapper::{color::Color, ray::Ray};
+use std::str::FromStr;
+
+mod bdpt;
+mod mlt;
+mod path_tracer;
+mod photon_mapping;
+mod spectral;
+
+pub use bdpt::*;
+pub use mlt::*;
+pub use path_tracer::*;
+pub use photon_mapping::*;
+pub use spectral::*;
+
+// 光輸送の計算方法
+pub trait Integrator: Sync {
+    fn render(&self, sensor: &Sensor, scene: &Scene) -> Picture;
+
+    // 描画結果とAOV(passesを含む)を同じパスで求める
+    // 既定ではpassesを振り分けず, AOVはAovs::renderで別に求める
+    fn render_aovs(
+        &self,
+        sensor: &Sensor,
+        scene: &Scene,
+        _passes: &[RenderPass],
+    ) -> (Picture, Aovs) {
+        (self.render(sensor, scene), Aovs::render(sensor, scene))
+    }
+}
+
+// カメラからのレイごとに独立に計算できる手法
+// 画像全体をまとめて計算する手法はIntegratorを直接実装する
+pub trait PixelIntegrator: Sync {
+    // カメラからのレイが運ぶ放射輝度
+    fn radience(&self, scene: &Scene, ray: Ray) -> Color;
+
+    // 放射輝度とその寄与をpassesに振り分けたもの, 振り分けられない手法はNone
+    fn light_paths<'a>(
+        &self,
+        _scene: &Scene,
+        _ray: Ray,
+        _passes: &'a [RenderPass],
+    ) -> Option<LightPaths<'a>> {
+        None
+    }
+}
+
+impl<T: PixelIntegrator> Integrator for T {
+    fn render(&self, sensor: &Sensor, scene: &Scene) -> Picture {
+        sensor.render_per_pixel(|ray| self.radience(scene, ray))
+    }
+
+    fn render_aovs(
+        &self,
+        sensor: &Sensor,
+        scene: &Scene,
+        passes: &[RenderPass],
+    ) -> (Picture, Aovs) {
+        Aovs::render_per_pixel(sensor, scene, passes, |ray| {
+            match self.light_paths(scene, ray.clone(), passes) {
+                Some(paths) => (paths.total, Some(paths.values)),
+                None => (self.radience(scene, ray), None),
+            }
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IntegratorKind {
+    PathTracing,
+    BidirectionalPathTracing,
+    PhotonMapping,
+    StochasticProgressivePhotonMapping,
+    MetropolisLightTransport,
+    SpectralPathTracing,
+}
+
+impl IntegratorKind {
+    // 関与媒質と表面下散乱を扱えるかどうか, 扱えない手法は媒質を素通りし表面下散乱を屈折として扱う
+    pub fn handles_media(&self) -> bool {
+        matches!(
+            self,
+            IntegratorKind::PathTracing | IntegratorKind::MetropolisLightTransport
+        )
+    }
+
+    // 経路の寄与ごとの放射輝度の上限(RadianceClamp)を使えるかどうか
+    pub fn clamps_radiance(&self) -> bool {
+        matches!(
+            self,
+            IntegratorKind::PathTracing | IntegratorKind::MetropolisLightTransport
+        )
+    }
+
+    // 画素ごとにサンプルを集める(render_per_pixelを使う)手法かどうか, Accumulationとチェックポイントはこれらでのみ使える
+    // PhotonMapperのフォトンマップは描画の最初に作り, シードがあればそれから決まるので再開しても同じになる
+    pub fn is_per_pixel(&self) -> bool {
+        matches!(
+            self,
+            IntegratorKind::PathTracing
+                | IntegratorKind::PhotonMapping
+                | IntegratorKind::SpectralPathTracing
+        )
+    }
+
+    pub fn build(&self, option: &RendererOption) -> Box<dyn Integrator> {
+        match self {
+            IntegratorKind::PathTracing => Box::new(PathTracer {
+                enable_mis: option.enable_mis,
+                enable_mis_debug_mode: option.enable_mis_debug_mode,
+                mis_power_heuristic: option.mis_power_heuristic,
+                clamp: option.clamp,
+            }),
+            IntegratorKind::BidirectionalPathTracing => Box::new(BidirectionalPathTracer {
+                max_depth: 16,
+                mis_power_heuristic: option.mis_power_heuristic,
+            }),
+            IntegratorKind::PhotonMapping => Box::new(PhotonMapper {
+                global_photons: 200000,
+                caustic_photons: 1000000,
+                nearest_photons: 64,
+                max_radius: 8.0,
+                final_gather_samples: 8,
+                max_depth: 16,
+            }),
+            IntegratorKind::StochasticProgressivePhotonMapping => {
+                Box::new(StochasticProgressivePhotonMapper {
+                    photons_per_iteration: 200000,
+                    initial_radius: 2.0,
+                    alpha: 2.0 / 3.0,
+                    max_depth: 16,
+                })
+            }
+            IntegratorKind::MetropolisLightTransport => Box::new(MetropolisLightTransport {
+                path_tracer: PathTracer {
+                    enable_mis: option.enable_mis,
+                    enable_mis_debug_mode: false,
+                    mis_power_heuristic: option.mis_power_heuristic,
+                    clamp: option.clamp,
+                },
+                bootstrap_samples: 100000,
+                chains: 1000,
+                sigma: 0.01,
+                large_step_probability: 0.3,
+            }),
+            IntegratorKind::SpectralPathTracing => Box::new(SpectralPathTracer {}),
+        }
+    }
+}
+
+impl FromStr for IntegratorKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pt" | "path_tracing" => Ok(IntegratorKind::PathTracing),
+            "bdpt" | "bidirectional_path_tracing" => Ok(IntegratorKind::BidirectionalPathTracing),
+            "pm" | "photon_mapping" => Ok(IntegratorKind::PhotonMapping),
+            "sppm" | "stochastic_progressive_photon_mapping" => {
+                Ok(IntegratorKind::StochasticProgressivePhotonMapping)
+            }
+            "mlt" | "pssmlt" => Ok(IntegratorKind::MetropolisLightTransport),
+            "spt" | "spectral" => Ok(IntegratorKind::SpectralPathTracing),
+            _ => Err(format!("unknown integrator: {}", s)),
+        }
+    }
+}
@@ -0,0 +1,667 @@
+use crate::renderer::{
+    uniform_random, HitRecord, Integrator, Object, Picture, Scene, Sensor, TransportMode,
+};
+use crate::wrapper::{
+    color::Color,
+    ray::Ray,
+    vec::{V3, V3U},
+};
+use rayon::prelude::*;
+
+// 光源側の経路の寄与をまとめて足し込む画素の塊の大きさ
+const SPLAT_CHUNK: usize = 4096;
+
+// 双方向パストレーシング
+// カメラ側と光源側の部分経路の全ての頂点の組を接続し, MISで重み付けする
+// 光源側の経路をカメラに直接接続する寄与(t=1)は対応する画素に足し込む
+// cf. Veach, Robust Monte Carlo Methods for Light Transport Simulation (1997), Chapter 10
+#[derive(Clone, Debug)]
+pub struct BidirectionalPathTracer {
+    pub max_depth: usize,
+    pub mis_power_heuristic: i32,
+}
+
+#[derive(Clone, Debug)]
+enum VertexKind<'a> {
+    Camera,
+    // 光源側の経路の始点, またはカメラ側の経路が飛び出した先の無限遠光源(番号はScene::emitter)
+    Light(usize),
+    Surface {
+        object: &'a Object,
+        hit: HitRecord,
+        // 一つ前の頂点への向き
+        wo: V3U,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct Vertex<'a> {
+    kind: VertexKind<'a>,
+    position: V3,
+    normal: Option<V3U>, // 面上の頂点の法線
+    beta: Color,         // 経路の寄与(重要度または放射輝度)/pdf
+    pdf_fwd: f64,        // 経路をたどる向きにこの頂点が選ばれる確率密度(面積測度)
+    pdf_rev: f64,        // 逆向きにたどった場合の確率密度
+    delta: bool,         // この頂点での散乱がデルタ分布か
+}
+
+impl<'a> Vertex<'a> {
+    fn emitter(&self, scene: &Scene) -> Option<usize> {
+        match &self.kind {
+            VertexKind::Light(i) => Some(*i),
+            VertexKind::Surface { object, .. } if object.emission > Color::black() => {
+                scene.emitter_of(object)
+            }
+            _ => None,
+        }
+    }
+
+    fn is_light(&self, scene: &Scene) -> bool {
+        self.emitter(scene).is_some()
+    }
+
+    fn is_infinite_light(&self, scene: &Scene) -> bool {
+        match self.kind {
+            VertexKind::Light(i) => scene.emitter(i).is_infinite(),
+            _ => false,
+        }
+    }
+
+    fn is_delta_light(&self, scene: &Scene) -> bool {
+        match self.kind {
+            VertexKind::Light(i) => scene.emitter(i).is_delta(),
+            _ => false,
+        }
+    }
+
+    fn is_connectible(&self, scene: &Scene) -> bool {
+        match &self.kind {
+            VertexKind::Camera => true,
+            // 向きがデルタ分布の光源(平行光源)には接続できない
+            VertexKind::Light(i) => {
+                !(scene.emitter(*i).is_delta() && scene.emitter(*i).is_infinite())
+            }
+            VertexKind::Surface { object, .. } => object.reflection.is_nee_target(),
+        }
+    }
+
+    fn dir_to(&self, next: &Vertex) -> V3U {
+        V3U::from_v3(next.position - self.position)
+    }
+
+    // この頂点でprevからnextへ散乱されるときのBSDF
+    fn f(&self, next: &Vertex) -> Color {
+        match &self.kind {
+            VertexKind::Surface { object, hit, wo } => {
+                object
+                    .color
+                    .scale(object.reflection.eval(wo, &self.dir_to(next), &hit.normal))
+            }
+            _ => Color::black(),
+        }
+    }
+
+    // この頂点から見た立体角測度のpdfをnextの面積測度に変換する
+    fn convert_density(&self, pdf: f64, next: &Vertex, scene: &Scene) -> f64 {
+        if next.is_infinite_light(scene) {
+            return pdf;
+        }
+
+        let w = next.position - self.position;
+        let dist2 = w.len_square();
+        if dist2 == 0.0 {
+            return 0.0;
+        }
+
+        let cosine = next.normal.map_or(1.0, |n| n.dot(&V3U::from_v3(w)).abs());
+        pdf * cosine / dist2
+    }
+
+    // prevからこの頂点に来た経路がnextへ進む確率密度(nextの面積測度)
+    fn pdf(&self, scene: &Scene, sensor: &Sensor, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        let wn = self.dir_to(next);
+        let pdf = match &self.kind {
+            VertexKind::Light(_) => return self.pdf_light(scene, next),
+            VertexKind::Camera => sensor.pdf_dir(&wn),
+            VertexKind::Surface { object, hit, .. } => match prev {
+                Some(prev) => object.reflection.pdf(&self.dir_to(prev), &wn, &hit.normal),
+                None => 0.0,
+            },
+        };
+
+        self.convert_density(pdf, next, scene)
+    }
+
+    // 光源であるこの頂点から放たれた光がnextに届く確率密度(nextの面積測度)
+    fn pdf_light(&self, scene: &Scene, next: &Vertex) -> f64 {
+        let (_, radius) = scene.bounding_sphere();
+        let emitter = match self.emitter(scene) {
+            Some(i) => scene.emitter(i),
+            None => return 0.0,
+        };
+
+        let w = next.position - self.position;
+        let dist2 = w.len_square();
+        let w = V3U::from_v3(w);
+
+        let mut pdf = if self.is_infinite_light(scene) {
+            1.0 / (std::f64::consts::PI * radius * radius)
+        } else {
+            emitter.emission_pdf(self.normal, &w, radius).1 / dist2
+        };
+
+        if let Some(n) = next.normal {
+            pdf *= n.dot(&w).abs();
+        }
+
+        pdf
+    }
+
+    // 光源であるこの頂点が光源側の経路の始点として選ばれる確率密度
+    fn pdf_light_origin(&self, scene: &Scene, next: &Vertex) -> f64 {
+        let (_, radius) = scene.bounding_sphere();
+        let i = match self.emitter(scene) {
+            Some(i) => i,
+            None => return 0.0,
+        };
+        let w = self.dir_to(next);
+
+        if self.is_infinite_light(scene) {
+            return infinite_light_density(scene, &w.neg());
+        }
+
+        scene.emitter_pmf(i) * scene.emitter(i).emission_pdf(self.normal, &w, radius).0
+    }
+
+    // この頂点からnextに向けて放たれる放射輝度
+    fn le(&self, scene: &Scene, next: &Vertex) -> Color {
+        match self.emitter(scene) {
+            Some(i) => scene.emitter(i).radiance(&self.dir_to(next)),
+            None => Color::black(),
+        }
+    }
+}
+
+// to_lightの向きにある無限遠光源を, 光源側の経路の始点として選ぶ確率密度
+fn infinite_light_density(scene: &Scene, to_light: &V3U) -> f64 {
+    match (scene.sky(), scene.sky_emitter()) {
+        (Some(sky), Some(i)) => scene.emitter_pmf(i) * sky.pdf(to_light),
+        _ => 0.0,
+    }
+}
+
+impl BidirectionalPathTracer {
+    fn camera_subpath<'a>(&self, sensor: &Sensor, scene: &'a Scene, pixel: i32) -> Vec<Vertex<'a>> {
+        let ray = sensor.ray(pixel, uniform_random(), uniform_random());
+        let mut path = vec![Vertex {
+            kind: VertexKind::Camera,
+            position: sensor.position,
+            normal: None,
+            beta: Color::new(1.0, 1.0, 1.0),
+            pdf_fwd: 1.0,
+            pdf_rev: 0.0,
+            delta: false,
+        }];
+
+        let pdf_dir = sensor.pdf_dir(&ray.dir);
+        self.random_walk(
+            scene,
+            ray,
+            Color::new(1.0, 1.0, 1.0),
+            pdf_dir,
+            self.max_depth + 2,
+            TransportMode::Radiance,
+            &mut path,
+        );
+
+        path
+    }
+
+    fn light_subpath<'a>(&self, scene: &'a Scene) -> Vec<Vertex<'a>> {
+        let mut path = Vec::new();
+        let (center, radius) = scene.bounding_sphere();
+
+        let (i, pmf) = match scene.sample_emitter() {
+            Some(r) => r,
+            None => return path,
+        };
+        let emission = match scene.emitter(i).sample_emission(center, radius) {
+            Some(e) if e.pdf_pos > 0.0 && e.pdf_dir > 0.0 && e.radiance > Color::black() => e,
+            _ => return path,
+        };
+
+        path.push(Vertex {
+            kind: VertexKind::Light(i),
+            position: emission.ray.origin,
+            normal: emission.normal,
+            beta: emission.radiance,
+            pdf_fwd: emission.pdf_pos * pmf,
+            pdf_rev: 0.0,
+            delta: false,
+        });
+
+        let cosine = emission
+            .normal
+            .map_or(1.0, |n| n.dot(&emission.ray.dir).abs());
+        let beta = emission
+            .radiance
+            .scale(cosine / (pmf * emission.pdf_pos * emission.pdf_dir));
+        let dir = emission.ray.dir;
+
+        self.random_walk(
+            scene,
+            emission.ray,
+            beta,
+            emission.pdf_dir,
+            self.max_depth + 1,
+            TransportMode::Importance,
+            &mut path,
+        );
+
+        // 無限遠光源からの経路では始点の代わりに光線の向きの確率密度を使う
+        if path[0].is_infinite_light(scene) {
+            if path.len() > 1 {
+                path[1].pdf_fwd =
+                    emission.pdf_pos * path[1].normal.map_or(1.0, |n| n.dot(&dir).abs());
+            }
+            path[0].pdf_fwd = infinite_light_density(scene, &dir.neg());
+        }
+
+        path
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn random_walk<'a>(
+        &self,
+        scene: &'a Scene,
+        ray: Ray,
+        beta: Color,
+        pdf: f64,
+        max_vertices: usize,
+        mode: TransportMode,
+        path: &mut Vec<Vertex<'a>>,
+    ) {
+        let mut ray = ray;
+        let mut beta = beta;
+        let mut pdf_fwd = pdf;
+
+        while path.len() < max_vertices {
+            let prev = path.len() - 1;
+            let (hit, object) = match scene.intersect(&ray) {
+                Some(r) => r,
+                None => {
+                    // カメラ側の経路は環境光に当たったものとして終わる
+                    if mode == TransportMode::Radiance {
+                        if let Some(i) = scene.sky_emitter() {
+                            path.push(Vertex {
+                                kind: VertexKind::Light(i),
+                                position: ray.origin + ray.dir.as_v3(),
+                                normal: None,
+                                beta,
+                                pdf_fwd,
+                                pdf_rev: 0.0,
+                                delta: false,
+                            });
+                        }
+                    }
+                    break;
+                }
+            };
+
+            let wo = ray.dir.neg();
+            let mut vertex = Vertex {
+                kind: VertexKind::Surface {
+                    object,
+                    hit: hit.clone(),
+                    wo,
+                },
+                position: hit.position,
+                normal: Some(hit.normal),
+                beta,
+                pdf_fwd: 0.0,
+                pdf_rev: 0.0,
+                delta: false,
+            };
+            vertex.pdf_fwd = path[prev].convert_density(pdf_fwd, &vertex, scene);
+            path.push(vertex);
+            if path.len() >= max_vertices {
+                break;
+            }
+
+            let sample = match object.reflection.sample_bsdf(&wo, &hit, mode) {
+                Some(s) => s,
+                None => break,
+            };
+            beta = beta.blend(object.color).scale(sample.weight);
+            if beta <= Color::black() {
+                break;
+            }
+
+            let current = path.len() - 1;
+            let mut pdf_rev = object.reflection.pdf(&sample.dir, &wo, &hit.normal);
+            pdf_fwd = sample.pdf_value;
+            // 接続できない頂点(Glossy)はデルタ分布と同じく接続の戦略から外す
+            if sample.is_delta || !object.reflection.is_nee_target() {
+                path[current].delta = true;
+                pdf_fwd = 0.0;
+                pdf_rev = 0.0;
+            }
+            path[prev].pdf_rev = path[current].convert_density(pdf_rev, &path[prev], scene);
+
+            ray = Ray {
+                origin: hit.position,
+                dir: sample.dir,
+            };
+        }
+    }
+
+    // 二つの頂点の間の幾何項(遮蔽を含む)
+    fn g(&self, scene: &Scene, a: &Vertex, b: &Vertex) -> f64 {
+        let d = b.position - a.position;
+        let dist2 = d.len_square();
+        let w = V3U::from_v3(d);
+        if !scene.unoccluded(&a.position, &w, dist2.sqrt()) {
+            return 0.0;
+        }
+
+        a.normal.map_or(1.0, |n| n.dot(&w).abs()) * b.normal.map_or(1.0, |n| n.dot(&w).abs())
+            / dist2
+    }
+
+    // 光源側のs頂点とカメラ側のt頂点をつないだ経路の寄与と, t=1の場合はその画素
+    // i番目の画素の1サンプル, その画素への寄与と光源側の経路をカメラに接続した他の画素への寄与
+    fn sample(&self, sensor: &Sensor, scene: &Scene, i: i32) -> (Color, Vec<(i32, Color)>) {
+        let camera = self.camera_subpath(sensor, scene, i);
+        let light = self.light_subpath(scene);
+        let mut own = Color::black();
+        let mut splats = Vec::new();
+
+        for t in 1..=camera.len() {
+            for s in 0..=light.len() {
+                let depth = s + t;
+                if (s == 1 && t == 1) || depth < 2 || depth - 2 > self.max_depth {
+                    continue;
+                }
+
+                let (l, raster) = self.connect(scene, sensor, &light, &camera, s, t);
+                if t == 1 {
+                    if let Some(j) = raster {
+                        splats.push((j, l));
+                    }
+                } else {
+                    own += l;
+                }
+            }
+        }
+
+        (own, splats)
+    }
+
+    fn connect<'a>(
+        &self,
+        scene: &'a Scene,
+        sensor: &Sensor,
+        light: &[Vertex<'a>],
+        camera: &[Vertex<'a>],
+        s: usize,
+        t: usize,
+    ) -> (Color, Option<i32>) {
+        // 環境光に当たったカメラ側の経路は他の頂点とは接続できない
+        if t > 1 && s != 0 {
+            if let VertexKind::Light(_) = camera[t - 1].kind {
+                return (Color::black(), None);
+            }
+        }
+
+        let mut sampled = None;
+        let mut raster = None;
+        let mut l = Color::black();
+
+        if s == 0 {
+            // カメラ側の経路がそのまま光源に当たったもの
+            let pt = &camera[t - 1];
+            if pt.is_light(scene) {
+                l = pt.le(scene, &camera[t - 2]).blend(pt.beta);
+            }
+        } else if t == 1 {
+            // 光源側の経路をカメラに直接つなぐ
+            let qs = &light[s - 1];
+            if qs.is_connectible(scene) {
+                let d = sensor.position - qs.position;
+                let dist2 = d.len_square();
+                let wi = V3U::from_v3(d);
+                let importance = sensor.importance(&wi.neg());
+                raster = sensor.raster(&qs.position);
+
+                if importance > 0.0 && raster.is_some() {
+                    // ピンホールカメラなのでレンズ上の位置の面積は1とする
+                    let pdf = dist2 / wi.neg().dot(&sensor.dir()).abs();
+                    let vertex = Vertex {
+                        kind: VertexKind::Camera,
+                        position: sensor.position,
+                        normal: None,
+                        beta: Color::new(1.0, 1.0, 1.0).scale(importance / pdf),
+                        pdf_fwd: 0.0,
+                        pdf_rev: 0.0,
+                        delta: false,
+                    };
+
+                    l = qs.beta.blend(qs.f(&vertex)).blend(vertex.beta);
+                    if let Some(n) = qs.normal {
+                        l = l.scale(n.dot(&wi).abs());
+                    }
+                    if l > Color::black() && !scene.unoccluded(&qs.position, &wi, dist2.sqrt()) {
+                        l = Color::black();
+                    }
+                    sampled = Some(vertex);
+                }
+            }
+        } else if s == 1 {
+            // カメラ側の経路の端点から光源をサンプリングする(NEE)
+            let pt = &camera[t - 1];
+            if pt.is_connectible(scene) {
+                if let Some(sample) = scene.sample_on_lights(&pt.position, pt.normal) {
+                    let (_, radius) = scene.bounding_sphere();
+                    let distance = if sample.distance.is_finite() {
+                        sample.distance
+                    } else {
+                        2.0 * radius
+                    };
+
+                    let mut vertex = Vertex {
+                        kind: VertexKind::Light(sample.light),
+                        position: pt.position + sample.dir.scale(distance),
+                        normal: sample.normal,
+                        beta: sample.radiance.scale(1.0 / sample.pdf_value),
+                        pdf_fwd: 0.0,
+                        pdf_rev: 0.0,
+                        delta: false,
+                    };
+                    vertex.pdf_fwd = vertex.pdf_light_origin(scene, pt);
+
+                    l = pt.beta.blend(pt.f(&vertex)).blend(vertex.beta);
+                    if let Some(n) = pt.normal {
+                        l = l.scale(n.dot(&sample.dir).abs());
+                    }
+                    if l > Color::black() && !scene.visible(&pt.position, &sample) {
+                        l = Color::black();
+                    }
+                    sampled = Some(vertex);
+                }
+            }
+        } else {
+            let qs = &light[s - 1];
+            let pt = &camera[t - 1];
+            if qs.is_connectible(scene) && pt.is_connectible(scene) {
+                l = qs.beta.blend(qs.f(pt)).blend(pt.f(qs)).blend(pt.beta);
+                if l > Color::black() {
+                    l = l.scale(self.g(scene, qs, pt));
+                }
+            }
+        }
+
+        if l <= Color::black() {
+            return (Color::black(), raster);
+        }
+
+        let weight = self.mis_weight(scene, sensor, light, camera, sampled.as_ref(), s, t);
+        (l.scale(weight), raster)
+    }
+
+    // 同じ経路を他の(s, t)の組で生成する確率密度との比からMISの重みを求める
+    #[allow(clippy::too_many_arguments)]
+    fn mis_weight(
+        &self,
+        scene: &Scene,
+        sensor: &Sensor,
+        light: &[Vertex],
+        camera: &[Vertex],
+        sampled: Option<&Vertex>,
+        s: usize,
+        t: usize,
+    ) -> f64 {
+        if s + t == 2 {
+            return 1.0;
+        }
+
+        // s=1, t=1 の場合はサンプリングした頂点で置き換える
+        let light_vertex = |i: usize| match sampled {
+            Some(v) if s == 1 && i == 0 => v,
+            _ => &light[i],
+        };
+        let camera_vertex = |i: usize| match sampled {
+            Some(v) if t == 1 && i == 0 => v,
+            _ => &camera[i],
+        };
+
+        // (pdf_fwd, pdf_rev, delta)
+        let mut lp = (0..s)
+            .map(|i| {
+                let v = light_vertex(i);
+                (v.pdf_fwd, v.pdf_rev, v.delta)
+            })
+            .collect::<Vec<_>>();
+        let mut cp = (0..t)
+            .map(|i| {
+                let v = camera_vertex(i);
+                (v.pdf_fwd, v.pdf_rev, v.delta)
+            })
+            .collect::<Vec<_>>();
+
+        let pt = camera_vertex(t - 1);
+        let qs = if s > 0 {
+            Some(light_vertex(s - 1))
+        } else {
+            None
+        };
+        let pt_minus = if t > 1 {
+            Some(camera_vertex(t - 2))
+        } else {
+            None
+        };
+        let qs_minus = if s > 1 {
+            Some(light_vertex(s - 2))
+        } else {
+            None
+        };
+
+        // 接続する頂点はデルタ分布ではない
+        cp[t - 1].2 = false;
+        if s > 0 {
+            lp[s - 1].2 = false;
+        }
+
+        // 接続によって決まる逆向きの確率密度
+        cp[t - 1].1 = match qs {
+            Some(qs) => qs.pdf(scene, sensor, qs_minus, pt),
+            None => pt.pdf_light_origin(scene, pt_minus.unwrap()),
+        };
+        if let Some(pt_minus) = pt_minus {
+            cp[t - 2].1 = match qs {
+                Some(qs) => pt.pdf(scene, sensor, Some(qs), pt_minus),
+                None => pt.pdf_light(scene, pt_minus),
+            };
+        }
+        if let Some(qs) = qs {
+            lp[s - 1].1 = pt.pdf(scene, sensor, pt_minus, qs);
+        }
+        if let Some(qs_minus) = qs_minus {
+            lp[s - 2].1 = qs.unwrap().pdf(scene, sensor, Some(pt), qs_minus);
+        }
+
+        // デルタ分布の頂点の確率密度は比を取るときに1として扱う
+        let remap = |f: f64| if f != 0.0 { f } else { 1.0 };
+        let beta = self.mis_power_heuristic;
+
+        let mut sum = 0.0;
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= (remap(cp[i].1) / remap(cp[i].0)).powi(beta);
+            if !cp[i].2 && !cp[i - 1].2 {
+                sum += ri;
+            }
+        }
+
+        let mut ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= (remap(lp[i].1) / remap(lp[i].0)).powi(beta);
+            let delta_light_vertex = if i > 0 {
+                lp[i - 1].2
+            } else {
+                light_vertex(0).is_delta_light(scene)
+            };
+            if !lp[i].2 && !delta_light_vertex {
+                sum += ri;
+            }
+        }
+
+        1.0 / (1.0 + sum)
+    }
+}
+
+impl Integrator for BidirectionalPathTracer {
+    // 光源側の経路の寄与は他の画素にも及ぶので, 画素の塊ごとに1サンプルずつ求めてから順番に足し込む
+    // 足し込む順序が並列化の分け方によらないので, シードがあれば結果も同じになる
+    fn render(&self, sensor: &Sensor, scene: &Scene) -> Picture {
+        let n = sensor.width * sensor.height;
+        let mut image = vec![Color::black(); n as usize];
+
+        for start in (0..n).step_by(SPLAT_CHUNK) {
+            let end = (start + SPLAT_CHUNK as i32).min(n);
+            for sample in 0..sensor.spp {
+                let contributions = (start..end)
+                    .into_par_iter()
+                    .map(|i| {
+                        sensor.with_sample_sampler(i, sample, || {
+                            // 診断モードでは他の画素への寄与も含めて有限かどうかを見る
+                            let checked = sensor.sample(i, || {
+                                let (own, splats) = self.sample(sensor, scene, i);
+                                let total = splats.iter().fold(own, |sum, (_, l)| sum + *l);
+                                (total, (own, splats))
+                            });
+                            checked.map(|(_, contribution)| contribution)
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                for (i, contribution) in (start..end).zip(contributions) {
+                    let (own, splats) = match contribution {
+                        Some(c) => c,
+                        None => continue,
+                    };
+                    image[i as usize] += own;
+                    for (j, l) in splats {
+                        image[j as usize] += l;
+                    }
+                }
+            }
+        }
+
+        Picture::new(
+            image
+                .into_iter()
+                .map(|c| c.scale(1.0 / sensor.spp as f64))
+                .collect(),
+        )
+    }
+}
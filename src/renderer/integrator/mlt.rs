@@ -0,0 +1,275 @@
+use crate::renderer::{
+    uniform_random, with_sampler, AliasTable, Integrator, PathTracer, Picture, PixelIntegrator,
+    Sampler, Scene, SeededSampler, Sensor,
+};
+use crate::wrapper::color::Color;
+use rayon::prelude::*;
+
+// 主標本空間(経路追跡が消費する乱数の列)の1次元分
+#[derive(Clone, Debug, Default)]
+struct PrimarySample {
+    value: f64,
+    last_modification_iteration: i64,
+    value_backup: f64,
+    modify_backup: i64,
+}
+
+impl PrimarySample {
+    fn backup(&mut self) {
+        self.value_backup = self.value;
+        self.modify_backup = self.last_modification_iteration;
+    }
+
+    fn restore(&mut self) {
+        self.value = self.value_backup;
+        self.last_modification_iteration = self.modify_backup;
+    }
+}
+
+// 主標本空間上の点を変異させながら乱数を供給する
+// 各次元は使われたときに, 最後に変異させてからの反復の分だけまとめて変異させる
+#[derive(Clone, Debug)]
+pub struct MltSampler {
+    rng: SeededSampler,
+    sigma: f64,
+    large_step_probability: f64,
+    samples: Vec<PrimarySample>,
+    current_iteration: i64,
+    large_step: bool,
+    last_large_step_iteration: i64,
+    sample_index: usize,
+}
+
+impl MltSampler {
+    pub fn new(rng: SeededSampler, sigma: f64, large_step_probability: f64) -> Self {
+        MltSampler {
+            rng,
+            sigma,
+            large_step_probability,
+            samples: Vec::new(),
+            current_iteration: 0,
+            large_step: true,
+            last_large_step_iteration: 0,
+            sample_index: 0,
+        }
+    }
+
+    pub fn start_iteration(&mut self) {
+        self.current_iteration += 1;
+        self.large_step = self.rng.next_f64() < self.large_step_probability;
+        self.sample_index = 0;
+    }
+
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step_iteration = self.current_iteration;
+        }
+    }
+
+    pub fn reject(&mut self) {
+        for sample in &mut self.samples {
+            if sample.last_modification_iteration == self.current_iteration {
+                sample.restore();
+            }
+        }
+        self.current_iteration -= 1;
+    }
+
+    fn ensure_ready(&mut self, index: usize) {
+        if index >= self.samples.len() {
+            self.samples.resize(index + 1, PrimarySample::default());
+        }
+        let sample = &mut self.samples[index];
+
+        // 最後の大きな変異より前の値はその時点で一様乱数に置き換わっている
+        if sample.last_modification_iteration < self.last_large_step_iteration {
+            sample.value = self.rng.next_f64();
+            sample.last_modification_iteration = self.last_large_step_iteration;
+        }
+
+        sample.backup();
+        if self.large_step {
+            sample.value = self.rng.next_f64();
+        } else {
+            // 小さな変異を重ねた分は分散を足し合わせた正規分布1回で済ませる
+            let n = (self.current_iteration - sample.last_modification_iteration) as f64;
+            let u1 = 1.0 - self.rng.next_f64();
+            let u2 = self.rng.next_f64();
+            let normal = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+
+            sample.value += normal * self.sigma * n.sqrt();
+            sample.value -= sample.value.floor();
+        }
+        sample.last_modification_iteration = self.current_iteration;
+    }
+}
+
+impl Sampler for MltSampler {
+    fn next_f64(&mut self) -> f64 {
+        let index = self.sample_index;
+        self.sample_index += 1;
+        self.ensure_ready(index);
+
+        // floorの丸めで1.0になることがある
+        self.samples[index].value.min(1.0 - f64::EPSILON)
+    }
+}
+
+// 主標本空間のメトロポリス光輸送(PSSMLT)
+// 経路追跡が使う乱数の列を変異させ, 画像への寄与の輝度に比例して経路を選ぶ
+// 画素あたりの変異の回数はsppとする
+// cf. Kelemen et al., A Simple and Robust Mutation Strategy for the Metropolis Light Transport Algorithm (2002)
+#[derive(Clone, Debug)]
+pub struct MetropolisLightTransport {
+    pub path_tracer: PathTracer,
+    pub bootstrap_samples: usize, // 正規化定数を求めるための経路の数
+    pub chains: usize,            // 並列に走らせるマルコフ連鎖の数
+    pub sigma: f64,               // 小さな変異の標準偏差
+    pub large_step_probability: f64,
+}
+
+impl MetropolisLightTransport {
+    // 乱数の列の最初の2つで画素上の位置を決め, 残りで経路追跡をする
+    fn evaluate(
+        &self,
+        sensor: &Sensor,
+        scene: &Scene,
+        sampler: MltSampler,
+    ) -> ((i32, Color), MltSampler) {
+        with_sampler(sampler, || {
+            let u = uniform_random() * sensor.width as f64;
+            let v = uniform_random() * sensor.height as f64;
+            let x = (u as i32).min(sensor.width - 1);
+            let row = (v as i32).min(sensor.height - 1);
+            let i = row * sensor.width + x;
+
+            let ray = sensor.ray(i, u - x as f64, 1.0 - (v - row as f64));
+            (i, self.path_tracer.radience(scene, ray))
+        })
+    }
+
+    // 描画のシードとindex番目の初期経路の候補で決まる乱数列
+    // 画素の番号と重ならないように負の番号を使う
+    fn new_sampler(&self, seed: u64, index: usize) -> MltSampler {
+        let rng = SeededSampler::for_sample(seed, -1, index as i32);
+        MltSampler::new(rng, self.sigma, self.large_step_probability)
+    }
+}
+
+impl Integrator for MetropolisLightTransport {
+    fn render(&self, sensor: &Sensor, scene: &Scene) -> Picture {
+        let n = (sensor.width * sensor.height) as usize;
+        // シードがなければ描画ごとに選ぶ
+        let seed = sensor.seed.unwrap_or_else(rand::random);
+
+        // 初期状態の候補となる経路を生成し, 画像全体の輝度の平均を求める
+        let weights = (0..self.bootstrap_samples)
+            .into_par_iter()
+            .map(|index| {
+                let ((_, l), _) = self.evaluate(sensor, scene, self.new_sampler(seed, index));
+                l.luminance()
+            })
+            .collect::<Vec<_>>();
+        let b = weights.iter().sum::<f64>() / self.bootstrap_samples as f64;
+        if b.is_nan() || b <= 0.0 {
+            return Picture::new(vec![Color::black(); n]);
+        }
+        let bootstrap = AliasTable::new(&weights);
+
+        let mutations = sensor.spp as u64 * n as u64;
+        let chains = self.chains.max(1) as u64;
+
+        let image = (0..chains)
+            .into_par_iter()
+            .fold(
+                || vec![Color::black(); n],
+                |mut image, chain| {
+                    let rng = SeededSampler::for_sample(seed, -2, chain as i32);
+                    let ((index, _), mut rng) = with_sampler(rng, || bootstrap.sample());
+
+                    // 選んだ経路を同じ乱数の列から作り直して連鎖の初期状態とする
+                    let ((mut current_pixel, mut current), mut sampler) =
+                        self.evaluate(sensor, scene, self.new_sampler(seed, index));
+
+                    // 連鎖ごとの変異の回数, 余りは先頭の連鎖に割り振る
+                    let count = mutations / chains + if chain < mutations % chains { 1 } else { 0 };
+                    for _ in 0..count {
+                        sampler.start_iteration();
+                        let ((proposed_pixel, proposed), next) =
+                            self.evaluate(sensor, scene, sampler);
+                        sampler = next;
+
+                        let accept = (proposed.luminance() / current.luminance()).clamp(0.0, 1.0);
+                        let accept = if accept.is_nan() { 0.0 } else { accept };
+
+                        // 受理されるかどうかによらず期待値で寄与を足し込む
+                        if accept > 0.0 {
+                            image[proposed_pixel as usize] +=
+                                proposed.scale(accept / proposed.luminance());
+                        }
+                        image[current_pixel as usize] +=
+                            current.scale((1.0 - accept) / current.luminance());
+
+                        if rng.next_f64() < accept {
+                            current_pixel = proposed_pixel;
+                            current = proposed;
+                            sampler.accept();
+                        } else {
+                            sampler.reject();
+                        }
+                    }
+
+                    image
+                },
+            )
+            .reduce(
+                || vec![Color::black(); n],
+                |mut a, b| {
+                    for (x, y) in a.iter_mut().zip(b) {
+                        *x += y;
+                    }
+                    a
+                },
+            );
+
+        let scale = b / sensor.spp as f64;
+        Picture::new(image.into_iter().map(|c| c.scale(scale)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mlt_sampler_reject_restores_samples() {
+        let mut sampler = MltSampler::new(SeededSampler::new(0), 0.01, 0.3);
+        let initial = (0..8).map(|_| sampler.next_f64()).collect::<Vec<_>>();
+
+        for _ in 0..100 {
+            sampler.start_iteration();
+            let proposed = (0..8).map(|_| sampler.next_f64()).collect::<Vec<_>>();
+            assert!(proposed.iter().all(|x| (0.0..1.0).contains(x)));
+            sampler.reject();
+        }
+
+        let restored = sampler.samples.iter().map(|s| s.value).collect::<Vec<_>>();
+        assert_eq!(initial, restored);
+    }
+
+    #[test]
+    fn mlt_sampler_small_step_stays_close() {
+        let mut sampler = MltSampler::new(SeededSampler::new(1), 0.01, 0.0);
+        let initial = (0..8).map(|_| sampler.next_f64()).collect::<Vec<_>>();
+
+        sampler.start_iteration();
+        let proposed = (0..8).map(|_| sampler.next_f64()).collect::<Vec<_>>();
+        sampler.accept();
+
+        for (x, y) in initial.iter().zip(&proposed) {
+            // 0と1は同一視する
+            let d = (x - y).abs();
+            assert!(d.min(1.0 - d) < 0.1, "{} -> {}", x, y);
+        }
+    }
+}
@@ -0,0 +1,648 @@
+use crate::renderer::{
+    report_non_finite, uniform_random, HitRecord, LightPaths, MediumEvent, MediumState, PathEvent,
+    PixelIntegrator, RadianceClamp, Reflection, RenderPass, Scene,
+};
+use crate::wrapper::{color::Color, ray::Ray, vec::V3};
+
+const DEPTH_LIMIT: i32 = 64;
+const DEPTH_MIN: i32 = 5;
+// 表面下散乱の物体の中での散乱回数の上限
+const SUBSURFACE_WALK_LIMIT: i32 = 256;
+
+// NEE(enable_mis)の有無を選べるパストレーサー
+#[derive(Clone, Debug)]
+pub struct PathTracer {
+    pub enable_mis: bool,
+    pub enable_mis_debug_mode: bool,
+    pub mis_power_heuristic: i32,
+    pub clamp: RadianceClamp,
+}
+
+impl PathTracer {
+    fn mis_weight(&self, pdf: f64, other_pdf: f64) -> f64 {
+        let a = pdf.powi(self.mis_power_heuristic);
+        let b = other_pdf.powi(self.mis_power_heuristic);
+        if a + b > 0.0 {
+            a / (a + b)
+        } else {
+            0.0
+        }
+    }
+}
+
+impl PixelIntegrator for PathTracer {
+    fn radience(&self, scene: &Scene, ray: Ray) -> Color {
+        self.trace(scene, ray, &[]).total
+    }
+
+    fn light_paths<'a>(
+        &self,
+        scene: &Scene,
+        ray: Ray,
+        passes: &'a [RenderPass],
+    ) -> Option<LightPaths<'a>> {
+        Some(self.trace(scene, ray, passes))
+    }
+}
+
+impl PathTracer {
+    // 放射輝度を求め, 寄与をpassesに振り分ける
+    pub fn trace<'a>(&self, scene: &Scene, ray: Ray, passes: &'a [RenderPass]) -> LightPaths<'a> {
+        let mut depth = 0;
+        let mut ray = ray;
+        let mut rad = LightPaths::new(passes, self.clamp);
+        let mut path_weight = 1.0;
+        let mut path_color = Color::new(1.0, 1.0, 1.0);
+        // カメラからのレイは光源を直接見る(媒質の境界を通り抜けても変わらない)
+        let mut reflected_from_specular_ray = true;
+        let mut medium_state = MediumState::default();
+        // 直前に媒質中で散乱した場合の散乱点と位相関数のpdf, 光源に当たったときのMISに使う
+        let mut medium_scatter: Option<(V3, f64)> = None;
+        let mut subsurface_steps = 0;
+        // 直前の頂点の深さとオブジェクト, 有限でない値が出たときの報告に使う
+        let mut vertex = (0, None);
+
+        loop {
+            report_if_non_finite(&[rad.total, path_color], path_weight, vertex);
+            let intersection = scene.intersect(&ray);
+            let t_max = intersection
+                .as_ref()
+                .map_or(f64::INFINITY, |(hit, _)| hit.distance);
+
+            // 関与媒質中での散乱
+            if let Some((medium, t0, t1)) = scene.medium_segment(&medium_state, &ray, t_max) {
+                let (event, emitted) = medium.sample(&ray, t0, t1, path_color);
+                rad.add(emitted.blend(path_color).scale(path_weight));
+
+                match event {
+                    MediumEvent::Absorb => break,
+                    MediumEvent::Pass { weight } => {
+                        path_color = path_color.blend(weight);
+                    }
+                    MediumEvent::Scatter { position, weight } => {
+                        vertex = (depth, None);
+                        path_color = path_color.blend(weight);
+                        let wo = ray.dir.neg();
+                        let mut rr_threshould = 1.0;
+
+                        if medium_state
+                            .inside
+                            .is_some_and(|o| matches!(o.reflection, Reflection::Subsurface(_)))
+                        {
+                            // 表面下散乱の物体の中からは境界に遮られて光源は見えない
+                            // 散乱回数が多くなるので深さとは別に数える
+                            subsurface_steps += 1;
+                            if subsurface_steps > SUBSURFACE_WALK_LIMIT {
+                                break;
+                            }
+                        } else {
+                            // NEE, 位相関数のサンプリングとMISで重み付けする
+                            if self.enable_mis || scene.has_delta_lights() {
+                                if let Some(sample) = scene.sample_on_lights(&position, None) {
+                                    if self.enable_mis || sample.is_delta {
+                                        let tr = scene.transmittance(
+                                            &position,
+                                            &sample.dir,
+                                            sample.distance,
+                                            medium_state,
+                                        );
+                                        let phase = medium.phase(&wo, &sample.dir);
+                                        let mis_weight = if sample.is_delta {
+                                            1.0
+                                        } else {
+                                            self.mis_weight(sample.pdf_value, phase)
+                                        };
+
+                                        rad.add_at(
+                                            PathEvent::Volume,
+                                            sample
+                                                .radiance
+                                                .blend(tr)
+                                                .scale(phase * mis_weight / sample.pdf_value)
+                                                .blend(path_color)
+                                                .scale(path_weight),
+                                        );
+                                    }
+                                }
+                            }
+
+                            // Russian Roulette
+                            rr_threshould = 0.5;
+                            if depth < DEPTH_MIN {
+                                rr_threshould = 1.0;
+                            } else if uniform_random() > rr_threshould || depth >= DEPTH_LIMIT {
+                                break;
+                            }
+                            depth += 1;
+                        }
+
+                        // 位相関数の値とpdfは等しいので重みは変わらない
+                        let (dir, pdf) = medium.sample_phase(&wo);
+                        path_color = path_color.scale(1.0 / rr_threshould);
+                        medium_scatter = Some((position, pdf));
+                        reflected_from_specular_ray = false;
+                        rad.scatter(PathEvent::Volume);
+                        ray = Ray {
+                            origin: position,
+                            dir,
+                        };
+                        continue;
+                    }
+                }
+            }
+
+            let (hit, target) = match intersection {
+                Some(r) => r,
+                None => {
+                    // 環境光(NEEが有効な場合は拡散面からのものはNEEで計算済み)
+                    if !self.enable_mis || reflected_from_specular_ray {
+                        rad.add(
+                            scene
+                                .environment(&ray.dir)
+                                .scale(path_weight)
+                                .blend(path_color),
+                        );
+                    } else if let Some((position, phase_pdf)) = medium_scatter {
+                        let light_pdf = scene.environment_pdf(&position, None, &ray.dir);
+                        rad.add(
+                            scene
+                                .environment(&ray.dir)
+                                .scale(path_weight * self.mis_weight(phase_pdf, light_pdf))
+                                .blend(path_color),
+                        );
+                    }
+                    break;
+                }
+            };
+            vertex = (depth, scene.object_id(target));
+
+            if target.emission > Color::black() {
+                if !self.enable_mis || reflected_from_specular_ray {
+                    rad.add(target.emission.scale(path_weight).blend(path_color));
+                } else if let Some((position, phase_pdf)) = medium_scatter {
+                    // 光源のpdfを立体角測度に直してMISの重みを求める
+                    let light_pdf = scene.light_pdf(&position, None, target, &hit.position)
+                        * (hit.position - position).len_square()
+                        / ray.dir.dot(&hit.normal).abs();
+                    rad.add(
+                        target
+                            .emission
+                            .scale(path_weight * self.mis_weight(phase_pdf, light_pdf))
+                            .blend(path_color),
+                    );
+                }
+            }
+
+            // 媒質の境界は経路の向きを変えずに通り抜ける
+            if target.reflection == Reflection::Transparent {
+                if depth >= DEPTH_LIMIT {
+                    break;
+                }
+
+                medium_state = medium_state.cross(target);
+                ray = Ray {
+                    origin: hit.position,
+                    dir: ray.dir,
+                };
+                depth += 1;
+                continue;
+            }
+
+            // 表面下散乱の物体から出ていくところは, 外側を向いた拡散面とみなす
+            let exiting_subsurface = matches!(target.reflection, Reflection::Subsurface(_))
+                && medium_state.inside.is_some_and(|o| std::ptr::eq(o, target));
+            let (hit, reflection, outside_state) = if exiting_subsurface {
+                (
+                    HitRecord {
+                        normal: hit.normal.neg(),
+                        ..hit
+                    },
+                    &Reflection::Diffuse,
+                    medium_state.cross(target),
+                )
+            } else {
+                (hit, &target.reflection, medium_state)
+            };
+            // NEEをする面での散乱の種類
+            let vertex_event = if exiting_subsurface {
+                PathEvent::Transmission
+            } else {
+                PathEvent::scattering(reflection, false)
+            };
+
+            // 形状を持たない光源はBSDFレイが当たらないのでMISが無効でもNEEで拾う
+            if (self.enable_mis || scene.has_delta_lights())
+                && target.emission <= Color::black()
+                && reflection.is_nee_target()
+            {
+                // NEE (MIS weight)
+                if let Some(sample) = scene.sample_on_lights(&hit.position, Some(hit.normal)) {
+                    let tr = if self.enable_mis || sample.is_delta {
+                        scene.transmittance(
+                            &hit.position,
+                            &sample.dir,
+                            sample.distance,
+                            outside_state,
+                        )
+                    } else {
+                        Color::black()
+                    };
+
+                    if tr > Color::black() {
+                        rad.add_at(
+                            vertex_event,
+                            (if self.enable_mis_debug_mode {
+                                Color::new(200.0, 0.0, 0.0)
+                            } else {
+                                sample.radiance
+                            })
+                            .blend(tr)
+                            .blend(
+                                (target.color)
+                                    .scale(reflection.nee_bsdf_weight(&ray, &hit, sample.dir)),
+                            )
+                            .scale(sample.dir.dot(&hit.normal).abs() / sample.pdf_value)
+                            .blend(path_color)
+                            .scale(path_weight),
+                        );
+                    }
+                }
+            }
+
+            // Russian Roulette
+            let r = uniform_random();
+            let mut rr_threshould = 0.5;
+
+            if depth < DEPTH_MIN {
+                rr_threshould = 1.0;
+            } else if r > rr_threshould || depth >= DEPTH_LIMIT {
+                break;
+            }
+
+            reflected_from_specular_ray = !reflection.is_nee_target();
+            medium_scatter = None;
+
+            // 反射
+            let reflected = reflection.reflected(&ray, &hit);
+            if reflected.contribution <= 0.0 {
+                // 吸収された
+                break;
+            }
+            let transmitted = reflected.ray.dir.dot(&hit.normal) * ray.dir.dot(&hit.normal) > 0.0;
+            if transmitted {
+                // 面を透過したので媒質が変わる
+                medium_state = medium_state.cross(target);
+            }
+            rad.scatter(if exiting_subsurface {
+                vertex_event
+            } else {
+                PathEvent::scattering(reflection, transmitted)
+            });
+            path_weight *= reflected.contribution;
+            path_color = path_color
+                .blend(target.color)
+                .scale(reflected.weight / rr_threshould);
+            ray = reflected.ray;
+            depth += 1;
+        }
+
+        report_if_non_finite(&[rad.total, path_color], path_weight, vertex);
+        rad
+    }
+}
+
+// 診断モードのために, 有限でない値が出た頂点を報告する
+fn report_if_non_finite(colors: &[Color], weight: f64, (depth, object): (i32, Option<usize>)) {
+    if !weight.is_finite() || colors.iter().any(|c| !c.is_finite()) {
+        report_non_finite(depth as usize, object);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::{
+        with_sampler, Diagnostics, Figure, Object, PhongParameter, RefractiveIndex, Rhombus,
+        SeededSampler, Sphere, SubsurfaceParameter,
+    };
+    use crate::wrapper::vec::V3U;
+
+    #[test]
+    fn subsurface_conserves_energy_in_furnace() {
+        // 一様に光る球の中に吸収のほとんどない表面下散乱の球を置くと, どこから見ても明るさは1
+        let scene = Scene::new(vec![
+            Object {
+                figure: Figure::Sphere(Sphere {
+                    center: V3::zero(),
+                    radius: 10.0,
+                }),
+                emission: Color::new(1.0, 1.0, 1.0),
+                ..Default::default()
+            },
+            Object {
+                figure: Figure::Sphere(Sphere {
+                    center: V3::zero(),
+                    radius: 1.0,
+                }),
+                color: Color::new(1.0, 1.0, 1.0),
+                reflection: Reflection::Subsurface(SubsurfaceParameter::new(
+                    Color::new(1.0, 1.0, 1.0),
+                    Color::new(0.2, 0.3, 0.5),
+                    0.0,
+                )),
+                ..Default::default()
+            },
+        ]);
+        let integrator = PathTracer {
+            enable_mis: true,
+            enable_mis_debug_mode: false,
+            mis_power_heuristic: 2,
+            clamp: RadianceClamp::default(),
+        };
+
+        let n = 20000;
+        let mut sum = Color::black();
+        for i in 0..n {
+            let offset = (i % 100) as f64 / 100.0 - 0.5;
+            sum += integrator.radience(
+                &scene,
+                Ray {
+                    origin: V3::new(offset, 0.0, 5.0),
+                    dir: V3U::from_v3(V3::new(0.0, 0.0, -1.0)),
+                },
+            );
+        }
+        let mean = sum.scale(1.0 / n as f64);
+
+        for i in 0..3 {
+            assert!((mean.channel(i) - 1.0).abs() < 0.05, "{:?}", mean);
+        }
+    }
+
+    // 一様に光る球(放射輝度1)の中に置いた球を正面から見たときの明るさと, その標準誤差
+    fn furnace(reflection: Reflection, color: Color) -> (Color, Color) {
+        let scene = Scene::new(vec![
+            Object {
+                figure: Figure::Sphere(Sphere {
+                    center: V3::zero(),
+                    radius: 10.0,
+                }),
+                emission: Color::new(1.0, 1.0, 1.0),
+                ..Default::default()
+            },
+            Object {
+                figure: Figure::Sphere(Sphere {
+                    center: V3::zero(),
+                    radius: 1.0,
+                }),
+                color,
+                reflection,
+                ..Default::default()
+            },
+        ]);
+        let integrator = PathTracer {
+            enable_mis: true,
+            enable_mis_debug_mode: false,
+            mis_power_heuristic: 2,
+            clamp: RadianceClamp::default(),
+        };
+
+        let n = 100000;
+        let ((sum, squared), _) = with_sampler(SeededSampler::new(1), || {
+            let mut sum = Color::black();
+            let mut squared = Color::black();
+            for _ in 0..n {
+                let c = integrator.radience(
+                    &scene,
+                    Ray {
+                        origin: V3::new(0.0, 0.0, 5.0),
+                        dir: V3U::from_v3(V3::new(0.0, 0.0, -1.0)),
+                    },
+                );
+                sum += c;
+                squared += c.blend(c);
+            }
+            (sum, squared)
+        });
+
+        let n = n as f64;
+        let mean = sum.scale(1.0 / n);
+        let error = |k| ((squared.channel(k) / n - mean.channel(k).powi(2)).max(0.0) / n).sqrt();
+        (mean, Color::new(error(0), error(1), error(2)))
+    }
+
+    #[test]
+    fn materials_reflect_their_albedo_in_furnace() {
+        let color = Color::new(0.5, 0.6, 0.7);
+        let white = Color::new(1.0, 1.0, 1.0);
+        let phong = PhongParameter {
+            diffuse_reflectivity: 0.3,
+            specular_reflectivity: 0.5,
+            exponent: 10,
+        };
+
+        // 正面から見るのでPhongの鏡面反射のローブは面の下にはみ出さない
+        for (reflection, color, albedo) in [
+            (Reflection::Diffuse, color, color),
+            (Reflection::Specular, color, color),
+            (Reflection::Glossy(0.3), color, color),
+            (Reflection::Phong(phong), color, color.scale(0.8)),
+            (Reflection::Refraction, white, white),
+            (Reflection::Dielectric(RefractiveIndex::bk7()), white, white),
+            (Reflection::Transparent, color, white),
+        ] {
+            // 乱数によらず通るように, 標準誤差の4倍までの違いは許す
+            let (result, error) = furnace(reflection.clone(), color);
+            for i in 0..3 {
+                assert!(
+                    (result.channel(i) - albedo.channel(i)).abs()
+                        <= 4.0 * error.channel(i) + 1e-6 * albedo.channel(i),
+                    "{:?}: {:?}, expected {:?}",
+                    reflection,
+                    result,
+                    albedo
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_light_is_reported_at_the_shading_vertex() {
+        // 面積0の光源へのNEEで有限でない値が出る
+        let scene = Scene::new(vec![
+            Object {
+                figure: Figure::Sphere(Sphere {
+                    center: V3::zero(),
+                    radius: 1.0,
+                }),
+                color: Color::new(0.5, 0.5, 0.5),
+                ..Default::default()
+            },
+            Object {
+                figure: Figure::Rhombus(Rhombus {
+                    origin: V3::new(0.0, 0.0, 3.0),
+                    a: V3::new(1.0, 0.0, 0.0),
+                    b: V3::new(2.0, 0.0, 0.0),
+                }),
+                emission: Color::new(1.0, 1.0, 1.0),
+                ..Default::default()
+            },
+        ]);
+        let integrator = PathTracer {
+            enable_mis: true,
+            enable_mis_debug_mode: false,
+            mis_power_heuristic: 2,
+            clamp: RadianceClamp::default(),
+        };
+
+        let diagnostics = Diagnostics::default();
+        for i in 0..16 {
+            let sample = diagnostics.check(i, || {
+                let ray = Ray {
+                    origin: V3::new(0.0, 0.0, 5.0),
+                    dir: V3U::from_v3(V3::new(0.0, 0.0, -1.0)),
+                };
+                (integrator.radience(&scene, ray), ())
+            });
+            assert!(sample.is_none());
+        }
+
+        let samples = diagnostics.samples();
+        assert_eq!(samples.len(), 16);
+        assert!(samples
+            .iter()
+            .all(|s| s.depth == Some(0) && s.object == Some(0)));
+    }
+
+    // 直接光と間接光のパスの平均
+    fn mean_lighting(integrator: &PathTracer, scene: &Scene, origin: V3) -> (f64, f64) {
+        let passes = RenderPass::defaults();
+        let n = 20000;
+        let mut sum = [Color::black(); 2];
+        for _ in 0..n {
+            let paths = integrator.trace(
+                scene,
+                Ray {
+                    origin,
+                    dir: V3U::from_v3(V3::new(0.0, 0.0, -1.0)),
+                },
+                &passes[..2],
+            );
+            sum[0] += paths.values[0];
+            sum[1] += paths.values[1];
+        }
+
+        (sum[0].luminance() / n as f64, sum[1].luminance() / n as f64)
+    }
+
+    #[test]
+    fn lighting_splits_direct_and_indirect() {
+        let a = 0.5;
+        let light = Object {
+            figure: Figure::Sphere(Sphere {
+                center: V3::zero(),
+                radius: 10.0,
+            }),
+            emission: Color::new(1.0, 1.0, 1.0),
+            ..Default::default()
+        };
+
+        // 一様に光る球の中の拡散面の凸な球は自分自身を照らさないので, 間接光はない
+        let scene = Scene::new(vec![
+            light.clone(),
+            Object {
+                figure: Figure::Sphere(Sphere {
+                    center: V3::zero(),
+                    radius: 1.0,
+                }),
+                color: Color::new(a, a, a),
+                ..Default::default()
+            },
+        ]);
+        for &enable_mis in &[true, false] {
+            let integrator = PathTracer {
+                enable_mis,
+                enable_mis_debug_mode: false,
+                mis_power_heuristic: 2,
+                clamp: RadianceClamp::default(),
+            };
+            let (direct, indirect) = mean_lighting(&integrator, &scene, V3::new(0.0, 0.0, 5.0));
+            assert!((direct - a).abs() < 0.03, "{} {}", enable_mis, direct);
+            assert_eq!(indirect, 0.0);
+        }
+
+        // 光りながら反射もする球の内側: 直接光は1 + a, 間接光はa^2 + a^3 + ... = a^2 / (1 - a)
+        let scene = Scene::new(vec![Object {
+            color: Color::new(a, a, a),
+            ..light
+        }]);
+        let integrator = PathTracer {
+            enable_mis: false,
+            enable_mis_debug_mode: false,
+            mis_power_heuristic: 2,
+            clamp: RadianceClamp::default(),
+        };
+        let (direct, indirect) = mean_lighting(&integrator, &scene, V3::zero());
+        assert!((direct - (1.0 + a)).abs() < 0.03, "{}", direct);
+        assert!((indirect - a * a / (1.0 - a)).abs() < 0.03, "{}", indirect);
+    }
+
+    #[test]
+    fn compositing_passes_partition_the_radiance() {
+        // 既定のパスのうち合成用の6つは, どの経路の寄与もちょうど1つに振り分ける
+        let sphere = |center, radius| Figure::Sphere(Sphere { center, radius });
+        let scene = Scene::new(vec![
+            Object {
+                figure: sphere(V3::zero(), 10.0),
+                emission: Color::new(1.0, 1.0, 1.0),
+                color: Color::new(0.5, 0.5, 0.5),
+                ..Default::default()
+            },
+            Object {
+                figure: sphere(V3::new(-1.5, 0.0, 0.0), 1.0),
+                color: Color::new(0.9, 0.9, 0.9),
+                reflection: Reflection::Refraction,
+                ..Default::default()
+            },
+            Object {
+                figure: sphere(V3::new(1.5, 0.0, 0.0), 1.0),
+                color: Color::new(0.5, 0.5, 0.5),
+                ..Default::default()
+            },
+            Object {
+                figure: sphere(V3::new(0.0, -3.0, 0.0), 1.0),
+                color: Color::new(0.5, 0.5, 0.5),
+                reflection: Reflection::Specular,
+                ..Default::default()
+            },
+        ]);
+        let integrator = PathTracer {
+            enable_mis: true,
+            enable_mis_debug_mode: false,
+            mis_power_heuristic: 2,
+            clamp: RadianceClamp::default(),
+        };
+        let passes = RenderPass::defaults();
+
+        for i in 0..2000 {
+            let x = (i % 40) as f64 / 10.0 - 2.0;
+            let paths = integrator.trace(
+                &scene,
+                Ray {
+                    origin: V3::new(x, 0.0, 5.0),
+                    dir: V3U::from_v3(V3::new(0.0, -0.3 * (i % 7) as f64 / 7.0, -1.0)),
+                },
+                &passes,
+            );
+
+            let sum = paths.values[2..]
+                .iter()
+                .fold(Color::black(), |acc, c| acc + *c);
+            let direct_indirect = paths.values[0] + paths.values[1];
+            for k in 0..3 {
+                assert!((sum.channel(k) - paths.total.channel(k)).abs() < 1e-9);
+                assert!((direct_indirect.channel(k) - paths.total.channel(k)).abs() < 1e-9);
+            }
+        }
+    }
+}
@@ -0,0 +1,459 @@
+use crate::renderer::{
+    uniform_random, with_sampler, HitRecord, Integrator, Object, Picture, Scene, SeededSampler,
+    Sensor, TransportMode,
+};
+use crate::wrapper::{color::Color, ray::Ray, vec::V3U};
+use rayon::prelude::*;
+
+mod photon_map;
+
+pub use photon_map::*;
+
+// フォトンマップに記録するフォトンの種類
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum PhotonKind {
+    All,      // 拡散面に当たった全てのフォトン
+    Indirect, // 直接光(光源から最初に当たったもの)を除く
+    Caustic,  // 光源から鏡面反射・屈折のみを経て拡散面に当たったもの
+}
+
+// 光源からcount個のフォトンを放ち, 拡散面(NEEの対象となる面)に当たったものを集める
+// フォトンの持つ放射束はcountで割ってある
+// seedがあればフォトンごとの乱数をseedとmap(何枚目のフォトンマップか)とフォトンの番号から決める
+fn trace_photons(
+    scene: &Scene,
+    count: usize,
+    max_depth: usize,
+    kind: PhotonKind,
+    seed: Option<u64>,
+    map: i32,
+) -> Vec<Photon> {
+    (0..count)
+        .into_par_iter()
+        .fold(Vec::new, |mut photons, n| {
+            let mut emit = || emit_photon(scene, max_depth, kind, 1.0 / count as f64, &mut photons);
+            match seed {
+                // 画素の番号と重ならないように負の番号を使う
+                Some(seed) => {
+                    with_sampler(SeededSampler::for_sample(seed, -1 - map, n as i32), emit).0
+                }
+                None => emit(),
+            }
+            photons
+        })
+        .reduce(Vec::new, |mut a, mut b| {
+            a.append(&mut b);
+            a
+        })
+}
+
+fn emit_photon(
+    scene: &Scene,
+    max_depth: usize,
+    kind: PhotonKind,
+    scale: f64,
+    photons: &mut Vec<Photon>,
+) {
+    let (center, radius) = scene.bounding_sphere();
+    let (i, pmf) = match scene.sample_emitter() {
+        Some(r) => r,
+        None => return,
+    };
+    let emission = match scene.emitter(i).sample_emission(center, radius) {
+        Some(e) if e.pdf_pos > 0.0 && e.pdf_dir > 0.0 => e,
+        _ => return,
+    };
+
+    let cosine = emission
+        .normal
+        .map_or(1.0, |n| n.dot(&emission.ray.dir).abs());
+    let mut power = emission
+        .radiance
+        .scale(scale * cosine / (pmf * emission.pdf_pos * emission.pdf_dir));
+    let mut ray = emission.ray;
+    let mut only_specular = true;
+
+    for depth in 0..max_depth {
+        let (hit, object) = match scene.intersect(&ray) {
+            Some(r) => r,
+            None => return,
+        };
+        let wo = ray.dir.neg();
+
+        if object.reflection.is_nee_target() {
+            let stored = match kind {
+                PhotonKind::All => true,
+                PhotonKind::Indirect => depth > 0,
+                PhotonKind::Caustic => depth > 0 && only_specular,
+            };
+            if stored {
+                photons.push(Photon {
+                    position: hit.position,
+                    dir: wo,
+                    power,
+                });
+            }
+
+            if kind == PhotonKind::Caustic {
+                return;
+            }
+            only_specular = false;
+        }
+
+        let sample = match object
+            .reflection
+            .sample_bsdf(&wo, &hit, TransportMode::Importance)
+        {
+            Some(s) => s,
+            None => return,
+        };
+        let next = power.blend(object.color).scale(sample.weight);
+
+        // ロシアンルーレット, 反射で減った分だけ打ち切る
+        let q = (next.luminance() / power.luminance()).min(1.0);
+        if q.is_nan() || q <= 0.0 || uniform_random() >= q {
+            return;
+        }
+        power = next.scale(1.0 / q);
+
+        ray = Ray {
+            origin: hit.position,
+            dir: sample.dir,
+        };
+    }
+}
+
+// hitで光源を直接サンプリングして得られる, woの向きへの反射光
+fn direct_lighting(scene: &Scene, object: &Object, hit: &HitRecord, wo: &V3U) -> Color {
+    let sample = match scene.sample_on_lights(&hit.position, Some(hit.normal)) {
+        Some(s) => s,
+        None => return Color::black(),
+    };
+
+    let f = object.reflection.eval(wo, &sample.dir, &hit.normal);
+    if f <= 0.0 || !scene.visible(&hit.position, &sample) {
+        return Color::black();
+    }
+
+    sample
+        .radiance
+        .blend(object.color)
+        .scale(f * sample.dir.dot(&hit.normal).abs() / sample.pdf_value)
+}
+
+// k近傍のフォトンによる, woの向きへの反射光の密度推定
+fn estimate_radiance(
+    map: &PhotonMap,
+    object: &Object,
+    hit: &HitRecord,
+    wo: &V3U,
+    k: usize,
+    max_radius: f64,
+) -> Color {
+    let (photons, r2) = map.nearest(&hit.position, k, max_radius);
+    // k個見つからなければ探した範囲全体の密度とする
+    let r2 = if photons.len() < k {
+        max_radius * max_radius
+    } else {
+        r2
+    };
+    if photons.is_empty() || r2 <= 0.0 {
+        return Color::black();
+    }
+
+    let mut flux = Color::black();
+    for photon in photons {
+        flux += photon
+            .power
+            .scale(object.reflection.eval(wo, &photon.dir, &hit.normal));
+    }
+
+    flux.blend(object.color)
+        .scale(1.0 / (std::f64::consts::PI * r2))
+}
+
+// 鏡面反射・屈折をたどってレイが最初に当たった拡散面の点
+struct DiffuseHit<'a> {
+    hit: HitRecord,
+    object: &'a Object,
+    wo: V3U,       // 視点側への向き
+    weight: Color, // 途中の反射による重み
+}
+
+// 鏡面反射・屈折をたどってレイが最初に当たる拡散面を探す
+// count_emissionなら経路上で見える発光(当たった拡散面のものも含む)も返す
+fn trace_to_diffuse(
+    scene: &Scene,
+    ray: Ray,
+    max_depth: usize,
+    count_emission: bool,
+) -> (Color, Option<DiffuseHit<'_>>) {
+    let mut ray = ray;
+    let mut weight = Color::new(1.0, 1.0, 1.0);
+    let mut emitted = Color::black();
+
+    for _ in 0..max_depth {
+        let (hit, object) = match scene.intersect(&ray) {
+            Some(r) => r,
+            None => {
+                if count_emission {
+                    emitted += weight.blend(scene.environment(&ray.dir));
+                }
+                return (emitted, None);
+            }
+        };
+
+        if count_emission {
+            emitted += weight.blend(object.emission);
+        }
+
+        let wo = ray.dir.neg();
+        if object.reflection.is_nee_target() {
+            return (
+                emitted,
+                Some(DiffuseHit {
+                    hit,
+                    object,
+                    wo,
+                    weight,
+                }),
+            );
+        }
+
+        let sample = match object
+            .reflection
+            .sample_bsdf(&wo, &hit, TransportMode::Radiance)
+        {
+            Some(s) => s,
+            None => break,
+        };
+        weight = weight.blend(object.color).scale(sample.weight);
+        ray = Ray {
+            origin: hit.position,
+            dir: sample.dir,
+        };
+    }
+
+    (emitted, None)
+}
+
+// 大域フォトンマップとコースティクスフォトンマップを使うフォトンマッピング
+// 直接光はNEE, コースティクスはフォトンの密度推定, それ以外の間接光は最終収集で求める
+// cf. Jensen, Realistic Image Synthesis Using Photon Mapping (2001)
+#[derive(Clone, Debug)]
+pub struct PhotonMapper {
+    pub global_photons: usize,  // 大域フォトンマップのために放つフォトンの数
+    pub caustic_photons: usize, // コースティクスフォトンマップのために放つフォトンの数
+    pub nearest_photons: usize, // 密度推定に使うフォトンの数
+    pub max_radius: f64,        // 密度推定でフォトンを探す最大の半径
+    pub final_gather_samples: usize,
+    pub max_depth: usize,
+}
+
+impl PhotonMapper {
+    fn radiance_with(
+        &self,
+        scene: &Scene,
+        global: &PhotonMap,
+        caustic: &PhotonMap,
+        ray: Ray,
+    ) -> Color {
+        let (emitted, diffuse) = trace_to_diffuse(scene, ray, self.max_depth, true);
+        let p = match diffuse {
+            Some(p) => p,
+            None => return emitted,
+        };
+
+        let mut l = direct_lighting(scene, p.object, &p.hit, &p.wo);
+        l += estimate_radiance(
+            caustic,
+            p.object,
+            &p.hit,
+            &p.wo,
+            self.nearest_photons,
+            self.max_radius,
+        );
+        l += self.final_gather(scene, global, &p);
+
+        emitted + p.weight.blend(l)
+    }
+
+    // 拡散面から放ったレイの先で大域フォトンマップを引いて間接光を求める
+    // 光源が直接見えるもの, 鏡面反射・屈折を経て見えるものはNEEとコースティクスで数えている
+    fn final_gather(&self, scene: &Scene, global: &PhotonMap, p: &DiffuseHit) -> Color {
+        let mut l = Color::black();
+
+        for _ in 0..self.final_gather_samples {
+            let sample =
+                match p
+                    .object
+                    .reflection
+                    .sample_bsdf(&p.wo, &p.hit, TransportMode::Radiance)
+                {
+                    Some(s) => s,
+                    None => continue,
+                };
+            let ray = Ray {
+                origin: p.hit.position,
+                dir: sample.dir,
+            };
+
+            if let (_, Some(q)) = trace_to_diffuse(scene, ray, self.max_depth, false) {
+                let radiance = estimate_radiance(
+                    global,
+                    q.object,
+                    &q.hit,
+                    &q.wo,
+                    self.nearest_photons,
+                    self.max_radius,
+                );
+                l += q
+                    .weight
+                    .blend(radiance)
+                    .blend(p.object.color)
+                    .scale(sample.weight);
+            }
+        }
+
+        l.scale(1.0 / self.final_gather_samples.max(1) as f64)
+    }
+}
+
+impl Integrator for PhotonMapper {
+    // フォトンマップは画像全体で共有する
+    fn render(&self, sensor: &Sensor, scene: &Scene) -> Picture {
+        let global = PhotonMap::new(trace_photons(
+            scene,
+            self.global_photons,
+            self.max_depth,
+            PhotonKind::All,
+            sensor.seed,
+            0,
+        ));
+        let caustic = PhotonMap::new(trace_photons(
+            scene,
+            self.caustic_photons,
+            self.max_depth,
+            PhotonKind::Caustic,
+            sensor.seed,
+            1,
+        ));
+
+        sensor.render_per_pixel(|ray| self.radiance_with(scene, &global, &caustic, ray))
+    }
+}
+
+// 確率的漸進的フォトンマッピング(SPPM)
+// 反復ごとに画素の可視点を選び直し, その反復で放ったフォトンだけで推定を更新する
+// 画素ごとの推定半径は反復とともに縮むので, メモリを増やさずに収束する
+// 反復の回数はsppとする
+// cf. Hachisuka and Jensen, Stochastic Progressive Photon Mapping (2009)
+#[derive(Clone, Debug)]
+pub struct StochasticProgressivePhotonMapper {
+    pub photons_per_iteration: usize,
+    pub initial_radius: f64,
+    pub alpha: f64, // 反復ごとに残すフォトンの割合
+    pub max_depth: usize,
+}
+
+// 画素ごとの推定の状態
+#[derive(Clone, Debug)]
+struct PixelState {
+    radius: f64,
+    photon_count: f64,
+    flux: Color,   // 半径内のフォトンによる放射束の和(τ)
+    direct: Color, // 光源が直接, または鏡面反射・屈折を経て見えるものとNEEの和
+}
+
+impl StochasticProgressivePhotonMapper {
+    // 画素iからレイを飛ばして可視点を探す
+    fn visible_point<'a>(
+        &self,
+        sensor: &Sensor,
+        scene: &'a Scene,
+        i: i32,
+    ) -> (Color, Option<DiffuseHit<'a>>) {
+        let ray = sensor.ray(i, uniform_random(), uniform_random());
+        let (emitted, diffuse) = trace_to_diffuse(scene, ray, self.max_depth, true);
+
+        match diffuse {
+            Some(p) => {
+                let direct = direct_lighting(scene, p.object, &p.hit, &p.wo);
+                (emitted + p.weight.blend(direct), Some(p))
+            }
+            None => (emitted, None),
+        }
+    }
+}
+
+impl Integrator for StochasticProgressivePhotonMapper {
+    fn render(&self, sensor: &Sensor, scene: &Scene) -> Picture {
+        let mut states = vec![
+            PixelState {
+                radius: self.initial_radius,
+                photon_count: 0.0,
+                flux: Color::black(),
+                direct: Color::black(),
+            };
+            (sensor.width * sensor.height) as usize
+        ];
+
+        for iteration in 0..sensor.spp {
+            let map = PhotonMap::new(trace_photons(
+                scene,
+                self.photons_per_iteration,
+                self.max_depth,
+                PhotonKind::Indirect,
+                sensor.seed,
+                iteration,
+            ));
+
+            states.par_iter_mut().enumerate().for_each(|(i, state)| {
+                let i = i as i32;
+                let (direct, point) = sensor
+                    .with_sample_sampler(i, iteration, || self.visible_point(sensor, scene, i));
+                state.direct += direct;
+
+                let p = match point {
+                    Some(p) => p,
+                    None => return,
+                };
+
+                let mut m = 0.0;
+                let mut phi = Color::black();
+                map.for_each_within(&p.hit.position, state.radius, |photon| {
+                    let f = p.object.reflection.eval(&p.wo, &photon.dir, &p.hit.normal);
+                    if f > 0.0 {
+                        m += 1.0;
+                        phi += photon.power.scale(f);
+                    }
+                });
+                if m == 0.0 {
+                    return;
+                }
+
+                // 見つかったフォトンのうちalphaの割合だけ残るように半径を縮める
+                let count = state.photon_count + self.alpha * m;
+                let radius = state.radius * (count / (state.photon_count + m)).sqrt();
+                let shrink = (radius / state.radius).powi(2);
+
+                state.flux = (state.flux + p.weight.blend(p.object.color).blend(phi)).scale(shrink);
+                state.photon_count = count;
+                state.radius = radius;
+            });
+        }
+
+        let iterations = sensor.spp as f64;
+        Picture::new(
+            states
+                .into_iter()
+                .map(|state| {
+                    state.direct.scale(1.0 / iterations)
+                        + state
+                            .flux
+                            .scale(1.0 / (iterations * std::f64::consts::PI * state.radius.powi(2)))
+                })
+                .collect(),
+        )
+    }
+}
@@ -0,0 +1,256 @@
+use crate::wrapper::{
+    aabb::Aabb,
+    color::Color,
+    vec::{V3, V3U},
+};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+#[derive(Clone, Debug)]
+pub struct Photon {
+    pub position: V3,
+    pub dir: V3U, // 光が来た向き(面から離れる向き)
+    pub power: Color,
+}
+
+// フォトンを格納する平衡kd木
+// 各部分列の中央の要素が節になるように並べ替えて配列のまま持つ
+#[derive(Clone, Debug)]
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<usize>,
+}
+
+impl PhotonMap {
+    pub fn new(photons: Vec<Photon>) -> Self {
+        let mut photons = photons;
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+
+        PhotonMap { photons, axes }
+    }
+
+    // pointから半径radius以内の全てのフォトン
+    pub fn for_each_within(&self, point: &V3, radius: f64, mut f: impl FnMut(&Photon)) {
+        within(&self.photons, &self.axes, point, radius * radius, &mut f);
+    }
+
+    // pointに近い順に高々k個のフォトンと, それらを含む半径の2乗
+    pub fn nearest(&self, point: &V3, k: usize, max_radius: f64) -> (Vec<&Photon>, f64) {
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        if k > 0 {
+            nearest(
+                &self.photons,
+                &self.axes,
+                0,
+                point,
+                k,
+                max_radius * max_radius,
+                &mut heap,
+            );
+        }
+
+        let r2 = heap.peek().map_or(0.0, |c: &Candidate| c.0);
+        (heap.into_iter().map(|c| &self.photons[c.1]).collect(), r2)
+    }
+}
+
+fn build(photons: &mut [Photon], axes: &mut [usize]) {
+    if photons.is_empty() {
+        return;
+    }
+
+    let bounds = photons.iter().fold(
+        Aabb::new(photons[0].position, photons[0].position),
+        |b, p| b.expand(p.position),
+    );
+    let axis = bounds.longest_axis();
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |a, b| {
+        a.position
+            .axis(axis)
+            .partial_cmp(&b.position.axis(axis))
+            .unwrap_or(Ordering::Equal)
+    });
+    axes[mid] = axis;
+
+    let (left, right) = photons.split_at_mut(mid);
+    let (left_axes, right_axes) = axes.split_at_mut(mid);
+    build(left, left_axes);
+    build(&mut right[1..], &mut right_axes[1..]);
+}
+
+fn within(photons: &[Photon], axes: &[usize], point: &V3, r2: f64, f: &mut impl FnMut(&Photon)) {
+    if photons.is_empty() {
+        return;
+    }
+
+    let mid = photons.len() / 2;
+    let photon = &photons[mid];
+    if (photon.position - *point).len_square() <= r2 {
+        f(photon);
+    }
+
+    let d = point.axis(axes[mid]) - photon.position.axis(axes[mid]);
+    let (near, far) = if d < 0.0 {
+        ((0, mid), (mid + 1, photons.len()))
+    } else {
+        ((mid + 1, photons.len()), (0, mid))
+    };
+
+    within(
+        &photons[near.0..near.1],
+        &axes[near.0..near.1],
+        point,
+        r2,
+        f,
+    );
+    if d * d <= r2 {
+        within(&photons[far.0..far.1], &axes[far.0..far.1], point, r2, f);
+    }
+}
+
+// (距離の2乗, フォトンの番号), 遠いものが先頭に来る
+#[derive(PartialEq)]
+struct Candidate(f64, usize);
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+fn nearest(
+    photons: &[Photon],
+    axes: &[usize],
+    offset: usize,
+    point: &V3,
+    k: usize,
+    max_r2: f64,
+    heap: &mut BinaryHeap<Candidate>,
+) {
+    if photons.is_empty() {
+        return;
+    }
+
+    let r2 = |heap: &BinaryHeap<Candidate>| {
+        if heap.len() < k {
+            max_r2
+        } else {
+            heap.peek().map_or(max_r2, |c| c.0)
+        }
+    };
+
+    let mid = photons.len() / 2;
+    let photon = &photons[mid];
+    let dist2 = (photon.position - *point).len_square();
+    if dist2 <= r2(heap) {
+        heap.push(Candidate(dist2, offset + mid));
+        if heap.len() > k {
+            heap.pop();
+        }
+    }
+
+    let d = point.axis(axes[mid]) - photon.position.axis(axes[mid]);
+    let (near, far) = if d < 0.0 {
+        ((0, mid), (mid + 1, photons.len()))
+    } else {
+        ((mid + 1, photons.len()), (0, mid))
+    };
+
+    nearest(
+        &photons[near.0..near.1],
+        &axes[near.0..near.1],
+        offset + near.0,
+        point,
+        k,
+        max_r2,
+        heap,
+    );
+    if d * d <= r2(heap) {
+        nearest(
+            &photons[far.0..far.1],
+            &axes[far.0..far.1],
+            offset + far.0,
+            point,
+            k,
+            max_r2,
+            heap,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_photons(n: usize) -> Vec<Photon> {
+        (0..n)
+            .map(|_| Photon {
+                position: V3::new(
+                    rand::random::<f64>(),
+                    rand::random::<f64>(),
+                    rand::random::<f64>(),
+                )
+                .scale(10.0),
+                dir: V3U::unit_y(),
+                power: Color::new(1.0, 1.0, 1.0),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn photon_map_within_matches_brute_force() {
+        let photons = random_photons(2000);
+        let map = PhotonMap::new(photons.clone());
+        assert_eq!(map.photons.len(), photons.len());
+
+        for _ in 0..50 {
+            let point = random_photons(1)[0].position;
+            let radius = rand::random::<f64>() * 3.0;
+
+            let mut found = 0;
+            map.for_each_within(&point, radius, |p| {
+                assert!((p.position - point).len() <= radius);
+                found += 1;
+            });
+
+            let expected = photons
+                .iter()
+                .filter(|p| (p.position - point).len_square() <= radius * radius)
+                .count();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn photon_map_nearest_matches_brute_force() {
+        let photons = random_photons(2000);
+        let map = PhotonMap::new(photons.clone());
+
+        for k in [1, 5, 50] {
+            let point = random_photons(1)[0].position;
+            let (found, r2) = map.nearest(&point, k, 100.0);
+
+            let mut dists = photons
+                .iter()
+                .map(|p| (p.position - point).len_square())
+                .collect::<Vec<_>>();
+            dists.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+            assert_eq!(found.len(), k);
+            assert!((r2 - dists[k - 1]).abs() < 1e-12);
+            for p in found {
+                assert!((p.position - point).len_square() <= dists[k - 1] + 1e-12);
+            }
+        }
+    }
+}
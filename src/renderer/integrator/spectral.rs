@@ -0,0 +1,247 @@
+use crate::renderer::{
+    uniform_random, Integrator, Picture, Reflection, SampledSpectrum, SampledWavelengths, Scene,
+    Sensor,
+};
+use crate::wrapper::{color::Color, ray::Ray};
+
+const DEPTH_LIMIT: i32 = 64;
+const DEPTH_MIN: i32 = 5;
+
+// 波長ごとに光を運ぶパストレーサー
+// RGBで与えた色や放射はスペクトルに持ち上げ, 結果はXYZに積分してからRGBに直す
+// シーンの色は作業色空間によらず線形sRGBとして扱う
+// 関与媒質のあるシーンは描画せず, 表面下散乱はRefractionとみなす
+#[derive(Clone, Debug)]
+pub struct SpectralPathTracer {}
+
+impl SpectralPathTracer {
+    // カメラからのレイが運ぶ放射輝度のXYZ
+    pub fn radience_xyz(&self, scene: &Scene, ray: Ray) -> Color {
+        let mut wavelengths = SampledWavelengths::sample();
+        let rad = self.radience_spectrum(scene, ray, &mut wavelengths);
+        wavelengths.spectrum_to_xyz(&rad)
+    }
+
+    fn radience_spectrum(
+        &self,
+        scene: &Scene,
+        ray: Ray,
+        wavelengths: &mut SampledWavelengths,
+    ) -> SampledSpectrum {
+        let spectrum =
+            |c: Color, wavelengths: &SampledWavelengths| SampledSpectrum::from_rgb(c, wavelengths);
+
+        let mut depth = 0;
+        let mut ray = ray;
+        let mut rad = SampledSpectrum::zero();
+        let mut path_weight = 1.0;
+        let mut throughput = SampledSpectrum::constant(1.0);
+        // 視点からのレイも鏡面反射と同じく光源に当たったら数える
+        let mut reflected_from_specular_ray = true;
+
+        loop {
+            let (hit, target) = match scene.intersect(&ray) {
+                Some(r) => r,
+                None => {
+                    // 拡散面からのものはNEEで計算済み
+                    if reflected_from_specular_ray {
+                        rad += spectrum(scene.environment(&ray.dir), wavelengths)
+                            .blend(throughput)
+                            .scale(path_weight);
+                    }
+                    break;
+                }
+            };
+
+            if target.emission > Color::black() && reflected_from_specular_ray {
+                rad += spectrum(target.emission, wavelengths)
+                    .blend(throughput)
+                    .scale(path_weight);
+            }
+
+            // 境界を通り抜けても直前の反射の種類は変わらない
+            if target.reflection == Reflection::Transparent {
+                if depth >= DEPTH_LIMIT {
+                    break;
+                }
+
+                ray = Ray {
+                    origin: hit.position,
+                    dir: ray.dir,
+                };
+                depth += 1;
+                continue;
+            }
+
+            // NEE
+            if target.emission <= Color::black() && target.reflection.is_nee_target() {
+                if let Some(sample) = scene.sample_on_lights(&hit.position, Some(hit.normal)) {
+                    if scene.visible(&hit.position, &sample) {
+                        rad += spectrum(sample.radiance, wavelengths)
+                            .blend(spectrum(target.color, wavelengths))
+                            .blend(throughput)
+                            .scale(
+                                target.reflection.nee_bsdf_weight(&ray, &hit, sample.dir)
+                                    * sample.dir.dot(&hit.normal).abs()
+                                    / sample.pdf_value
+                                    * path_weight,
+                            );
+                    }
+                }
+            }
+
+            // Russian Roulette
+            let mut rr_threshould = 0.5;
+            if depth < DEPTH_MIN {
+                rr_threshould = 1.0;
+            } else if uniform_random() > rr_threshould || depth >= DEPTH_LIMIT {
+                break;
+            }
+
+            reflected_from_specular_ray = !target.reflection.is_nee_target();
+
+            // 屈折率が波長で変わる場合はheroの波長の向きに進み, 他の波長は打ち切る
+            let reflected = if target.reflection.is_dispersive() {
+                throughput = throughput.blend(wavelengths.terminate_secondary());
+                target
+                    .reflection
+                    .reflected_at(&ray, &hit, wavelengths.hero())
+            } else {
+                target.reflection.reflected(&ray, &hit)
+            };
+            if reflected.contribution <= 0.0 {
+                break;
+            }
+
+            path_weight *= reflected.contribution;
+            throughput = throughput
+                .blend(spectrum(target.color, wavelengths))
+                .scale(reflected.weight / rr_threshould);
+            ray = reflected.ray;
+            depth += 1;
+        }
+
+        rad
+    }
+}
+
+impl Integrator for SpectralPathTracer {
+    fn render(&self, sensor: &Sensor, scene: &Scene) -> Picture {
+        let xyz = sensor.render_per_pixel(|ray| self.radience_xyz(scene, ray));
+        Picture::from_xyz(xyz.into_vec(), sensor.working_space)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::{
+        xyz_to_rgb, Figure, Object, PathTracer, PixelIntegrator, RefractiveIndex, Sphere,
+    };
+    use crate::wrapper::vec::{V3, V3U};
+
+    #[test]
+    fn spectral_agrees_with_rgb_on_diffuse_furnace() {
+        // 一様に光る球の中の拡散面の球
+        let color = Color::new(0.75, 0.25, 0.25);
+        let scene = Scene::new(vec![
+            Object {
+                figure: Figure::Sphere(Sphere {
+                    center: V3::zero(),
+                    radius: 10.0,
+                }),
+                emission: Color::new(1.0, 1.0, 1.0),
+                ..Default::default()
+            },
+            Object {
+                figure: Figure::Sphere(Sphere {
+                    center: V3::zero(),
+                    radius: 1.0,
+                }),
+                color,
+                ..Default::default()
+            },
+        ]);
+        let ray = Ray {
+            origin: V3::new(0.0, 0.0, 5.0),
+            dir: V3U::from_v3(V3::new(0.0, 0.0, -1.0)),
+        };
+
+        let n = 100000;
+        let mut spectral = Color::black();
+        let mut rgb = Color::black();
+        let pt = PathTracer {
+            enable_mis: true,
+            enable_mis_debug_mode: false,
+            mis_power_heuristic: 2,
+            clamp: Default::default(),
+        };
+        for _ in 0..n {
+            spectral += xyz_to_rgb(SpectralPathTracer {}.radience_xyz(&scene, ray.clone()));
+            rgb += pt.radience(&scene, ray.clone());
+        }
+
+        let (spectral, rgb) = (spectral.scale(1.0 / n as f64), rgb.scale(1.0 / n as f64));
+        for i in 0..3 {
+            assert!(
+                (spectral.channel(i) - rgb.channel(i)).abs() < 0.03,
+                "{:?} vs {:?}",
+                spectral,
+                rgb
+            );
+        }
+    }
+
+    #[test]
+    fn emission_is_seen_through_transparent_boundaries() {
+        // 光る球の中の透明な境界の球を通して, 反射しない光源の内側を見る
+        let scene = Scene::new(vec![
+            Object {
+                figure: Figure::Sphere(Sphere {
+                    center: V3::zero(),
+                    radius: 10.0,
+                }),
+                emission: Color::new(1.0, 1.0, 1.0),
+                ..Default::default()
+            },
+            Object {
+                figure: Figure::Sphere(Sphere {
+                    center: V3::zero(),
+                    radius: 1.0,
+                }),
+                reflection: Reflection::Transparent,
+                ..Default::default()
+            },
+        ]);
+        let ray = Ray {
+            origin: V3::new(0.0, 0.0, 5.0),
+            dir: V3U::from_v3(V3::new(0.0, 0.0, -1.0)),
+        };
+
+        let n = 10000;
+        let mut rad = Color::black();
+        for _ in 0..n {
+            rad += xyz_to_rgb(SpectralPathTracer {}.radience_xyz(&scene, ray.clone()));
+        }
+
+        let rad = rad.scale(1.0 / n as f64);
+        for i in 0..3 {
+            assert!((rad.channel(i) - 1.0).abs() < 0.03, "{:?}", rad);
+        }
+    }
+
+    #[test]
+    fn refractive_index_has_normal_dispersion() {
+        for ior in &[
+            RefractiveIndex::bk7(),
+            RefractiveIndex::Cauchy {
+                a: 1.5046,
+                b: 0.0042,
+            },
+        ] {
+            // 短い波長ほど屈折率が大きい
+            assert!(ior.at(450.0) > ior.at(550.0) && ior.at(550.0) > ior.at(650.0));
+        }
+        assert!((RefractiveIndex::bk7().at(587.6) - 1.5168).abs() < 1e-3);
+    }
+}
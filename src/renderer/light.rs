@@ -0,0 +1,453 @@
+use crate::renderer::{uniform_random, Object, SampleRecord, Sky};
+use crate::wrapper::{
+    color::Color,
+    ray::Ray,
+    vec::{V3, V3U},
+};
+use std::f64::consts::PI;
+
+// シェーディング点から見た光源上のサンプル
+#[derive(Clone, Debug, PartialEq)]
+pub struct LightSample {
+    // シェーディング点から光源への向き
+    pub dir: V3U,
+    // 光源までの距離(無限遠光源ではf64::INFINITY)
+    pub distance: f64,
+    // シェーディング点に届く放射輝度(点光源などでは距離による減衰を含む)
+    pub radiance: Color,
+    // 立体角測度でのpdf(光源の選択確率込み), デルタ光源では選択確率のみ
+    pub pdf_value: f64,
+    // BSDFサンプリングでは到達しない光源かどうか
+    pub is_delta: bool,
+    // シーンでの光源の番号
+    pub light: usize,
+    // 面光源上の点の法線
+    pub normal: Option<V3U>,
+}
+
+impl LightSample {
+    // 面光源上の点のサンプルから作る
+    pub fn from_area(point: &V3, sample: &SampleRecord, emission: Color) -> Option<LightSample> {
+        let d = sample.point - *point;
+        let distance = d.len();
+        if distance <= 0.0 {
+            return None;
+        }
+
+        let dir = V3U::from_v3_unsafe(d.scale(1.0 / distance));
+        let cos_light = sample.normal.dot(&dir).abs();
+        if cos_light <= 0.0 {
+            return None;
+        }
+
+        Some(LightSample {
+            dir,
+            distance,
+            radiance: emission,
+            pdf_value: sample.pdf_value * distance * distance / cos_light,
+            is_delta: false,
+            light: 0,
+            normal: Some(sample.normal),
+        })
+    }
+}
+
+// 形状を持たない光源(BSDFレイは当たらずNEEでのみ寄与する)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Light {
+    Point(PointLight),
+    Spot(SpotLight),
+    Directional(DirectionalLight),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PointLight {
+    pub position: V3,
+    pub intensity: Color, // 放射強度
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpotLight {
+    pub position: V3,
+    pub dir: V3U,
+    pub intensity: Color,
+    pub cone_angle: f64,     // 照らす円錐の半頂角(ラジアン)
+    pub penumbra_angle: f64, // 円錐の縁から内側に向かって減衰する幅(ラジアン)
+}
+
+impl SpotLight {
+    pub fn cos_falloff_start(&self) -> f64 {
+        (self.cone_angle - self.penumbra_angle).max(0.0).cos()
+    }
+
+    pub fn cos_falloff_end(&self) -> f64 {
+        self.cone_angle.cos()
+    }
+
+    // 光軸となす角のcosに対する減衰
+    pub fn falloff(&self, cos_theta: f64) -> f64 {
+        let start = self.cos_falloff_start();
+        let end = self.cos_falloff_end();
+        if cos_theta >= start {
+            1.0
+        } else if cos_theta <= end {
+            0.0
+        } else {
+            let t = (cos_theta - end) / (start - end);
+            t * t * (3.0 - 2.0 * t)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirectionalLight {
+    pub dir: V3U,              // 光の進む向き
+    pub irradiance: Color,     // 光に垂直な面での放射照度
+    pub angular_diameter: f64, // 光源の視直径(ラジアン), 0なら完全な平行光
+}
+
+impl Light {
+    // シェーディング点pointから光源をサンプリングする(pdfは選択確率を含まない)
+    pub fn sample(&self, point: &V3) -> Option<LightSample> {
+        match self {
+            Light::Point(light) => {
+                let d = light.position - *point;
+                let dist2 = d.len_square();
+
+                Some(LightSample {
+                    dir: V3U::from_v3(d),
+                    distance: dist2.sqrt(),
+                    radiance: light.intensity.scale(1.0 / dist2),
+                    pdf_value: 1.0,
+                    is_delta: true,
+                    light: 0,
+                    normal: None,
+                })
+            }
+            Light::Spot(light) => {
+                let d = light.position - *point;
+                let dist2 = d.len_square();
+                let dir = V3U::from_v3(d);
+                let falloff = light.falloff(light.dir.dot(&dir.neg()));
+                if falloff <= 0.0 {
+                    return None;
+                }
+
+                Some(LightSample {
+                    dir,
+                    distance: dist2.sqrt(),
+                    radiance: light.intensity.scale(falloff / dist2),
+                    pdf_value: 1.0,
+                    is_delta: true,
+                    light: 0,
+                    normal: None,
+                })
+            }
+            Light::Directional(light) => {
+                let to_light = light.dir.neg();
+                if light.angular_diameter <= 0.0 {
+                    return Some(LightSample {
+                        dir: to_light,
+                        distance: f64::INFINITY,
+                        radiance: light.irradiance,
+                        pdf_value: 1.0,
+                        is_delta: true,
+                        light: 0,
+                        normal: None,
+                    });
+                }
+
+                // 視直径の円錐内を一様にサンプリングする
+                // 一様な放射輝度Lの円盤が作る放射照度は E = Lπsin²α
+                let half = light.angular_diameter / 2.0;
+                let cos_max = half.cos();
+
+                Some(LightSample {
+                    dir: sample_cone(&to_light, cos_max),
+                    distance: f64::INFINITY,
+                    radiance: light.irradiance.scale(1.0 / (PI * half.sin() * half.sin())),
+                    pdf_value: 1.0 / (2.0 * PI * (1.0 - cos_max)),
+                    is_delta: true,
+                    light: 0,
+                    normal: None,
+                })
+            }
+        }
+    }
+
+    // 放射束, 平行光源はシーンを囲む球の半径scene_radiusで見積もる
+    pub fn power(&self, scene_radius: f64) -> f64 {
+        match self {
+            Light::Point(light) => 4.0 * PI * light.intensity.luminance(),
+            Light::Spot(light) => {
+                2.0 * PI
+                    * light.intensity.luminance()
+                    * (1.0 - 0.5 * (light.cos_falloff_start() + light.cos_falloff_end()))
+            }
+            Light::Directional(light) => {
+                PI * scene_radius * scene_radius * light.irradiance.luminance()
+            }
+        }
+    }
+
+    pub fn is_infinite(&self) -> bool {
+        matches!(self, Light::Directional(_))
+    }
+}
+
+// 光源から出る光線のサンプル(光源側からたどる経路の始点)
+#[derive(Clone, Debug)]
+pub struct EmissionSample {
+    pub ray: Ray,
+    pub normal: Option<V3U>, // 面光源の法線
+    pub radiance: Color,
+    pub pdf_pos: f64, // 面積測度(位置がデルタ分布なら1), 光源の選択確率は含まない
+    pub pdf_dir: f64, // 立体角測度(向きがデルタ分布なら1)
+}
+
+// 光を放つものへの参照
+#[derive(Clone, Copy, Debug)]
+pub enum Emitter<'a> {
+    Area(&'a Object),
+    Light(&'a Light),
+    Sky(&'a Sky),
+}
+
+impl<'a> Emitter<'a> {
+    // 位置か向きがデルタ分布で, 経路が偶然たどり着くことがない光源
+    pub fn is_delta(&self) -> bool {
+        matches!(self, Emitter::Light(_))
+    }
+
+    // 位置を持たない光源, 光線の始点はシーンを囲む球(scene_center, scene_radius)に接する円盤から取る
+    pub fn is_infinite(&self) -> bool {
+        match self {
+            Emitter::Area(_) => false,
+            Emitter::Light(light) => light.is_infinite(),
+            Emitter::Sky(_) => true,
+        }
+    }
+
+    // 光源上の点からdirの向きに放たれる放射輝度, 無限遠光源ではdirは光の進む向き
+    pub fn radiance(&self, dir: &V3U) -> Color {
+        match self {
+            Emitter::Area(object) => object.emission,
+            Emitter::Sky(sky) => sky.radiance(&dir.neg()),
+            Emitter::Light(_) => Color::black(),
+        }
+    }
+
+    pub fn sample_emission(&self, scene_center: V3, scene_radius: f64) -> Option<EmissionSample> {
+        match self {
+            Emitter::Area(object) => {
+                // 面光源は両面から完全拡散で放射する
+                let sample = object.sample();
+                let normal = if uniform_random() < 0.5 {
+                    sample.normal
+                } else {
+                    sample.normal.neg()
+                };
+                let dir = sample_cosine_hemisphere(&normal);
+
+                Some(EmissionSample {
+                    ray: Ray {
+                        origin: sample.point,
+                        dir,
+                    },
+                    normal: Some(sample.normal),
+                    radiance: object.emission,
+                    pdf_pos: sample.pdf_value,
+                    pdf_dir: dir.dot(&normal) / (2.0 * PI),
+                })
+            }
+            Emitter::Light(Light::Point(light)) => {
+                let dir = sample_uniform_sphere();
+
+                Some(EmissionSample {
+                    ray: Ray {
+                        origin: light.position,
+                        dir,
+                    },
+                    normal: None,
+                    radiance: light.intensity,
+                    pdf_pos: 1.0,
+                    pdf_dir: 1.0 / (4.0 * PI),
+                })
+            }
+            Emitter::Light(Light::Spot(light)) => {
+                let cos_max = light.cos_falloff_end();
+                let dir = sample_cone(&light.dir, cos_max);
+
+                Some(EmissionSample {
+                    ray: Ray {
+                        origin: light.position,
+                        dir,
+                    },
+                    normal: None,
+                    radiance: light.intensity.scale(light.falloff(dir.dot(&light.dir))),
+                    pdf_pos: 1.0,
+                    pdf_dir: 1.0 / (2.0 * PI * (1.0 - cos_max)),
+                })
+            }
+            Emitter::Light(Light::Directional(light)) => {
+                let to_light = light.dir.neg();
+                let (to_light, radiance, pdf_dir) = if light.angular_diameter <= 0.0 {
+                    (to_light, light.irradiance, 1.0)
+                } else {
+                    let half = light.angular_diameter / 2.0;
+                    (
+                        sample_cone(&to_light, half.cos()),
+                        light.irradiance.scale(1.0 / (PI * half.sin() * half.sin())),
+                        1.0 / (2.0 * PI * (1.0 - half.cos())),
+                    )
+                };
+
+                Some(infinite_emission(
+                    to_light,
+                    radiance,
+                    pdf_dir,
+                    scene_center,
+                    scene_radius,
+                ))
+            }
+            Emitter::Sky(sky) => {
+                let sample = sky.sample()?;
+
+                Some(infinite_emission(
+                    sample.dir,
+                    sample.radiance,
+                    sample.pdf_value,
+                    scene_center,
+                    scene_radius,
+                ))
+            }
+        }
+    }
+
+    // sample_emissionでpointからdirの向きの光線が選ばれる確率密度(pdf_pos, pdf_dir)
+    pub fn emission_pdf(&self, normal: Option<V3U>, dir: &V3U, scene_radius: f64) -> (f64, f64) {
+        match self {
+            Emitter::Area(object) => (
+                object.area_pdf(),
+                normal.map_or(0.0, |n| n.dot(dir).abs() / (2.0 * PI)),
+            ),
+            Emitter::Light(Light::Point(_)) => (0.0, 1.0 / (4.0 * PI)),
+            Emitter::Light(Light::Spot(light)) => {
+                let cos_max = light.cos_falloff_end();
+                if dir.dot(&light.dir) >= cos_max {
+                    (0.0, 1.0 / (2.0 * PI * (1.0 - cos_max)))
+                } else {
+                    (0.0, 0.0)
+                }
+            }
+            Emitter::Light(Light::Directional(_)) => {
+                (1.0 / (PI * scene_radius * scene_radius), 0.0)
+            }
+            Emitter::Sky(sky) => (
+                1.0 / (PI * scene_radius * scene_radius),
+                sky.pdf(&dir.neg()),
+            ),
+        }
+    }
+}
+
+// 無限遠光源からto_lightの逆向きに進む光線, 始点はシーンを囲む球に接する円盤上に一様に取る
+fn infinite_emission(
+    to_light: V3U,
+    radiance: Color,
+    pdf_dir: f64,
+    scene_center: V3,
+    scene_radius: f64,
+) -> EmissionSample {
+    let (u, v) = to_light.orthonormal_basis();
+    let r = uniform_random().sqrt();
+    let phi = 2.0 * PI * uniform_random();
+    let disk = u.scale(r * phi.cos()) + v.scale(r * phi.sin());
+
+    EmissionSample {
+        ray: Ray {
+            origin: scene_center + (disk + to_light.as_v3()).scale(scene_radius),
+            dir: to_light.neg(),
+        },
+        normal: None,
+        radiance,
+        pdf_pos: 1.0 / (PI * scene_radius * scene_radius),
+        pdf_dir,
+    }
+}
+
+fn sample_uniform_sphere() -> V3U {
+    let z = 1.0 - 2.0 * uniform_random();
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * uniform_random();
+
+    V3U::from_v3_unsafe(V3::new(r * phi.cos(), r * phi.sin(), z))
+}
+
+fn sample_cone(axis: &V3U, cos_max: f64) -> V3U {
+    let (u, v) = axis.orthonormal_basis();
+    let cos_theta = 1.0 - uniform_random() * (1.0 - cos_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * uniform_random();
+
+    V3U::from_v3(
+        u.scale(sin_theta * phi.cos()) + v.scale(sin_theta * phi.sin()) + axis.scale(cos_theta),
+    )
+}
+
+fn sample_cosine_hemisphere(w: &V3U) -> V3U {
+    let (u, v) = w.orthonormal_basis();
+    let r1 = 2.0 * PI * uniform_random();
+    let r2 = uniform_random();
+    let r2s = r2.sqrt();
+
+    V3U::from_v3(u.scale(r1.cos() * r2s) + v.scale(r1.sin() * r2s) + w.scale((1.0 - r2).sqrt()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spot_light_falloff() {
+        let spot = SpotLight {
+            position: V3::zero(),
+            dir: V3U::from_v3(V3::new(0.0, -1.0, 0.0)),
+            intensity: Color::new(1.0, 1.0, 1.0),
+            cone_angle: 30f64.to_radians(),
+            penumbra_angle: 10f64.to_radians(),
+        };
+
+        assert_eq!(spot.falloff(1.0), 1.0);
+        assert_eq!(spot.falloff(15f64.to_radians().cos()), 1.0);
+        assert_eq!(spot.falloff(45f64.to_radians().cos()), 0.0);
+
+        let mid = spot.falloff(25f64.to_radians().cos());
+        assert!(0.0 < mid && mid < 1.0);
+
+        let light = Light::Spot(spot);
+        assert!(light.sample(&V3::new(0.0, -10.0, 0.0)).is_some());
+        assert!(light.sample(&V3::new(10.0, -1.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn directional_light_irradiance() {
+        // 視直径を持つ平行光源でも法線方向の放射照度は irradiance に一致する
+        let light = Light::Directional(DirectionalLight {
+            dir: V3U::from_v3(V3::new(0.0, -1.0, 0.0)),
+            irradiance: Color::new(2.0, 2.0, 2.0),
+            angular_diameter: 10f64.to_radians(),
+        });
+
+        let n = 100000;
+        let irradiance = (0..n)
+            .map(|_| {
+                let s = light.sample(&V3::zero()).unwrap();
+                s.radiance.luminance() * s.dir.dot(&V3U::unit_y()) / s.pdf_value
+            })
+            .sum::<f64>()
+            / n as f64;
+
+        assert!((irradiance - 2.0).abs() < 1e-2, "{}", irradiance);
+    }
+}
@@ -0,0 +1,503 @@
+use crate::renderer::{RadianceClamp, Reflection};
+use crate::wrapper::color::Color;
+use std::str::FromStr;
+
+// 経路上の出来事, カメラから光源に向かってたどった順に並べたものにLPEを当てはめる
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PathEvent {
+    Camera,
+    // 拡散反射
+    Diffuse,
+    // 光沢反射(GlossyとPhong)
+    Glossy,
+    // 鏡面反射
+    Specular,
+    // 面を透過する屈折(表面下散乱の物体への出入りを含む)
+    Transmission,
+    // 媒質中での散乱
+    Volume,
+    // 光源(発光する面, 媒質, 環境光)
+    Light,
+}
+
+impl PathEvent {
+    const ALL: [PathEvent; 7] = [
+        PathEvent::Camera,
+        PathEvent::Diffuse,
+        PathEvent::Glossy,
+        PathEvent::Specular,
+        PathEvent::Transmission,
+        PathEvent::Volume,
+        PathEvent::Light,
+    ];
+
+    pub fn symbol(&self) -> char {
+        match self {
+            PathEvent::Camera => 'C',
+            PathEvent::Diffuse => 'D',
+            PathEvent::Glossy => 'G',
+            PathEvent::Specular => 'S',
+            PathEvent::Transmission => 'T',
+            PathEvent::Volume => 'V',
+            PathEvent::Light => 'L',
+        }
+    }
+
+    fn from_symbol(c: char) -> Option<PathEvent> {
+        PathEvent::ALL.iter().copied().find(|e| e.symbol() == c)
+    }
+
+    fn bit(&self) -> u8 {
+        1 << PathEvent::ALL.iter().position(|e| e == self).unwrap()
+    }
+
+    // 面での散乱, transmittedは面の反対側に抜けたかどうか
+    pub fn scattering(reflection: &Reflection, transmitted: bool) -> PathEvent {
+        use Reflection::*;
+
+        match reflection {
+            Diffuse => PathEvent::Diffuse,
+            Glossy(_) | Phong(_) => PathEvent::Glossy,
+            Specular => PathEvent::Specular,
+            Refraction | Dielectric(_) | Subsurface(_) | Transparent => {
+                if transmitted {
+                    PathEvent::Transmission
+                } else {
+                    PathEvent::Specular
+                }
+            }
+        }
+    }
+}
+
+// Thompsonの構成によるNFAの状態
+#[derive(Clone, Debug, PartialEq)]
+enum State {
+    // いずれかの出来事(ビットの集合)を読んでnextへ
+    Event(u8, usize),
+    Split(usize, usize),
+    Accept,
+}
+
+// 状態の集合(ビット集合)
+type StateSet = u128;
+
+// light path expression: 経路の出来事の列に対する正規表現
+// 出来事の記号, '.'(任意), [DG], [^S], (..), '|', '*', '+', '?'を使える, 空白は無視する
+// 例: 拡散面での直接光は"CDL", 間接光は"CD.+L"
+#[derive(Clone, Debug, PartialEq)]
+pub struct LightPathExpression {
+    states: Vec<State>,
+    start: usize,
+}
+
+// 構文木
+enum Node {
+    Events(u8),
+    Concat(Vec<Node>),
+    Alternation(Vec<Node>),
+    Star(Box<Node>),
+    Plus(Box<Node>),
+    Optional(Box<Node>),
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.chars.next();
+        }
+        self.chars.peek().copied()
+    }
+
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut nodes = vec![self.concat()?];
+        while self.peek() == Some('|') {
+            self.chars.next();
+            nodes.push(self.concat()?);
+        }
+
+        Ok(if nodes.len() == 1 {
+            nodes.pop().unwrap()
+        } else {
+            Node::Alternation(nodes)
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            nodes.push(self.repeat()?);
+        }
+
+        Ok(Node::Concat(nodes))
+    }
+
+    fn repeat(&mut self) -> Result<Node, String> {
+        let mut node = self.atom()?;
+        loop {
+            node = match self.peek() {
+                Some('*') => Node::Star(Box::new(node)),
+                Some('+') => Node::Plus(Box::new(node)),
+                Some('?') => Node::Optional(Box::new(node)),
+                _ => return Ok(node),
+            };
+            self.chars.next();
+        }
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.chars.next() {
+            Some('(') => {
+                let node = self.alternation()?;
+                match self.peek() {
+                    Some(')') => {
+                        self.chars.next();
+                        Ok(node)
+                    }
+                    _ => Err("missing ')'".to_string()),
+                }
+            }
+            Some('.') => Ok(Node::Events(u8::MAX)),
+            Some('[') => {
+                let negated = self.peek() == Some('^');
+                if negated {
+                    self.chars.next();
+                }
+
+                let mut mask = 0;
+                loop {
+                    match self.peek() {
+                        Some(']') => {
+                            self.chars.next();
+                            break;
+                        }
+                        Some(c) => {
+                            self.chars.next();
+                            mask |= event_bit(c)?;
+                        }
+                        None => return Err("missing ']'".to_string()),
+                    }
+                }
+
+                Ok(Node::Events(if negated { !mask } else { mask }))
+            }
+            Some(c) => event_bit(c).map(Node::Events),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+fn event_bit(c: char) -> Result<u8, String> {
+    PathEvent::from_symbol(c)
+        .map(|e| e.bit())
+        .ok_or_else(|| format!("unknown path event: {}", c))
+}
+
+impl LightPathExpression {
+    // nodeを読んでからnextに進む状態を作り, その最初の状態を返す
+    fn compile(states: &mut Vec<State>, node: &Node, next: usize) -> usize {
+        match node {
+            Node::Events(mask) => {
+                states.push(State::Event(*mask, next));
+                states.len() - 1
+            }
+            Node::Concat(nodes) => nodes.iter().rev().fold(next, |next, n| {
+                LightPathExpression::compile(states, n, next)
+            }),
+            Node::Alternation(nodes) => {
+                let starts = nodes
+                    .iter()
+                    .map(|n| LightPathExpression::compile(states, n, next))
+                    .collect::<Vec<_>>();
+                starts
+                    .into_iter()
+                    .reduce(|a, b| {
+                        states.push(State::Split(a, b));
+                        states.len() - 1
+                    })
+                    .unwrap()
+            }
+            Node::Star(inner) => {
+                // 繰り返しの入口は中身を作ってから書き換える
+                states.push(State::Accept);
+                let split = states.len() - 1;
+                let start = LightPathExpression::compile(states, inner, split);
+                states[split] = State::Split(start, next);
+                split
+            }
+            Node::Plus(inner) => {
+                states.push(State::Accept);
+                let split = states.len() - 1;
+                let start = LightPathExpression::compile(states, inner, split);
+                states[split] = State::Split(start, next);
+                start
+            }
+            Node::Optional(inner) => {
+                let start = LightPathExpression::compile(states, inner, next);
+                states.push(State::Split(start, next));
+                states.len() - 1
+            }
+        }
+    }
+
+    fn closure(&self, set: StateSet, state: usize) -> StateSet {
+        if set & (1 << state) != 0 {
+            return set;
+        }
+
+        let set = set | (1 << state);
+        match self.states[state] {
+            State::Split(a, b) => self.closure(self.closure(set, a), b),
+            _ => set,
+        }
+    }
+
+    fn initial(&self) -> StateSet {
+        self.closure(0, self.start)
+    }
+
+    fn advance(&self, set: StateSet, event: PathEvent) -> StateSet {
+        let mut next = 0;
+        for (i, state) in self.states.iter().enumerate() {
+            if set & (1 << i) == 0 {
+                continue;
+            }
+            if let State::Event(mask, to) = state {
+                if mask & event.bit() != 0 {
+                    next = self.closure(next, *to);
+                }
+            }
+        }
+
+        next
+    }
+
+    fn accepts(&self, set: StateSet) -> bool {
+        self.states
+            .iter()
+            .enumerate()
+            .any(|(i, s)| *s == State::Accept && set & (1 << i) != 0)
+    }
+}
+
+impl FromStr for LightPathExpression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            chars: s.chars().peekable(),
+        };
+        let node = parser.alternation()?;
+        if parser.peek().is_some() {
+            return Err(format!("unexpected ')' in {}", s));
+        }
+
+        let mut states = vec![State::Accept];
+        let start = LightPathExpression::compile(&mut states, &node, 0);
+        if states.len() > StateSet::BITS as usize {
+            return Err(format!("expression too long: {}", s));
+        }
+
+        Ok(LightPathExpression { states, start })
+    }
+}
+
+// LPEに当てはまる経路の寄与を集めた, 名前の付いた出力
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderPass {
+    pub name: String,
+    pub expression: LightPathExpression,
+}
+
+impl RenderPass {
+    pub fn new(name: &str, expression: &str) -> Result<Self, String> {
+        Ok(RenderPass {
+            name: name.to_string(),
+            expression: expression.parse()?,
+        })
+    }
+
+    // 直接光と間接光, および合成用のパス(最後の6つの和は最終的な画像になる)
+    pub fn defaults() -> Vec<RenderPass> {
+        [
+            ("direct", "C.?L"),
+            ("indirect", "C..+L"),
+            ("diffuse_direct", "CDL"),
+            ("diffuse_indirect", "CD.+L"),
+            ("specular", "C[GS].*L"),
+            ("transmission", "CT.*L"),
+            ("volume", "CV.*L"),
+            ("emission", "CL"),
+        ]
+        .iter()
+        .map(|(name, e)| RenderPass::new(name, e).unwrap())
+        .collect()
+    }
+
+    // "default"または"name=expression,..."
+    pub fn parse_list(s: &str) -> Result<Vec<RenderPass>, String> {
+        if s == "default" {
+            return Ok(RenderPass::defaults());
+        }
+
+        s.split(',')
+            .filter(|p| !p.trim().is_empty())
+            .map(|p| match p.split_once('=') {
+                Some((name, e)) => RenderPass::new(name.trim(), e),
+                None => Err(format!("expected name=expression: {}", p)),
+            })
+            .collect()
+    }
+}
+
+// 経路をたどりながら, 寄与を全体とパスごとに振り分けて集める
+#[derive(Clone, Debug)]
+pub struct LightPaths<'a> {
+    passes: &'a [RenderPass],
+    // ここまでの出来事の列に対する各パスのNFAの状態
+    states: Vec<StateSet>,
+    // ここまでに散乱した回数, 寄与が直接光(散乱が1回以下)かどうかの判断に使う
+    scatters: usize,
+    clamp: RadianceClamp,
+    pub total: Color,
+    pub values: Vec<Color>,
+}
+
+impl<'a> LightPaths<'a> {
+    pub fn new(passes: &'a [RenderPass], clamp: RadianceClamp) -> Self {
+        LightPaths {
+            passes,
+            states: passes
+                .iter()
+                .map(|p| {
+                    let e = &p.expression;
+                    e.advance(e.initial(), PathEvent::Camera)
+                })
+                .collect(),
+            scatters: 0,
+            clamp,
+            total: Color::black(),
+            values: vec![Color::black(); passes.len()],
+        }
+    }
+
+    pub fn scatter(&mut self, event: PathEvent) {
+        self.scatters += 1;
+        for (state, pass) in self.states.iter_mut().zip(self.passes) {
+            *state = pass.expression.advance(*state, event);
+        }
+    }
+
+    // 光源に届いた経路の寄与
+    pub fn add(&mut self, c: Color) {
+        self.add_events(&[PathEvent::Light], c);
+    }
+
+    // eventで散乱して光源に届いた経路(NEE)の寄与
+    pub fn add_at(&mut self, event: PathEvent, c: Color) {
+        self.add_events(&[event, PathEvent::Light], c);
+    }
+
+    fn add_events(&mut self, events: &[PathEvent], c: Color) {
+        let c = self.clamp.apply(c, self.scatters + events.len() - 1 <= 1);
+        self.total += c;
+        for (i, pass) in self.passes.iter().enumerate() {
+            let e = &pass.expression;
+            if e.accepts(
+                events
+                    .iter()
+                    .fold(self.states[i], |s, ev| e.advance(s, *ev)),
+            ) {
+                self.values[i] += c;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(lpe: &LightPathExpression, events: &[PathEvent]) -> bool {
+        let set = events
+            .iter()
+            .fold(lpe.initial(), |set, e| lpe.advance(set, *e));
+        lpe.accepts(set)
+    }
+
+    fn events(s: &str) -> Vec<PathEvent> {
+        s.chars()
+            .map(|c| PathEvent::from_symbol(c).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn expressions_match_paths() {
+        let cases = [
+            ("CDL", "CDL", true),
+            ("CDL", "CDDL", false),
+            ("CD.+L", "CDL", false),
+            ("CD.+L", "CDSTL", true),
+            ("C[GS].*L", "CGL", true),
+            ("C[GS].*L", "CSDDL", true),
+            ("C[GS].*L", "CDSL", false),
+            ("C[^D]L", "CTL", true),
+            ("C[^D]L", "CDL", false),
+            ("C (D|S)+ L", "CDSDL", true),
+            ("C (D|S)+ L", "CL", false),
+            ("C.?L", "CL", true),
+            ("C.?L", "CVL", true),
+            ("C.?L", "CVVL", false),
+            ("C(DS)*L", "CDSDSL", true),
+            ("C(DS)*L", "CDSDL", false),
+        ];
+        for (e, path, expected) in cases.iter() {
+            let lpe = e.parse::<LightPathExpression>().unwrap();
+            assert_eq!(matches(&lpe, &events(path)), *expected, "{} {}", e, path);
+        }
+
+        assert!("C(DL".parse::<LightPathExpression>().is_err());
+        assert!("CXL".parse::<LightPathExpression>().is_err());
+        assert!("CD)L".parse::<LightPathExpression>().is_err());
+    }
+
+    #[test]
+    fn light_paths_route_contributions() {
+        let passes = RenderPass::parse_list("diffuse=CD.*L, emission=CL").unwrap();
+        let mut paths = LightPaths::new(&passes, RadianceClamp::default());
+        paths.add(Color::new(1.0, 0.0, 0.0));
+        paths.add_at(PathEvent::Diffuse, Color::new(0.0, 1.0, 0.0));
+        paths.scatter(PathEvent::Specular);
+        paths.add_at(PathEvent::Diffuse, Color::new(0.0, 0.0, 1.0));
+
+        assert_eq!(paths.total, Color::new(1.0, 1.0, 1.0));
+        assert_eq!(paths.values[0], Color::new(0.0, 1.0, 0.0));
+        assert_eq!(paths.values[1], Color::new(1.0, 0.0, 0.0));
+        assert!(RenderPass::parse_list("nope").is_err());
+    }
+
+    #[test]
+    fn clamp_depends_on_scatters() {
+        let clamp = RadianceClamp {
+            direct: 2.0,
+            indirect: 1.0,
+        };
+        let mut paths = LightPaths::new(&[], clamp);
+        let c = Color::new(4.0, 4.0, 4.0);
+        paths.add(c);
+        paths.add_at(PathEvent::Diffuse, c);
+        assert_eq!(paths.total, Color::new(4.0, 4.0, 4.0));
+
+        paths.scatter(PathEvent::Diffuse);
+        paths.add(c);
+        paths.add_at(PathEvent::Diffuse, c);
+        assert_eq!(paths.total, Color::new(7.0, 7.0, 7.0));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::renderer::uniform_random;
+use crate::wrapper::vec::{V3, V3U};
+use std::str::FromStr;
+
+mod alias_table;
+mod light_bvh;
+
+pub use alias_table::*;
+pub use light_bvh::*;
+
+// 光源数がこれ以上の場合はデフォルトでBVHを使う
+const LIGHT_BVH_THRESHOLD: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LightSelection {
+    Uniform,
+    Power, // 放射束に比例
+    Bvh,   // シェーディング点での寄与の見積もりに比例
+}
+
+impl LightSelection {
+    pub fn default_for(light_count: usize) -> Self {
+        if light_count >= LIGHT_BVH_THRESHOLD {
+            LightSelection::Bvh
+        } else {
+            LightSelection::Power
+        }
+    }
+}
+
+impl FromStr for LightSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(LightSelection::Uniform),
+            "power" => Ok(LightSelection::Power),
+            "bvh" => Ok(LightSelection::Bvh),
+            _ => Err(format!("unknown light selection: {}", s)),
+        }
+    }
+}
+
+// 光源選択に使う各光源の情報
+#[derive(Clone, Debug)]
+pub struct LightEntry {
+    pub power: f64,
+    pub bounds: Option<LightBounds>, // 無限遠光源はNone
+}
+
+#[derive(Clone, Debug)]
+pub enum LightSampler {
+    Uniform(usize),
+    Power(AliasTable),
+    Bvh {
+        bvh: LightBvh,
+        // BVHに入らない無限遠光源は別に一様に選ぶ
+        infinite: Vec<usize>,
+    },
+}
+
+impl LightSampler {
+    // lightsのi番目の光源がi番目として選ばれる
+    pub fn new(selection: LightSelection, lights: &[LightEntry]) -> Self {
+        match selection {
+            LightSelection::Uniform => LightSampler::Uniform(lights.len()),
+            LightSelection::Power => LightSampler::Power(AliasTable::new(
+                &lights.iter().map(|l| l.power).collect::<Vec<_>>(),
+            )),
+            LightSelection::Bvh => LightSampler::Bvh {
+                bvh: LightBvh::new(
+                    lights.len(),
+                    lights
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(i, l)| l.bounds.clone().map(|b| (i, b)))
+                        .collect(),
+                ),
+                infinite: lights
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, l)| l.bounds.is_none())
+                    .map(|(i, _)| i)
+                    .collect(),
+            },
+        }
+    }
+
+    // returns index, probability
+    pub fn sample(&self, point: &V3, normal: Option<V3U>) -> Option<(usize, f64)> {
+        match self {
+            LightSampler::Uniform(0) => None,
+            LightSampler::Uniform(n) => Some((uniform_index(*n), 1.0 / *n as f64)),
+            LightSampler::Power(table) if table.is_empty() => None,
+            LightSampler::Power(table) => Some(table.sample()),
+            LightSampler::Bvh { bvh, infinite } => {
+                let p_infinite = Self::infinite_probability(bvh, infinite);
+                if uniform_random() < p_infinite {
+                    let i = uniform_index(infinite.len());
+                    Some((infinite[i], p_infinite / infinite.len() as f64))
+                } else {
+                    bvh.sample(point, normal)
+                        .map(|(i, pmf)| (i, pmf * (1.0 - p_infinite)))
+                }
+            }
+        }
+    }
+
+    pub fn pmf(&self, point: &V3, normal: Option<V3U>, i: usize) -> f64 {
+        match self {
+            LightSampler::Uniform(n) => 1.0 / *n as f64,
+            LightSampler::Power(table) => table.pmf(i),
+            LightSampler::Bvh { bvh, infinite } => {
+                let p_infinite = Self::infinite_probability(bvh, infinite);
+                if infinite.contains(&i) {
+                    p_infinite / infinite.len() as f64
+                } else {
+                    bvh.pmf(point, normal, i) * (1.0 - p_infinite)
+                }
+            }
+        }
+    }
+
+    // BVH全体を一つの光源とみなして無限遠光源と等確率で選ぶ
+    fn infinite_probability(bvh: &LightBvh, infinite: &[usize]) -> f64 {
+        let finite = if bvh.is_empty() { 0 } else { 1 };
+        if infinite.is_empty() {
+            0.0
+        } else {
+            infinite.len() as f64 / (infinite.len() + finite) as f64
+        }
+    }
+}
+
+fn uniform_index(n: usize) -> usize {
+    ((uniform_random() * n as f64) as usize).min(n - 1)
+}
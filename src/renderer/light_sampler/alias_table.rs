@@ -0,0 +1,133 @@
+use crate::renderer::uniform_random;
+
+// Walker's alias method (Vose's algorithm)
+// 重みに比例した離散分布から O(1) でサンプリングする
+#[derive(Clone, Debug)]
+pub struct AliasTable {
+    bins: Vec<AliasBin>,
+}
+
+#[derive(Clone, Debug)]
+struct AliasBin {
+    pmf: f64,
+    threshold: f64,
+    alias: usize,
+}
+
+impl AliasTable {
+    pub fn new(weights: &[f64]) -> Self {
+        let n = weights.len();
+        let total = weights.iter().sum::<f64>();
+
+        // 重みが全て0の場合は一様分布とする
+        let pmfs = if total > 0.0 {
+            weights.iter().map(|w| w / total).collect::<Vec<_>>()
+        } else {
+            vec![1.0 / n as f64; n]
+        };
+
+        let mut bins = pmfs
+            .iter()
+            .map(|&pmf| AliasBin {
+                pmf,
+                threshold: 1.0,
+                alias: 0,
+            })
+            .collect::<Vec<_>>();
+
+        let mut scaled = pmfs.iter().map(|p| p * n as f64).collect::<Vec<_>>();
+        let mut under = Vec::new();
+        let mut over = Vec::new();
+        for (i, &p) in scaled.iter().enumerate() {
+            if p < 1.0 {
+                under.push(i);
+            } else {
+                over.push(i);
+            }
+        }
+
+        while let (Some(u), Some(o)) = (under.pop(), over.pop()) {
+            bins[u].threshold = scaled[u];
+            bins[u].alias = o;
+
+            scaled[o] -= 1.0 - scaled[u];
+            if scaled[o] < 1.0 {
+                under.push(o);
+            } else {
+                over.push(o);
+            }
+        }
+
+        // 丸め誤差で残ったものは確率1で自分自身を返す
+        for i in under.into_iter().chain(over) {
+            bins[i].threshold = 1.0;
+            bins[i].alias = i;
+        }
+
+        AliasTable { bins }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bins.is_empty()
+    }
+
+    // returns index, probability
+    pub fn sample(&self) -> (usize, f64) {
+        let u = uniform_random() * self.bins.len() as f64;
+        let i = (u as usize).min(self.bins.len() - 1);
+        let up = u - i as f64;
+
+        let j = if up < self.bins[i].threshold {
+            i
+        } else {
+            self.bins[i].alias
+        };
+
+        (j, self.bins[j].pmf)
+    }
+
+    pub fn pmf(&self, i: usize) -> f64 {
+        self.bins[i].pmf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alias_table_frequency() {
+        let weights = [9000.0, 0.0, 1.0, 3.0, 250.0, 250.0];
+        let table = AliasTable::new(&weights);
+        let total = weights.iter().sum::<f64>();
+
+        let n = 1000000;
+        let mut counts = vec![0; weights.len()];
+        for _ in 0..n {
+            let (i, pmf) = table.sample();
+            assert_eq!(pmf, table.pmf(i));
+            counts[i] += 1;
+        }
+
+        for (i, w) in weights.iter().enumerate() {
+            let expected = w / total;
+            assert!((table.pmf(i) - expected).abs() < 1e-12);
+
+            let freq = counts[i] as f64 / n as f64;
+            assert!(
+                (freq - expected).abs() < 5.0 * (expected / n as f64).sqrt() + 1e-6,
+                "{}: {} vs {}",
+                i,
+                freq,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn alias_table_zero_weights() {
+        let table = AliasTable::new(&[0.0, 0.0]);
+        assert_eq!(table.pmf(0), 0.5);
+        assert_eq!(table.pmf(1), 0.5);
+    }
+}
@@ -0,0 +1,470 @@
+use crate::renderer::{uniform_random, Figure, Light, Object};
+use crate::wrapper::{
+    aabb::Aabb,
+    vec::{V3, V3U},
+};
+use std::f64::consts::PI;
+
+// 光源の空間的な広がりと放射方向の範囲
+// 放射方向は axis を中心とする角度 θo の円錐 + そこから θe までの広がりで表す
+#[derive(Clone, Debug)]
+pub struct LightBounds {
+    pub bounds: Aabb,
+    pub phi: f64,
+    pub axis: V3U,
+    pub theta_o: f64,
+    pub theta_e: f64,
+    pub two_sided: bool,
+}
+
+impl LightBounds {
+    pub fn from_object(object: &Object) -> Self {
+        let (axis, theta_o, two_sided) = match &object.figure {
+            // 面光源は両面から放射する
+            Figure::Rhombus(r) => (V3U::from_v3(r.a.cross(r.b)), 0.0, true),
+            _ => (V3U::unit_z(), PI, false),
+        };
+
+        LightBounds {
+            bounds: object.figure.bounds(),
+            phi: object.power(),
+            axis,
+            theta_o,
+            theta_e: PI / 2.0,
+            two_sided,
+        }
+    }
+
+    // 無限遠光源はNone
+    pub fn from_light(light: &Light) -> Option<Self> {
+        match light {
+            Light::Point(point) => Some(LightBounds {
+                bounds: Aabb::new(point.position, point.position),
+                phi: light.power(0.0),
+                axis: V3U::unit_z(),
+                theta_o: PI,
+                theta_e: PI / 2.0,
+                two_sided: false,
+            }),
+            Light::Spot(spot) => Some(LightBounds {
+                bounds: Aabb::new(spot.position, spot.position),
+                phi: light.power(0.0),
+                axis: spot.dir,
+                theta_o: spot.cos_falloff_start().acos(),
+                theta_e: spot.cos_falloff_end().acos() - spot.cos_falloff_start().acos(),
+                two_sided: false,
+            }),
+            Light::Directional(_) => None,
+        }
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        let (axis, theta_o) = cone_union((self.axis, self.theta_o), (other.axis, other.theta_o));
+
+        LightBounds {
+            bounds: self.bounds.union(other.bounds),
+            phi: self.phi + other.phi,
+            axis,
+            theta_o,
+            theta_e: self.theta_e.max(other.theta_e),
+            two_sided: self.two_sided || other.two_sided,
+        }
+    }
+
+    // pointから見たこのクラスタの寄与の見積もり(上界に近いもの)
+    // normalが与えられた場合はシェーディング点のcos項も考慮する
+    pub fn importance(&self, point: &V3, normal: Option<V3U>) -> f64 {
+        if self.phi <= 0.0 {
+            return 0.0;
+        }
+
+        let center = self.bounds.centroid();
+        let d = *point - center;
+        let dist2 = d
+            .len_square()
+            .max((self.bounds.diagonal().len() / 2.0).powi(2));
+        let wi = if d.len_square() > 0.0 {
+            V3U::from_v3(d)
+        } else {
+            self.axis
+        };
+
+        let mut cos_w = self.axis.dot(&wi);
+        if self.two_sided {
+            cos_w = cos_w.abs();
+        }
+        let theta_w = cos_w.clamp(-1.0, 1.0).acos();
+
+        // クラスタを囲む球が見込む角度
+        let (sphere_center, radius) = self.bounds.bounding_sphere();
+        let sphere_dist2 = (*point - sphere_center).len_square();
+        let theta_b = if sphere_dist2 <= radius * radius {
+            PI
+        } else {
+            (radius * radius / sphere_dist2).sqrt().min(1.0).asin()
+        };
+
+        let theta_x = (theta_w - self.theta_o - theta_b).max(0.0);
+        if theta_x >= self.theta_e {
+            return 0.0;
+        }
+
+        let mut importance = self.phi * theta_x.cos() / dist2;
+
+        if let Some(n) = normal {
+            let theta_i = n.dot(&wi).abs().min(1.0).acos();
+            importance *= (theta_i - theta_b).max(0.0).cos();
+        }
+
+        importance.max(0.0)
+    }
+}
+
+// 二つの方向円錐を含む最小の円錐
+fn cone_union(a: (V3U, f64), b: (V3U, f64)) -> (V3U, f64) {
+    let (wa, theta_a) = a;
+    let (wb, theta_b) = b;
+    let theta_d = wa.dot(&wb).clamp(-1.0, 1.0).acos();
+
+    if (theta_d + theta_b).min(PI) <= theta_a {
+        return a;
+    }
+    if (theta_d + theta_a).min(PI) <= theta_b {
+        return b;
+    }
+
+    let theta_o = (theta_a + theta_d + theta_b) / 2.0;
+    if theta_o >= PI {
+        return (wa, PI);
+    }
+
+    // waをwa×wb軸周りにθrだけ回転させたものが新しい軸
+    let theta_r = theta_o - theta_a;
+    let wr = wa.as_v3().cross(wb.as_v3());
+    if wr.len_square() == 0.0 {
+        return (wa, PI);
+    }
+    let k = wr.normalize();
+    let v = wa.as_v3();
+    let rotated = v.scale(theta_r.cos())
+        + k.cross(v).scale(theta_r.sin())
+        + k.scale(k.dot(&v) * (1.0 - theta_r.cos()));
+
+    (V3U::from_v3(rotated), theta_o)
+}
+
+#[derive(Clone, Debug)]
+enum LightBvhNode {
+    Leaf {
+        bounds: LightBounds,
+        light: usize,
+    },
+    Interior {
+        bounds: LightBounds,
+        children: [usize; 2],
+    },
+}
+
+impl LightBvhNode {
+    fn bounds(&self) -> &LightBounds {
+        match self {
+            LightBvhNode::Leaf { bounds, .. } => bounds,
+            LightBvhNode::Interior { bounds, .. } => bounds,
+        }
+    }
+}
+
+// 多数の光源からシェーディング点での寄与の見積もりに比例して一つを選ぶためのBVH
+#[derive(Clone, Debug)]
+pub struct LightBvh {
+    nodes: Vec<LightBvhNode>,
+    // 各光源の葉へ至る経路(根から順に下位ビットから, 1なら右の子), BVHに含まれない光源はNone
+    trails: Vec<Option<u64>>,
+}
+
+impl LightBvh {
+    // lightsは(光源の番号, その範囲)の組, 番号はlight_count未満
+    pub fn new(light_count: usize, lights: Vec<(usize, LightBounds)>) -> Self {
+        let mut bvh = LightBvh {
+            nodes: Vec::with_capacity(lights.len() * 2),
+            trails: vec![None; light_count],
+        };
+
+        if !lights.is_empty() {
+            let mut lights = lights;
+            bvh.build(&mut lights, 0, 0);
+        }
+
+        bvh
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    fn build(&mut self, lights: &mut [(usize, LightBounds)], trail: u64, depth: u32) -> usize {
+        if lights.len() == 1 {
+            let (light, bounds) = lights[0].clone();
+            self.trails[light] = Some(trail);
+            self.nodes.push(LightBvhNode::Leaf { bounds, light });
+            return self.nodes.len() - 1;
+        }
+
+        // 重心の広がりが最大の軸について中央値で分割する
+        let centroids = lights
+            .iter()
+            .map(|(_, b)| b.bounds.centroid())
+            .collect::<Vec<_>>();
+        let axis = Aabb::from_points(&centroids).longest_axis();
+        lights.sort_by(|(_, a), (_, b)| {
+            a.bounds
+                .centroid()
+                .axis(axis)
+                .partial_cmp(&b.bounds.centroid().axis(axis))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        // 経路は64bitに収める
+        assert!(depth < 64, "light BVH is too deep");
+
+        let index = self.nodes.len();
+        self.nodes.push(LightBvhNode::Leaf {
+            bounds: lights[0].1.clone(),
+            light: 0,
+        });
+
+        let mid = lights.len() / 2;
+        let (left, right) = lights.split_at_mut(mid);
+        let l = self.build(left, trail, depth + 1);
+        let r = self.build(right, trail | (1 << depth), depth + 1);
+
+        self.nodes[index] = LightBvhNode::Interior {
+            bounds: self.nodes[l].bounds().union(self.nodes[r].bounds()),
+            children: [l, r],
+        };
+
+        index
+    }
+
+    // returns index, probability
+    pub fn sample(&self, point: &V3, normal: Option<V3U>) -> Option<(usize, f64)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let mut node = 0;
+        let mut pmf = 1.0;
+        loop {
+            match &self.nodes[node] {
+                LightBvhNode::Leaf { bounds, light } => {
+                    // 根が葉の場合だけはここで寄与を確認する
+                    if node == 0 && bounds.importance(point, normal) == 0.0 {
+                        return None;
+                    }
+
+                    return Some((*light, pmf));
+                }
+                LightBvhNode::Interior { children, .. } => {
+                    let ci = [
+                        self.nodes[children[0]].bounds().importance(point, normal),
+                        self.nodes[children[1]].bounds().importance(point, normal),
+                    ];
+                    if ci[0] == 0.0 && ci[1] == 0.0 {
+                        return None;
+                    }
+
+                    let p0 = ci[0] / (ci[0] + ci[1]);
+                    if uniform_random() < p0 {
+                        node = children[0];
+                        pmf *= p0;
+                    } else {
+                        node = children[1];
+                        pmf *= 1.0 - p0;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn pmf(&self, point: &V3, normal: Option<V3U>, light: usize) -> f64 {
+        let mut trail = match self.trails[light] {
+            Some(trail) => trail,
+            None => return 0.0,
+        };
+        let mut node = 0;
+        let mut pmf = 1.0;
+
+        loop {
+            match &self.nodes[node] {
+                LightBvhNode::Leaf { bounds, .. } => {
+                    if node == 0 && bounds.importance(point, normal) == 0.0 {
+                        return 0.0;
+                    }
+
+                    return pmf;
+                }
+                LightBvhNode::Interior { children, .. } => {
+                    let ci = [
+                        self.nodes[children[0]].bounds().importance(point, normal),
+                        self.nodes[children[1]].bounds().importance(point, normal),
+                    ];
+                    let next = (trail & 1) as usize;
+                    if ci[next] == 0.0 {
+                        return 0.0;
+                    }
+
+                    pmf *= ci[next] / (ci[0] + ci[1]);
+                    node = children[next];
+                    trail >>= 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::{PointLight, Rhombus, Sphere};
+    use crate::wrapper::color::Color;
+
+    fn light_grid() -> Vec<Object> {
+        let mut objects = Vec::new();
+        for i in 0..10 {
+            for j in 0..10 {
+                objects.push(Object {
+                    figure: if (i + j) % 2 == 0 {
+                        Figure::Sphere(Sphere {
+                            center: V3::new(i as f64 * 10.0, 50.0, j as f64 * 10.0),
+                            radius: 1.0 + (i as f64) * 0.2,
+                        })
+                    } else {
+                        Figure::Rhombus(Rhombus {
+                            origin: V3::new(i as f64 * 10.0, 40.0, j as f64 * 10.0),
+                            a: V3::new(3.0, 0.0, 0.0),
+                            b: V3::new(0.0, 1.0, 2.0),
+                        })
+                    },
+                    emission: Color::new(1.0 + j as f64, 1.0, 1.0),
+                    ..Default::default()
+                });
+            }
+        }
+
+        objects
+    }
+
+    #[test]
+    fn light_bvh_pmf_sums_to_one() {
+        let objects = light_grid();
+        let bvh = LightBvh::new(
+            objects.len(),
+            objects
+                .iter()
+                .map(LightBounds::from_object)
+                .enumerate()
+                .collect(),
+        );
+
+        for (point, normal) in &[
+            (V3::new(45.0, 0.0, 45.0), Some(V3U::unit_y())),
+            (V3::new(-30.0, 45.0, 10.0), None),
+            (V3::new(20.0, 45.0, 20.0), Some(V3U::unit_x())),
+        ] {
+            let total = (0..objects.len())
+                .map(|i| bvh.pmf(point, *normal, i))
+                .sum::<f64>();
+            assert!((total - 1.0).abs() < 1e-9, "{:?}: {}", point, total);
+        }
+    }
+
+    #[test]
+    fn light_bvh_sample_matches_pmf() {
+        let objects = light_grid();
+        let bvh = LightBvh::new(
+            objects.len(),
+            objects
+                .iter()
+                .map(LightBounds::from_object)
+                .enumerate()
+                .collect(),
+        );
+        let point = V3::new(12.0, 10.0, 70.0);
+        let normal = Some(V3U::unit_y());
+
+        let n = 200000;
+        let mut counts = vec![0; objects.len()];
+        for _ in 0..n {
+            let (i, pmf) = bvh.sample(&point, normal).unwrap();
+            assert!((pmf - bvh.pmf(&point, normal, i)).abs() < 1e-12);
+            counts[i] += 1;
+        }
+
+        for (i, count) in counts.iter().enumerate() {
+            let expected = bvh.pmf(&point, normal, i);
+            let freq = *count as f64 / n as f64;
+            assert!(
+                (freq - expected).abs() < 5.0 * (expected / n as f64).sqrt() + 1e-4,
+                "{}: {} vs {}",
+                i,
+                freq,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn light_bvh_follows_irradiance_at_any_scale() {
+        // 小さな塊と大きな塊の点光源, 塊ごとの大きさと距離の比は同じにする
+        let offsets = [
+            V3::new(0.0, 0.0, 0.0),
+            V3::new(1.0, 0.0, 0.0),
+            V3::new(0.0, 1.0, 0.0),
+            V3::new(1.0, 1.0, 0.5),
+        ];
+        let intensities = [1.0, 2.0, 4.0, 8.0];
+        for scale in [0.01, 1.0, 100.0] {
+            let lights = (offsets.iter().zip(&intensities))
+                .map(|(o, i)| {
+                    Light::Point(PointLight {
+                        position: o.scale(scale),
+                        intensity: Color::new(*i, *i, *i),
+                    })
+                })
+                .collect::<Vec<_>>();
+            let bvh = LightBvh::new(
+                lights.len(),
+                lights
+                    .iter()
+                    .map(|l| LightBounds::from_light(l).unwrap())
+                    .enumerate()
+                    .collect(),
+            );
+            let point = V3::new(3.0, 0.5, 0.2).scale(scale);
+
+            // 点光源の放射照度 I / r^2
+            let irradiance = (offsets.iter().zip(&intensities))
+                .map(|(o, i)| i / (point - o.scale(scale)).len_square())
+                .collect::<Vec<_>>();
+            let total = irradiance.iter().sum::<f64>();
+
+            let n = 100000;
+            let mut counts = vec![0; lights.len()];
+            for _ in 0..n {
+                counts[bvh.sample(&point, None).unwrap().0] += 1;
+            }
+            // BVHの見積もりは近似なので, 正確な割合から15%までのずれは許す
+            for (count, e) in counts.iter().zip(&irradiance) {
+                let freq = *count as f64 / n as f64;
+                let expected = e / total;
+                assert!(
+                    (freq - expected).abs() < 0.15 * expected + 5.0 * (expected / n as f64).sqrt(),
+                    "scale {}: {} vs {}",
+                    scale,
+                    freq,
+                    expected
+                );
+            }
+        }
+    }
+}
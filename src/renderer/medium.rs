@@ -0,0 +1,467 @@
+use crate::renderer::{uniform_random, Object};
+use crate::wrapper::{
+    color::Color,
+    ray::Ray,
+    vec::{V3, V3U},
+};
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+mod grid;
+pub use grid::*;
+
+// 関与媒質
+// gridがあれば係数と放射に各点のボクセルの値を掛ける(なければ一様)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Medium {
+    pub sigma_a: Color,  // 吸収係数
+    pub sigma_s: Color,  // 散乱係数
+    pub g: f64,          // Henyey-Greensteinの位相関数の非対称パラメータ(-1, 1)
+    pub emission: Color, // 吸収係数に比例して放射する放射輝度(炎など)
+    pub grid: Option<Arc<VolumeGrid>>,
+}
+
+// 距離サンプリングの結果
+#[derive(Clone, Debug)]
+pub enum MediumEvent {
+    // 媒質中で散乱した
+    Scatter { position: V3, weight: Color },
+    // 区間の終わり(面)まで散乱しなかった
+    Pass { weight: Color },
+    // 媒質に吸収された
+    Absorb,
+}
+
+impl Medium {
+    pub fn sigma_t(&self) -> Color {
+        self.sigma_a + self.sigma_s
+    }
+
+    // レイの[t0, t1]の区間の透過率, 不均質な場合はratio trackingによる推定値
+    pub fn transmittance(&self, ray: &Ray, t0: f64, t1: f64) -> Color {
+        match &self.grid {
+            None => self.homogeneous_transmittance(t1 - t0),
+            Some(grid) => {
+                let majorant = self.majorant(grid);
+                let mut tr = Color::new(1.0, 1.0, 1.0);
+                if majorant <= 0.0 {
+                    return tr;
+                }
+
+                let mut t = t0;
+                loop {
+                    t -= (1.0 - uniform_random()).ln() / majorant;
+                    if t >= t1 || tr <= Color::black() {
+                        return tr;
+                    }
+
+                    let d = grid.density(&ray.extend_at(t));
+                    tr = tr.blend(self.sigma_t().map(|s| 1.0 - s * d / majorant));
+                }
+            }
+        }
+    }
+
+    fn homogeneous_transmittance(&self, length: f64) -> Color {
+        if length <= 0.0 {
+            return Color::new(1.0, 1.0, 1.0);
+        }
+
+        self.sigma_t()
+            .map(|s| if s > 0.0 { (-s * length).exp() } else { 1.0 })
+    }
+
+    // 密度が最大のボクセルでの消散係数の最大のチャンネル
+    fn majorant(&self, grid: &VolumeGrid) -> f64 {
+        let sigma_t = self.sigma_t();
+        (0..3).map(|i| sigma_t.channel(i)).fold(0.0, f64::max) * grid.max_density()
+    }
+
+    // レイの[t0, t1]の区間を通り抜ける間に散乱する位置をサンプリングする
+    // throughputはここまでの経路の重みで, 重みの大きいチャンネルほどその係数に従って距離を選ぶ
+    // 区間内の媒質が放つ放射輝度の推定値(イベントの重みを掛ける前の経路の重みに対するもの)も返す
+    pub fn sample(&self, ray: &Ray, t0: f64, t1: f64, throughput: Color) -> (MediumEvent, Color) {
+        match &self.grid {
+            None => self.sample_homogeneous(ray, t0, t1, throughput),
+            Some(grid) => self.sample_grid(grid, ray, t0, t1, throughput),
+        }
+    }
+
+    // 消散係数の色ごとの違いは, チャンネルを選んでその係数で指数分布に従う距離を選び
+    // pdfはチャンネルを選ぶ確率で重み付けした平均とする(one-sample MIS)
+    fn sample_homogeneous(
+        &self,
+        ray: &Ray,
+        t0: f64,
+        t1: f64,
+        throughput: Color,
+    ) -> (MediumEvent, Color) {
+        let sigma_t = self.sigma_t();
+        let w = channel_probabilities(throughput);
+        let u = uniform_random();
+        let channel = if u < w.channel(0) {
+            0
+        } else if u < w.channel(0) + w.channel(1) {
+            1
+        } else {
+            2
+        };
+        let s = sigma_t.channel(channel);
+
+        let distance = if s > 0.0 {
+            -(1.0 - uniform_random()).ln() / s
+        } else {
+            f64::INFINITY
+        };
+        let length = t1 - t0;
+        let weighted_sum = |c: Color| (0..3).map(|i| w.channel(i) * c.channel(i)).sum::<f64>();
+
+        if distance < length {
+            let tr = self.homogeneous_transmittance(distance);
+            let pdf = weighted_sum(sigma_t.blend(tr));
+
+            (
+                MediumEvent::Scatter {
+                    position: ray.extend_at(t0 + distance),
+                    weight: tr.blend(self.sigma_s).scale(1.0 / pdf),
+                },
+                tr.blend(self.sigma_a).blend(self.emission).scale(1.0 / pdf),
+            )
+        } else {
+            let tr = self.homogeneous_transmittance(length);
+            let pdf = weighted_sum(tr);
+
+            (
+                MediumEvent::Pass {
+                    weight: if pdf > 0.0 {
+                        tr.scale(1.0 / pdf)
+                    } else {
+                        Color::black()
+                    },
+                },
+                Color::black(),
+            )
+        }
+    }
+
+    // majorantによるdelta tracking
+    // 衝突点では吸収, 散乱, 空衝突を経路の重みで平均した係数に比例して選ぶ(spectral tracking)
+    fn sample_grid(
+        &self,
+        grid: &VolumeGrid,
+        ray: &Ray,
+        t0: f64,
+        t1: f64,
+        throughput: Color,
+    ) -> (MediumEvent, Color) {
+        let majorant = self.majorant(grid);
+        let mut weight = Color::new(1.0, 1.0, 1.0);
+        let mut emitted = Color::black();
+        if majorant <= 0.0 {
+            return (MediumEvent::Pass { weight }, emitted);
+        }
+
+        let mut t = t0;
+        loop {
+            t -= (1.0 - uniform_random()).ln() / majorant;
+            if t >= t1 {
+                return (MediumEvent::Pass { weight }, emitted);
+            }
+
+            let position = ray.extend_at(t);
+            let d = grid.density(&position);
+            let sigma_a = self.sigma_a.scale(d);
+            let sigma_s = self.sigma_s.scale(d);
+            let sigma_n = (sigma_a + sigma_s).map(|s| majorant - s);
+
+            if self.emission > Color::black() {
+                emitted += weight
+                    .blend(sigma_a)
+                    .blend(self.emission.scale(grid.emission(&position)))
+                    .scale(1.0 / majorant);
+            }
+
+            let w = channel_probabilities(throughput.blend(weight));
+            let probability =
+                |c: Color| (0..3).map(|i| w.channel(i) * c.channel(i)).sum::<f64>() / majorant;
+            let p_a = probability(sigma_a);
+            let p_s = probability(sigma_s);
+            let u = uniform_random();
+            if u < p_a {
+                return (MediumEvent::Absorb, emitted);
+            } else if u < p_a + p_s {
+                weight = weight.blend(sigma_s).scale(1.0 / (majorant * p_s));
+                return (MediumEvent::Scatter { position, weight }, emitted);
+            } else {
+                let p_n = 1.0 - p_a - p_s;
+                if p_n <= 0.0 {
+                    return (MediumEvent::Absorb, emitted);
+                }
+                weight = weight.blend(sigma_n).scale(1.0 / (majorant * p_n));
+            }
+        }
+    }
+
+    // woは散乱前の経路の向き(散乱点から離れる向き), wiは散乱後の向き
+    pub fn phase(&self, wo: &V3U, wi: &V3U) -> f64 {
+        henyey_greenstein(-wo.dot(wi), self.g)
+    }
+
+    // 位相関数に比例した重点的サンプリング, 位相関数の値がそのままpdfになる
+    pub fn sample_phase(&self, wo: &V3U) -> (V3U, f64) {
+        let g = self.g;
+        let u1 = uniform_random();
+        let u2 = uniform_random();
+
+        // 進行方向(-wo)とのなす角
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u1
+        } else {
+            let sq = (1.0 - g * g) / (1.0 + g - 2.0 * g * u1);
+            (1.0 + g * g - sq * sq) / (2.0 * g)
+        }
+        .clamp(-1.0, 1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u2;
+
+        let w = wo.neg();
+        let (u, v) = w.orthonormal_basis();
+        let wi = V3U::from_v3(
+            u.scale(sin_theta * phi.cos()) + v.scale(sin_theta * phi.sin()) + w.scale(cos_theta),
+        );
+
+        (wi, henyey_greenstein(cos_theta, g))
+    }
+}
+
+// 経路の重みに比例したチャンネルの選択確率(重みがなければ一様)
+fn channel_probabilities(throughput: Color) -> Color {
+    let throughput = throughput.map(|v| v.max(0.0));
+    let sum = (0..3).map(|i| throughput.channel(i)).sum::<f64>();
+    if sum > 0.0 {
+        throughput.scale(1.0 / sum)
+    } else {
+        Color::new(1.0, 1.0, 1.0).scale(1.0 / 3.0)
+    }
+}
+
+// cos_thetaは散乱前後の進行方向のなす角
+fn henyey_greenstein(cos_theta: f64, g: f64) -> f64 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt())
+}
+
+// 経路が今いる媒質
+// 媒質を持つオブジェクトの内部にいるときはそのオブジェクト, そうでなければシーン全体の媒質
+// 媒質の入れ子は扱わない
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MediumState<'a> {
+    pub inside: Option<&'a Object>,
+}
+
+impl<'a> MediumState<'a> {
+    // objectの面を透過したあとの状態
+    pub fn cross(self, object: &'a Object) -> Self {
+        match self.inside {
+            Some(inside) if std::ptr::eq(inside, object) => MediumState { inside: None },
+            _ => MediumState {
+                inside: Some(object),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn henyey_greenstein_integrates_to_one() {
+        for &g in &[-0.7, 0.0, 0.3, 0.9] {
+            let n = 100000;
+            let sum = (0..n)
+                .map(|i| {
+                    let cos_theta = -1.0 + 2.0 * (i as f64 + 0.5) / n as f64;
+                    henyey_greenstein(cos_theta, g) * 2.0 * PI * (2.0 / n as f64)
+                })
+                .sum::<f64>();
+            assert!((sum - 1.0).abs() < 1e-3, "g = {}: {}", g, sum);
+        }
+    }
+
+    #[test]
+    fn sample_phase_matches_phase() {
+        let medium = Medium {
+            sigma_a: Color::black(),
+            sigma_s: Color::new(1.0, 1.0, 1.0),
+            g: 0.6,
+            ..Default::default()
+        };
+        let wo = V3U::from_v3(V3::new(0.3, -0.5, 0.8));
+
+        // 前方散乱なので進行方向側に偏る
+        let n = 100000;
+        let mut forward = 0;
+        for _ in 0..n {
+            let (wi, pdf) = medium.sample_phase(&wo);
+            assert!((pdf - medium.phase(&wo, &wi)).abs() < 1e-9 * pdf.max(1.0));
+            if wi.dot(&wo) < 0.0 {
+                forward += 1;
+            }
+        }
+
+        // HG の前方半球の確率 (1 + g) / (2g) * (1 - (1 - g) / sqrt(1 + g^2))
+        let g = medium.g;
+        let expected = (1.0 + g) / (2.0 * g) * (1.0 - (1.0 - g) / (1.0 + g * g).sqrt());
+        let freq = forward as f64 / n as f64;
+        assert!((freq - expected).abs() < 0.01, "{} vs {}", freq, expected);
+    }
+
+    #[test]
+    fn medium_sample_is_unbiased_for_transmittance() {
+        // 散乱しない確率の重みの期待値は透過率
+        let medium = Medium {
+            sigma_a: Color::new(0.1, 0.2, 0.3),
+            sigma_s: Color::new(0.2, 0.1, 0.0),
+            g: 0.0,
+            ..Default::default()
+        };
+        let ray = Ray {
+            origin: V3::zero(),
+            dir: V3U::unit_x(),
+        };
+
+        let n = 200000;
+        let mut pass = Color::black();
+        for _ in 0..n {
+            if let (MediumEvent::Pass { weight }, _) =
+                medium.sample(&ray, 0.0, 3.0, Color::new(0.2, 1.0, 0.5))
+            {
+                pass += weight;
+            }
+        }
+        let pass = pass.scale(1.0 / n as f64);
+        let expected = medium.transmittance(&ray, 0.0, 3.0);
+        for i in 0..3 {
+            assert!(
+                (pass.channel(i) - expected.channel(i)).abs() < 0.01,
+                "{:?} vs {:?}",
+                pass,
+                expected
+            );
+        }
+    }
+
+    // 密度が一定の格子なら均質な媒質と同じ結果になる
+    fn constant_grid_medium() -> (Medium, Medium) {
+        use crate::wrapper::aabb::Aabb;
+
+        let density = 0.5;
+        let grid = VolumeGrid::new(
+            Aabb::new(V3::new(-1.0, -1.0, -1.0), V3::new(4.0, 1.0, 1.0)),
+            (5, 2, 2),
+            vec![density; 20],
+            None,
+        );
+        let medium = Medium {
+            sigma_a: Color::new(0.1, 0.2, 0.3),
+            sigma_s: Color::new(0.2, 0.1, 0.0),
+            g: 0.0,
+            emission: Color::new(2.0, 1.0, 0.5),
+            grid: Some(Arc::new(grid)),
+        };
+        let homogeneous = Medium {
+            sigma_a: medium.sigma_a.scale(density),
+            sigma_s: medium.sigma_s.scale(density),
+            grid: None,
+            ..medium.clone()
+        };
+
+        (medium, homogeneous)
+    }
+
+    #[test]
+    fn grid_medium_tracking_is_unbiased() {
+        let (medium, homogeneous) = constant_grid_medium();
+        let ray = Ray {
+            origin: V3::zero(),
+            dir: V3U::unit_x(),
+        };
+
+        let n = 200000;
+        let mut ratio = Color::black();
+        let mut pass = Color::black();
+        let mut emitted = Color::black();
+        for _ in 0..n {
+            ratio += medium.transmittance(&ray, 0.0, 3.0);
+            let (event, e) = medium.sample(&ray, 0.0, 3.0, Color::new(0.2, 1.0, 0.5));
+            if let MediumEvent::Pass { weight } = event {
+                pass += weight;
+            }
+            emitted += e;
+        }
+
+        let tr = homogeneous.transmittance(&ray, 0.0, 3.0);
+        // ∫ T(t) σa Le dt
+        let sigma_t = homogeneous.sigma_t();
+        let expected_emission = Color::new(
+            (1.0 - tr.channel(0)) / sigma_t.channel(0),
+            (1.0 - tr.channel(1)) / sigma_t.channel(1),
+            (1.0 - tr.channel(2)) / sigma_t.channel(2),
+        )
+        .blend(homogeneous.sigma_a)
+        .blend(homogeneous.emission);
+
+        for (estimate, expected) in &[(ratio, tr), (pass, tr), (emitted, expected_emission)] {
+            let estimate = estimate.scale(1.0 / n as f64);
+            for i in 0..3 {
+                assert!(
+                    (estimate.channel(i) - expected.channel(i)).abs() < 0.01,
+                    "{:?} vs {:?}",
+                    estimate,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn scene_transmittance_through_medium_sphere() {
+        use crate::renderer::{Figure, Reflection, Scene, Sphere};
+
+        let medium = Medium {
+            sigma_a: Color::new(0.1, 0.2, 0.3),
+            sigma_s: Color::new(0.1, 0.0, 0.0),
+            g: 0.0,
+            ..Default::default()
+        };
+        let scene = Scene::new(vec![Object {
+            figure: Figure::Sphere(Sphere {
+                center: V3::zero(),
+                radius: 2.0,
+            }),
+            reflection: Reflection::Transparent,
+            medium: Some(medium.clone()),
+            ..Default::default()
+        }]);
+
+        // 球の中心を通り抜ける
+        let tr = scene.transmittance(
+            &V3::new(-10.0, 0.0, 0.0),
+            &V3U::unit_x(),
+            20.0,
+            MediumState::default(),
+        );
+        let expected = medium.transmittance(
+            &Ray {
+                origin: V3::zero(),
+                dir: V3U::unit_x(),
+            },
+            0.0,
+            4.0,
+        );
+        for i in 0..3 {
+            assert!((tr.channel(i) - expected.channel(i)).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use crate::wrapper::{aabb::Aabb, vec::V3};
+use std::fmt;
+use std::io::{Error, ErrorKind, Read};
+
+// 格子状に並んだボクセルの値(密度と, あれば放射の強さ)
+// 値はボクセルの中心にあるものとし, 間は三線形補間する. boundsの外では0
+#[derive(Clone, PartialEq)]
+pub struct VolumeGrid {
+    pub bounds: Aabb,
+    nx: usize,
+    ny: usize,
+    nz: usize,
+    density: Vec<f64>,
+    emission: Option<Vec<f64>>,
+    max_density: f64,
+}
+
+const MAGIC: &[u8; 4] = b"VOL1";
+
+impl VolumeGrid {
+    // 値はxが最も速く変わる順に並べる
+    pub fn new(
+        bounds: Aabb,
+        (nx, ny, nz): (usize, usize, usize),
+        density: Vec<f64>,
+        emission: Option<Vec<f64>>,
+    ) -> Self {
+        assert!(nx > 0 && ny > 0 && nz > 0);
+        assert_eq!(density.len(), nx * ny * nz);
+        if let Some(e) = &emission {
+            assert_eq!(e.len(), nx * ny * nz);
+        }
+
+        let max_density = density.iter().cloned().fold(0.0, f64::max);
+        VolumeGrid {
+            bounds,
+            nx,
+            ny,
+            nz,
+            density,
+            emission,
+            max_density,
+        }
+    }
+
+    // ファイル形式(リトルエンディアン)
+    //   "VOL1", nx, ny, nz: u32, channels: u32 (1: 密度のみ, 2: 密度と放射の強さ)
+    //   続いてnx*ny*nz*channels個のf32, xが最も速く変わり, 各ボクセルのチャンネルは続けて並ぶ
+    pub fn load(path: &str, bounds: Aabb) -> std::io::Result<Self> {
+        let mut bytes = Vec::new();
+        std::fs::File::open(path)?.read_to_end(&mut bytes)?;
+        VolumeGrid::from_bytes(&bytes, bounds)
+    }
+
+    pub fn from_bytes(bytes: &[u8], bounds: Aabb) -> std::io::Result<Self> {
+        let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+
+        if bytes.len() < 20 || &bytes[0..4] != MAGIC {
+            return Err(invalid("not a volume grid file"));
+        }
+        let header = |i: usize| {
+            let mut b = [0; 4];
+            b.copy_from_slice(&bytes[4 + 4 * i..8 + 4 * i]);
+            u32::from_le_bytes(b) as usize
+        };
+        let (nx, ny, nz, channels) = (header(0), header(1), header(2), header(3));
+        if !(channels == 1 || channels == 2) {
+            return Err(invalid("channels must be 1 or 2"));
+        }
+        if nx == 0 || ny == 0 || nz == 0 {
+            return Err(invalid("grid size must be positive"));
+        }
+
+        // ヘッダの値によっては掛け算が溢れる
+        let size = nx
+            .checked_mul(ny)
+            .and_then(|n| n.checked_mul(nz))
+            .and_then(|n| n.checked_mul(channels * 4))
+            .ok_or_else(|| invalid("grid is too large"))?;
+        let body = &bytes[20..];
+        if body.len() != size {
+            return Err(invalid("size does not match the header"));
+        }
+
+        let values = body
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]) as f64)
+            .collect::<Vec<_>>();
+        if values.iter().any(|v| !v.is_finite() || *v < 0.0) {
+            return Err(invalid("values must be finite and non-negative"));
+        }
+        let density = values.iter().step_by(channels).cloned().collect();
+        let emission = if channels == 2 {
+            Some(values.iter().skip(1).step_by(2).cloned().collect())
+        } else {
+            None
+        };
+
+        Ok(VolumeGrid::new(bounds, (nx, ny, nz), density, emission))
+    }
+
+    // 密度と放射の強さのすべての値
+    pub fn values(&self) -> impl Iterator<Item = &f64> {
+        self.density.iter().chain(self.emission.iter().flatten())
+    }
+
+    pub fn max_density(&self) -> f64 {
+        self.max_density
+    }
+
+    pub fn has_emission(&self) -> bool {
+        self.emission.is_some()
+    }
+
+    pub fn density(&self, p: &V3) -> f64 {
+        self.lookup(&self.density, p)
+    }
+
+    // 放射の強さ, 放射のチャンネルがなければ1
+    pub fn emission(&self, p: &V3) -> f64 {
+        match &self.emission {
+            Some(e) => self.lookup(e, p),
+            None => 1.0,
+        }
+    }
+
+    fn lookup(&self, values: &[f64], p: &V3) -> f64 {
+        if !self.bounds.contains(p) {
+            return 0.0;
+        }
+
+        let d = self.bounds.diagonal();
+        let n = [self.nx, self.ny, self.nz];
+        let mut index = [0; 3];
+        let mut frac = [0.0; 3];
+        for axis in 0..3 {
+            let x =
+                (p.axis(axis) - self.bounds.min.axis(axis)) / d.axis(axis) * n[axis] as f64 - 0.5;
+            let i = x.floor();
+            index[axis] = i as i64;
+            frac[axis] = x - i;
+        }
+
+        let at = |dx: i64, dy: i64, dz: i64| {
+            let i = (index[0] + dx).clamp(0, self.nx as i64 - 1) as usize;
+            let j = (index[1] + dy).clamp(0, self.ny as i64 - 1) as usize;
+            let k = (index[2] + dz).clamp(0, self.nz as i64 - 1) as usize;
+            values[(k * self.ny + j) * self.nx + i]
+        };
+        let lerp = |t: f64, a: f64, b: f64| a + (b - a) * t;
+
+        let [fx, fy, fz] = frac;
+        lerp(
+            fz,
+            lerp(
+                fy,
+                lerp(fx, at(0, 0, 0), at(1, 0, 0)),
+                lerp(fx, at(0, 1, 0), at(1, 1, 0)),
+            ),
+            lerp(
+                fy,
+                lerp(fx, at(0, 0, 1), at(1, 0, 1)),
+                lerp(fx, at(0, 1, 1), at(1, 1, 1)),
+            ),
+        )
+    }
+}
+
+impl fmt::Debug for VolumeGrid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VolumeGrid")
+            .field("bounds", &self.bounds)
+            .field("size", &(self.nx, self.ny, self.nz))
+            .field("max_density", &self.max_density)
+            .field("has_emission", &self.has_emission())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ファイル形式に書き出す
+    fn to_bytes(grid: &VolumeGrid) -> Vec<u8> {
+        let channels = if grid.emission.is_some() { 2 } else { 1 };
+        let mut bytes = MAGIC.to_vec();
+        for v in &[grid.nx, grid.ny, grid.nz, channels] {
+            bytes.extend_from_slice(&(*v as u32).to_le_bytes());
+        }
+
+        for i in 0..grid.density.len() {
+            bytes.extend_from_slice(&(grid.density[i] as f32).to_le_bytes());
+            if let Some(e) = &grid.emission {
+                bytes.extend_from_slice(&(e[i] as f32).to_le_bytes());
+            }
+        }
+
+        bytes
+    }
+
+    fn grid() -> VolumeGrid {
+        // 密度はxに比例, 放射はzに比例
+        let (nx, ny, nz) = (4, 3, 2);
+        let mut density = Vec::new();
+        let mut emission = Vec::new();
+        for k in 0..nz {
+            for _ in 0..ny {
+                for i in 0..nx {
+                    density.push(i as f64);
+                    emission.push(k as f64 * 10.0);
+                }
+            }
+        }
+
+        VolumeGrid::new(
+            Aabb::new(V3::zero(), V3::new(4.0, 3.0, 2.0)),
+            (nx, ny, nz),
+            density,
+            Some(emission),
+        )
+    }
+
+    #[test]
+    fn volume_grid_trilinear_lookup() {
+        let grid = grid();
+        assert_eq!(grid.max_density(), 3.0);
+
+        // ボクセルの中心では格子の値そのもの
+        assert!((grid.density(&V3::new(1.5, 1.5, 0.5)) - 1.0).abs() < 1e-12);
+        // 中心の間は線形補間
+        assert!((grid.density(&V3::new(2.25, 0.7, 1.2)) - 1.75).abs() < 1e-12);
+        assert!((grid.emission(&V3::new(2.0, 1.0, 1.0)) - 5.0).abs() < 1e-12);
+        // 端は外側に延長しない
+        assert!((grid.density(&V3::new(0.1, 1.0, 1.0)) - 0.0).abs() < 1e-12);
+        // 範囲外
+        assert_eq!(grid.density(&V3::new(-0.1, 1.0, 1.0)), 0.0);
+    }
+
+    #[test]
+    fn volume_grid_roundtrip_bytes() {
+        let grid = grid();
+        let loaded = VolumeGrid::from_bytes(&to_bytes(&grid), grid.bounds).unwrap();
+        assert_eq!(grid, loaded);
+
+        assert!(VolumeGrid::from_bytes(b"VOL1", grid.bounds).is_err());
+        let mut broken = to_bytes(&grid);
+        broken.pop();
+        assert!(VolumeGrid::from_bytes(&broken, grid.bounds).is_err());
+    }
+
+    #[test]
+    fn volume_grid_rejects_malformed_headers() {
+        let bounds = grid().bounds;
+        let file = |size: [u32; 4], values: &[f32]| {
+            let mut bytes = MAGIC.to_vec();
+            for v in &size {
+                bytes.extend_from_slice(&v.to_le_bytes());
+            }
+            for v in values {
+                bytes.extend_from_slice(&v.to_le_bytes());
+            }
+            bytes
+        };
+
+        assert!(VolumeGrid::from_bytes(&file([1, 1, 1, 1], &[0.5]), bounds).is_ok());
+        // 大きさが0
+        assert!(VolumeGrid::from_bytes(&file([0, 1, 1, 1], &[]), bounds).is_err());
+        assert!(VolumeGrid::from_bytes(&file([1, 1, 0, 2], &[]), bounds).is_err());
+        // 掛け算が溢れて小さな値になる大きさ
+        let huge = file([u32::MAX, u32::MAX, u32::MAX, 2], &[0.5]);
+        assert!(VolumeGrid::from_bytes(&huge, bounds).is_err());
+        assert!(VolumeGrid::from_bytes(&file([65536, 65536, 65536, 1], &[]), bounds).is_err());
+        // チャンネル数
+        assert!(VolumeGrid::from_bytes(&file([1, 1, 1, 3], &[0.5; 3]), bounds).is_err());
+        // 負の値や有限でない値
+        for v in &[-1.0, f32::NAN, f32::INFINITY] {
+            assert!(VolumeGrid::from_bytes(&file([1, 1, 1, 1], &[*v]), bounds).is_err());
+            assert!(VolumeGrid::from_bytes(&file([1, 1, 1, 2], &[0.5, *v]), bounds).is_err());
+        }
+    }
+}
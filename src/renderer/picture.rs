@@ -1,5 +1,15 @@
+use crate::renderer::{xyz_to_rgb, ColorSpace, TransferFunction};
 use crate::wrapper::color::Color;
 
+mod denoise;
+mod image_file;
+mod metrics;
+mod tone_mapping;
+pub use denoise::*;
+pub use image_file::*;
+pub use metrics::*;
+pub use tone_mapping::*;
+
 pub struct Picture {
     pixels: Vec<Color>,
 }
@@ -9,33 +19,51 @@ impl Picture {
         Picture { pixels }
     }
 
-    pub fn as_vec(self) -> Vec<Color> {
+    // CIE XYZの画素からcolor_spaceの画像を作る
+    pub fn from_xyz(pixels: Vec<Color>, color_space: ColorSpace) -> Picture {
+        Picture::new(
+            pixels
+                .into_iter()
+                .map(|c| ColorSpace::LinearSrgb.convert(xyz_to_rgb(c), color_space))
+                .collect(),
+        )
+    }
+
+    pub fn pixels(&self) -> &[Color] {
+        &self.pixels
+    }
+
+    pub fn into_vec(self) -> Vec<Color> {
         self.pixels
     }
 
-    pub fn correct_gamma(&mut self, gamma: f64) {
+    pub fn convert(&mut self, from: ColorSpace, to: ColorSpace) {
         for i in 0..self.pixels.len() {
-            self.pixels[i] = self.pixels[i].gamma_correction(gamma);
+            self.pixels[i] = from.convert(self.pixels[i], to);
         }
     }
 
-    pub fn tone_map(&mut self) {
-        let max_white = self.get_max_luminance();
-        let reinhard_ext = |c: Color| {
-            let lumi = c.luminance();
-            c.adjust_luminance(lumi * (1.0 + (lumi / (max_white * max_white))) / (1.0 + lumi))
-        };
+    // 表示用の信号値にする
+    pub fn encode(&mut self, transfer_function: &TransferFunction) {
+        for i in 0..self.pixels.len() {
+            self.pixels[i] = self.pixels[i].map(|v| transfer_function.encode(v));
+        }
+    }
 
+    // 露光を合わせてからトーンマッピングする
+    pub fn tone_map(&mut self, tone_mapping: &ToneMapping, exposure: &Exposure) {
+        let scale = self.exposure_scale(exposure);
         for i in 0..self.pixels.len() {
-            let c = self.pixels[i];
-            self.pixels[i] = reinhard_ext(c);
+            self.pixels[i] = tone_mapping.apply(self.pixels[i].scale(scale));
         }
     }
 
-    fn get_max_luminance(&self) -> f64 {
-        // find maximum
-        self.pixels
-            .iter()
-            .fold(0.0 / 0.0, |m, v| v.luminance().max(m))
+    pub fn exposure_scale(&self, exposure: &Exposure) -> f64 {
+        match exposure {
+            Exposure::Fixed(ev) => ev.exp2(),
+            Exposure::Auto { percentile, key } => {
+                luminance_percentile(&self.pixels, *percentile).map_or(1.0, |lumi| key / lumi)
+            }
+        }
     }
 }
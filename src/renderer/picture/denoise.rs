@@ -0,0 +1,259 @@
+use crate::renderer::{Aovs, Picture};
+use crate::wrapper::color::Color;
+use rayon::prelude::*;
+use std::str::FromStr;
+
+// AOVの反射率, 法線, 深度を手がかりにしたedge-avoiding à-trous wavelet filter (Dammertz et al. 2010)
+// 反射率で割った照度をぼかしてから反射率を掛け直す
+#[derive(Clone, Debug)]
+pub struct Denoiser {
+    pub iterations: i32,
+    pub sigma_color: f64,
+    pub sigma_normal: f64,
+    pub sigma_albedo: f64,
+    pub sigma_depth: f64, // 相対的な差
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Denoiser {
+            iterations: 5,
+            sigma_color: 0.5,
+            sigma_normal: 0.3,
+            sigma_albedo: 0.1,
+            sigma_depth: 0.05,
+        }
+    }
+}
+
+// 出力する画像
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum DenoiseOutput {
+    // デノイズしない
+    #[default]
+    Off,
+    // デノイズしたものだけ
+    Denoised,
+    // ノイズのあるものと, デノイズしたもの(ファイル名に.denoisedを付ける)の両方
+    Both,
+}
+
+impl FromStr for DenoiseOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(DenoiseOutput::Off),
+            "denoised" | "on" => Ok(DenoiseOutput::Denoised),
+            "both" => Ok(DenoiseOutput::Both),
+            _ => Err(format!("unknown denoise output: {}", s)),
+        }
+    }
+}
+
+impl Denoiser {
+    pub fn denoise(&self, picture: &Picture, aovs: &Aovs) -> Picture {
+        const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+        let (width, height) = (aovs.width, aovs.height);
+        let albedo = |i: usize| aovs.albedo[i].map(|v| v.max(1e-3));
+
+        let irradiance = picture
+            .pixels()
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let a = albedo(i);
+                Color::new(
+                    c.channel(0) / a.channel(0),
+                    c.channel(1) / a.channel(1),
+                    c.channel(2) / a.channel(2),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut irradiance = suppress_fireflies(&irradiance, width, height);
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            // 細かいスケールほど色の違いに敏感にする
+            let sigma_color = self.sigma_color * 0.5f64.powi(iteration);
+            let input = irradiance.clone();
+
+            irradiance = (0..width * height)
+                .into_par_iter()
+                .map(|p| {
+                    let (px, py) = (p % width, p / width);
+                    let p = p as usize;
+                    let cp = compress(input[p]);
+
+                    let mut sum = Color::black();
+                    let mut weight_sum = 0.0;
+                    for (dy, ky) in KERNEL.iter().enumerate() {
+                        for (dx, kx) in KERNEL.iter().enumerate() {
+                            let qx = px + (dx as i32 - 2) * step;
+                            let qy = py + (dy as i32 - 2) * step;
+                            if qx < 0 || qy < 0 || qx >= width || qy >= height {
+                                continue;
+                            }
+                            let q = (qy * width + qx) as usize;
+
+                            let w = kx
+                                * ky
+                                * gaussian(distance2(cp, compress(input[q])), sigma_color)
+                                * gaussian(
+                                    (aovs.normal[p] - aovs.normal[q]).len_square(),
+                                    self.sigma_normal,
+                                )
+                                * gaussian(
+                                    distance2(aovs.albedo[p], aovs.albedo[q]),
+                                    self.sigma_albedo,
+                                )
+                                * gaussian(
+                                    relative_difference(aovs.depth[p], aovs.depth[q]).powi(2),
+                                    self.sigma_depth,
+                                );
+
+                            sum += input[q].scale(w);
+                            weight_sum += w;
+                        }
+                    }
+
+                    sum.scale(1.0 / weight_sum)
+                })
+                .collect();
+        }
+
+        Picture::new(
+            irradiance
+                .into_iter()
+                .enumerate()
+                .map(|(i, c)| c.blend(albedo(i)))
+                .collect(),
+        )
+    }
+}
+
+// 周りの8画素のどれよりも明るい画素を, その最大の明るさに抑える
+// 孤立した明るい画素は色の重みで周りと混ざらず, そのまま残ってしまうため
+fn suppress_fireflies(pixels: &[Color], width: i32, height: i32) -> Vec<Color> {
+    (0..width * height)
+        .into_par_iter()
+        .map(|p| {
+            let (px, py) = (p % width, p / width);
+            let mut max = 0.0f64;
+            for qy in (py - 1).max(0)..=(py + 1).min(height - 1) {
+                for qx in (px - 1).max(0)..=(px + 1).min(width - 1) {
+                    if (qx, qy) != (px, py) {
+                        max = max.max(pixels[(qy * width + qx) as usize].luminance());
+                    }
+                }
+            }
+
+            let c = pixels[p as usize];
+            if c.luminance() > max {
+                c.adjust_luminance(max)
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+fn gaussian(distance2: f64, sigma: f64) -> f64 {
+    (-distance2 / (sigma * sigma)).exp()
+}
+
+fn distance2(a: Color, b: Color) -> f64 {
+    (0..3).map(|i| (a.channel(i) - b.channel(i)).powi(2)).sum()
+}
+
+// 明るい画素の差が重みを支配しないように[0, 1)に縮める
+fn compress(c: Color) -> Color {
+    c.map(|v| v.max(0.0) / (1.0 + v.max(0.0)))
+}
+
+fn relative_difference(a: f64, b: f64) -> f64 {
+    if a == b {
+        // どちらも無限遠の場合を含む
+        0.0
+    } else {
+        (a - b).abs() / a.min(b).max(1e-6)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::uniform_random;
+    use crate::wrapper::vec::V3;
+
+    // 左右で法線の異なる平面に一様なノイズを乗せた画像
+    fn noisy_picture(width: i32, height: i32) -> (Picture, Aovs) {
+        let n = (width * height) as usize;
+        let pixels = (0..n)
+            .map(|i| {
+                let base = if (i as i32 % width) < width / 2 {
+                    0.2
+                } else {
+                    0.8
+                };
+                let v = base * (0.5 + uniform_random());
+                Color::new(v, v, v)
+            })
+            .collect::<Vec<_>>();
+        let aovs = Aovs {
+            width,
+            height,
+            position: vec![V3::zero(); n],
+            albedo: vec![Color::new(1.0, 1.0, 1.0); n],
+            normal: (0..n)
+                .map(|i| {
+                    if (i as i32 % width) < width / 2 {
+                        V3::new(1.0, 0.0, 0.0)
+                    } else {
+                        V3::new(0.0, 1.0, 0.0)
+                    }
+                })
+                .collect(),
+            depth: vec![10.0; n],
+            object_id: vec![None; n],
+            material_id: vec![None; n],
+            passes: Vec::new(),
+            sample_count: vec![1; n],
+        };
+
+        (Picture::new(pixels), aovs)
+    }
+
+    #[test]
+    fn denoiser_reduces_noise_and_keeps_edges() {
+        let (width, height) = (64, 32);
+        let (picture, aovs) = noisy_picture(width, height);
+        let denoised = Denoiser::default().denoise(&picture, &aovs);
+
+        let stats = |pixels: &[Color], left: bool| {
+            let values = pixels
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| ((*i as i32 % width) < width / 2) == left)
+                .map(|(_, c)| c.luminance())
+                .collect::<Vec<_>>();
+            let mean = values.iter().sum::<f64>() / values.len() as f64;
+            let var = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64;
+            (mean, var)
+        };
+
+        for &left in &[true, false] {
+            let (mean, var) = stats(picture.pixels(), left);
+            let (denoised_mean, denoised_var) = stats(denoised.pixels(), left);
+
+            // 法線の違う反対側の明るさが混ざらない
+            assert!(
+                (denoised_mean - mean).abs() < 0.02 * mean,
+                "{} {}",
+                mean,
+                denoised_mean
+            );
+            assert!(denoised_var < 0.1 * var, "{} {}", var, denoised_var);
+        }
+    }
+}
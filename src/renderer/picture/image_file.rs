@@ -0,0 +1,235 @@
+use crate::renderer::{decode_exr, decode_pfm, dither_noise, quantize, Picture, TransferFunction};
+use crate::wrapper::color::Color;
+use std::io::{Error, ErrorKind};
+
+// 画像ファイルを拡張子で見分けて読み, 線形の値にして返す
+// PPMは"# colorspace:"のコメントにある伝達関数で, PNGはsRGBとして戻す
+pub fn read_image(file_path: &str) -> std::io::Result<(i32, i32, Picture)> {
+    let bytes = std::fs::read(file_path)?;
+    let extension = std::path::Path::new(file_path)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase());
+    let (width, height, pixels) = match extension.as_deref() {
+        Some("ppm") => decode_ppm(&bytes)?,
+        Some("pfm") => decode_pfm(&bytes)?,
+        Some("exr") => decode_exr(&bytes)?,
+        Some("png") => decode_png(&bytes)?,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("unknown image format: {}", file_path),
+            ))
+        }
+    };
+
+    Ok((width, height, Picture::new(pixels)))
+}
+
+// 表示用の信号値にした画像をP3のPPMに書き, 伝達関数の名前をコメントに残す
+pub fn write_encoded_ppm(
+    file_path: &str,
+    width: i32,
+    height: i32,
+    picture: &Picture,
+    transfer_function: &TransferFunction,
+    dither: bool,
+) -> std::io::Result<()> {
+    use std::io::{BufWriter, Write};
+
+    let mut file = BufWriter::new(std::fs::File::create(file_path)?);
+    write!(
+        file,
+        "P3\n# colorspace: {}\n{} {}\n255\n",
+        transfer_function.name(),
+        width,
+        height
+    )?;
+
+    for c in picture.pixels() {
+        let q = |i: usize| {
+            let noise = if dither { dither_noise() } else { 0.0 };
+            quantize(c.channel(i), noise)
+        };
+        writeln!(file, "{} {} {}", q(0), q(1), q(2))?;
+    }
+
+    Ok(())
+}
+
+// P3とP6のPPM
+pub fn decode_ppm(bytes: &[u8]) -> std::io::Result<(i32, i32, Vec<Color>)> {
+    let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+
+    // ヘッダの4つの値, 途中のコメントから伝達関数を拾う
+    let mut transfer_function = TransferFunction::Srgb;
+    let mut fields = Vec::new();
+    let mut at = 0;
+    while fields.len() < 4 {
+        while at < bytes.len() && bytes[at].is_ascii_whitespace() {
+            at += 1;
+        }
+        if bytes.get(at) == Some(&b'#') {
+            let newline = bytes[at..].iter().position(|b| *b == b'\n');
+            let end = at + newline.unwrap_or(bytes.len() - at);
+            let comment = String::from_utf8_lossy(&bytes[at + 1..end]);
+            if let Some(name) = comment.trim().strip_prefix("colorspace:") {
+                transfer_function = TransferFunction::from_name(name.trim())
+                    .ok_or_else(|| invalid("unknown colorspace"))?;
+            }
+            at = (end + 1).min(bytes.len());
+            continue;
+        }
+        let start = at;
+        while at < bytes.len() && !bytes[at].is_ascii_whitespace() {
+            at += 1;
+        }
+        if start == at {
+            return Err(invalid("truncated ppm header"));
+        }
+        fields.push(String::from_utf8_lossy(&bytes[start..at]).into_owned());
+    }
+
+    let number = |s: &str| s.parse::<u32>().map_err(|_| invalid("invalid ppm header"));
+    let (width, height, max) = (
+        number(&fields[1])?,
+        number(&fields[2])?,
+        number(&fields[3])?,
+    );
+    if width == 0 || height == 0 || width > i32::MAX as u32 || height > i32::MAX as u32 {
+        return Err(invalid("invalid ppm header"));
+    }
+    if max == 0 || max > 65535 {
+        return Err(invalid("invalid ppm header"));
+    }
+    let count = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|n| n.checked_mul(3))
+        .ok_or_else(|| invalid("invalid ppm header"))?;
+
+    let values = match fields[0].as_str() {
+        "P3" => String::from_utf8_lossy(&bytes[at..])
+            .split_ascii_whitespace()
+            .map(number)
+            .collect::<std::io::Result<Vec<_>>>()?,
+        "P6" => {
+            let body = &bytes[(at + 1).min(bytes.len())..];
+            if max < 256 {
+                body.iter().map(|b| *b as u32).collect()
+            } else {
+                (body.chunks_exact(2))
+                    .map(|b| u16::from_be_bytes([b[0], b[1]]) as u32)
+                    .collect()
+            }
+        }
+        _ => return Err(invalid("not a ppm file")),
+    };
+    if values.len() != count {
+        return Err(invalid("size does not match the header"));
+    }
+
+    let decode = |v: u32| transfer_function.decode(v as f64 / max as f64);
+    let pixels = values
+        .chunks_exact(3)
+        .map(|c| Color::new(decode(c[0]), decode(c[1]), decode(c[2])))
+        .collect();
+
+    Ok((width as i32, height as i32, pixels))
+}
+
+// 8bitと16bitのグレースケールとRGB(アルファは捨てる)
+pub fn decode_png(bytes: &[u8]) -> std::io::Result<(i32, i32, Vec<Color>)> {
+    let invalid = |e: png::DecodingError| Error::new(ErrorKind::InvalidData, e);
+
+    let mut decoder = png::Decoder::new(bytes);
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info().map_err(invalid)?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(invalid)?;
+
+    let (color_type, bit_depth) = reader.output_color_type();
+    let channels = color_type.samples();
+    let max = if bit_depth == png::BitDepth::Sixteen {
+        65535.0
+    } else {
+        255.0
+    };
+    let values = if bit_depth == png::BitDepth::Sixteen {
+        (buffer[..info.buffer_size()].chunks_exact(2))
+            .map(|b| u16::from_be_bytes([b[0], b[1]]) as f64)
+            .collect::<Vec<_>>()
+    } else {
+        (buffer[..info.buffer_size()].iter())
+            .map(|b| *b as f64)
+            .collect()
+    };
+
+    let decode = |v: f64| TransferFunction::Srgb.decode(v / max);
+    let pixels = values
+        .chunks_exact(channels)
+        .map(|c| {
+            if channels < 3 {
+                Color::new(decode(c[0]), decode(c[0]), decode(c[0]))
+            } else {
+                Color::new(decode(c[0]), decode(c[1]), decode(c[2]))
+            }
+        })
+        .collect();
+
+    Ok((info.width as i32, info.height as i32, pixels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ppm_is_decoded_with_its_transfer_function() {
+        let ascii = b"P3\n# colorspace: linear sRGB\n2 1\n255\n255 0 51\n0 255 0\n";
+        let (width, height, pixels) = decode_ppm(ascii).unwrap();
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(
+            pixels,
+            vec![Color::new(1.0, 0.0, 0.2), Color::new(0.0, 1.0, 0.0)]
+        );
+
+        let binary = [b"P6 1 1 65535\n".as_slice(), &[255, 255, 0, 0, 128, 0]].concat();
+        let (_, _, pixels) = decode_ppm(&binary).unwrap();
+        assert!((pixels[0].channel(0) - 1.0).abs() < 1e-12);
+        assert_eq!(pixels[0].channel(1), 0.0);
+        assert!((pixels[0].channel(2) - 0.2140482).abs() < 1e-6);
+
+        assert!(decode_ppm(b"P3\n2 1\n255\n0 0 0\n").is_err());
+
+        // 壊れたヘッダはpanicせずInvalidDataにする
+        for broken in [
+            b"P3\n# no newline".as_slice(),
+            b"P3 2 1\n#".as_slice(),
+            b"P6 4294967295 4294967295 255\n".as_slice(),
+            b"P6 2147483647 2147483647 255\n".as_slice(),
+        ] {
+            let error = decode_ppm(broken).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn png_is_decoded_as_srgb() {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, 2, 1);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header().unwrap();
+            writer
+                .write_image_data(&[255, 0, 0, 255, 0, 0, 255, 0])
+                .unwrap();
+        }
+
+        let (width, height, pixels) = decode_png(&bytes).unwrap();
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(
+            pixels,
+            vec![Color::new(1.0, 0.0, 0.0), Color::new(0.0, 0.0, 1.0)]
+        );
+    }
+}
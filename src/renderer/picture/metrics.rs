@@ -0,0 +1,257 @@
+use crate::renderer::{Picture, ToneMapping, TransferFunction};
+use crate::wrapper::color::Color;
+
+// relMSEで0除算を避けるために分母に足す値
+pub const RELATIVE_MSE_EPS: f64 = 0.01;
+// SSIMの窓の半径(7x7)と, 分母を安定させる定数
+const SSIM_RADIUS: i32 = 3;
+const SSIM_C1: f64 = 0.01 * 0.01;
+const SSIM_C2: f64 = 0.03 * 0.03;
+
+// 描画結果と参照画像の違い
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageMetrics {
+    pub mse: f64,
+    pub rmse: f64,
+    // (a - b)^2 / (b^2 + ε) の平均, 明るさによらない
+    pub relative_mse: f64,
+    // 最大値を1としたPSNR(dB), 同じ画像なら無限大
+    pub psnr: f64,
+    // 表示したときの輝度で求めたSSIMの平均, 同じ画像なら1
+    pub ssim: f64,
+    // flip_error_mapの平均
+    pub flip: f64,
+}
+
+impl ImageMetrics {
+    pub fn compare(width: i32, height: i32, image: &Picture, reference: &Picture) -> Self {
+        let pairs = || image.pixels().iter().zip(reference.pixels());
+        let n = (image.pixels().len() * 3).max(1) as f64;
+        let squared = |(a, b): (&Color, &Color), k: usize| (a.channel(k) - b.channel(k)).powi(2);
+
+        let mse = pairs()
+            .map(|p| (0..3).map(|k| squared(p, k)).sum::<f64>())
+            .sum::<f64>()
+            / n;
+        let relative_mse = pairs()
+            .map(|p| {
+                (0..3)
+                    .map(|k| squared(p, k) / (p.1.channel(k).powi(2) + RELATIVE_MSE_EPS))
+                    .sum::<f64>()
+            })
+            .sum::<f64>()
+            / n;
+        let flip = flip_error_map(width, height, image, reference);
+
+        let ssim = ssim_map(width, height, image, reference);
+
+        ImageMetrics {
+            mse,
+            rmse: mse.sqrt(),
+            relative_mse,
+            psnr: -10.0 * mse.log10(),
+            ssim: ssim.iter().sum::<f64>() / ssim.len().max(1) as f64,
+            flip: flip.iter().sum::<f64>() / flip.len().max(1) as f64,
+        }
+    }
+}
+
+// FLIPを簡略化した画素ごとの見た目の違い(0から1)
+// 既定のトーンマッピングで表示したものを, 見る距離を考えて少しぼかしてからCIELABの色差で比べる
+pub fn flip_error_map(width: i32, height: i32, image: &Picture, reference: &Picture) -> Vec<f64> {
+    let a = perceived(width, height, image);
+    let b = perceived(width, height, reference);
+
+    a.iter()
+        .zip(&b)
+        .map(|(a, b)| {
+            let d = (0..3)
+                .map(|k| (a.channel(k) - b.channel(k)).powi(2))
+                .sum::<f64>();
+            (d.sqrt() / 100.0).min(1.0)
+        })
+        .collect()
+}
+
+// 画素ごとのSSIM, 窓の中の平均と分散と共分散から求める
+pub fn ssim_map(width: i32, height: i32, image: &Picture, reference: &Picture) -> Vec<f64> {
+    let a = displayed_luminance(image);
+    let b = displayed_luminance(reference);
+    let index = |x: i32, y: i32| (y.clamp(0, height - 1) * width + x.clamp(0, width - 1)) as usize;
+
+    (0..width * height)
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            let window = (-SSIM_RADIUS..=SSIM_RADIUS)
+                .flat_map(|dy| (-SSIM_RADIUS..=SSIM_RADIUS).map(move |dx| (dx, dy)))
+                .map(|(dx, dy)| index(x + dx, y + dy))
+                .collect::<Vec<_>>();
+            let n = window.len() as f64;
+            let mean = |v: &[f64]| window.iter().map(|j| v[*j]).sum::<f64>() / n;
+            let (mean_a, mean_b) = (mean(&a), mean(&b));
+            let (mut var_a, mut var_b, mut cov) = (0.0, 0.0, 0.0);
+            for j in &window {
+                let (da, db) = (a[*j] - mean_a, b[*j] - mean_b);
+                var_a += da * da / n;
+                var_b += db * db / n;
+                cov += da * db / n;
+            }
+
+            (2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * cov + SSIM_C2)
+                / ((mean_a * mean_a + mean_b * mean_b + SSIM_C1) * (var_a + var_b + SSIM_C2))
+        })
+        .collect()
+}
+
+// 誤差(0から1)をmagmaの色で表した画像
+pub fn error_heatmap(errors: &[f64]) -> Picture {
+    const MAGMA: [[f64; 3]; 5] = [
+        [0.001462, 0.000466, 0.013866],
+        [0.316654, 0.071690, 0.485380],
+        [0.716387, 0.214982, 0.474720],
+        [0.986700, 0.535582, 0.382210],
+        [0.987053, 0.991438, 0.749504],
+    ];
+
+    Picture::new(
+        errors
+            .iter()
+            .map(|e| {
+                let t = e.clamp(0.0, 1.0) * (MAGMA.len() - 1) as f64;
+                let i = (t as usize).min(MAGMA.len() - 2);
+                let f = t - i as f64;
+                let c = |k: usize| {
+                    let v = MAGMA[i][k] * (1.0 - f) + MAGMA[i + 1][k] * f;
+                    // 色の表は表示用の値なので線形に戻す
+                    TransferFunction::Srgb.decode(v)
+                };
+                Color::new(c(0), c(1), c(2))
+            })
+            .collect(),
+    )
+}
+
+// 既定のトーンマッピングで表示したときの輝度(0から1)
+fn displayed_luminance(picture: &Picture) -> Vec<f64> {
+    picture
+        .pixels()
+        .iter()
+        .map(|c| {
+            let lumi = srgb_to_xyz(ToneMapping::default().apply(*c)).channel(1);
+            TransferFunction::Srgb.encode(lumi.min(1.0))
+        })
+        .collect()
+}
+
+// 表示したときのCIELAB
+fn perceived(width: i32, height: i32, picture: &Picture) -> Vec<Color> {
+    let xyz = picture
+        .pixels()
+        .iter()
+        .map(|c| srgb_to_xyz(ToneMapping::default().apply(*c)))
+        .collect::<Vec<_>>();
+
+    // [1, 2, 1]の3x3ガウシアン, 端は近い画素で埋める
+    const WEIGHTS: [f64; 3] = [0.25, 0.5, 0.25];
+    let at =
+        |x: i32, y: i32| xyz[(y.clamp(0, height - 1) * width + x.clamp(0, width - 1)) as usize];
+    (0..width * height)
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            let mut sum = Color::black();
+            for (dy, wy) in WEIGHTS.iter().enumerate() {
+                for (dx, wx) in WEIGHTS.iter().enumerate() {
+                    sum += at(x + dx as i32 - 1, y + dy as i32 - 1).scale(wx * wy);
+                }
+            }
+            xyz_to_lab(sum)
+        })
+        .collect()
+}
+
+fn srgb_to_xyz(c: Color) -> Color {
+    let (r, g, b) = (c.channel(0), c.channel(1), c.channel(2));
+    Color::new(
+        0.4124564 * r + 0.3575761 * g + 0.1804375 * b,
+        0.2126729 * r + 0.7151522 * g + 0.072175 * b,
+        0.0193339 * r + 0.119192 * g + 0.9503041 * b,
+    )
+}
+
+// D65を白とするCIELAB
+fn xyz_to_lab(xyz: Color) -> Color {
+    const WHITE: [f64; 3] = [0.95047, 1.0, 1.08883];
+    let f = |k: usize| {
+        let t = xyz.channel(k) / WHITE[k];
+        if t > (6.0f64 / 29.0).powi(3) {
+            t.cbrt()
+        } else {
+            t / (3.0 * (6.0f64 / 29.0).powi(2)) + 4.0 / 29.0
+        }
+    };
+
+    Color::new(
+        116.0 * f(1) - 16.0,
+        500.0 * (f(0) - f(1)),
+        200.0 * (f(1) - f(2)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_grow_with_the_difference() {
+        let reference = Picture::new(vec![Color::new(0.5, 0.5, 0.5); 16]);
+        let same = ImageMetrics::compare(4, 4, &reference, &reference);
+        assert_eq!(
+            same,
+            ImageMetrics {
+                mse: 0.0,
+                rmse: 0.0,
+                relative_mse: 0.0,
+                psnr: f64::INFINITY,
+                ssim: 1.0,
+                flip: 0.0
+            }
+        );
+
+        let mut pixels = vec![Color::new(0.5, 0.5, 0.5); 16];
+        pixels[5] = Color::new(0.6, 0.5, 0.5);
+        let slight = ImageMetrics::compare(4, 4, &Picture::new(pixels.clone()), &reference);
+        pixels[5] = Color::new(5.0, 0.5, 0.5);
+        let large = ImageMetrics::compare(4, 4, &Picture::new(pixels), &reference);
+
+        assert!((slight.rmse - (0.01f64 / 48.0).sqrt()).abs() < 1e-12);
+        assert!(0.0 < slight.flip && slight.flip < large.flip);
+        assert!(slight.relative_mse < large.relative_mse);
+        assert!((slight.psnr - 10.0 * (48.0f64 / 0.01).log10()).abs() < 1e-9);
+        assert!(large.psnr < slight.psnr);
+        assert!(large.ssim < slight.ssim && slight.ssim < 1.0);
+    }
+
+    #[test]
+    fn ssim_detects_inverted_structure() {
+        // 平均が同じでも模様が逆ならSSIMは負になる
+        let checker = |flip: bool| {
+            Picture::new(
+                (0..64)
+                    .map(|i| {
+                        let v = if ((i % 8 + i / 8) % 2 == 0) ^ flip {
+                            0.8
+                        } else {
+                            0.05
+                        };
+                        Color::new(v, v, v)
+                    })
+                    .collect(),
+            )
+        };
+        let inverted = ImageMetrics::compare(8, 8, &checker(false), &checker(true));
+        assert!(inverted.ssim < 0.0);
+
+        let heatmap = error_heatmap(&[0.0, 1.0]);
+        assert!(heatmap.pixels()[0].channel(0) < heatmap.pixels()[1].channel(0));
+    }
+}
@@ -0,0 +1,251 @@
+use crate::renderer::TransferFunction;
+use crate::wrapper::color::Color;
+use std::str::FromStr;
+
+// 露光を合わせたあとの放射輝度を[0, 1]の表示用の値に写す方法
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ToneMapping {
+    // そのまま(1を超えた分は飛ぶ)
+    Linear,
+    // 輝度に対するextended Reinhard, whiteの輝度がちょうど1になる
+    Reinhard { white: f64 },
+    // ACES filmicのNarkowiczによる近似
+    Aces,
+    // Uncharted 2のfilmic curve (John Hable)
+    Hable,
+    // AgX (Blenderのものの近似)
+    Agx,
+}
+
+// 露光(トーンマッピングの前に掛ける倍率)の決め方
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Exposure {
+    // EV, 2^evを掛ける
+    Fixed(f64),
+    // 輝度のヒストグラムのpercentileの位置の輝度がkeyになるように合わせる
+    Auto { percentile: f64, key: f64 },
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        ToneMapping::Reinhard { white: 4.0 }
+    }
+}
+
+impl Default for Exposure {
+    fn default() -> Self {
+        Exposure::Fixed(0.0)
+    }
+}
+
+impl ToneMapping {
+    pub fn apply(&self, c: Color) -> Color {
+        match self {
+            ToneMapping::Linear => c,
+            ToneMapping::Reinhard { white } => {
+                let lumi = c.luminance();
+                c.adjust_luminance((lumi * (1.0 + lumi / (white * white)) / (1.0 + lumi)).min(1.0))
+            }
+            ToneMapping::Aces => c.map(|x| {
+                let x = x.max(0.0);
+                (x * (2.51 * x + 0.03) / (x * (2.43 * x + 0.59) + 0.14)).clamp(0.0, 1.0)
+            }),
+            ToneMapping::Hable => {
+                const EXPOSURE_BIAS: f64 = 2.0;
+                const WHITE: f64 = 11.2;
+                c.map(|x| (hable(x.max(0.0) * EXPOSURE_BIAS) / hable(WHITE)).min(1.0))
+            }
+            ToneMapping::Agx => agx(c),
+        }
+    }
+}
+
+fn hable(x: f64) -> f64 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+}
+
+fn agx(c: Color) -> Color {
+    const MIN_EV: f64 = -12.47393;
+    const MAX_EV: f64 = 4.026069;
+    let inset = [
+        [0.842479062253094, 0.0784335999999992, 0.0792237451477643],
+        [0.0423282422610123, 0.878468636469772, 0.0791661274605434],
+        [0.0423756549057051, 0.0784336, 0.879142973793104],
+    ];
+    let outset = [
+        [1.19687900512017, -0.0980208811401368, -0.0990297440797205],
+        [-0.0528968517574562, 1.15190312990417, -0.0989611768448433],
+        [-0.0529716355144438, -0.0980434501171241, 1.15107367264116],
+    ];
+    let mul = |m: &[[f64; 3]; 3], c: Color| {
+        Color::new(
+            m[0][0] * c.channel(0) + m[0][1] * c.channel(1) + m[0][2] * c.channel(2),
+            m[1][0] * c.channel(0) + m[1][1] * c.channel(1) + m[1][2] * c.channel(2),
+            m[2][0] * c.channel(0) + m[2][1] * c.channel(1) + m[2][2] * c.channel(2),
+        )
+    };
+
+    let c = mul(&inset, c).map(|x| {
+        let x = (x.max(1e-10).log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+        // default contrastの多項式近似
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232
+    });
+
+    // 結果はsRGBでエンコードされているので, 後の伝達関数に合わせて線形に戻す
+    mul(&outset, c).map(|x| TransferFunction::Srgb.decode(x.clamp(0.0, 1.0)))
+}
+
+impl FromStr for ToneMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(ToneMapping::Linear),
+            "reinhard" => Ok(ToneMapping::default()),
+            "aces" => Ok(ToneMapping::Aces),
+            "hable" | "uncharted" => Ok(ToneMapping::Hable),
+            "agx" => Ok(ToneMapping::Agx),
+            _ => match s.strip_prefix("reinhard:").map(|w| w.parse::<f64>()) {
+                Some(Ok(white)) => Ok(ToneMapping::Reinhard { white }),
+                _ => Err(format!("unknown tone mapping: {}", s)),
+            },
+        }
+    }
+}
+
+impl FromStr for Exposure {
+    type Err = String;
+
+    // "auto", "auto:<percentile>"またはEVの数値
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let auto = |percentile| Exposure::Auto {
+            percentile,
+            key: 0.18,
+        };
+
+        if s == "auto" {
+            return Ok(auto(0.5));
+        }
+        if let Some(p) = s.strip_prefix("auto:") {
+            return p
+                .parse::<f64>()
+                .map(auto)
+                .map_err(|_| format!("invalid percentile: {}", p));
+        }
+
+        s.parse::<f64>()
+            .map(Exposure::Fixed)
+            .map_err(|_| format!("invalid exposure: {}", s))
+    }
+}
+
+// 輝度の対数のヒストグラムからpercentile(0から1)の位置の輝度を求める, 真っ黒な画素は除く
+pub fn luminance_percentile(pixels: &[Color], percentile: f64) -> Option<f64> {
+    const BINS: usize = 512;
+    const MIN_LOG: f64 = -20.0;
+    const MAX_LOG: f64 = 20.0;
+
+    let mut histogram = [0usize; BINS];
+    let mut count = 0;
+    for c in pixels {
+        let lumi = c.luminance();
+        if lumi.is_nan() || lumi <= 0.0 {
+            continue;
+        }
+
+        let x = (lumi.log2() - MIN_LOG) / (MAX_LOG - MIN_LOG);
+        histogram[((x * BINS as f64) as usize).min(BINS - 1)] += 1;
+        count += 1;
+    }
+    if count == 0 {
+        return None;
+    }
+
+    let target = (percentile.clamp(0.0, 1.0) * count as f64).ceil().max(1.0) as usize;
+    let mut accumulated = 0;
+    for (i, n) in histogram.iter().enumerate() {
+        accumulated += n;
+        if accumulated >= target {
+            // ビンの中央の値
+            let log = MIN_LOG + (i as f64 + 0.5) / BINS as f64 * (MAX_LOG - MIN_LOG);
+            return Some(log.exp2());
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tone_mapping_is_monotone_and_bounded() {
+        for tm in &[
+            ToneMapping::Reinhard { white: 4.0 },
+            ToneMapping::Aces,
+            ToneMapping::Hable,
+            ToneMapping::Agx,
+        ] {
+            let mut prev = -1.0;
+            for i in 0..=200 {
+                let x = (i as f64 / 10.0 - 10.0).exp2();
+                let y = tm.apply(Color::new(x, x, x)).luminance();
+                assert!(
+                    y >= prev - 1e-9 && y <= 1.0 + 1e-6,
+                    "{:?}: {} -> {}",
+                    tm,
+                    x,
+                    y
+                );
+                prev = y;
+            }
+            assert!(tm.apply(Color::black()).luminance() < 0.01, "{:?}", tm);
+        }
+
+        let white = ToneMapping::Reinhard { white: 4.0 }.apply(Color::new(4.0, 4.0, 4.0));
+        assert!((white.luminance() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn agx_maps_middle_gray_to_half_in_srgb() {
+        let gray = ToneMapping::Agx.apply(Color::new(0.18, 0.18, 0.18));
+        for i in 0..3 {
+            let encoded = TransferFunction::Srgb.encode(gray.channel(i));
+            assert!((encoded - 0.4967).abs() < 1e-3, "{:?}", gray);
+        }
+    }
+
+    #[test]
+    fn luminance_percentile_ignores_outliers() {
+        let mut pixels = vec![Color::new(0.5, 0.5, 0.5); 99];
+        pixels.push(Color::new(1e6, 1e6, 1e6));
+        pixels.push(Color::black());
+
+        let median = luminance_percentile(&pixels, 0.5).unwrap();
+        assert!((median / 0.5 - 1.0).abs() < 0.05, "{}", median);
+        assert!(luminance_percentile(&[Color::black()], 0.5).is_none());
+    }
+
+    #[test]
+    fn parse_tone_mapping_and_exposure() {
+        assert_eq!("agx".parse::<ToneMapping>(), Ok(ToneMapping::Agx));
+        assert_eq!(
+            "reinhard:2".parse::<ToneMapping>(),
+            Ok(ToneMapping::Reinhard { white: 2.0 })
+        );
+        assert!("foo".parse::<ToneMapping>().is_err());
+        assert_eq!("-1.5".parse::<Exposure>(), Ok(Exposure::Fixed(-1.5)));
+        assert_eq!(
+            "auto:0.9".parse::<Exposure>(),
+            Ok(Exposure::Auto {
+                percentile: 0.9,
+                key: 0.18
+            })
+        );
+    }
+}
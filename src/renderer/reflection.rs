@@ -1,5 +1,5 @@
-use crate::renderer::HitRecord;
-use crate::wrapper::{ray::Ray, vec::V3U};
+use crate::renderer::{uniform_random, HitRecord, Medium};
+use crate::wrapper::{color::Color, ray::Ray, vec::V3U};
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct PhongParameter {
@@ -22,53 +22,155 @@ impl PhongParameter {
         (self.exponent as f64 + 2.0) * cosine_value.powi(self.exponent)
             / (2.0 * std::f64::consts::PI)
     }
+
+    // 拡散成分と鏡面反射方向周りのローブを反射率の確率で選ぶ場合のPDF
+    // (吸収される確率の分だけ全体の積分は1より小さい)
+    pub fn sampling_pdf(&self, cos_theta: f64, cos_alpha: f64) -> f64 {
+        self.diffuse_reflectivity * cos_theta / std::f64::consts::PI
+            + self.specular_reflectivity
+                * (self.exponent as f64 + 1.0)
+                * cos_alpha.powi(self.exponent)
+                / (2.0 * std::f64::consts::PI)
+    }
 }
 
+// 波長(nm)によって変わる屈折率
 #[derive(Clone, PartialEq, Debug)]
+pub enum RefractiveIndex {
+    Constant(f64),
+    // n = a + b / λ^2 (λはμm)
+    Cauchy { a: f64, b: f64 },
+    // n^2 = 1 + Σ b_i λ^2 / (λ^2 - c_i) (λはμm)
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+impl RefractiveIndex {
+    // BK7ガラス
+    pub fn bk7() -> Self {
+        RefractiveIndex::Sellmeier {
+            b: [1.03961212, 0.231792344, 1.01046945],
+            c: [0.00600069867, 0.0200179144, 103.560653],
+        }
+    }
+
+    pub fn at(&self, wavelength: f64) -> f64 {
+        let l2 = (wavelength / 1000.0).powi(2);
+        match self {
+            RefractiveIndex::Constant(n) => *n,
+            RefractiveIndex::Cauchy { a, b } => a + b / l2,
+            RefractiveIndex::Sellmeier { b, c } => {
+                (1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f64>()).sqrt()
+            }
+        }
+    }
+}
+
+// 表面下散乱(ランダムウォーク)のパラメータ
+// 表面はRefractionと同じ誘電体の境界で, 内部は均質な媒質として散乱させる
+#[derive(Clone, PartialEq, Debug)]
+pub struct SubsurfaceParameter {
+    medium: Medium,
+}
+
+impl SubsurfaceParameter {
+    // albedoは多重散乱した結果の見た目の反射率, mean_free_pathは平均自由行程(チャンネルごと)
+    pub fn new(albedo: Color, mean_free_path: Color, g: f64) -> Self {
+        // 見た目の反射率から一回散乱のアルベドへの変換(Christensen-Burleyのフィッティング)
+        let single_scattering_albedo = albedo.map(|a| {
+            let a = a.clamp(0.0, 0.999);
+            let s = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+            1.0 - s * s
+        });
+        let sigma_t = mean_free_path.map(|l| 1.0 / l.max(1e-6));
+        let sigma_s = sigma_t.blend(single_scattering_albedo);
+
+        SubsurfaceParameter {
+            medium: Medium {
+                sigma_a: sigma_t.blend(single_scattering_albedo.map(|a| 1.0 - a)),
+                sigma_s,
+                g,
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn medium(&self) -> &Medium {
+        &self.medium
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
 pub enum Reflection {
+    #[default]
     Diffuse,
     Specular,
     Refraction,
     Glossy(f64),           // primitive glossy surface
     Phong(PhongParameter), // glossy surface based on Phong model
+    Transparent,           // 光を曲げずに素通りさせる面(媒質の容れ物)
+    // 表面下散乱, 内部の散乱はPathTracerでのみ扱い, 他の手法ではRefractionとみなす
+    Subsurface(SubsurfaceParameter),
+    // 屈折率を指定したRefraction, スペクトルモード以外ではREFERENCE_WAVELENGTHでの屈折率を使う
+    Dielectric(RefractiveIndex),
 }
 
-impl Default for Reflection {
-    fn default() -> Self {
-        Reflection::Diffuse
-    }
+const EPS: f64 = 0.0001;
+// Refractionの物体の屈折率(外側は真空)
+const REFRACTIVE_INDEX: f64 = 1.5;
+// 波長を追跡しない場合に屈折率を決める波長(ヘリウムのd線)
+pub const REFERENCE_WAVELENGTH: f64 = 587.6;
+
+// 経路が運ぶ量, 屈折での放射輝度のスケーリングは放射輝度にのみ掛かる
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TransportMode {
+    Radiance,   // カメラからたどる経路
+    Importance, // 光源からたどる経路
 }
 
-const EPS: f64 = 0.0001;
+// 双方向の手法のためのBSDFサンプリングの結果
+#[derive(Clone, Debug)]
+pub struct BsdfSample {
+    pub dir: V3U,
+    pub weight: f64,    // BSDF*|cos(θ)|/PDF (反射率は別)
+    pub pdf_value: f64, // 立体角測度のPDF, デルタ分布では0
+    pub is_delta: bool,
+}
 
 #[derive(Default)]
 pub struct Reflected {
     pub ray: Ray,
     pub contribution: f64,
     pub weight: f64, // BSDF*cos(θ)/PDFの値(ただし反射率は別で計算される(データの持ち方の都合上…))
-    pub pdf_value: f64, // 計算に使用したPDFの値、MISのweight計算用
 }
 
 impl Reflected {
-    pub fn new(ray: Ray, weight: f64, pdf_value: f64) -> Reflected {
+    pub fn new(ray: Ray, weight: f64) -> Reflected {
         Reflected {
             ray,
             contribution: 1.0,
             weight,
-            pdf_value,
         }
     }
 }
 
 impl Reflection {
+    // 物体の内側の屈折率
+    pub fn refractive_index(&self, wavelength: f64) -> f64 {
+        match self {
+            Reflection::Dielectric(ior) => ior.at(wavelength),
+            _ => REFRACTIVE_INDEX,
+        }
+    }
+
+    // 屈折の向きが波長によって変わるかどうか
+    pub fn is_dispersive(&self) -> bool {
+        matches!(self, Reflection::Dielectric(ior) if !matches!(ior, RefractiveIndex::Constant(_)))
+    }
+
     pub fn is_nee_target(&self) -> bool {
         use Reflection::*;
 
-        match self {
-            Diffuse => true,
-            Phong(_) => true,
-            _ => false,
-        }
+        matches!(self, Diffuse | Phong(_))
     }
 
     // NEE用に3点経路上のBSDFを計算する(反射率は除く)
@@ -77,14 +179,167 @@ impl Reflection {
 
         match self {
             Diffuse => 1.0 / std::f64::consts::PI,
-            Phong(params) => params.bsdf(hit.reflected_dir(ray.dir).dot(&light_dir)),
+            Phong(params) => params.bsdf(hit.reflected_dir(ray.dir).dot(&light_dir).max(0.0)),
             Specular => 0.0,
             Refraction => 0.0,
-            _ => unimplemented!(),
+            Transparent => 0.0,
+            Subsurface(_) => 0.0,
+            Dielectric(_) => 0.0,
+            // NEEの対象ではない(BSDFサンプリングだけで扱う)
+            Glossy(_) => 0.0,
+        }
+    }
+
+    // BSDFの値(反射率は除く), woは視点側, wiは光源側の向きでどちらも面から離れる向き
+    // デルタ分布を持つ反射では0
+    pub fn eval(&self, wo: &V3U, wi: &V3U, normal: &V3U) -> f64 {
+        use Reflection::*;
+
+        // 透過はしない
+        if wo.dot(normal) * wi.dot(normal) <= 0.0 {
+            return 0.0;
+        }
+
+        match self {
+            Diffuse => 1.0 / std::f64::consts::PI,
+            Phong(params) => params.bsdf(mirror_dir(wo, normal).dot(wi).max(0.0)),
+            // サンプリングのpdfをcos(θ)で割ったものをBSDFとする(weightは1)
+            Glossy(r) if *r > 0.0 => self.pdf(wo, wi, normal) / wi.dot(normal).abs(),
+            _ => 0.0,
+        }
+    }
+
+    // sample_bsdfでwoからwiが選ばれる確率密度(立体角測度)
+    pub fn pdf(&self, wo: &V3U, wi: &V3U, normal: &V3U) -> f64 {
+        use Reflection::*;
+
+        if wo.dot(normal) * wi.dot(normal) <= 0.0 {
+            return 0.0;
+        }
+
+        match self {
+            Diffuse => wi.dot(normal).abs() / std::f64::consts::PI,
+            Phong(params) => params.sampling_pdf(
+                wi.dot(normal).abs(),
+                mirror_dir(wo, normal).dot(wi).max(0.0),
+            ),
+            Glossy(r) if *r > 0.0 => {
+                let normal = if wo.dot(normal) >= 0.0 {
+                    *normal
+                } else {
+                    normal.neg()
+                };
+                glossy_pdf(*r, wo, wi, &normal)
+            }
+            _ => 0.0,
+        }
+    }
+
+    // BSDFが立体角測度の密度を持つ(デルタ分布でない)かどうか
+    fn has_density(&self) -> bool {
+        match self {
+            Reflection::Diffuse | Reflection::Phong(_) => true,
+            Reflection::Glossy(r) => *r > 0.0,
+            _ => false,
+        }
+    }
+
+    pub fn sample_bsdf(
+        &self,
+        wo: &V3U,
+        hit: &HitRecord,
+        mode: TransportMode,
+    ) -> Option<BsdfSample> {
+        // 法線はwoの側に向ける
+        let normal = if wo.dot(&hit.normal) >= 0.0 {
+            hit.normal
+        } else {
+            hit.normal.neg()
+        };
+        let (u, v) = normal.orthonormal_basis();
+
+        match self {
+            _ if self.has_density() => {
+                let dir = match self {
+                    Reflection::Phong(params) => {
+                        let xi = uniform_random();
+                        if xi < params.diffuse_reflectivity {
+                            sample_cosine_hemisphere(&normal, &u, &v)
+                        } else if xi < params.diffuse_reflectivity + params.specular_reflectivity {
+                            let r = mirror_dir(wo, &normal);
+                            let (ru, rv) = r.orthonormal_basis();
+                            let cos_alpha =
+                                uniform_random().powf(1.0 / (params.exponent as f64 + 1.0));
+                            let sin_alpha = (1.0 - cos_alpha * cos_alpha).max(0.0).sqrt();
+                            let phi = 2.0 * std::f64::consts::PI * uniform_random();
+                            V3U::from_v3(
+                                ru.scale(sin_alpha * phi.cos())
+                                    + rv.scale(sin_alpha * phi.sin())
+                                    + r.scale(cos_alpha),
+                            )
+                        } else {
+                            // 吸収
+                            return None;
+                        }
+                    }
+                    // 鏡面反射方向に, 法線周りのcosine分布の方向をr倍して足す
+                    // 面の裏に向いたものはpdfが0になり吸収される
+                    Reflection::Glossy(r) => V3U::from_v3(
+                        mirror_dir(wo, &normal).as_v3()
+                            + sample_cosine_hemisphere(&normal, &u, &v).scale(*r),
+                    ),
+                    _ => sample_cosine_hemisphere(&normal, &u, &v),
+                };
+
+                let pdf_value = self.pdf(wo, &dir, &normal);
+                if pdf_value <= 0.0 {
+                    return None;
+                }
+
+                Some(BsdfSample {
+                    dir,
+                    weight: self.eval(wo, &dir, &normal) * dir.dot(&normal).abs() / pdf_value,
+                    pdf_value,
+                    is_delta: false,
+                })
+            }
+            _ => {
+                // デルタ分布を持つものは経路追跡と同じ方法でサンプリングする
+                let reflected = self.reflected(
+                    &Ray {
+                        origin: hit.position,
+                        dir: wo.neg(),
+                    },
+                    hit,
+                );
+                let mut weight = reflected.contribution * reflected.weight;
+
+                // 屈折による放射輝度のスケーリングは重要度の輸送には掛からない
+                if matches!(self, Reflection::Refraction | Reflection::Dielectric(_))
+                    && mode == TransportMode::Importance
+                    && reflected.ray.dir.dot(&hit.normal) < 0.0
+                {
+                    let n = self.refractive_index(REFERENCE_WAVELENGTH);
+                    let nnt = if hit.is_into { 1.0 / n } else { n };
+                    weight /= nnt * nnt;
+                }
+
+                Some(BsdfSample {
+                    dir: reflected.ray.dir,
+                    weight,
+                    pdf_value: 0.0,
+                    is_delta: true,
+                })
+            }
         }
     }
 
     pub fn reflected(&self, ray: &Ray, hit: &HitRecord) -> Reflected {
+        self.reflected_at(ray, hit, REFERENCE_WAVELENGTH)
+    }
+
+    // 波長wavelength(nm)の光としての反射, 屈折率の波長依存性にのみ影響する
+    pub fn reflected_at(&self, ray: &Ray, hit: &HitRecord, wavelength: f64) -> Reflected {
         let specular_ray = Ray {
             origin: hit.position,
             dir: hit.reflected_dir(ray.dir),
@@ -101,8 +356,8 @@ impl Reflection {
 
         let diffuse_ray = {
             // 半球に沿ったimportance sampling
-            let r1 = 2.0 * std::f64::consts::PI * rand::random::<f64>();
-            let r2 = rand::random::<f64>();
+            let r1 = 2.0 * std::f64::consts::PI * uniform_random();
+            let r2 = uniform_random();
             let r2s = r2.sqrt();
 
             Ray {
@@ -117,44 +372,21 @@ impl Reflection {
             Reflection::Diffuse => {
                 // Diffuseでは入射角のcosine値/πに沿ったimportance samplingを行っているのでそれがpdfとなる
                 // Diffuse面でのBSDFはρ/πでpdfはcos(θ)/πなのでweight = BSDF・cos(θ)/ρ・pdf = 1
-                Reflected::new(diffuse_ray, 1.0, ray.dir.dot(&hit.normal).abs())
-            }
-            Reflection::Specular => {
-                // specular面の場合はデルタ関数が出てくるがここでは適当な巨大な数にしておく
-                Reflected::new(
-                    specular_ray,
-                    1.0,
-                    1000000.0 / ray.dir.dot(&hit.normal).abs(),
-                )
-            }
-            Reflection::Glossy(r) => {
-                let mut specular_ray_mut = specular_ray;
-                specular_ray_mut.dir =
-                    V3U::from_v3(specular_ray_mut.dir.as_v3() + diffuse_ray.dir.as_v3().scale(*r));
-
-                let specular_angle_cosine = specular_ray_mut.dir.dot(&hit.normal).abs();
-
-                // これはウソ(scatter rayの選び方が適当なのでpdfよくわからないがspecular rayとのcosine値から適当に計算しておく)
-                Reflected::new(
-                    specular_ray_mut,
-                    1.0 / specular_angle_cosine,
-                    1.0 / specular_angle_cosine,
-                )
+                Reflected::new(diffuse_ray, 1.0)
             }
-            Reflection::Refraction => {
+            // 粗さが0のGlossyも鏡面反射
+            Reflection::Specular => Reflected::new(specular_ray, 1.0),
+            Reflection::Glossy(r) if *r <= 0.0 => Reflected::new(specular_ray, 1.0),
+            Reflection::Refraction | Reflection::Subsurface(_) | Reflection::Dielectric(_) => {
                 let nc = 1.0; // 真空の屈折率
-                let nt = 1.5; // このオブジェクトの屈折率
+                let nt = self.refractive_index(wavelength); // このオブジェクトの屈折率
                 let nnt = if hit.is_into { nc / nt } else { nt / nc };
                 let d = ray.dir.dot(&hit.normal);
                 let cos2t = 1.0 - nnt * nnt * (1.0 - d * d);
 
                 // 全反射
                 if cos2t < 0.0 {
-                    return Reflected::new(
-                        specular_ray,
-                        1.0,
-                        1000000.0 / ray.dir.dot(&hit.normal).abs(),
-                    );
+                    return Reflected::new(specular_ray, 1.0);
                 }
 
                 let refraction_ray = Ray {
@@ -179,11 +411,16 @@ impl Reflection {
                 // 反射光の寄与
                 let re = r0 + (1.0 - r0) * c.powf(5.0);
                 // 屈折光の寄与
-                let tr = (1.0 - re) * nnt.powf(2.0);
+                // 表面下散乱では出るときに屈折しないので, 入るときも放射輝度をスケーリングしない
+                let tr = if let Reflection::Subsurface(_) = self {
+                    1.0 - re
+                } else {
+                    (1.0 - re) * nnt.powf(2.0)
+                };
 
                 // Russian Roulette
                 let q = 0.25 + 0.5 * re;
-                let r = rand::random::<f64>();
+                let r = uniform_random();
 
                 // 反射
                 if r < q {
@@ -191,60 +428,309 @@ impl Reflection {
                         ray: specular_ray,
                         contribution: re,
                         weight: 1.0 / q,
-                        pdf_value: 1000000.0 / ray.dir.dot(&hit.normal).abs(),
                     }
                 } else {
                     Reflected {
                         ray: refraction_ray,
                         contribution: tr,
                         weight: 1.0 / (1.0 - q),
-                        pdf_value: 1000000.0 / ray.dir.dot(&hit.normal).abs(),
                     }
                 }
             }
-            Reflection::Phong(params) => {
-                // diffuseをとるかspecularをとるかをランダムに決定する(contributionがない場合もある)
-                if params.diffuse_reflectivity + params.specular_reflectivity > 1.0 {
-                    unreachable!()
+            Reflection::Transparent => Reflected::new(
+                Ray {
+                    origin: hit.position,
+                    dir: ray.dir,
+                },
+                1.0,
+            ),
+            // BDPTと同じサンプリング
+            // Phongは拡散成分と鏡面反射方向周りのローブを反射率の確率で選ぶ
+            Reflection::Phong(_) | Reflection::Glossy(_) => {
+                match self.sample_bsdf(&ray.dir.neg(), hit, TransportMode::Radiance) {
+                    Some(sample) => Reflected::new(
+                        Ray {
+                            origin: hit.position,
+                            dir: sample.dir,
+                        },
+                        sample.weight,
+                    ),
+                    None => Reflected {
+                        contribution: 0.0,
+                        weight: 0.0,
+                        ..Default::default()
+                    },
                 }
+            }
+        }
+    }
+}
 
-                let xi = rand::random::<f64>();
-                if xi < params.diffuse_reflectivity {
-                    Reflected {
-                        ray: diffuse_ray,
-                        contribution: params.diffuse_reflectivity,
-                        weight: 1.0,
-                        pdf_value: ray.dir.dot(&hit.normal).abs(),
-                    }
-                } else if xi < params.diffuse_reflectivity + params.specular_reflectivity {
-                    // specular lobe sampling
-                    let r1 = 2.0 * std::f64::consts::PI * rand::random::<f64>();
-                    let r2 = rand::random::<f64>();
-                    let t = r2.powf(1.0 / (params.exponent as f64 + 1.0));
-
-                    let phong_reflect_dir = V3U::from_v3(
-                        u.scale(r1.cos() * (1.0 - t).sqrt())
-                            + v.scale(r1.sin() * (1.0 - t).sqrt())
-                            + w.scale(t.sqrt()),
+// 法線normalに関するwoの鏡面反射方向
+fn mirror_dir(wo: &V3U, normal: &V3U) -> V3U {
+    V3U::from_v3(normal.scale(2.0 * normal.dot(wo)) - wo.as_v3())
+}
+
+// Glossyのサンプリングで選ばれる方向のpdf
+// 鏡面反射方向mを中心とする半径rの球面上の点m + r dを, 原点から見た方向の密度に直す
+// (wiの向きに距離tの点が球面上にあるとき, 立体角の比はt^2 / (r^2 |d・wi|))
+fn glossy_pdf(r: f64, wo: &V3U, wi: &V3U, normal: &V3U) -> f64 {
+    let m = mirror_dir(wo, normal);
+    let b = wi.dot(&m);
+    let discriminant = b * b - 1.0 + r * r;
+    if discriminant <= 0.0 {
+        return 0.0;
+    }
+
+    let root = discriminant.sqrt();
+    [b - root, b + root]
+        .iter()
+        .copied()
+        .filter(|t| *t > 0.0)
+        .map(|t| {
+            // dの法線とのcosine
+            let cosine = (t * wi.dot(normal) - m.dot(normal)) / r;
+            cosine.max(0.0) / std::f64::consts::PI * t * t / (r * root)
+        })
+        .sum()
+}
+
+fn sample_cosine_hemisphere(w: &V3U, u: &V3U, v: &V3U) -> V3U {
+    let r1 = 2.0 * std::f64::consts::PI * uniform_random();
+    let r2 = uniform_random();
+    let r2s = r2.sqrt();
+
+    V3U::from_v3(u.scale(r1.cos() * r2s) + v.scale(r1.sin() * r2s) + w.scale((1.0 - r2).sqrt()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::{with_sampler, SeededSampler};
+    use crate::wrapper::vec::V3;
+
+    const COS_BINS: usize = 10;
+    const PHI_BINS: usize = 20;
+    // 格子の1つのセルでpdfを積分するときの分割数
+    const SUBDIVISIONS: usize = 64;
+
+    // 方向を法線(z軸)からのcos θとφの格子に分けた頻度と, pdfをセルごとに積分した期待度数を比べる
+    // 吸収されたサンプルは最後のセルに数え, その期待度数はpdfの積分の残り
+    fn chi_square_test(
+        name: &str,
+        pdf: impl Fn(&V3U) -> f64,
+        mut sample: impl FnMut() -> Option<V3U>,
+    ) {
+        let n = 200000;
+        let cell = |dir: &V3U| {
+            let c = (((dir.dot(&V3U::unit_z()) + 1.0) / 2.0 * COS_BINS as f64) as usize)
+                .min(COS_BINS - 1);
+            let phi = dir.y().atan2(dir.x()) + std::f64::consts::PI;
+            let p =
+                ((phi / (2.0 * std::f64::consts::PI) * PHI_BINS as f64) as usize).min(PHI_BINS - 1);
+            c * PHI_BINS + p
+        };
+
+        let mut observed = vec![0.0; COS_BINS * PHI_BINS + 1];
+        with_sampler(SeededSampler::new(7), || {
+            for _ in 0..n {
+                match sample() {
+                    Some(dir) => observed[cell(&dir)] += 1.0,
+                    None => observed[COS_BINS * PHI_BINS] += 1.0,
+                }
+            }
+        });
+
+        let (dc, dp) = (
+            2.0 / COS_BINS as f64,
+            2.0 * std::f64::consts::PI / PHI_BINS as f64,
+        );
+        let mut expected = (0..COS_BINS * PHI_BINS)
+            .map(|i| {
+                let (c0, p0) = (
+                    (i / PHI_BINS) as f64 * dc - 1.0,
+                    (i % PHI_BINS) as f64 * dp - std::f64::consts::PI,
+                );
+                let mut integral = 0.0;
+                for j in 0..SUBDIVISIONS * SUBDIVISIONS {
+                    let c = c0 + ((j / SUBDIVISIONS) as f64 + 0.5) * dc / SUBDIVISIONS as f64;
+                    let p = p0 + ((j % SUBDIVISIONS) as f64 + 0.5) * dp / SUBDIVISIONS as f64;
+                    let s = (1.0 - c * c).max(0.0).sqrt();
+                    integral += pdf(&V3U::from_v3(V3::new(s * p.cos(), s * p.sin(), c)));
+                }
+                integral * dc * dp / (SUBDIVISIONS * SUBDIVISIONS) as f64 * n as f64
+            })
+            .collect::<Vec<_>>();
+        let absorbed = n as f64 - expected.iter().sum::<f64>();
+        expected.push(absorbed.max(0.0));
+
+        // 期待度数の小さいセルはまとめる
+        let (mut statistic, mut cells) = (0.0, 0);
+        let (mut pooled_observed, mut pooled_expected) = (0.0, 0.0);
+        for (o, e) in observed.iter().zip(&expected) {
+            if *e < 5.0 {
+                pooled_observed += o;
+                pooled_expected += e;
+            } else {
+                statistic += (o - e).powi(2) / e;
+                cells += 1;
+            }
+        }
+        if pooled_expected > 0.0 {
+            statistic += (pooled_observed - pooled_expected).powi(2) / pooled_expected;
+            cells += 1;
+        } else {
+            assert_eq!(pooled_observed, 0.0, "{}: samples where the pdf is 0", name);
+        }
+
+        // Wilson-Hilfertyの近似による有意水準0.01%の棄却限界
+        let dof = (cells - 1) as f64;
+        let z = 3.719;
+        let critical = dof * (1.0 - 2.0 / (9.0 * dof) + z * (2.0 / (9.0 * dof)).sqrt()).powi(3);
+        assert!(
+            statistic < critical,
+            "{}: chi-square {} exceeds {} ({} dof)",
+            name,
+            statistic,
+            critical,
+            dof
+        );
+    }
+
+    fn hit(normal: V3U) -> HitRecord {
+        HitRecord {
+            distance: 1.0,
+            position: V3::zero(),
+            normal,
+            is_into: true,
+        }
+    }
+
+    #[test]
+    fn sampling_matches_pdf() {
+        let normal = V3U::unit_z();
+        let phong = Reflection::Phong(PhongParameter {
+            diffuse_reflectivity: 0.3,
+            specular_reflectivity: 0.5,
+            exponent: 10,
+        });
+
+        let (normal_incidence, oblique) = (V3::new(0.0, 0.0, 1.0), V3::new(0.8, 0.3, 0.5));
+
+        // 粗さが1未満のGlossyを斜めから見ると, pdfが鏡面反射方向を囲む円錐の縁で(積分可能な範囲で)発散し
+        // セルごとの積分が合わなくなるので正面からだけ調べる
+        for (name, reflection, wos) in [
+            (
+                "diffuse",
+                Reflection::Diffuse,
+                vec![normal_incidence, oblique],
+            ),
+            ("phong", phong, vec![normal_incidence, oblique]),
+            ("glossy", Reflection::Glossy(0.5), vec![normal_incidence]),
+            (
+                "rough glossy",
+                Reflection::Glossy(1.5),
+                vec![normal_incidence, oblique],
+            ),
+        ] {
+            for wo in wos {
+                let wo = V3U::from_v3(wo);
+                let pdf = |wi: &V3U| reflection.pdf(&wo, wi, &normal);
+
+                chi_square_test(&format!("{} sample_bsdf", name), pdf, || {
+                    reflection
+                        .sample_bsdf(&wo, &hit(normal), TransportMode::Radiance)
+                        .map(|s| s.dir)
+                });
+                chi_square_test(&format!("{} reflected", name), pdf, || {
+                    let reflected = reflection.reflected(
+                        &Ray {
+                            origin: wo.as_v3(),
+                            dir: wo.neg(),
+                        },
+                        &hit(normal),
                     );
+                    Some(reflected.ray.dir).filter(|_| reflected.contribution > 0.0)
+                });
+            }
+        }
+    }
 
-                    Reflected {
-                        ray: Ray {
-                            origin: hit.position,
-                            dir: phong_reflect_dir,
+    #[test]
+    fn weights_agree_with_bsdf_over_pdf() {
+        let normal = V3U::unit_z();
+        let wo = V3U::from_v3(V3::new(0.3, 0.0, 1.0));
+        let phong = Reflection::Phong(PhongParameter {
+            diffuse_reflectivity: 0.3,
+            specular_reflectivity: 0.5,
+            exponent: 10,
+        });
+
+        with_sampler(SeededSampler::new(3), || {
+            for reflection in [Reflection::Diffuse, phong, Reflection::Glossy(0.3)] {
+                for _ in 0..1000 {
+                    let r = reflection.reflected(
+                        &Ray {
+                            origin: wo.as_v3(),
+                            dir: wo.neg(),
                         },
-                        contribution: params.specular_reflectivity,
-                        weight: phong_reflect_dir.dot(&hit.normal),
-                        pdf_value: params.specular_pdf(r2),
+                        &hit(normal),
+                    );
+                    if r.contribution <= 0.0 {
+                        continue;
                     }
-                } else {
-                    Reflected {
-                        contribution: 0.0,
-                        weight: 0.0,
-                        ..Default::default()
+                    let wi = r.ray.dir;
+                    let expected = reflection.eval(&wo, &wi, &normal) * wi.dot(&normal)
+                        / reflection.pdf(&wo, &wi, &normal);
+                    assert!((r.contribution * r.weight - expected).abs() < 1e-9 * expected);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn delta_reflections_have_no_density() {
+        let normal = V3U::unit_z();
+        let wo = V3U::from_v3(V3::new(0.6, 0.0, 0.8));
+        let mirror = V3::new(-0.6, 0.0, 0.8);
+
+        with_sampler(SeededSampler::new(5), || {
+            for reflection in [
+                Reflection::Specular,
+                Reflection::Glossy(0.0),
+                Reflection::Refraction,
+                Reflection::Dielectric(RefractiveIndex::bk7()),
+            ] {
+                for _ in 0..100 {
+                    let sample = reflection
+                        .sample_bsdf(&wo, &hit(normal), TransportMode::Radiance)
+                        .unwrap();
+                    assert!(sample.is_delta, "{:?}", reflection);
+                    assert_eq!(sample.pdf_value, 0.0);
+                    assert_eq!(reflection.eval(&wo, &sample.dir, &normal), 0.0);
+                    assert_eq!(reflection.pdf(&wo, &sample.dir, &normal), 0.0);
+
+                    // 鏡面反射方向か, スネルの法則を満たす屈折方向
+                    let dir = sample.dir.as_v3();
+                    if dir.z() > 0.0 {
+                        assert!((dir - mirror).len() < 1e-9, "{:?}: {:?}", reflection, dir);
+                    } else {
+                        assert!(!matches!(
+                            reflection,
+                            Reflection::Specular | Reflection::Glossy(_)
+                        ));
+                        let n = reflection.refractive_index(REFERENCE_WAVELENGTH);
+                        let sin_t = (1.0 - dir.z() * dir.z()).sqrt();
+                        assert!(
+                            (0.6 - n * sin_t).abs() < 1e-9,
+                            "{:?}: {:?}",
+                            reflection,
+                            dir
+                        );
+                        assert!(dir.x() < 0.0);
                     }
                 }
             }
-        }
+        });
     }
 }
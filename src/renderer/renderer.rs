@@ -1,49 +1,91 @@
-use crate::renderer::{picture::Picture, Scene};
+use crate::renderer::{
+    uniform_random, with_sampler, write_encoded_ppm, write_exr, write_pfm, Accumulation,
+    Accumulator, AovLayer, AovOutput, Aovs, Checkpoint, Checkpointer, ColorSpace, DenoiseOutput,
+    Denoiser, Diagnostics, Exposure, Integrator, IntegratorKind, Picture, RadianceClamp,
+    RenderPass, Scene, SceneError, SeededSampler, ToneMapping, TransferFunction,
+};
 use crate::wrapper::{
     color::Color,
     ray::Ray,
     vec::{V3, V3U},
 };
 use rayon::prelude::*;
-use std::fs::File;
+use std::fmt;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct RendererOption {
+    pub integrator: IntegratorKind,
     pub enable_mis: bool,
     pub enable_mis_debug_mode: bool,
     pub mis_power_heuristic: i32,
+    // パストレーサー(とMLT)での寄与の上限
+    pub clamp: RadianceClamp,
 }
 
 pub struct Renderer {
     pub width: i32,
     pub height: i32,
-    pub spp: i32,   // samples per pixel
-    pub gamma: f64, // for gamma correction
+    pub spp: i32, // samples per pixel
+    // シーンの色と描画結果のRGBの色空間
+    pub working_space: ColorSpace,
+    pub tone_mapping: ToneMapping,
+    pub exposure: Exposure,
+    pub transfer_function: TransferFunction,
+    // 8bitに丸めるときにディザをかけるかどうか
+    pub dither: bool,
+    pub accumulation: Accumulation,
+    pub denoise: DenoiseOutput,
+    pub aov: AovOutput,
+    // AOVとして出力する, LPEで振り分けた経路の寄与
+    pub passes: Vec<RenderPass>,
+    // 有限でない放射輝度のサンプルを記録して捨て, 描画の最後に報告する
+    pub diagnostics: bool,
+    // 指定すると乱数を画素とサンプルごと(MLTでは連鎖ごと)にシードから決め, 同じ結果を再現できるようにする
+    pub seed: Option<u64>,
+    // 途中経過を書き出す, 画素ごとに計算する手法でAOVなしの場合のみ
+    pub checkpoint: Option<Checkpoint>,
     pub option: RendererOption,
 }
 
+#[derive(Debug)]
 pub struct Camera {
     pub position: V3,
     pub dir: V3U,
     pub up: V3U,
 }
 
+#[derive(Debug)]
 pub struct Screen {
     pub width: f64,
     pub height: f64,
     pub dist: f64,
 }
 
+#[derive(Debug)]
 pub struct WorldSetting {
     pub camera: Camera,
     pub screen: Screen,
 }
 
-const DEPTH_LIMIT: i32 = 64;
-const DEPTH_MIN: i32 = 5;
+// カメラと画面の設定から求めた, 画素とレイの対応
+pub struct Sensor {
+    pub width: i32,
+    pub height: i32,
+    pub spp: i32,
+    pub working_space: ColorSpace,
+    pub accumulation: Accumulation,
+    pub diagnostics: Option<Arc<Diagnostics>>,
+    pub seed: Option<u64>,
+    pub checkpoint: Option<Checkpointer>,
+    pub position: V3,
+    pub screen_center: V3,
+    pub screen_x: V3,
+    pub screen_y: V3,
+}
 
-impl Renderer {
-    pub fn render(&self, world: &WorldSetting, scene: &Scene) -> Picture {
+impl Sensor {
+    pub fn new(renderer: &Renderer, world: &WorldSetting) -> Self {
         let screen_x = (world.camera.dir.as_v3())
             .cross(world.camera.up.as_v3())
             .normalize()
@@ -54,144 +96,240 @@ impl Renderer {
             .scale(world.screen.height);
         let screen_center = world.camera.position + world.camera.dir.scale(world.screen.dist);
 
-        let mut pixels = Vec::with_capacity((self.width * self.height) as usize);
+        Sensor {
+            width: renderer.width,
+            height: renderer.height,
+            spp: renderer.spp,
+            working_space: renderer.working_space,
+            accumulation: renderer.accumulation,
+            diagnostics: renderer
+                .diagnostics
+                .then(|| Arc::new(Diagnostics::default())),
+            seed: renderer.seed,
+            checkpoint: None,
+            position: world.camera.position,
+            screen_center,
+            screen_x,
+            screen_y,
+        }
+    }
 
-        (0..self.width * self.height)
-            .into_par_iter()
-            .map(move |i| {
-                let mut radience = Color::black();
-                for _ in 0..self.spp {
-                    let x = (i % self.width) as f64;
-                    let y = (self.height - i / self.width - 1) as f64;
+    // i番目の画素内の(r1, r2)の位置を通るレイ
+    pub fn ray(&self, i: i32, r1: f64, r2: f64) -> Ray {
+        let x = (i % self.width) as f64;
+        let y = (self.height - i / self.width - 1) as f64;
 
-                    let r1 = rand::random::<f64>();
-                    let r2 = rand::random::<f64>();
+        let screen_position = self.screen_center
+            + self.screen_x.scale((r1 + x) / self.width as f64 - 0.5)
+            + self.screen_y.scale((r2 + y) / self.height as f64 - 0.5);
 
-                    let screen_position = screen_center
-                        + screen_x.scale((r1 + x) / self.width as f64 - 0.5)
-                        + screen_y.scale((r2 + y) / self.height as f64 - 0.5);
-                    let ray = Ray {
-                        origin: world.camera.position,
-                        dir: V3U::from_v3(screen_position - world.camera.position),
-                    };
+        Ray {
+            origin: self.position,
+            dir: V3U::from_v3(screen_position - self.position),
+        }
+    }
 
-                    radience += self.radience(scene, ray);
-                }
+    pub fn dir(&self) -> V3U {
+        V3U::from_v3(self.screen_center - self.position)
+    }
 
-                radience.scale(1.0 / self.spp as f64)
-            })
-            .collect_into_vec(&mut pixels);
+    // 視点から距離1の位置での画面の面積
+    fn unit_screen_area(&self) -> f64 {
+        let dist2 = (self.screen_center - self.position).len_square();
+        self.screen_x.len() * self.screen_y.len() / dist2
+    }
+
+    // pointが写る画素の番号
+    pub fn raster(&self, point: &V3) -> Option<i32> {
+        let d = *point - self.position;
+        let cos_theta = d.dot(&self.dir().as_v3());
+        if cos_theta <= 0.0 {
+            return None;
+        }
 
-        Picture::new(pixels)
+        let dist = (self.screen_center - self.position).len();
+        let local = self.position + d.scale(dist / cos_theta) - self.screen_center;
+        let u = local.dot(&self.screen_x) / self.screen_x.len_square() + 0.5;
+        let v = local.dot(&self.screen_y) / self.screen_y.len_square() + 0.5;
+        if !(0.0..1.0).contains(&u) || !(0.0..1.0).contains(&v) {
+            return None;
+        }
+
+        let x = ((u * self.width as f64) as i32).min(self.width - 1);
+        let y = ((v * self.height as f64) as i32).min(self.height - 1);
+        Some((self.height - y - 1) * self.width + x)
     }
 
-    fn radience(&self, scene: &Scene, ray: Ray) -> Color {
-        let mut depth = 0;
-        let mut ray = ray;
-        let mut rad = Color::black();
-        let mut path_weight = 1.0;
-        let mut path_color = Color::new(1.0, 1.0, 1.0);
-        let mut reflected_from_specular_ray = false;
-        let mut bsdf_pdf: f64 = 1.0;
+    // カメラの重要度関数 We (画面全体で ∫We cos(θ) dω = 1 となるように正規化)
+    pub fn importance(&self, dir: &V3U) -> f64 {
+        let cos_theta = dir.dot(&self.dir());
+        if cos_theta <= 0.0 || self.raster(&(self.position + dir.as_v3())).is_none() {
+            return 0.0;
+        }
+
+        1.0 / (self.unit_screen_area() * cos_theta.powi(4))
+    }
 
-        while let Some((hit, target)) = scene.intersect(&ray) {
-            if (!self.option.enable_mis || (reflected_from_specular_ray || depth == 0))
-                && target.emission > Color::black()
-            {
-                rad += target.emission.scale(path_weight).blend(path_color);
+    // カメラからのレイの向きのpdf(立体角測度)
+    pub fn pdf_dir(&self, dir: &V3U) -> f64 {
+        let cos_theta = dir.dot(&self.dir());
+        if cos_theta <= 0.0 || self.raster(&(self.position + dir.as_v3())).is_none() {
+            return 0.0;
+        }
+
+        1.0 / (self.unit_screen_area() * cos_theta.powi(3))
+    }
+
+    // i番目の画素のsample番目のサンプルを求める間, seedがあれば乱数をseedと画素とサンプルの番号から決める
+    pub fn with_sample_sampler<R>(&self, i: i32, sample: i32, f: impl FnOnce() -> R) -> R {
+        match self.seed {
+            Some(seed) => with_sampler(SeededSampler::for_sample(seed, i, sample), f).0,
+            None => f(),
+        }
+    }
+
+    // i番目の画素のサンプルを求める, 診断モードでは有限でないものを記録してNoneを返す
+    pub fn sample<T>(&self, i: i32, sample: impl FnOnce() -> (Color, T)) -> Option<(Color, T)> {
+        match &self.diagnostics {
+            Some(diagnostics) => diagnostics.check(i, sample),
+            None => Some(sample()),
+        }
+    }
+
+    // 描画の終わりに, 残った有限でない画素を黒にして報告を出す
+    fn finish(&self, picture: Picture) -> Picture {
+        match &self.diagnostics {
+            Some(diagnostics) => {
+                let picture = diagnostics.check_picture(picture);
+                eprintln!("{}", diagnostics.summary(self.width));
+                picture
             }
+            None => picture,
+        }
+    }
 
-            if self.option.enable_mis
-                && !(target.emission > Color::black())
-                && target.reflection.is_nee_target()
-            {
-                // NEE (MIS weight)
-                if let Some((sample, light)) = scene.sample_on_lights() {
-                    // 衝突点から光源点への向き
-                    let shadow_dir = V3U::from_v3(sample.point - hit.position);
-                    let object = scene
-                        .intersect(&Ray {
-                            origin: hit.position,
-                            dir: shadow_dir,
-                        })
-                        .unwrap()
-                        .1;
-                    if object == light {
-                        // 幾何項
-                        let g = shadow_dir.dot(&hit.normal).abs()
-                            * shadow_dir.dot(&sample.normal).abs()
-                            / (sample.point - hit.position).len_square();
-                        /*
-                        // 単位をlightのpdfに合わせる
-                        let bsdf_pdf = target.reflection.nee_bsdf_weight(&ray, &hit, shadow_dir)
-                            * cos_light
-                            / dist2;
-                        let mis_weight = sample.pdf_value.powi(self.option.mis_power_heuristic)
-                            / (sample.pdf_value.powi(self.option.mis_power_heuristic)
-                                + bsdf_pdf.powi(self.option.mis_power_heuristic));
-                        */
-
-                        rad += (if self.option.enable_mis_debug_mode {
-                            Color::new(200.0, 0.0, 0.0)
-                        } else {
-                            light.emission
-                        })
-                        .blend(
-                            (target.color)
-                                .scale(target.reflection.nee_bsdf_weight(&ray, &hit, shadow_dir)),
-                        )
-                        .scale(g / sample.pdf_value)
-                        .blend(path_color)
-                        .scale(path_weight);
-                    }
-                }
+    // 画素ごとにspp本のレイを飛ばしてradienceをまとめる(既定では平均)
+    // チェックポイントがあれば, その途中経過から続けてintervalサンプルごとに書き出す
+    pub fn render_per_pixel(&self, radience: impl Fn(Ray) -> Color + Sync) -> Picture {
+        let (mut done, mut pixels) = match self.checkpoint.as_ref().and_then(|c| c.take_resumed()) {
+            Some(resumed) => resumed,
+            None => (
+                0,
+                (0..self.width * self.height)
+                    .map(|_| Accumulator::new(&self.accumulation))
+                    .collect(),
+            ),
+        };
+        let interval = self.checkpoint.as_ref().map_or(self.spp, |c| c.interval());
+
+        while done < self.spp {
+            let end = (done + interval).min(self.spp);
+            pixels.par_iter_mut().enumerate().for_each(|(i, rad)| {
+                let i = i as i32;
+                for sample in done..end {
+                    self.with_sample_sampler(i, sample, || {
+                        let r1 = uniform_random();
+                        let r2 = uniform_random();
 
-                /*
-                // BSDF Sampling (MIS weight)
-                if target.emission > Color::black() {
-                    // 単位をBSDFのpdfに合わせる
-                    let light_pdf = target.area_pdf() * (ray.origin - hit.position).len_square()
-                        / (ray.dir.dot(&hit.normal).cos().abs());
-                    let mis_weight = bsdf_pdf.powi(self.option.mis_power_heuristic)
-                        / (bsdf_pdf.powi(self.option.mis_power_heuristic)
-                            + light_pdf.powi(self.option.mis_power_heuristic));
-
-                    rad += (if self.option.enable_mis_debug_mode {
-                        Color::new(0.0, 200.0, 0.0)
-                    } else {
-                        target.emission
-                    })
-                    .blend(path_color)
-                    .scale(mis_weight)
-                    .scale(path_weight);
+                        if let Some((c, ())) =
+                            self.sample(i, || (radience(self.ray(i, r1, r2)), ()))
+                        {
+                            rad.add(c);
+                        }
+                    });
                 }
-                */
+            });
+            done = end;
+
+            if let (Some(checkpoint), Some(seed)) = (&self.checkpoint, self.seed) {
+                checkpoint.save(self.width, self.height, seed, done, &pixels);
             }
+        }
 
-            // Russian Roulette
-            let r = rand::random::<f64>();
-            let mut rr_threshould = 0.5;
+        Picture::new(pixels.iter().map(|rad| rad.result()).collect())
+    }
+}
 
-            if depth < DEPTH_MIN {
-                rr_threshould = 1.0;
-            } else if r > rr_threshould || depth >= DEPTH_LIMIT {
-                break;
-            }
+// 描画を始められなかった理由
+#[derive(Debug)]
+pub enum RenderError {
+    Scene(SceneError),
+    // チェックポイントを読めない, または設定が違う
+    Checkpoint(std::io::Error),
+    // 積分器が扱えないシーンや設定
+    Unsupported(String),
+}
+
+impl Renderer {
+    // 描画の前にシーンを検証する
+    pub fn render(&self, world: &WorldSetting, scene: &Scene) -> Result<Picture, RenderError> {
+        let integrator = self.option.integrator.build(&self.option);
+        self.render_with(integrator.as_ref(), world, scene)
+    }
+
+    pub fn render_with(
+        &self,
+        integrator: &dyn Integrator,
+        world: &WorldSetting,
+        scene: &Scene,
+    ) -> Result<Picture, RenderError> {
+        let sensor = self.sensor(world, scene, false)?;
+        Ok(sensor.finish(integrator.render(&sensor, scene)))
+    }
 
-            reflected_from_specular_ray = !target.reflection.is_nee_target();
+    pub fn render_aovs(
+        &self,
+        world: &WorldSetting,
+        scene: &Scene,
+    ) -> Result<(Picture, Aovs), RenderError> {
+        let integrator = self.option.integrator.build(&self.option);
+        let sensor = self.sensor(world, scene, true)?;
+        let (picture, aovs) = integrator.render_aovs(&sensor, scene, &self.passes);
+        Ok((sensor.finish(picture), aovs))
+    }
 
-            // 反射
-            let reflected = target.reflection.reflected(&ray, &hit);
-            path_weight = path_weight * reflected.contribution;
-            path_color = path_color
-                .blend(target.color)
-                .scale(reflected.weight / rr_threshould);
-            ray = reflected.ray;
-            depth += 1;
-            bsdf_pdf = reflected.pdf_value;
+    // シーンと設定を検証し, チェックポイントを使うなら再開する途中経過と乱数のシードを持たせる
+    // aovsはAOVも描画するかどうか
+    fn sensor(
+        &self,
+        world: &WorldSetting,
+        scene: &Scene,
+        aovs: bool,
+    ) -> Result<Sensor, RenderError> {
+        scene.validate()?;
+        if let Some(message) = self.unsupported(scene, aovs) {
+            return Err(RenderError::Unsupported(message));
+        }
+
+        let mut sensor = Sensor::new(self, world);
+        if let Some(checkpoint) = &self.checkpoint {
+            let (checkpointer, seed) = Checkpointer::open(self, checkpoint, world, scene)
+                .map_err(RenderError::Checkpoint)?;
+            sensor.checkpoint = Some(checkpointer);
+            sensor.seed = Some(seed);
         }
 
-        rad
+        Ok(sensor)
+    }
+
+    // 積分器が黙って無視してしまうシーンや設定
+    fn unsupported(&self, scene: &Scene, aovs: bool) -> Option<String> {
+        let kind = self.option.integrator;
+        if scene.has_media() && !kind.handles_media() {
+            Some(format!("{:?} does not handle participating media", kind))
+        } else if scene.has_subsurface() && !kind.handles_media() {
+            Some(format!("{:?} does not handle subsurface scattering", kind))
+        } else if self.option.clamp != RadianceClamp::default() && !kind.clamps_radiance() {
+            Some(format!("{:?} does not clamp radiance", kind))
+        } else if self.accumulation != Accumulation::Mean && !kind.is_per_pixel() {
+            Some(format!("{:?} does not accumulate samples per pixel", kind))
+        } else if self.checkpoint.is_some() && !kind.is_per_pixel() {
+            Some(format!("{:?} does not write checkpoints", kind))
+        } else if self.checkpoint.is_some() && aovs {
+            Some("checkpoints are not written with AOVs or denoising".to_string())
+        } else {
+            None
+        }
     }
 
     pub fn write_ppm(
@@ -200,20 +338,116 @@ impl Renderer {
         world: &WorldSetting,
         scene: &Scene,
     ) -> std::io::Result<()> {
-        use std::io::{BufWriter, Write};
+        if self.aov == AovOutput::Off && self.denoise == DenoiseOutput::Off {
+            let picture = self.render(world, scene)?;
+            return self.write_picture(file_path, picture);
+        }
 
-        let mut picture = self.render(world, scene);
-        picture.tone_map();
-        picture.correct_gamma(self.gamma);
+        // デノイズの手がかりもAOVとして描画と同じパスで求める
+        let (picture, aovs) = self.render_aovs(world, scene)?;
+        self.write_aovs(file_path, &picture, &aovs)?;
+        if self.denoise == DenoiseOutput::Off {
+            return self.write_picture(file_path, picture);
+        }
 
-        let mut file = BufWriter::new(File::create(file_path)?);
-        write!(file, "P3\n{} {}\n255\n", self.width, self.height)?;
+        let denoised = Denoiser::default().denoise(&picture, &aovs);
+        match self.denoise {
+            DenoiseOutput::Both => {
+                self.write_picture(file_path, picture)?;
+                self.write_picture(&derived_path(file_path, "denoised", "ppm"), denoised)
+            }
+            _ => self.write_picture(file_path, denoised),
+        }
+    }
+
+    // out.ppmに対してout.<層>.pfmかout.exrに書き出す
+    fn write_aovs(&self, file_path: &str, picture: &Picture, aovs: &Aovs) -> std::io::Result<()> {
+        match self.aov {
+            AovOutput::Off => Ok(()),
+            AovOutput::Separate => {
+                for layer in aovs.layers() {
+                    write_pfm(
+                        &derived_path(file_path, &layer.name, "pfm"),
+                        self.width,
+                        self.height,
+                        &layer,
+                    )?;
+                }
+                Ok(())
+            }
+            AovOutput::Exr => {
+                // 最終的な画像はトーンマッピング前の線形な値
+                let mut layers = vec![AovLayer::color("", picture.pixels())];
+                layers.extend(aovs.layers());
+                write_exr(
+                    &derived_path(file_path, "", "exr"),
+                    self.width,
+                    self.height,
+                    self.working_space,
+                    &layers,
+                )
+            }
+        }
+    }
+
+    fn write_picture(&self, file_path: &str, mut picture: Picture) -> std::io::Result<()> {
+        // 表示用の原色(sRGB/Rec.709)に直してからトーンマッピングする
+        picture.convert(self.working_space, ColorSpace::LinearSrgb);
+        picture.tone_map(&self.tone_mapping, &self.exposure);
+        picture.encode(&self.transfer_function);
+
+        write_encoded_ppm(
+            file_path,
+            self.width,
+            self.height,
+            &picture,
+            &self.transfer_function,
+            self.dither,
+        )
+    }
+}
 
-        for c in picture.as_vec() {
-            let (r, g, b) = c.as_rgb();
-            write!(file, "{} {} {}\n", r, g, b)?;
+impl From<SceneError> for RenderError {
+    fn from(e: SceneError) -> Self {
+        RenderError::Scene(e)
+    }
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderError::Scene(e) => write!(f, "{}", e),
+            RenderError::Checkpoint(e) => write!(f, "{}", e),
+            RenderError::Unsupported(message) => write!(f, "unsupported: {}", message),
         }
+    }
+}
+
+impl std::error::Error for RenderError {}
 
-        Ok(())
+impl From<RenderError> for std::io::Error {
+    fn from(e: RenderError) -> Self {
+        match e {
+            RenderError::Scene(e) => e.into(),
+            RenderError::Checkpoint(e) => e,
+            RenderError::Unsupported(message) => {
+                std::io::Error::new(std::io::ErrorKind::Unsupported, message)
+            }
+        }
     }
 }
+
+// out.ppm -> out.<suffix>.<extension>
+pub fn derived_path(file_path: &str, suffix: &str, extension: &str) -> String {
+    let path = std::path::Path::new(file_path);
+    let stem = path
+        .file_stem()
+        .map_or(file_path.into(), |s| s.to_string_lossy());
+    let name = if suffix.is_empty() {
+        format!("{}.{}", stem, extension)
+    } else {
+        format!("{}.{}.{}", stem, suffix, extension)
+    };
+
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
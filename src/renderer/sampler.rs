@@ -0,0 +1,178 @@
+use std::any::Any;
+use std::cell::RefCell;
+
+// 経路の計算に使う一様乱数の供給元
+// 既定ではスレッドごとの乱数生成器を使うが, with_samplerで差し替えられる
+pub trait Sampler: Any {
+    // [0, 1) の一様乱数
+    fn next_f64(&mut self) -> f64;
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Box<dyn Sampler>>> = RefCell::new(None);
+}
+
+// 経路の計算で使う[0, 1)の一様乱数
+pub fn uniform_random() -> f64 {
+    CURRENT.with(|current| match current.borrow_mut().as_mut() {
+        Some(sampler) => sampler.next_f64(),
+        None => rand::random::<f64>(),
+    })
+}
+
+// with_samplerの前の供給元, fがpanicしても戻す
+struct Restore(Option<Option<Box<dyn Sampler>>>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if let Some(prev) = self.0.take() {
+            CURRENT.with(|current| current.replace(prev));
+        }
+    }
+}
+
+// このスレッドでfを実行する間, uniform_randomの値をsamplerから取る
+pub fn with_sampler<S: Sampler, R>(sampler: S, f: impl FnOnce() -> R) -> (R, S) {
+    let mut restore = Restore(Some(
+        CURRENT.with(|current| current.replace(Some(Box::new(sampler)))),
+    ));
+    let result = f();
+    let prev = restore.0.take().unwrap();
+    let sampler: Box<dyn Any> = CURRENT
+        .with(|current| current.replace(prev))
+        .expect("sampler is removed while in use");
+
+    match sampler.downcast::<S>() {
+        Ok(sampler) => (result, *sampler),
+        Err(_) => unreachable!(),
+    }
+}
+
+// PCG32 (O'Neill 2014)
+// 描画の再現に使う乱数列が依存するクレートの版や環境で変わらないように, アルゴリズムを固定して自前で持つ
+#[derive(Clone, Debug)]
+pub struct Pcg32 {
+    state: u64,
+    increment: u64,
+}
+
+impl Pcg32 {
+    // streamが違えば, 同じseedでも別の乱数列になる
+    pub fn new(seed: u64, stream: u64) -> Self {
+        let mut rng = Pcg32 {
+            state: 0,
+            increment: (stream << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(self.increment);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+
+    // [0, 1) の一様乱数, 2つの出力から53bitを取る
+    pub fn next_f64(&mut self) -> f64 {
+        let high = (self.next_u32() >> 5) as u64;
+        let low = (self.next_u32() >> 6) as u64;
+        ((high << 26) | low) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// 64bitの値を混ぜる(SplitMix64の出力関数)
+fn mix(x: u64) -> u64 {
+    let x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+// シードから決まる乱数列, 描画を再現するのに使う
+#[derive(Clone, Debug)]
+pub struct SeededSampler {
+    rng: Pcg32,
+}
+
+impl SeededSampler {
+    // テストで使う, シードだけで決まる乱数列
+    #[cfg(test)]
+    pub fn new(seed: u64) -> Self {
+        SeededSampler {
+            rng: Pcg32::new(mix(seed), 0),
+        }
+    }
+
+    // 描画のシードと画素とサンプルの番号で決まる乱数列
+    // サンプルごとに独立なので, 描画を途中で区切って再開しても同じ値になる
+    pub fn for_sample(seed: u64, pixel: i32, sample: i32) -> Self {
+        let index = ((pixel as u32 as u64) << 32) | sample as u32 as u64;
+        SeededSampler {
+            rng: Pcg32::new(mix(seed), mix(index)),
+        }
+    }
+}
+
+impl Sampler for SeededSampler {
+    fn next_f64(&mut self) -> f64 {
+        self.rng.next_f64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Sequence(Vec<f64>);
+
+    impl Sampler for Sequence {
+        fn next_f64(&mut self) -> f64 {
+            self.0.remove(0)
+        }
+    }
+
+    #[test]
+    fn with_sampler_replaces_uniform_random() {
+        let (values, rest) = with_sampler(Sequence(vec![0.25, 0.5, 0.75]), || {
+            (uniform_random(), uniform_random())
+        });
+        assert_eq!(values, (0.25, 0.5));
+        assert_eq!(rest.0, vec![0.75]);
+
+        let x = uniform_random();
+        assert!((0.0..1.0).contains(&x));
+    }
+
+    #[test]
+    fn pcg32_matches_the_reference_implementation() {
+        // pcg32-demoのpcg32_srandom(42, 54)の出力
+        let mut rng = Pcg32::new(42, 54);
+        let values = (0..6).map(|_| rng.next_u32()).collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            vec![0xa15c02b7, 0x7b47f409, 0xba1d3330, 0x83d2f293, 0xbfa4784b, 0xcbed606e]
+        );
+
+        let x = rng.next_f64();
+        assert!((0.0..1.0).contains(&x));
+    }
+
+    #[test]
+    fn with_sampler_restores_previous_sampler_on_panic() {
+        let (x, _) = with_sampler(Sequence(vec![0.25]), || {
+            let result = std::panic::catch_unwind(|| {
+                with_sampler(Sequence(vec![]), || {
+                    panic!("path tracing failed");
+                })
+            });
+            assert!(result.is_err());
+            uniform_random()
+        });
+        assert_eq!(x, 0.25);
+    }
+}
@@ -1,30 +1,254 @@
-use crate::renderer::{HitRecord, Object, SampleRecord};
-use crate::wrapper::{color::Color, ray::Ray};
+use crate::renderer::{
+    AliasTable, Emitter, HitRecord, Light, LightBounds, LightEntry, LightSample, LightSampler,
+    LightSelection, Medium, MediumState, Object, Reflection, Sky,
+};
+use crate::wrapper::{
+    aabb::Aabb,
+    color::Color,
+    ray::Ray,
+    vec::{V3, V3U},
+};
+use std::fmt;
 
-#[derive(Clone)]
+mod validate;
+pub use validate::*;
+
+#[derive(Clone, Debug)]
 pub struct Scene {
     objects: Vec<Object>,
+    // オブジェクトごとの材質の番号, 見た目(色, 発光, 反射, 媒質)が同じオブジェクトのうち最初のものの番号とする
+    materials: Vec<usize>,
+    // 発光するオブジェクトの番号
     lights: Vec<usize>,
+    // 形状を持たない光源, 光源の番号はlightsの後に続く
+    delta_lights: Vec<Light>,
+    // どこにも当たらなかったレイが見る環境光, 光源の番号は最後
+    sky: Option<Sky>,
+    light_sampler: LightSampler,
+    // 光源側から経路をたどる手法のための, 放射束に比例した光源の選択
+    emission_table: AliasTable,
+    // シーン全体を満たす媒質, 範囲はset_mediumした時点でのシーンを囲む箱
+    medium: Option<(Medium, Aabb)>,
+    // シーンを囲む球(中心, 半径), 経路ごとに使うので最初に求めておく
+    bounding_sphere: (V3, f64),
 }
 
 impl Scene {
     pub fn new(objects: Vec<Object>) -> Self {
+        Scene::with_lights(objects, Vec::new())
+    }
+
+    pub fn with_lights(objects: Vec<Object>, delta_lights: Vec<Light>) -> Self {
         let light_indices = objects
             .iter()
             .enumerate()
             .filter(|(_, obj)| obj.emission > Color::black())
             .map(|(i, _)| i)
             .collect::<Vec<_>>();
+        let selection = LightSelection::default_for(light_indices.len() + delta_lights.len());
+        let materials = (0..objects.len())
+            .map(|i| {
+                let same = |o: &Object| {
+                    o.color == objects[i].color
+                        && o.emission == objects[i].emission
+                        && o.reflection == objects[i].reflection
+                        && o.medium == objects[i].medium
+                };
+                objects[..i].iter().position(same).unwrap_or(i)
+            })
+            .collect();
 
-        Scene {
+        let mut scene = Scene {
             objects,
+            materials,
             lights: light_indices,
+            delta_lights,
+            sky: None,
+            light_sampler: LightSampler::Uniform(0),
+            emission_table: AliasTable::new(&[]),
+            medium: None,
+            bounding_sphere: (V3::zero(), 0.0),
+        };
+        scene.bounding_sphere = scene
+            .bounds()
+            .map_or((V3::zero(), 0.0), |b| b.bounding_sphere());
+        scene.set_light_selection(selection);
+
+        scene
+    }
+
+    pub fn set_sky(&mut self, sky: Option<Sky>) {
+        self.sky = sky;
+        self.set_light_selection(LightSelection::default_for(self.light_count()));
+    }
+
+    pub fn sky(&self) -> Option<&Sky> {
+        self.sky.as_ref()
+    }
+
+    // どこにも当たらなかったレイが受け取る放射輝度
+    pub fn environment(&self, dir: &V3U) -> Color {
+        self.sky
+            .as_ref()
+            .map_or(Color::black(), |sky| sky.radiance(dir))
+    }
+
+    // 関与媒質はPathTracerでのみ扱う
+    pub fn set_medium(&mut self, medium: Option<Medium>) {
+        self.medium = medium.and_then(|m| self.bounds().map(|b| (m, b)));
+    }
+
+    // 関与媒質(シーン全体か物体の内部)があるかどうか, 表面下散乱は含まない
+    pub fn has_media(&self) -> bool {
+        self.medium.is_some() || self.objects.iter().any(|o| o.medium.is_some())
+    }
+
+    // 表面下散乱の物体があるかどうか, 内部のランダムウォークは媒質と同じ仕組みで追う
+    pub fn has_subsurface(&self) -> bool {
+        self.objects
+            .iter()
+            .any(|o| matches!(o.reflection, Reflection::Subsurface(_)))
+    }
+
+    // チェックポイントの指紋のために内容を全て書き出す
+    // 媒質の格子はDebugでは大きさしか出ないので値も書く
+    pub fn describe(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        write!(out, "{:?}", self)?;
+        let media = (self.medium.iter().map(|(m, _)| m))
+            .chain(self.objects.iter().filter_map(|o| o.medium.as_ref()));
+        for grid in media.filter_map(|m| m.grid.as_ref()) {
+            for v in grid.values() {
+                write!(out, "{:x} ", v.to_bits())?;
+            }
+        }
+        Ok(())
+    }
+
+    // レイの[0, t_max]のうち, stateの媒質がある区間
+    pub fn medium_segment<'a>(
+        &'a self,
+        state: &MediumState<'a>,
+        ray: &Ray,
+        t_max: f64,
+    ) -> Option<(&'a Medium, f64, f64)> {
+        match state.inside {
+            Some(object) => object.interior_medium().map(|m| (m, 0.0, t_max)),
+            None => {
+                let (medium, bounds) = self.medium.as_ref()?;
+                let (t0, t1) = bounds.intersect_range(ray)?;
+                let t1 = t1.min(t_max);
+
+                if t0 < t1 {
+                    Some((medium, t0, t1))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    pub fn light_count(&self) -> usize {
+        self.lights.len() + self.delta_lights.len() + self.sky.iter().count()
+    }
+
+    pub fn set_light_selection(&mut self, selection: LightSelection) {
+        let scene_radius = self.bounding_sphere.1;
+        let entries = self
+            .lights
+            .iter()
+            .map(|&i| LightEntry {
+                power: self.objects[i].power(),
+                bounds: Some(LightBounds::from_object(&self.objects[i])),
+            })
+            .chain(self.delta_lights.iter().map(|light| LightEntry {
+                power: light.power(scene_radius),
+                bounds: LightBounds::from_light(light),
+            }))
+            .chain(self.sky.iter().map(|sky| LightEntry {
+                power: sky.power(scene_radius),
+                bounds: None,
+            }))
+            .collect::<Vec<_>>();
+
+        self.light_sampler = LightSampler::new(selection, &entries);
+        self.emission_table = AliasTable::new(&entries.iter().map(|e| e.power).collect::<Vec<_>>());
+    }
+
+    pub fn emitter(&self, i: usize) -> Emitter<'_> {
+        if i < self.lights.len() {
+            Emitter::Area(&self.objects[self.lights[i]])
+        } else if i < self.lights.len() + self.delta_lights.len() {
+            Emitter::Light(&self.delta_lights[i - self.lights.len()])
+        } else {
+            Emitter::Sky(self.sky.as_ref().unwrap())
+        }
+    }
+
+    // 発光するオブジェクトの光源としての番号
+    pub fn emitter_of(&self, object: &Object) -> Option<usize> {
+        self.object_id(object)
+            .and_then(|i| self.lights.binary_search(&i).ok())
+    }
+
+    // シーンのオブジェクトの番号
+    pub fn object_id(&self, object: &Object) -> Option<usize> {
+        let i = (object as *const Object as usize).wrapping_sub(self.objects.as_ptr() as usize)
+            / std::mem::size_of::<Object>();
+        if i < self.objects.len() && std::ptr::eq(&self.objects[i], object) {
+            Some(i)
+        } else {
+            None
+        }
+    }
+
+    // 材質の番号, シーンを作るときに求めておいたもの
+    pub fn material_id(&self, object: &Object) -> Option<usize> {
+        self.object_id(object).map(|i| self.materials[i])
+    }
+
+    pub fn sky_emitter(&self) -> Option<usize> {
+        self.sky
+            .as_ref()
+            .map(|_| self.lights.len() + self.delta_lights.len())
+    }
+
+    // 放射束に比例して光源を選ぶ, returns index, probability
+    pub fn sample_emitter(&self) -> Option<(usize, f64)> {
+        if self.emission_table.is_empty() {
+            None
+        } else {
+            Some(self.emission_table.sample())
         }
     }
 
+    pub fn emitter_pmf(&self, i: usize) -> f64 {
+        self.emission_table.pmf(i)
+    }
+
+    // シーンを囲む球(中心, 半径)
+    pub fn bounding_sphere(&self) -> (V3, f64) {
+        self.bounding_sphere
+    }
+
+    pub fn has_delta_lights(&self) -> bool {
+        !self.delta_lights.is_empty()
+    }
+
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.objects
+            .iter()
+            .map(|obj| obj.figure.bounds())
+            .chain(
+                self.delta_lights
+                    .iter()
+                    .filter_map(|light| LightBounds::from_light(light).map(|b| b.bounds)),
+            )
+            .fold(None, |acc, b| Some(acc.map_or(b, |a: Aabb| a.union(b))))
+    }
+
     /// Finds the closest object
     pub fn intersect(&self, ray: &Ray) -> Option<(HitRecord, &Object)> {
-        let mut dist = std::f64::MAX;
+        let mut dist = f64::MAX;
         let mut result = None;
 
         // 線形探索
@@ -40,14 +264,90 @@ impl Scene {
         result
     }
 
-    // returns point, normal, reference to object
-    pub fn sample_on_lights(&self) -> Option<(SampleRecord, &Object)> {
-        if self.lights.is_empty() {
-            return None;
+    // pointから光源上のサンプルが見えるかどうか
+    pub fn visible(&self, point: &V3, sample: &LightSample) -> bool {
+        self.unoccluded(point, &sample.dir, sample.distance)
+    }
+
+    // pointからdir方向にdistanceだけ離れた点までの間に遮るものがないかどうか
+    pub fn unoccluded(&self, point: &V3, dir: &V3U, distance: f64) -> bool {
+        match self.intersect(&Ray {
+            origin: *point,
+            dir: *dir,
+        }) {
+            Some((hit, _)) => hit.distance >= distance * (1.0 - 1e-4),
+            None => true,
         }
+    }
+
+    // pointからdir方向にdistanceだけ離れた点までの透過率
+    // Transparentの面は通り抜け, それ以外の面に遮られたら0
+    pub fn transmittance(&self, point: &V3, dir: &V3U, distance: f64, state: MediumState) -> Color {
+        let mut tr = Color::new(1.0, 1.0, 1.0);
+        let mut state = state;
+        let mut origin = *point;
+        let mut remaining = distance;
+
+        loop {
+            let ray = Ray { origin, dir: *dir };
+            let blocker = self
+                .intersect(&ray)
+                .filter(|(hit, _)| hit.distance < remaining * (1.0 - 1e-4));
+            let t_max = blocker.as_ref().map_or(remaining, |(hit, _)| hit.distance);
+
+            if let Some((medium, t0, t1)) = self.medium_segment(&state, &ray, t_max) {
+                tr = tr.blend(medium.transmittance(&ray, t0, t1));
+            }
 
-        let i = rand::random::<usize>() % self.lights.len();
-        let sr = self.objects[self.lights[i]].sample();
-        Some((sr, &self.objects[self.lights[i]]))
+            match blocker {
+                None => return tr,
+                Some((hit, object)) => {
+                    if object.reflection != Reflection::Transparent {
+                        return Color::black();
+                    }
+
+                    state = state.cross(object);
+                    origin = hit.position;
+                    remaining -= hit.distance;
+                }
+            }
+        }
+    }
+
+    // pointは光源を見込むシェーディング点, normalはその法線(あれば)
+    // pdf_valueは光源の選択確率込み
+    pub fn sample_on_lights(&self, point: &V3, normal: Option<V3U>) -> Option<LightSample> {
+        let (i, pmf) = self.light_sampler.sample(point, normal)?;
+
+        let sample = if i < self.lights.len() {
+            let light = &self.objects[self.lights[i]];
+            LightSample::from_area(point, &light.sample_from(point), light.emission)?
+        } else if i < self.lights.len() + self.delta_lights.len() {
+            self.delta_lights[i - self.lights.len()].sample(point)?
+        } else {
+            self.sky.as_ref()?.sample()?
+        };
+
+        Some(LightSample {
+            pdf_value: sample.pdf_value * pmf,
+            light: i,
+            ..sample
+        })
+    }
+
+    // sample_on_lightsで環境光のdirの向きが選ばれる確率密度(立体角測度)
+    pub fn environment_pdf(&self, point: &V3, normal: Option<V3U>, dir: &V3U) -> f64 {
+        match (self.sky.as_ref(), self.sky_emitter()) {
+            (Some(sky), Some(i)) => self.light_sampler.pmf(point, normal, i) * sky.pdf(dir),
+            _ => 0.0,
+        }
+    }
+
+    // sample_on_lightsでlightのxが選ばれる確率密度(面積測度)
+    pub fn light_pdf(&self, point: &V3, normal: Option<V3U>, light: &Object, x: &V3) -> f64 {
+        match self.emitter_of(light) {
+            Some(i) => self.light_sampler.pmf(point, normal, i) * light.area_pdf_from(point, x),
+            None => 0.0,
+        }
     }
 }
@@ -0,0 +1,512 @@
+use crate::renderer::{
+    Figure, Light, Medium, Object, Reflection, RefractiveIndex, Scene, Sky, REFERENCE_WAVELENGTH,
+    WAVELENGTH_MAX, WAVELENGTH_MIN,
+};
+use crate::wrapper::{color::Color, vec::V3};
+use std::fmt;
+
+// 不正な値の種類
+#[derive(Clone, Debug, PartialEq)]
+pub enum SceneIssue {
+    NonFinite,
+    Negative,
+    // 球の半径が正でない
+    NonPositiveRadius(f64),
+    // 菱形の2辺が平行で面積がない
+    ZeroArea,
+    // 子を持たないFigures
+    EmptyFigures,
+    // Phongの拡散と鏡面の反射率の和が1を超える
+    ReflectivityAboveOne(f64),
+    // Henyey-Greensteinのgが(-1, 1)の外
+    AnisotropyOutOfRange(f64),
+    // 可視域のどこかで屈折率が正の有限な値にならない
+    InvalidRefractiveIndex(f64),
+    // スポットライトの角度や光源の視直径が範囲外
+    AngleOutOfRange(f64),
+    // 空の濁りがモデルの範囲[2, 10]の外
+    TurbidityOutOfRange(f64),
+}
+
+// 問題のある場所
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SceneElement {
+    Object(usize),
+    // 形状を持たない光源
+    Light(usize),
+    Sky,
+    // シーン全体を満たす媒質
+    Medium,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneProblem {
+    pub element: SceneElement,
+    // "figure.radius", "reflection.exponent"のような値の位置
+    pub parameter: String,
+    pub issue: SceneIssue,
+}
+
+// Scene::validateで見つかったすべての問題
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneError {
+    pub problems: Vec<SceneProblem>,
+}
+
+impl Scene {
+    // 描画中にpanicしたりNaNを出したりする設定を, 描画の前にまとめて調べる
+    pub fn validate(&self) -> Result<(), SceneError> {
+        let mut problems = Vec::new();
+        for (i, object) in self.objects.iter().enumerate() {
+            let mut report = |parameter: String, issue: SceneIssue| {
+                problems.push(SceneProblem {
+                    element: SceneElement::Object(i),
+                    parameter,
+                    issue,
+                })
+            };
+            validate_object(object, &mut report);
+        }
+        for (i, light) in self.delta_lights.iter().enumerate() {
+            validate_light(light, &mut |parameter, issue| {
+                problems.push(SceneProblem {
+                    element: SceneElement::Light(i),
+                    parameter,
+                    issue,
+                })
+            });
+        }
+        if let Some(sky) = &self.sky {
+            validate_sky(sky, &mut |parameter, issue| {
+                problems.push(SceneProblem {
+                    element: SceneElement::Sky,
+                    parameter,
+                    issue,
+                })
+            });
+        }
+        if let Some((medium, _)) = &self.medium {
+            validate_medium(medium, "medium", &mut |parameter, issue| {
+                problems.push(SceneProblem {
+                    element: SceneElement::Medium,
+                    parameter,
+                    issue,
+                })
+            });
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(SceneError { problems })
+        }
+    }
+}
+
+type Report<'a> = dyn FnMut(String, SceneIssue) + 'a;
+
+fn validate_object(object: &Object, report: &mut Report) {
+    validate_figure(&object.figure, "figure", report);
+    validate_color(object.color, "color", report);
+    validate_color(object.emission, "emission", report);
+    if let Some(medium) = &object.medium {
+        validate_medium(medium, "medium", report);
+    }
+
+    match &object.reflection {
+        Reflection::Glossy(r) => validate_number(*r, "reflection.roughness", report),
+        Reflection::Phong(params) => {
+            let diffuse = params.diffuse_reflectivity;
+            let specular = params.specular_reflectivity;
+            validate_number(diffuse, "reflection.diffuse_reflectivity", report);
+            validate_number(specular, "reflection.specular_reflectivity", report);
+            validate_number(params.exponent as f64, "reflection.exponent", report);
+            if diffuse + specular > 1.0 {
+                report(
+                    "reflection".into(),
+                    SceneIssue::ReflectivityAboveOne(diffuse + specular),
+                );
+            }
+        }
+        Reflection::Subsurface(params) => validate_medium(params.medium(), "reflection", report),
+        Reflection::Dielectric(ior) => validate_refractive_index(ior, "reflection", report),
+        _ => {}
+    }
+}
+
+// Cauchyのaが正でないものや, Sellmeierの極が可視域にあって屈折率がNaNになるもの
+fn validate_refractive_index(ior: &RefractiveIndex, parameter: &str, report: &mut Report) {
+    if let RefractiveIndex::Cauchy { a, .. } = ior {
+        if a.is_nan() || *a <= 0.0 {
+            report(parameter.into(), SceneIssue::InvalidRefractiveIndex(*a));
+            return;
+        }
+    }
+
+    let steps = 47;
+    let invalid = (0..=steps)
+        .map(|i| WAVELENGTH_MIN + (WAVELENGTH_MAX - WAVELENGTH_MIN) * i as f64 / steps as f64)
+        .chain(std::iter::once(REFERENCE_WAVELENGTH))
+        .map(|l| ior.at(l))
+        .find(|n| !(n.is_finite() && *n > 0.0));
+    if let Some(n) = invalid {
+        report(parameter.into(), SceneIssue::InvalidRefractiveIndex(n));
+    }
+}
+
+fn validate_light(light: &Light, report: &mut Report) {
+    match light {
+        Light::Point(light) => {
+            validate_vector(light.position, "position", report);
+            validate_color(light.intensity, "intensity", report);
+        }
+        Light::Spot(light) => {
+            validate_vector(light.position, "position", report);
+            validate_vector(light.dir.as_v3(), "dir", report);
+            validate_color(light.intensity, "intensity", report);
+            validate_angle(
+                light.cone_angle,
+                0.0,
+                std::f64::consts::PI,
+                "cone_angle",
+                report,
+            );
+            validate_angle(
+                light.penumbra_angle,
+                0.0,
+                light.cone_angle,
+                "penumbra_angle",
+                report,
+            );
+        }
+        Light::Directional(light) => {
+            validate_vector(light.dir.as_v3(), "dir", report);
+            validate_color(light.irradiance, "irradiance", report);
+            validate_angle(
+                light.angular_diameter,
+                0.0,
+                std::f64::consts::PI,
+                "angular_diameter",
+                report,
+            );
+        }
+    }
+}
+
+fn validate_sky(sky: &Sky, report: &mut Report) {
+    let param = &sky.param;
+    validate_vector(param.sun_dir.as_v3(), "sun_dir", report);
+    if !(2.0..=10.0).contains(&param.turbidity) {
+        report(
+            "turbidity".into(),
+            SceneIssue::TurbidityOutOfRange(param.turbidity),
+        );
+    }
+    validate_color(param.ground_albedo, "ground_albedo", report);
+    validate_number(param.intensity, "intensity", report);
+}
+
+fn validate_figure(figure: &Figure, parameter: &str, report: &mut Report) {
+    match figure {
+        Figure::Sphere(sphere) => {
+            validate_vector(sphere.center, &format!("{}.center", parameter), report);
+            if !(sphere.radius > 0.0 && sphere.radius.is_finite()) {
+                report(
+                    format!("{}.radius", parameter),
+                    SceneIssue::NonPositiveRadius(sphere.radius),
+                );
+            }
+        }
+        Figure::Rhombus(rhombus) => {
+            let area = rhombus.area();
+            let origin = rhombus.origin.len();
+            if !(area.is_finite() && origin.is_finite()) {
+                report(parameter.into(), SceneIssue::NonFinite);
+            } else if area <= 0.0 {
+                report(parameter.into(), SceneIssue::ZeroArea);
+            }
+        }
+        Figure::Figures(figures) => {
+            if figures.is_empty() {
+                report(parameter.into(), SceneIssue::EmptyFigures);
+            }
+            for (i, figure) in figures.iter().enumerate() {
+                validate_figure(figure, &format!("{}[{}]", parameter, i), report);
+            }
+        }
+    }
+}
+
+fn validate_medium(medium: &Medium, parameter: &str, report: &mut Report) {
+    validate_color(medium.sigma_a, &format!("{}.sigma_a", parameter), report);
+    validate_color(medium.sigma_s, &format!("{}.sigma_s", parameter), report);
+    validate_color(medium.emission, &format!("{}.emission", parameter), report);
+    if let Some(grid) = &medium.grid {
+        let parameter = format!("{}.grid", parameter);
+        if grid.values().any(|v| !v.is_finite()) {
+            report(parameter, SceneIssue::NonFinite);
+        } else if grid.values().any(|v| *v < 0.0) {
+            report(parameter, SceneIssue::Negative);
+        }
+    }
+    if !(medium.g > -1.0 && medium.g < 1.0) {
+        report(
+            format!("{}.g", parameter),
+            SceneIssue::AnisotropyOutOfRange(medium.g),
+        );
+    }
+}
+
+fn validate_number(v: f64, parameter: &str, report: &mut Report) {
+    if !v.is_finite() {
+        report(parameter.into(), SceneIssue::NonFinite);
+    } else if v < 0.0 {
+        report(parameter.into(), SceneIssue::Negative);
+    }
+}
+
+fn validate_vector(v: V3, parameter: &str, report: &mut Report) {
+    if !(v.x().is_finite() && v.y().is_finite() && v.z().is_finite()) {
+        report(parameter.into(), SceneIssue::NonFinite);
+    }
+}
+
+// [min, max]の角度
+fn validate_angle(angle: f64, min: f64, max: f64, parameter: &str, report: &mut Report) {
+    if !(min..=max).contains(&angle) {
+        report(parameter.into(), SceneIssue::AngleOutOfRange(angle));
+    }
+}
+
+fn validate_color(c: Color, parameter: &str, report: &mut Report) {
+    if !c.is_finite() {
+        report(parameter.into(), SceneIssue::NonFinite);
+    } else if (0..3).any(|i| c.channel(i) < 0.0) {
+        report(parameter.into(), SceneIssue::Negative);
+    }
+}
+
+impl fmt::Display for SceneIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneIssue::NonFinite => write!(f, "is not finite"),
+            SceneIssue::Negative => write!(f, "is negative"),
+            SceneIssue::NonPositiveRadius(r) => write!(f, "must be positive (got {})", r),
+            SceneIssue::ZeroArea => write!(f, "has zero area"),
+            SceneIssue::EmptyFigures => write!(f, "has no figures"),
+            SceneIssue::ReflectivityAboveOne(sum) => write!(
+                f,
+                "diffuse + specular reflectivity must be at most 1 (got {})",
+                sum
+            ),
+            SceneIssue::AnisotropyOutOfRange(g) => {
+                write!(f, "must be between -1 and 1 exclusive (got {})", g)
+            }
+            SceneIssue::InvalidRefractiveIndex(n) => write!(
+                f,
+                "must give a positive finite refractive index over the visible range (got {})",
+                n
+            ),
+            SceneIssue::AngleOutOfRange(a) => write!(f, "is out of range (got {} rad)", a),
+            SceneIssue::TurbidityOutOfRange(t) => {
+                write!(f, "must be between 2 and 10 (got {})", t)
+            }
+        }
+    }
+}
+
+impl fmt::Display for SceneElement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneElement::Object(i) => write!(f, "object {}", i),
+            SceneElement::Light(i) => write!(f, "light {}", i),
+            SceneElement::Sky => write!(f, "sky"),
+            SceneElement::Medium => write!(f, "scene medium"),
+        }
+    }
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid scene:")?;
+        for p in &self.problems {
+            write!(f, "\n  {}: {} {}", p.element, p.parameter, p.issue)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+impl From<SceneError> for std::io::Error {
+    fn from(e: SceneError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::{
+        DirectionalLight, PhongParameter, PointLight, Rhombus, SkyParameter, Sphere, SpotLight,
+        VolumeGrid,
+    };
+    use crate::wrapper::{aabb::Aabb, vec::V3U};
+    use std::sync::Arc;
+
+    #[test]
+    fn validate_lists_every_problem() {
+        let scene = Scene::new(vec![
+            Object {
+                figure: Figure::Sphere(Sphere {
+                    center: V3::zero(),
+                    radius: 1.0,
+                }),
+                ..Default::default()
+            },
+            Object {
+                figure: Figure::Sphere(Sphere {
+                    center: V3::zero(),
+                    radius: -1.0,
+                }),
+                reflection: Reflection::Phong(PhongParameter {
+                    diffuse_reflectivity: 0.6,
+                    specular_reflectivity: 0.6,
+                    exponent: 10,
+                }),
+                ..Default::default()
+            },
+            Object {
+                figure: Figure::Figures(vec![
+                    Figure::Rhombus(Rhombus {
+                        origin: V3::zero(),
+                        a: V3::new(1.0, 0.0, 0.0),
+                        b: V3::new(0.0, 1.0, 0.0),
+                    }),
+                    Figure::Rhombus(Rhombus {
+                        origin: V3::zero(),
+                        a: V3::new(1.0, 0.0, 0.0),
+                        b: V3::new(2.0, 0.0, 0.0),
+                    }),
+                ]),
+                color: Color::new(f64::NAN, 0.0, 0.0),
+                ..Default::default()
+            },
+        ]);
+
+        let error = scene.validate().unwrap_err();
+        let problem = |element, parameter: &str, issue| SceneProblem {
+            element,
+            parameter: parameter.into(),
+            issue,
+        };
+        assert_eq!(
+            error.problems,
+            vec![
+                problem(
+                    SceneElement::Object(1),
+                    "figure.radius",
+                    SceneIssue::NonPositiveRadius(-1.0)
+                ),
+                problem(
+                    SceneElement::Object(1),
+                    "reflection",
+                    SceneIssue::ReflectivityAboveOne(1.2)
+                ),
+                problem(SceneElement::Object(2), "figure[1]", SceneIssue::ZeroArea),
+                problem(SceneElement::Object(2), "color", SceneIssue::NonFinite),
+            ]
+        );
+        assert!(error
+            .to_string()
+            .contains("object 1: figure.radius must be positive (got -1)"));
+    }
+
+    #[test]
+    fn validate_checks_lights_sky_dielectrics_and_grids() {
+        let sphere = |reflection, medium| Object {
+            figure: Figure::Sphere(Sphere {
+                center: V3::zero(),
+                radius: 1.0,
+            }),
+            reflection,
+            medium,
+            ..Default::default()
+        };
+        let grid = VolumeGrid::new(
+            Aabb::new(V3::zero(), V3::new(1.0, 1.0, 1.0)),
+            (2, 1, 1),
+            vec![0.5, -1.0],
+            None,
+        );
+        let mut scene = Scene::with_lights(
+            vec![
+                sphere(Reflection::Dielectric(RefractiveIndex::bk7()), None),
+                sphere(
+                    Reflection::Dielectric(RefractiveIndex::Cauchy { a: 0.0, b: 0.01 }),
+                    None,
+                ),
+                // 極が可視域(0.5μm)にある
+                sphere(
+                    Reflection::Dielectric(RefractiveIndex::Sellmeier {
+                        b: [1.0, 0.0, 0.0],
+                        c: [0.25, 0.0, 0.0],
+                    }),
+                    None,
+                ),
+                sphere(
+                    Reflection::Refraction,
+                    Some(Medium {
+                        sigma_a: Color::new(0.1, 0.1, 0.1),
+                        sigma_s: Color::new(0.1, 0.1, 0.1),
+                        g: 0.0,
+                        emission: Color::black(),
+                        grid: Some(Arc::new(grid)),
+                    }),
+                ),
+            ],
+            vec![
+                Light::Point(PointLight {
+                    position: V3::new(f64::NAN, 0.0, 0.0),
+                    intensity: Color::new(1.0, 1.0, 1.0),
+                }),
+                Light::Spot(SpotLight {
+                    position: V3::zero(),
+                    dir: V3U::unit_y(),
+                    intensity: Color::new(1.0, 1.0, 1.0),
+                    cone_angle: 0.5,
+                    penumbra_angle: 0.8,
+                }),
+                Light::Directional(DirectionalLight {
+                    dir: V3U::unit_y(),
+                    irradiance: Color::new(-1.0, 1.0, 1.0),
+                    angular_diameter: 0.01,
+                }),
+            ],
+        );
+        scene.set_sky(Some(Sky::new(SkyParameter {
+            turbidity: 0.5,
+            ..Default::default()
+        })));
+
+        let problems = scene
+            .validate()
+            .unwrap_err()
+            .problems
+            .into_iter()
+            .map(|p| (p.element, p.parameter))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            vec![
+                (SceneElement::Object(1), "reflection".to_string()),
+                (SceneElement::Object(2), "reflection".to_string()),
+                (SceneElement::Object(3), "medium.grid".to_string()),
+                (SceneElement::Light(0), "position".to_string()),
+                (SceneElement::Light(1), "penumbra_angle".to_string()),
+                (SceneElement::Light(2), "irradiance".to_string()),
+                (SceneElement::Sky, "turbidity".to_string()),
+            ]
+        );
+    }
+}
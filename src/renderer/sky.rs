@@ -0,0 +1,320 @@
+use crate::renderer::{uniform_random, LightSample};
+use crate::wrapper::{
+    color::Color,
+    vec::{V3, V3U},
+};
+use std::f64::consts::PI;
+
+// 太陽の視直径(ラジアン)
+const SUN_ANGULAR_DIAMETER: f64 = 0.00925;
+// 大気圏外での太陽の輝度(kcd/m², 空の輝度と同じ単位)
+const SUN_LUMINANCE: f64 = 1.6e6;
+// NEEで太陽の円盤を選ぶ確率
+const SUN_SAMPLE_PROBABILITY: f64 = 0.5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SkyParameter {
+    pub sun_dir: V3U,         // 太陽への向き(+yが天頂)
+    pub turbidity: f64,       // 大気の濁り(2: 快晴 〜 10: 霞んだ空)
+    pub ground_albedo: Color, // 地平線より下に見える地面の反射率
+    pub intensity: f64,       // 空と太陽の輝度に掛ける係数
+}
+
+impl Default for SkyParameter {
+    fn default() -> Self {
+        SkyParameter {
+            sun_dir: V3U::from_v3(V3::new(0.3, 0.8, -0.5)),
+            turbidity: 3.0,
+            ground_albedo: Color::new(0.3, 0.3, 0.3),
+            intensity: 1.0,
+        }
+    }
+}
+
+// Preethamの昼光モデルによる空と太陽の環境光
+// A Practical Analytic Model for Daylight (Preetham, Shirley, Smits 1999)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sky {
+    pub param: SkyParameter,
+    // Perez関数の係数(Y, x, y)
+    perez: [[f64; 5]; 3],
+    // 天頂の輝度と色度(Y, x, y)
+    zenith: [f64; 3],
+    sun_radiance: Color,
+    ground_radiance: Color,
+    cos_sun_max: f64,
+}
+
+impl Sky {
+    pub fn new(param: SkyParameter) -> Self {
+        let t = param.turbidity;
+        // 太陽が地平線より下の場合はモデルの範囲外なので地平線上に置く
+        let theta_s = param
+            .sun_dir
+            .y()
+            .clamp(0.0, 1.0)
+            .acos()
+            .min(PI / 2.0 - 1e-3);
+
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let (s1, s2, s3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let zenith = [
+            (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192,
+            t * t * (0.00166 * s3 - 0.00375 * s2 + 0.00209 * s1)
+                + t * (-0.02903 * s3 + 0.06377 * s2 - 0.03202 * s1 + 0.00394)
+                + (0.11693 * s3 - 0.21196 * s2 + 0.06052 * s1 + 0.25886),
+            t * t * (0.00275 * s3 - 0.00610 * s2 + 0.00317 * s1)
+                + t * (-0.04214 * s3 + 0.08970 * s2 - 0.04153 * s1 + 0.00516)
+                + (0.15346 * s3 - 0.26756 * s2 + 0.06670 * s1 + 0.26688),
+        ];
+
+        let mut sky = Sky {
+            sun_radiance: sun_radiance(t, theta_s).scale(param.intensity),
+            param,
+            perez,
+            zenith,
+            ground_radiance: Color::black(),
+            cos_sun_max: (SUN_ANGULAR_DIAMETER / 2.0).cos(),
+        };
+        sky.ground_radiance = sky
+            .param
+            .ground_albedo
+            .blend(sky.irradiance())
+            .scale(1.0 / PI);
+
+        sky
+    }
+
+    // dir方向から届く放射輝度(太陽の円盤を含む), 地平線より下の円盤は地面に隠れる
+    pub fn radiance(&self, dir: &V3U) -> Color {
+        let sky = self.sky_radiance(dir);
+        if dir.y() > 0.0 && dir.dot(&self.param.sun_dir) >= self.cos_sun_max {
+            sky + self.sun_radiance
+        } else {
+            sky
+        }
+    }
+
+    // 太陽の円盤を除いた空(と地面)の放射輝度
+    fn sky_radiance(&self, dir: &V3U) -> Color {
+        if dir.y() <= 0.0 {
+            return self.ground_radiance;
+        }
+
+        let theta_s = self
+            .param
+            .sun_dir
+            .y()
+            .clamp(0.0, 1.0)
+            .acos()
+            .min(PI / 2.0 - 1e-3);
+        let cos_theta = dir.y().max(0.01);
+        let gamma = dir.dot(&self.param.sun_dir).clamp(-1.0, 1.0).acos();
+
+        let v = [0, 1, 2].map(|i| {
+            let f = perez(&self.perez[i], cos_theta, gamma);
+            let f0 = perez(&self.perez[i], 1.0, theta_s);
+            self.zenith[i] * f / f0
+        });
+
+        xyy_to_rgb(v[0], v[1], v[2])
+            .map(|c| c.max(0.0))
+            .scale(self.param.intensity)
+    }
+
+    // 水平面が受ける放射照度
+    fn irradiance(&self) -> Color {
+        // 天頂角方向は cos θ で等間隔に分割して数値積分する
+        let n_theta = 64;
+        let n_phi = 128;
+        let mut e = Color::black();
+        for i in 0..n_theta {
+            let cos_theta = (i as f64 + 0.5) / n_theta as f64;
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            for j in 0..n_phi {
+                let phi = 2.0 * PI * (j as f64 + 0.5) / n_phi as f64;
+                let dir = V3U::from_v3_unsafe(V3::new(
+                    sin_theta * phi.cos(),
+                    cos_theta,
+                    sin_theta * phi.sin(),
+                ));
+                e += self.sky_radiance(&dir).scale(cos_theta);
+            }
+        }
+        let e = e.scale(2.0 * PI / (n_theta * n_phi) as f64);
+
+        e + self
+            .sun_radiance
+            .scale(2.0 * PI * (1.0 - self.cos_sun_max) * self.param.sun_dir.y().max(0.0))
+    }
+
+    // NEE用に空または太陽の円盤から方向をサンプリングする(pdfは選択確率を含まない)
+    pub fn sample(&self) -> Option<LightSample> {
+        let dir = if uniform_random() < self.sun_probability() {
+            let (u, v) = self.param.sun_dir.orthonormal_basis();
+            let cos_theta = 1.0 - uniform_random() * (1.0 - self.cos_sun_max);
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            let phi = 2.0 * PI * uniform_random();
+            V3U::from_v3(
+                u.scale(sin_theta * phi.cos())
+                    + v.scale(sin_theta * phi.sin())
+                    + self.param.sun_dir.scale(cos_theta),
+            )
+        } else {
+            let z = 1.0 - 2.0 * uniform_random();
+            let r = (1.0 - z * z).max(0.0).sqrt();
+            let phi = 2.0 * PI * uniform_random();
+            V3U::from_v3_unsafe(V3::new(r * phi.cos(), r * phi.sin(), z))
+        };
+
+        Some(LightSample {
+            dir,
+            distance: f64::INFINITY,
+            radiance: self.radiance(&dir),
+            pdf_value: self.pdf(&dir),
+            is_delta: false,
+            light: 0,
+            normal: None,
+        })
+    }
+
+    // sampleでdirが選ばれる確率密度(立体角測度)
+    pub fn pdf(&self, dir: &V3U) -> f64 {
+        let p_sun = self.sun_probability();
+        let sun = if dir.dot(&self.param.sun_dir) >= self.cos_sun_max {
+            1.0 / (2.0 * PI * (1.0 - self.cos_sun_max))
+        } else {
+            0.0
+        };
+
+        p_sun * sun + (1.0 - p_sun) / (4.0 * PI)
+    }
+
+    fn sun_probability(&self) -> f64 {
+        if self.param.sun_dir.y() > 0.0 {
+            SUN_SAMPLE_PROBABILITY
+        } else {
+            0.0
+        }
+    }
+
+    // 光源選択用の放射束の見積もり
+    pub fn power(&self, scene_radius: f64) -> f64 {
+        PI * scene_radius * scene_radius * self.irradiance().luminance()
+    }
+}
+
+fn perez(c: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+    (1.0 + c[0] * (c[1] / cos_theta).exp())
+        * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos() * gamma.cos())
+}
+
+fn xyy_to_rgb(luminance: f64, x: f64, y: f64) -> Color {
+    let big_x = x * luminance / y;
+    let big_z = (1.0 - x - y) * luminance / y;
+
+    Color::new(
+        3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z,
+        -0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z,
+        0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z,
+    )
+}
+
+// 大気による減衰を考慮した太陽の放射輝度
+// Rayleigh散乱とエアロゾル(Ångströmの式)のみを考え, RGBをそれぞれ代表波長で近似する
+fn sun_radiance(turbidity: f64, theta_s: f64) -> Color {
+    let beta = 0.04608 * turbidity - 0.04586;
+    let alpha = 1.3;
+    // 相対的な大気路程
+    let m = 1.0 / (theta_s.cos() + 0.15 * (93.885 - theta_s.to_degrees()).powf(-1.253));
+
+    let transmittance = |lambda: f64| {
+        let rayleigh = (-0.008735 * lambda.powf(-4.08) * m).exp();
+        let aerosol = (-beta * lambda.powf(-alpha) * m).exp();
+        rayleigh * aerosol
+    };
+
+    // 波長はμm
+    Color::new(
+        transmittance(0.68),
+        transmittance(0.55),
+        transmittance(0.44),
+    )
+    .scale(SUN_LUMINANCE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sky_is_brighter_near_the_sun() {
+        let sky = Sky::new(SkyParameter::default());
+        let near = V3U::from_v3(sky.param.sun_dir.as_v3() + V3::new(0.0, 0.0, 0.1));
+        let far = V3U::from_v3(V3::new(-0.3, 0.8, 0.5));
+
+        assert!(sky.radiance(&near).luminance() > sky.radiance(&far).luminance());
+        assert!(
+            sky.radiance(&sky.param.sun_dir).luminance() > 1000.0 * sky.radiance(&near).luminance()
+        );
+    }
+
+    #[test]
+    fn ground_hides_the_sun_below_the_horizon() {
+        // 円盤の下半分が地平線にかかる太陽
+        let sky = Sky::new(SkyParameter {
+            sun_dir: V3U::from_v3(V3::new(0.0, 0.002, 1.0)),
+            ..Default::default()
+        });
+        let below = V3U::from_v3(V3::new(0.0, -0.002, 1.0));
+        let above = V3U::from_v3(V3::new(0.0, 0.004, 1.0));
+
+        assert_eq!(sky.radiance(&below), sky.ground_radiance);
+        assert!(sky.radiance(&above).luminance() > sky.sky_radiance(&above).luminance());
+    }
+
+    #[test]
+    fn sky_sample_matches_pdf() {
+        let sky = Sky::new(SkyParameter::default());
+
+        // ∫ pdf dω = 1
+        let n = 200000;
+        let integral = (0..n)
+            .map(|_| {
+                let s = sky.sample().unwrap();
+                assert!((s.pdf_value - sky.pdf(&s.dir)).abs() < 1e-9 * s.pdf_value);
+                1.0 / s.pdf_value
+            })
+            .sum::<f64>()
+            / n as f64;
+        assert!(
+            (integral - 4.0 * PI).abs() < 0.05 * 4.0 * PI,
+            "{}",
+            integral
+        );
+    }
+}
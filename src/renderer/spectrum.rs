@@ -0,0 +1,382 @@
+use crate::renderer::uniform_random;
+use crate::wrapper::color::Color;
+use std::ops::{Add, AddAssign};
+use std::sync::OnceLock;
+
+// 扱う可視光の波長の範囲(nm)
+pub const WAVELENGTH_MIN: f64 = 360.0;
+pub const WAVELENGTH_MAX: f64 = 830.0;
+// 一本の経路で同時に運ぶ波長の数
+pub const WAVELENGTH_SAMPLES: usize = 4;
+
+// hero wavelength: 一様に選んだ波長と, それを範囲内で等間隔にずらした波長
+#[derive(Clone, Copy, Debug)]
+pub struct SampledWavelengths {
+    pub lambda: [f64; WAVELENGTH_SAMPLES],
+    // 屈折率が波長によって変わる面で, hero以外の波長の経路を打ち切ったかどうか
+    terminated: bool,
+}
+
+impl SampledWavelengths {
+    pub fn sample() -> Self {
+        SampledWavelengths::from_hero(
+            WAVELENGTH_MIN + (WAVELENGTH_MAX - WAVELENGTH_MIN) * uniform_random(),
+        )
+    }
+
+    pub fn from_hero(hero: f64) -> Self {
+        let range = WAVELENGTH_MAX - WAVELENGTH_MIN;
+        let mut lambda = [0.0; WAVELENGTH_SAMPLES];
+        for (i, l) in lambda.iter_mut().enumerate() {
+            let offset = (hero - WAVELENGTH_MIN) + range * i as f64 / WAVELENGTH_SAMPLES as f64;
+            *l = WAVELENGTH_MIN + offset % range;
+        }
+
+        SampledWavelengths {
+            lambda,
+            terminated: false,
+        }
+    }
+
+    pub fn hero(&self) -> f64 {
+        self.lambda[0]
+    }
+
+    // hero以外の経路を打ち切ったときに掛ける重み(一度目だけ)
+    pub fn terminate_secondary(&mut self) -> SampledSpectrum {
+        if self.terminated {
+            return SampledSpectrum::constant(1.0);
+        }
+
+        self.terminated = true;
+        let mut s = [0.0; WAVELENGTH_SAMPLES];
+        s[0] = WAVELENGTH_SAMPLES as f64;
+        SampledSpectrum(s)
+    }
+
+    // 各波長の値からCIE XYZを推定する
+    // 波長は一様分布から選んでいるので, 等エネルギーの放射輝度1でY = 1になるように正規化する
+    pub fn spectrum_to_xyz(&self, spectrum: &SampledSpectrum) -> Color {
+        let mut xyz = Color::black();
+        for (l, s) in self.lambda.iter().zip(spectrum.0.iter()) {
+            xyz += cie_xyz(*l).scale(*s);
+        }
+
+        xyz.scale(
+            (WAVELENGTH_MAX - WAVELENGTH_MIN) / (WAVELENGTH_SAMPLES as f64 * cie_y_integral()),
+        )
+    }
+}
+
+// SampledWavelengthsの各波長での値
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampledSpectrum(pub [f64; WAVELENGTH_SAMPLES]);
+
+impl SampledSpectrum {
+    pub fn constant(v: f64) -> Self {
+        SampledSpectrum([v; WAVELENGTH_SAMPLES])
+    }
+
+    pub fn zero() -> Self {
+        SampledSpectrum::constant(0.0)
+    }
+
+    // RGBの反射率や放射輝度を, 見た目が同じRGBに戻る滑らかなスペクトルに持ち上げる
+    // 値は0以上で各チャンネルの最大値を超えない, 負のチャンネルは0とみなす
+    pub fn from_rgb(color: Color, wavelengths: &SampledWavelengths) -> Self {
+        let c = [0, 1, 2].map(|i| color.channel(i).max(0.0));
+        let primaries = rgb_primaries();
+        SampledSpectrum(
+            wavelengths
+                .lambda
+                .map(|l| upsample(c, primaries.map(|p| p.at(l)))),
+        )
+    }
+
+    pub fn scale(self, scaler: f64) -> Self {
+        SampledSpectrum(self.0.map(|v| v * scaler))
+    }
+
+    pub fn blend(self, other: Self) -> Self {
+        let mut s = self.0;
+        for (v, o) in s.iter_mut().zip(other.0.iter()) {
+            *v *= o;
+        }
+        SampledSpectrum(s)
+    }
+}
+
+impl Add for SampledSpectrum {
+    type Output = SampledSpectrum;
+
+    fn add(self, other: Self) -> Self {
+        let mut s = self.0;
+        for (v, o) in s.iter_mut().zip(other.0.iter()) {
+            *v += o;
+        }
+        SampledSpectrum(s)
+    }
+}
+
+impl AddAssign for SampledSpectrum {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+// XYZから線形sRGBへの変換
+// 等エネルギー白色が(1, 1, 1)になるように白色点を合わせる
+pub fn xyz_to_rgb(xyz: Color) -> Color {
+    xyz_to_srgb(xyz).blend(white_balance())
+}
+
+fn xyz_to_srgb(xyz: Color) -> Color {
+    let (x, y, z) = (xyz.channel(0), xyz.channel(1), xyz.channel(2));
+    Color::new(
+        3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+        -0.969266 * x + 1.8760108 * y + 0.041556 * z,
+        0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+    )
+}
+
+// CIE 1931の等色関数の多峰ガウス関数による近似(Wyman, Sloan, Shirley 2013)
+pub fn cie_xyz(lambda: f64) -> Color {
+    let g = |mu: f64, sigma1: f64, sigma2: f64| {
+        let t = (lambda - mu) / if lambda < mu { sigma1 } else { sigma2 };
+        (-0.5 * t * t).exp()
+    };
+
+    Color::new(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+// 波長についての数値積分
+fn integrate(f: impl Fn(f64) -> Color) -> Color {
+    let n = 940;
+    let step = (WAVELENGTH_MAX - WAVELENGTH_MIN) / n as f64;
+    let mut sum = Color::black();
+    for i in 0..n {
+        sum += f(WAVELENGTH_MIN + (i as f64 + 0.5) * step);
+    }
+    sum.scale(step)
+}
+
+fn cie_y_integral() -> f64 {
+    static INTEGRAL: OnceLock<f64> = OnceLock::new();
+    *INTEGRAL.get_or_init(|| integrate(cie_xyz).channel(1))
+}
+
+fn white_balance() -> Color {
+    static WHITE: OnceLock<Color> = OnceLock::new();
+    *WHITE.get_or_init(|| {
+        let white = xyz_to_srgb(integrate(cie_xyz).scale(1.0 / cie_y_integral()));
+        Color::new(
+            1.0 / white.channel(0),
+            1.0 / white.channel(1),
+            1.0 / white.channel(2),
+        )
+    })
+}
+
+// sigmoidに2次式を入れた, 値が(0, 1)に収まる滑らかなスペクトル
+// cf. Jakob and Hanika, A Low-Dimensional Function Space for Efficient Spectral Upsampling (2019)
+#[derive(Clone, Copy, Debug)]
+struct SigmoidPolynomial([f64; 3]);
+
+impl SigmoidPolynomial {
+    fn at(&self, lambda: f64) -> f64 {
+        let t = (lambda - WAVELENGTH_MIN) / (WAVELENGTH_MAX - WAVELENGTH_MIN);
+        let x = (self.0[0] * t + self.0[1]) * t + self.0[2];
+        0.5 + x / (2.0 * (1.0 + x * x).sqrt())
+    }
+
+    fn rgb(&self) -> [f64; 3] {
+        let rgb =
+            xyz_to_rgb(integrate(|l| cie_xyz(l).scale(self.at(l))).scale(1.0 / cie_y_integral()));
+        [rgb.channel(0), rgb.channel(1), rgb.channel(2)]
+    }
+
+    // RGBがtargetになる係数をNewton法で求める, 誤差が減らなければ歩幅を半分にする
+    fn fit(target: [f64; 3], initial: [f64; 3]) -> Self {
+        let error = |rgb: [f64; 3]| [0, 1, 2].map(|i| rgb[i] - target[i]);
+        let norm = |e: [f64; 3]| e.iter().map(|v| v * v).sum::<f64>().sqrt();
+
+        let mut p = SigmoidPolynomial(initial);
+        let mut e = error(p.rgb());
+        for _ in 0..100 {
+            if norm(e) < 1e-10 {
+                break;
+            }
+
+            // 数値微分のヤコビ行列
+            let h = 1e-5;
+            let mut jacobian = [[0.0; 3]; 3];
+            for j in 0..3 {
+                let mut q = p;
+                q.0[j] += h;
+                let rgb = q.rgb();
+                for (i, row) in jacobian.iter_mut().enumerate() {
+                    row[j] = (rgb[i] - target[i] - e[i]) / h;
+                }
+            }
+            let inv = invert(jacobian);
+            let step = [0, 1, 2].map(|i| -(0..3).map(|j| inv[i][j] * e[j]).sum::<f64>());
+
+            let mut t = 1.0;
+            while t > 1e-4 {
+                let q = SigmoidPolynomial([0, 1, 2].map(|i| p.0[i] + t * step[i]));
+                let f = error(q.rgb());
+                if norm(f) < norm(e) {
+                    p = q;
+                    e = f;
+                    break;
+                }
+                t /= 2.0;
+            }
+        }
+
+        p
+    }
+}
+
+// RGBがそれぞれ(1, 0, 0), (0, 1, 0), (0, 0, 1)になるスペクトル
+fn rgb_primaries() -> &'static [SigmoidPolynomial; 3] {
+    static PRIMARIES: OnceLock<[SigmoidPolynomial; 3]> = OnceLock::new();
+    PRIMARIES.get_or_init(|| {
+        [
+            SigmoidPolynomial::fit([1.0, 0.0, 0.0], [0.0, 10.0, -6.0]),
+            SigmoidPolynomial::fit([0.0, 1.0, 0.0], [-30.0, 30.0, -7.0]),
+            SigmoidPolynomial::fit([0.0, 0.0, 1.0], [0.0, -10.0, 2.0]),
+        ]
+    })
+}
+
+// 白, 二次色(白から原色を引いたもの), 原色の順に重ねてcに戻るようにする(Smits 1999)
+// 重みは0以上で和がcの最大値なので, 原色の値が[0, 1]なら結果は[0, cの最大値]に収まる
+fn upsample(c: [f64; 3], primaries: [f64; 3]) -> f64 {
+    let mut order = [0, 1, 2];
+    order.sort_by(|&i, &j| c[i].total_cmp(&c[j]));
+    let [lo, mid, hi] = order;
+
+    c[lo] + (c[mid] - c[lo]) * (1.0 - primaries[lo]) + (c[hi] - c[mid]) * primaries[hi]
+}
+
+fn invert(m: [[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let cofactor = |i: usize, j: usize| {
+        let (r0, r1) = ((i + 1) % 3, (i + 2) % 3);
+        let (c0, c1) = ((j + 1) % 3, (j + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let det = (0..3).map(|j| m[0][j] * cofactor(0, j)).sum::<f64>();
+
+    let mut inv = [[0.0; 3]; 3];
+    for (i, row) in inv.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = cofactor(j, i) / det;
+        }
+    }
+    inv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 波長を一様に選んで推定したXYZをRGBに戻す
+    fn roundtrip(color: Color, n: usize) -> Color {
+        let mut xyz = Color::black();
+        for i in 0..n {
+            let hero =
+                WAVELENGTH_MIN + (WAVELENGTH_MAX - WAVELENGTH_MIN) * (i as f64 + 0.5) / n as f64;
+            let wavelengths = SampledWavelengths::from_hero(hero);
+            xyz += wavelengths.spectrum_to_xyz(&SampledSpectrum::from_rgb(color, &wavelengths));
+        }
+
+        xyz_to_rgb(xyz.scale(1.0 / n as f64))
+    }
+
+    #[test]
+    fn white_is_flat_spectrum() {
+        let wavelengths = SampledWavelengths::sample();
+        let s = SampledSpectrum::from_rgb(Color::new(1.0, 1.0, 1.0), &wavelengths);
+        for v in s.0.iter() {
+            assert!((v - 1.0).abs() < 1e-9, "{:?}", s);
+        }
+
+        let rgb = roundtrip(Color::new(1.0, 1.0, 1.0), 1000);
+        for i in 0..3 {
+            assert!((rgb.channel(i) - 1.0).abs() < 1e-3, "{:?}", rgb);
+        }
+    }
+
+    #[test]
+    fn upsampled_rgb_roundtrips() {
+        for color in &[
+            Color::new(0.75, 0.25, 0.25),
+            Color::new(0.25, 0.25, 0.75),
+            Color::new(0.25, 0.75, 0.25),
+            Color::new(0.5, 0.4, 0.3),
+        ] {
+            let rgb = roundtrip(*color, 1000);
+            for i in 0..3 {
+                assert!(
+                    (rgb.channel(i) - color.channel(i)).abs() < 1e-2,
+                    "{:?} {:?}",
+                    color,
+                    rgb
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn saturated_rgb_roundtrips_within_bounds() {
+        let primaries = rgb_primaries();
+        for (i, p) in primaries.iter().enumerate() {
+            let rgb = p.rgb();
+            for (j, v) in rgb.iter().enumerate() {
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((v - expected).abs() < 1e-6, "{:?}", rgb);
+            }
+        }
+
+        for color in &[
+            Color::new(1.0, 0.0, 0.0),
+            Color::new(0.0, 1.0, 0.0),
+            Color::new(0.0, 0.0, 1.0),
+            Color::new(0.0, 1.0, 1.0),
+            Color::new(1.0, 0.0, 1.0),
+            Color::new(1.0, 1.0, 0.0),
+            Color::new(0.9, 0.05, 0.3),
+        ] {
+            let rgb = roundtrip(*color, 1000);
+            for i in 0..3 {
+                assert!(
+                    (rgb.channel(i) - color.channel(i)).abs() < 1e-2,
+                    "{:?} {:?}",
+                    color,
+                    rgb
+                );
+            }
+
+            // 反射率として1を超えない
+            for k in 0..100 {
+                let l = WAVELENGTH_MIN + (WAVELENGTH_MAX - WAVELENGTH_MIN) * k as f64 / 100.0;
+                let v = SampledSpectrum::from_rgb(*color, &SampledWavelengths::from_hero(l)).0[0];
+                assert!((0.0..=1.0).contains(&v), "{:?} at {}: {}", color, l, v);
+            }
+        }
+    }
+
+    #[test]
+    fn wavelengths_cover_the_range() {
+        let wavelengths = SampledWavelengths::from_hero(800.0);
+        for l in wavelengths.lambda.iter() {
+            assert!((WAVELENGTH_MIN..WAVELENGTH_MAX).contains(l));
+        }
+        assert_eq!(wavelengths.hero(), 800.0);
+    }
+}
@@ -1,3 +1,4 @@
+pub mod aabb;
 pub mod color;
 pub mod ray;
 pub mod vec;
@@ -0,0 +1,95 @@
+use crate::wrapper::{ray::Ray, vec::V3};
+
+// axis-aligned bounding box
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Aabb {
+    pub min: V3,
+    pub max: V3,
+}
+
+impl Aabb {
+    pub fn new(p: V3, q: V3) -> Self {
+        Aabb {
+            min: p.min(q),
+            max: p.max(q),
+        }
+    }
+
+    pub fn from_points(points: &[V3]) -> Self {
+        points
+            .iter()
+            .skip(1)
+            .fold(Aabb::new(points[0], points[0]), |b, p| b.expand(*p))
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Aabb {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn expand(self, p: V3) -> Self {
+        Aabb {
+            min: self.min.min(p),
+            max: self.max.max(p),
+        }
+    }
+
+    pub fn centroid(&self) -> V3 {
+        (self.min + self.max).scale(0.5)
+    }
+
+    pub fn diagonal(&self) -> V3 {
+        self.max - self.min
+    }
+
+    pub fn longest_axis(&self) -> usize {
+        let d = self.diagonal();
+        if d.x() >= d.y() && d.x() >= d.z() {
+            0
+        } else if d.y() >= d.z() {
+            1
+        } else {
+            2
+        }
+    }
+
+    pub fn contains(&self, p: &V3) -> bool {
+        (0..3).all(|i| self.min.axis(i) <= p.axis(i) && p.axis(i) <= self.max.axis(i))
+    }
+
+    // レイが箱の中にある区間 [t0, t1] (t0 >= 0)
+    pub fn intersect_range(&self, ray: &Ray) -> Option<(f64, f64)> {
+        let mut t0 = 0.0_f64;
+        let mut t1 = f64::INFINITY;
+
+        for i in 0..3 {
+            let inv = 1.0 / ray.dir.as_v3().axis(i);
+            let mut near = (self.min.axis(i) - ray.origin.axis(i)) * inv;
+            let mut far = (self.max.axis(i) - ray.origin.axis(i)) * inv;
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+
+            // 軸に平行なレイではNaNになるので比較で弾かれるようにする
+            if near > t0 {
+                t0 = near;
+            }
+            if far < t1 {
+                t1 = far;
+            }
+            if t0 > t1 {
+                return None;
+            }
+        }
+
+        Some((t0, t1))
+    }
+
+    // 外接球(中心, 半径)
+    pub fn bounding_sphere(&self) -> (V3, f64) {
+        let center = self.centroid();
+        (center, (self.max - center).len())
+    }
+}
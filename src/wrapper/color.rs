@@ -8,22 +8,6 @@ impl Color {
         Color(x, y, z)
     }
 
-    pub fn as_rgb(self) -> (u8, u8, u8) {
-        (
-            (self.0.min(1.0) * 255.0) as u8,
-            (self.1.min(1.0) * 255.0) as u8,
-            (self.2.min(1.0) * 255.0) as u8,
-        )
-    }
-
-    pub fn gamma_correction(self, gamma: f64) -> Self {
-        Color(
-            self.0.powf(1.0 / gamma),
-            self.1.powf(1.0 / gamma),
-            self.2.powf(1.0 / gamma),
-        )
-    }
-
     pub fn black() -> Self {
         Color(0.0, 0.0, 0.0)
     }
@@ -40,14 +24,23 @@ impl Color {
         Color(f(self.0), f(self.1), f(self.2))
     }
 
-    pub fn brightness(&self) -> f64 {
-        (self.0 + self.1 + self.2) / 3.0
+    pub fn channel(&self, i: usize) -> f64 {
+        match i {
+            0 => self.0,
+            1 => self.1,
+            2 => self.2,
+            _ => unreachable!(),
+        }
     }
 
     pub fn luminance(&self) -> f64 {
         self.0 * 0.2126 + self.1 * 0.7152 + self.2 * 0.0722
     }
 
+    pub fn is_finite(&self) -> bool {
+        self.0.is_finite() && self.1.is_finite() && self.2.is_finite()
+    }
+
     pub fn adjust_luminance(self, new_luminance: f64) -> Self {
         if self.luminance() < 0.0001 && new_luminance < 0.0001 {
             self
@@ -72,8 +65,3 @@ impl AddAssign for Color {
         self.2 += other.2;
     }
 }
-
-#[test]
-fn color_white() {
-    assert_eq!(Color(1.0, 1.0, 1.0).as_rgb(), (255, 255, 255));
-}
@@ -8,6 +8,6 @@ pub struct Ray {
 
 impl Ray {
     pub fn extend_at(&self, scaler: f64) -> V3 {
-        self.origin.clone() + self.dir.clone().as_v3().scale(scaler)
+        self.origin + self.dir.as_v3().scale(scaler)
     }
 }
@@ -37,14 +37,36 @@ impl V3 {
         self.scale(1.0 / s)
     }
 
-    pub fn multiply(self, other: Self) -> Self {
-        V3(self.0 * other.0, self.1 * other.1, self.2 * other.2)
-    }
-
     pub fn zero() -> Self {
         V3(0.0, 0.0, 0.0)
     }
 
+    pub fn min(self, other: Self) -> Self {
+        V3(
+            self.0.min(other.0),
+            self.1.min(other.1),
+            self.2.min(other.2),
+        )
+    }
+
+    pub fn max(self, other: Self) -> Self {
+        V3(
+            self.0.max(other.0),
+            self.1.max(other.1),
+            self.2.max(other.2),
+        )
+    }
+
+    // 0: x, 1: y, 2: z
+    pub fn axis(&self, axis: usize) -> f64 {
+        match axis {
+            0 => self.0,
+            1 => self.1,
+            2 => self.2,
+            _ => unreachable!(),
+        }
+    }
+
     pub fn x(&self) -> f64 {
         self.0
     }
@@ -118,16 +140,24 @@ impl V3U {
         self.0.y()
     }
 
-    pub fn z(&self) -> f64 {
-        self.0.z()
-    }
-
     pub fn neg(self) -> Self {
         V3U(self.0.scale(-1.0))
     }
 
+    // selfをw軸とする正規直交基底のu, v
+    pub fn orthonormal_basis(&self) -> (V3U, V3U) {
+        let u = if self.x().abs() > 0.1 {
+            V3U::from_v3(V3U::unit_y().as_v3().cross(self.as_v3()))
+        } else {
+            V3U::from_v3(V3U::unit_x().as_v3().cross(self.as_v3()))
+        };
+        let v = V3U::from_v3_unsafe(self.as_v3().cross(u.as_v3()));
+
+        (u, v)
+    }
+
     pub fn flip_if_close(self, target: &V3U) -> V3U {
-        if self.dot(&target) < 0.0 {
+        if self.dot(target) < 0.0 {
             self
         } else {
             self.neg()
@@ -158,9 +188,7 @@ mod tests {
 
     #[quickcheck]
     fn cross_product_perpendicularity(v1: V3, v2: V3) -> bool {
-        let v1_clone = v1.clone();
-        let v2_clone = v2.clone();
         let c = v1.cross(v2);
-        c.dot(&v1_clone).abs() <= 0.01 && c.dot(&v2_clone).abs() <= 0.01
+        c.dot(&v1).abs() <= 0.01 && c.dot(&v2).abs() <= 0.01
     }
 }